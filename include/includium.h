@@ -14,7 +14,7 @@ typedef struct includium_ctx includium_ctx;
 
 // Configuration structure for the preprocessor
 typedef struct includium_config {
-  // Target OS: 0=Linux, 1=Windows, 2=MacOS
+  // Target OS: 0=Linux, 1=Windows, 2=MacOS, 3=FreeBSD
   int target;
   // Compiler: 0=GCC, 1=Clang, 2=MSVC
   int compiler;
@@ -30,6 +30,14 @@ void includium_free(includium_ctx *ctx);
 
 char *includium_process(includium_ctx *ctx, const char *input);
 
+// Process len bytes of input, which may contain NUL bytes; with lossy
+// non-zero, invalid UTF-8 becomes U+FFFD instead of an error
+char *includium_process_bytes(includium_ctx *ctx, const char *input, size_t len, int lossy);
+
+// Define an object-like macro; line breaks in body become spaces.
+// Returns 0 on success, -1 on error
+int includium_define(includium_ctx *ctx, const char *name, const char *body);
+
 void includium_free_result(char *result);
 
 const char *includium_last_error(void);
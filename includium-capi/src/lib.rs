@@ -0,0 +1,7 @@
+//! The includium C API as a shared library
+//!
+//! The functions live in the `includium` crate; this crate only links them
+//! into `libincludium`, so that `includium` itself stays a plain Rust library
+//! that builds without `std`.
+
+extern crate includium_core;
@@ -2,9 +2,15 @@
 #![warn(clippy::expect_used)]
 
 use anyhow::{Context, Result};
-use clap::{Parser, ValueEnum};
-use includium::{Compiler, PreprocessorConfig, Target, WarningHandler};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use includium::{
+    Compiler, FileSystemResolver, IncludeResolution, Language, PreprocessorConfig,
+    PreprocessorConfigBuilder, Standard, Target, WarningHandler,
+};
 use std::{
+    cell::RefCell,
+    collections::BTreeSet,
     fs,
     io::{self, prelude::*},
     path::{Path, PathBuf},
@@ -14,6 +20,10 @@ use std::{
     time::{Duration, Instant},
 };
 
+mod repl;
+#[cfg(feature = "watch")]
+mod watch;
+
 /// Exit codes for different error conditions
 mod exit_code {
     pub const SUCCESS: i32 = 0;
@@ -53,8 +63,41 @@ mod exit_code {
 
 For more information, visit: https://github.com/walker84837/includium"
 )]
-#[command(arg_required_else_help = true)]
+#[command(
+    arg_required_else_help = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    preprocess: Option<PreprocessArgs>,
+}
+
+/// Auxiliary subcommands; preprocessing is the default when none is given
+#[derive(Subcommand)]
+enum Command {
+    /// Print a shell completion script to stdout
+    #[command(hide = true)]
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+
+    /// Print a roff man page to stdout
+    #[command(hide = true)]
+    Manpage,
+
+    /// Preprocess lines as they are typed, keeping macros between them
+    Repl(repl::ReplArgs),
+}
+
+/// Options for the default preprocess invocation
+#[derive(Args)]
+struct PreprocessArgs {
     /// Input file to preprocess (use '-' for stdin)
     #[arg(help = "Input C/C++ file to preprocess (use '-' for stdin)")]
     input: PathBuf,
@@ -96,6 +139,102 @@ struct Cli {
     )]
     include_dirs: Vec<PathBuf>,
 
+    /// Ignore include paths from the environment
+    #[arg(long, help = "Ignore CPATH, C_INCLUDE_PATH and CPLUS_INCLUDE_PATH")]
+    no_env_includes: bool,
+
+    /// Limit runs of blank output lines
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Keep at most N consecutive blank lines in the output"
+    )]
+    collapse_blank_lines: Option<usize>,
+
+    /// Give up after this many seconds
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_timeout,
+        help = "Stop with an error if preprocessing takes longer than SECONDS"
+    )]
+    timeout: Option<Duration>,
+
+    /// Largest header accepted
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_size,
+        help = "Stop with an error if an included file is larger than BYTES (K, M and G suffixes accepted)"
+    )]
+    max_include_size: Option<usize>,
+
+    /// Largest total input accepted
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_size,
+        help = "Stop with an error if the input and its includes add up to more than BYTES"
+    )]
+    max_total_size: Option<usize>,
+
+    /// Source language
+    #[arg(
+        short = 'x',
+        long,
+        value_enum,
+        help = "Source language [default: from the input file extension, else c]"
+    )]
+    language: Option<LanguageValue>,
+
+    /// Language standard
+    #[arg(
+        long = "std",
+        value_enum,
+        value_name = "STD",
+        help = "Language standard for #if keywords [default: c17, or c++17 for C++]"
+    )]
+    standard: Option<StandardValue>,
+
+    /// Print each header as it is opened
+    #[arg(
+        short = 'H',
+        long,
+        value_enum,
+        value_name = "STYLE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "gcc",
+        help = "Print each included header to stderr as it is opened (gcc: dots per depth, msvc: /showIncludes notes)"
+    )]
+    show_includes: Option<ShowIncludesStyle>,
+
+    /// Write the include graph as Graphviz DOT
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write the include graph to FILE in Graphviz DOT format"
+    )]
+    deps_dot: Option<PathBuf>,
+
+    /// Define a macro
+    #[arg(
+        short = 'D',
+        long = "define",
+        value_name = "NAME[=VALUE]",
+        help = "Define a macro (value defaults to 1)"
+    )]
+    defines: Vec<String>,
+
+    /// Undefine a macro
+    #[arg(
+        short = 'U',
+        long = "undefine",
+        value_name = "NAME",
+        help = "Undefine a macro"
+    )]
+    undefines: Vec<String>,
+
     /// Maximum recursion depth for macro expansion
     #[arg(
         long,
@@ -109,6 +248,31 @@ struct Cli {
     #[cfg(feature = "json")]
     json: bool,
 
+    /// Write the macro table after processing as JSON
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Write all macros defined after processing to FILE as JSON"
+    )]
+    #[cfg(feature = "json")]
+    export_macros: Option<PathBuf>,
+
+    /// Start from a macro table written by --export-macros
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Define the macros in FILE, written by --export-macros, before processing"
+    )]
+    #[cfg(feature = "json")]
+    import_macros: Option<PathBuf>,
+
+    /// Print the effective macro table instead of the preprocessed output
+    #[arg(
+        long,
+        help = "Print all macros defined after processing instead of the output"
+    )]
+    list_macros: bool,
+
     /// Output in plain text format (no formatting)
     #[arg(long, help = "Output in plain text format for scripts")]
     plain: bool,
@@ -129,6 +293,49 @@ struct Cli {
     #[arg(short = 'W', long, help = "Enable preprocessing warnings")]
     warnings: bool,
 
+    /// Reprocess whenever the input or one of its includes changes
+    #[cfg(feature = "watch")]
+    #[arg(
+        long,
+        help = "Reprocess whenever the input or one of its includes changes"
+    )]
+    watch: bool,
+
+    /// Leave an existing output file alone when preprocessing fails
+    #[arg(
+        long,
+        help = "Keep an existing output file instead of removing it when preprocessing fails"
+    )]
+    keep_output_on_error: bool,
+
+    /// Treat conformance diagnostics as errors
+    #[arg(
+        long,
+        help = "Treat conformance problems (conflicting redefinitions, invalid pastes, '##' at either end of a macro body, unknown directives, null characters) as errors"
+    )]
+    strict: bool,
+
+    /// Strip MSVC-specific syntax
+    #[arg(
+        long,
+        help = "With --compiler msvc, remove __declspec(...), calling conventions and SAL annotations"
+    )]
+    strip_msvc_extensions: bool,
+
+    /// Keep `#line` directives in the output
+    #[arg(
+        long,
+        help = "Keep #line directives in the output, so later tools report the positions they declare"
+    )]
+    keep_line_directives: bool,
+
+    /// Warn about function-like macro names used without arguments
+    #[arg(
+        long,
+        help = "Warn when a function-like macro name appears without an argument list (needs -W to be shown)"
+    )]
+    warn_bare_function_macro: bool,
+
     /// Show what would happen without preprocessing
     #[arg(
         short = 'n',
@@ -153,6 +360,8 @@ enum TargetValue {
     Windows,
     #[clap(name = "macos")]
     MacOS,
+    #[clap(name = "freebsd")]
+    FreeBSD,
 }
 
 impl From<TargetValue> for Target {
@@ -161,6 +370,73 @@ impl From<TargetValue> for Target {
             TargetValue::Linux => Target::Linux,
             TargetValue::Windows => Target::Windows,
             TargetValue::MacOS => Target::MacOS,
+            TargetValue::FreeBSD => Target::FreeBSD,
+        }
+    }
+}
+
+/// Output styles for `--show-includes`
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ShowIncludesStyle {
+    /// `gcc -H`: one dot per nesting level, then the path
+    Gcc,
+    /// MSVC `/showIncludes`: `Note: including file:` indented by depth
+    Msvc,
+}
+
+/// Source language values for CLI
+#[derive(Clone, Debug, ValueEnum)]
+enum LanguageValue {
+    C,
+    #[clap(name = "c++")]
+    Cpp,
+}
+
+impl From<LanguageValue> for Language {
+    fn from(value: LanguageValue) -> Self {
+        match value {
+            LanguageValue::C => Language::C,
+            LanguageValue::Cpp => Language::Cpp,
+        }
+    }
+}
+
+/// Language standard values for CLI
+#[derive(Clone, Debug, ValueEnum)]
+enum StandardValue {
+    C89,
+    C99,
+    C11,
+    C17,
+    C23,
+    #[clap(name = "c++98")]
+    Cpp98,
+    #[clap(name = "c++11")]
+    Cpp11,
+    #[clap(name = "c++14")]
+    Cpp14,
+    #[clap(name = "c++17")]
+    Cpp17,
+    #[clap(name = "c++20")]
+    Cpp20,
+    #[clap(name = "c++23")]
+    Cpp23,
+}
+
+impl From<StandardValue> for Standard {
+    fn from(value: StandardValue) -> Self {
+        match value {
+            StandardValue::C89 => Standard::C89,
+            StandardValue::C99 => Standard::C99,
+            StandardValue::C11 => Standard::C11,
+            StandardValue::C17 => Standard::C17,
+            StandardValue::C23 => Standard::C23,
+            StandardValue::Cpp98 => Standard::Cpp98,
+            StandardValue::Cpp11 => Standard::Cpp11,
+            StandardValue::Cpp14 => Standard::Cpp14,
+            StandardValue::Cpp17 => Standard::Cpp17,
+            StandardValue::Cpp20 => Standard::Cpp20,
+            StandardValue::Cpp23 => Standard::Cpp23,
         }
     }
 }
@@ -223,7 +499,21 @@ fn determine_exit_code(error: &anyhow::Error) -> i32 {
 
 /// Run the main application logic
 fn run() -> Result<()> {
-    let cli = Cli::parse();
+    let cli = match Cli::parse() {
+        Cli {
+            command: Some(command),
+            ..
+        } => return run_command(command),
+        Cli {
+            preprocess: Some(args),
+            ..
+        } => args,
+        // `arg_required_else_help` makes clap print help before we get here
+        Cli { .. } => {
+            Cli::command().print_help()?;
+            return Ok(());
+        }
+    };
 
     // Validate arguments
     validate_args(&cli)?;
@@ -234,15 +524,82 @@ fn run() -> Result<()> {
         return Ok(());
     }
 
+    #[cfg(feature = "watch")]
+    if cli.watch {
+        return watch::watch(&cli);
+    }
+
+    run_once(&cli)?;
+    Ok(())
+}
+
+/// Run one of the auxiliary subcommands
+fn run_command(command: Command) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    match command {
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "includium", &mut stdout);
+        }
+        Command::Manpage => {
+            clap_mangen::Man::new(Cli::command())
+                .render(&mut stdout)
+                .context("Failed to write man page")?;
+        }
+        Command::Repl(args) => repl::run(&args)?,
+    }
+    Ok(())
+}
+
+/// Preprocess the input once and write the result
+///
+/// Returns the files that were read through the include resolver, so callers
+/// can track what the output depends on. On failure an existing output file
+/// is removed, unless `--keep-output-on-error` is given, so later build steps
+/// can't pick up stale output.
+fn run_once(cli: &PreprocessArgs) -> Result<Vec<PathBuf>> {
+    let result = preprocess_and_write(cli);
+    if result.is_err() && !cli.keep_output_on_error {
+        remove_stale_output(cli);
+    }
+    result
+}
+
+/// Remove the output file left over from an earlier run, if there is one
+fn remove_stale_output(cli: &PreprocessArgs) {
+    let Some(output_path) = cli.output.as_ref().filter(|p| p.as_os_str() != "-") else {
+        return;
+    };
+    match fs::remove_file(output_path) {
+        Ok(()) => {
+            if cli.verbose && !cli.quiet {
+                eprintln!("Removed stale output file: {}", output_path.display());
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!(
+            "Warning: failed to remove stale output file {}: {e}",
+            output_path.display()
+        ),
+    }
+}
+
+/// Read, preprocess and write once; the body of [`run_once`]
+fn preprocess_and_write(cli: &PreprocessArgs) -> Result<Vec<PathBuf>> {
     // Read input
     let input_content = read_input(&cli.input)?;
 
     // Create preprocessor configuration
-    let config = create_config(&cli)?;
+    let dependencies = Dependencies::default();
+    let config = create_config(cli, &dependencies)?;
 
     // Preprocess the input
     let start_time = Instant::now();
     let mut driver = includium::PreprocessorDriver::new();
+    // Imported macros come first so that -D and -U still apply on top of them
+    #[cfg(feature = "json")]
+    if let Some(path) = &cli.import_macros {
+        driver.import_macros(&read_macro_set(path)?);
+    }
     driver.apply_config(&config);
     if cli.input.as_os_str() != "-" {
         driver.set_current_file(cli.input.to_string_lossy().to_string());
@@ -251,17 +608,37 @@ fn run() -> Result<()> {
         Ok(output) => output,
         Err(e) => {
             eprintln!("Preprocessing error: {:#?}", e);
+            #[cfg(feature = "json")]
+            if cli.json {
+                write_json_error(&e)?;
+            }
             return Err(anyhow::anyhow!("Failed to preprocess input: {}", e));
         }
     };
     let processing_time = start_time.elapsed();
 
+    if let Some(dot_path) = &cli.deps_dot {
+        fs::write(dot_path, driver.include_graph_dot())
+            .with_context(|| format!("Failed to write include graph: {}", dot_path.display()))?;
+    }
+
+    #[cfg(feature = "json")]
+    if let Some(path) = &cli.export_macros {
+        let json = serde_json::to_string_pretty(&driver.export_macros())?;
+        fs::write(path, json + "\n")
+            .with_context(|| format!("Failed to write macros: {}", path.display()))?;
+    }
+
     // Write output
-    write_output(&cli, &processed_output)?;
+    if cli.list_macros {
+        write_macro_list(cli, &driver)?;
+    } else {
+        write_output(cli, &processed_output)?;
+    }
 
     // Show verbose information
     if cli.verbose {
-        show_verbose_info(&cli, processing_time);
+        show_verbose_info(cli, processing_time);
     }
 
     // Show success message in verbose mode
@@ -274,11 +651,12 @@ fn run() -> Result<()> {
         eprintln!("✓ Preprocessed {input_display} -> {output_display}");
     }
 
-    Ok(())
+    let dependencies = dependencies.borrow().iter().cloned().collect();
+    Ok(dependencies)
 }
 
 /// Validate command-line arguments
-fn validate_args(cli: &Cli) -> Result<()> {
+fn validate_args(cli: &PreprocessArgs) -> Result<()> {
     // Check that input and output are not the same file
     if let Some(output) = &cli.output
         && output != &PathBuf::from("-")
@@ -290,16 +668,27 @@ fn validate_args(cli: &Cli) -> Result<()> {
         ));
     }
 
-    // Validate recursion limit
-    if cli.recursion_limit == 0 {
-        return Err(anyhow::anyhow!("Recursion limit must be greater than 0"));
+    // Watching needs a file to watch and somewhere other than the terminal pipe to write
+    #[cfg(feature = "watch")]
+    if cli.watch {
+        if cli.input.as_os_str() == "-" {
+            return Err(anyhow::anyhow!("--watch cannot be used with stdin input"));
+        }
+        if cli.output.as_ref().is_some_and(|o| o.as_os_str() == "-") {
+            return Err(anyhow::anyhow!(
+                "--watch cannot be used when piping output to stdout with '-o -'"
+            ));
+        }
     }
 
+    // Validate the configuration up front so dry runs report the same errors
+    config_builder(cli, &Dependencies::default()).build()?;
+
     Ok(())
 }
 
 /// Show dry run information
-fn show_dry_run_info(cli: &Cli) {
+fn show_dry_run_info(cli: &PreprocessArgs) {
     let input_display = format_input(&cli.input);
     let output_display = cli
         .output
@@ -326,61 +715,121 @@ fn show_dry_run_info(cli: &Cli) {
     }
 }
 
-/// Create preprocessor configuration from CLI arguments
-fn create_config(cli: &Cli) -> Result<PreprocessorConfig> {
-    let target: Target = cli.target.clone().into();
-    let compiler: Compiler = cli.compiler.clone().into();
-
-    let mut config = match target {
-        Target::Linux => PreprocessorConfig::for_linux().with_compiler(compiler),
-        Target::Windows => PreprocessorConfig::for_windows().with_compiler(compiler),
-        Target::MacOS => PreprocessorConfig::for_macos().with_compiler(compiler),
+/// Files read through the include resolver during a run
+type Dependencies = Rc<RefCell<BTreeSet<PathBuf>>>;
+
+/// Parse a `--timeout` value in seconds, which may have a fractional part
+fn parse_timeout(value: &str) -> std::result::Result<Duration, String> {
+    let seconds: f64 = value.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("{e}"))
+}
+
+/// Parse a size in bytes, optionally followed by a `K`, `M` or `G` suffix
+/// for binary kilo-, mega- and gigabytes
+fn parse_size(value: &str) -> std::result::Result<usize, String> {
+    let (digits, shift) = match value.strip_suffix(['K', 'k', 'M', 'm', 'G', 'g']) {
+        Some(digits) => match value.as_bytes()[value.len() - 1].to_ascii_uppercase() {
+            b'K' => (digits, 10),
+            b'M' => (digits, 20),
+            _ => (digits, 30),
+        },
+        None => (value, 0),
     };
+    let size: usize = digits.parse().map_err(|e| format!("{e}"))?;
+    size.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size too large: {value}"))
+}
 
-    // Set recursion limit
-    config.recursion_limit = cli.recursion_limit;
-
-    // Setup include resolver
-    let include_dirs = cli.include_dirs.clone();
-    config.include_resolver = Some(Rc::new(move |path, kind, context| {
-        let mut search_dirs = Vec::new();
-
-        // For local includes, search the directory of the including file first
-        if kind == includium::IncludeKind::Local
-            && let Some(including_file) = context.include_stack.last()
-            && including_file != "<stdin>"
-            && let Some(parent) = Path::new(including_file).parent()
-        {
-            search_dirs.push(parent.to_path_buf());
+/// Create preprocessor configuration from CLI arguments
+fn create_config(cli: &PreprocessArgs, dependencies: &Dependencies) -> Result<PreprocessorConfig> {
+    let (config, warnings) = config_builder(cli, dependencies).build_with_warnings()?;
+    if !cli.quiet {
+        for warning in warnings {
+            eprintln!("Warning: {warning}");
         }
+    }
 
-        // Add explicitly provided include directories
-        for dir in &include_dirs {
-            search_dirs.push(dir.clone());
-        }
+    Ok(config)
+}
 
-        // Search for the file
-        for dir in search_dirs {
-            let full_path = dir.join(path);
-            if full_path.exists() && full_path.is_file() {
-                return fs::read_to_string(full_path).ok();
-            }
-        }
+/// Collect the command-line settings into a configuration builder
+fn config_builder(cli: &PreprocessArgs, dependencies: &Dependencies) -> PreprocessorConfigBuilder {
+    let language = cli
+        .language
+        .clone()
+        .map_or_else(|| Language::from_extension(&cli.input), Language::from);
+    let standard = cli
+        .standard
+        .clone()
+        .map_or_else(|| Standard::default_for(language), Standard::from);
+    let mut builder = PreprocessorConfig::builder()
+        .target(cli.target.clone().into())
+        .compiler(cli.compiler.clone().into())
+        .standard(standard)
+        .recursion_limit(cli.recursion_limit)
+        .strict(cli.strict)
+        .msvc_compat_stubs(cli.strip_msvc_extensions)
+        .passthrough_line_directives(cli.keep_line_directives)
+        .warn_bare_function_macro(cli.warn_bare_function_macro)
+        .record_include_graph(cli.deps_dot.is_some())
+        .require_existing_include_dirs(true);
+
+    if let Some(max) = cli.collapse_blank_lines {
+        builder = builder.collapse_blank_lines(max);
+    }
+    if let Some(timeout) = cli.timeout {
+        builder = builder.deadline(Instant::now() + timeout);
+    }
+    if let Some(max) = cli.max_include_size {
+        builder = builder.max_include_bytes(max);
+    }
+    if let Some(max) = cli.max_total_size {
+        builder = builder.max_total_bytes(max);
+    }
+    for define in &cli.defines {
+        builder = builder.define(define);
+    }
+    for name in &cli.undefines {
+        builder = builder.undefine(name);
+    }
+    for dir in &cli.include_dirs {
+        builder = builder.include_dir(dir);
+    }
 
-        None
+    // Setup include resolver, recording every header it reads
+    let resolver = if cli.no_env_includes {
+        FileSystemResolver::new()
+    } else {
+        FileSystemResolver::from_env(language)
+    };
+    let dependencies = Rc::clone(dependencies);
+    builder = builder.include_resolver(Rc::new(move |path, kind, context| {
+        let resolution = resolver.resolve(path, &kind, context);
+        if let IncludeResolution::File { path, .. } = &resolution {
+            dependencies.borrow_mut().insert(PathBuf::from(path));
+        }
+        resolution
     }));
 
+    if let Some(style) = cli.show_includes {
+        builder = builder.on_include_enter(Rc::new(move |path, depth, _kind| match style {
+            ShowIncludesStyle::Gcc => eprintln!("{} {path}", ".".repeat(depth)),
+            ShowIncludesStyle::Msvc => {
+                eprintln!("Note: including file:{}{path}", " ".repeat(depth));
+            }
+        }));
+    }
+
     // Setup warning handler if warnings are enabled
     if cli.warnings {
-        let warning_handler = create_warning_handler(cli);
-        config.warning_handler = Some(warning_handler);
+        builder = builder.warning_handler(create_warning_handler(cli));
     }
 
-    Ok(config)
+    builder
 }
 
 /// Create a warning handler
-fn create_warning_handler(cli: &Cli) -> WarningHandler {
+fn create_warning_handler(cli: &PreprocessArgs) -> WarningHandler {
     let show_warnings = cli.warnings;
     let quiet = cli.quiet;
 
@@ -394,46 +843,146 @@ fn create_warning_handler(cli: &Cli) -> WarningHandler {
 
 /// Read input from file or stdin
 fn read_input(input_path: &PathBuf) -> Result<String> {
-    if input_path == &PathBuf::from("-") {
-        let mut buffer = String::new();
+    let bytes = if input_path == &PathBuf::from("-") {
+        let mut buffer = Vec::new();
         io::stdin()
-            .read_to_string(&mut buffer)
+            .read_to_end(&mut buffer)
             .context("Failed to read from stdin")?;
-        Ok(buffer)
+        buffer
     } else {
         // Read from file
-        fs::read_to_string(input_path)
-            .with_context(|| format!("Failed to read input file: {}", input_path.display()))
+        fs::read(input_path)
+            .with_context(|| format!("Failed to read input file: {}", input_path.display()))?
+    };
+    decode_input(bytes, &format_input(input_path))
+}
+
+/// Decode raw input bytes as UTF-8, dropping a leading byte order mark
+fn decode_input(mut bytes: Vec<u8>, name: &str) -> Result<String> {
+    const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+    if bytes.starts_with(UTF8_BOM) {
+        bytes.drain(..UTF8_BOM.len());
     }
+    String::from_utf8(bytes).map_err(|e| {
+        let error = e.utf8_error();
+        let line = e.as_bytes()[..error.valid_up_to()]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1;
+        anyhow::anyhow!(
+            "{name} is not valid UTF-8: invalid byte sequence at line {line} (byte offset {})",
+            error.valid_up_to()
+        )
+    })
 }
 
 /// Write output to file or stdout
-fn write_output(cli: &Cli, content: &str) -> Result<()> {
+fn write_output(cli: &PreprocessArgs, content: &str) -> Result<()> {
     #[cfg(feature = "json")]
     if cli.json {
         return write_json_output(cli, content);
     }
 
-    let output_content = content.to_string();
+    write_text(cli, content)
+}
 
+/// Write plain text to the output file or stdout
+fn write_text(cli: &PreprocessArgs, content: &str) -> Result<()> {
     match &cli.output {
         Some(output_path) if output_path != &PathBuf::from("-") => {
-            fs::write(output_path, output_content).with_context(|| {
+            write_atomically(output_path, content).with_context(|| {
                 format!("Failed to write to output file: {}", output_path.display())
             })?;
         }
         _ => {
-            // Write to stdout
-            print!("{}", output_content);
+            let mut stdout = io::stdout().lock();
+            stdout
+                .write_all(content.as_bytes())
+                .and_then(|()| stdout.flush())
+                .context("Failed to write to stdout")?;
         }
     }
 
     Ok(())
 }
 
+/// Replace `path` with `content` so readers see either the old file or the
+/// complete new one, never a partly written file
+///
+/// The content goes to a temporary file in the same directory, which is
+/// synced and then renamed over `path`.
+fn write_atomically(path: &Path, content: &str) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name().map_or_else(
+        || "output".into(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    let temp_path = dir.join(format!(".{file_name}.{}.tmp", process::id()));
+
+    let result = (|| {
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Write the macro table in `#define` form (or JSON)
+fn write_macro_list(cli: &PreprocessArgs, driver: &includium::PreprocessorDriver) -> Result<()> {
+    #[cfg(feature = "json")]
+    if cli.json {
+        return write_json_macros(cli, driver);
+    }
+
+    write_text(cli, &driver.dump_macros())
+}
+
+/// Read a macro table written by `--export-macros`
+#[cfg(feature = "json")]
+fn read_macro_set(path: &Path) -> Result<includium::MacroSet> {
+    let json = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read macros: {}", path.display()))?;
+    serde_json::from_str(&json).with_context(|| format!("Invalid macro file: {}", path.display()))
+}
+
+/// Write the macro table as a JSON array
+#[cfg(feature = "json")]
+fn write_json_macros(cli: &PreprocessArgs, driver: &includium::PreprocessorDriver) -> Result<()> {
+    use serde_json::json;
+
+    let mut macros: Vec<_> = driver.get_macros().iter().collect();
+    macros.sort_by(|a, b| a.0.cmp(b.0));
+
+    let result: Vec<_> = macros
+        .into_iter()
+        .map(|(name, mac)| {
+            json!({
+                "name": name,
+                "params": mac.params(),
+                "variadic": mac.is_variadic(),
+                "body": mac.body_text(),
+                "builtin": mac.is_builtin(),
+            })
+        })
+        .collect();
+
+    let mut json = serde_json::to_string_pretty(&result)?;
+    json.push('\n');
+    write_text(cli, &json)
+}
+
 /// Write JSON output
 #[cfg(feature = "json")]
-fn write_json_output(cli: &Cli, content: &str) -> Result<()> {
+fn write_json_output(cli: &PreprocessArgs, content: &str) -> Result<()> {
     use serde_json::json;
 
     let result = json!({
@@ -451,8 +1000,29 @@ fn write_json_output(cli: &Cli, content: &str) -> Result<()> {
     Ok(())
 }
 
+/// Write a preprocessing error as JSON, keyed by its stable code
+#[cfg(feature = "json")]
+fn write_json_error(error: &includium::PreprocessError) -> Result<()> {
+    use serde_json::json;
+
+    let result = json!({
+        "success": false,
+        "error": {
+            "code": error.code(),
+            "message": error.to_string(),
+            "file": error.file,
+            "line": error.line,
+            "column": error.column,
+            "included_path": error.included_path(),
+        }
+    });
+
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
 /// Show verbose information
-fn show_verbose_info(cli: &Cli, processing_time: Duration) {
+fn show_verbose_info(cli: &PreprocessArgs, processing_time: Duration) {
     if cli.quiet {
         return;
     }
@@ -494,6 +1064,7 @@ fn format_target(target: &TargetValue) -> String {
         TargetValue::Linux => "Linux".to_string(),
         TargetValue::Windows => "Windows".to_string(),
         TargetValue::MacOS => "macOS".to_string(),
+        TargetValue::FreeBSD => "FreeBSD".to_string(),
     }
 }
 
@@ -505,3 +1076,54 @@ fn format_compiler(compiler: &CompilerValue) -> String {
         CompilerValue::MSVC => "MSVC".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_invocation_parses_into_preprocess() {
+        let cli =
+            Cli::try_parse_from(["includium", "input.c", "-o", "out.i", "-I", "inc"]).unwrap();
+        assert!(cli.command.is_none());
+        let args = cli.preprocess.unwrap();
+        assert_eq!(args.input, PathBuf::from("input.c"));
+        assert_eq!(args.output, Some(PathBuf::from("out.i")));
+        assert_eq!(args.include_dirs, vec![PathBuf::from("inc")]);
+    }
+
+    #[test]
+    fn subcommands_parse_without_input() {
+        let cli = Cli::try_parse_from(["includium", "completions", "zsh"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Completions { shell: Shell::Zsh })
+        ));
+
+        let cli = Cli::try_parse_from(["includium", "manpage"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Manpage)));
+    }
+
+    #[test]
+    fn flags_without_input_are_rejected() {
+        assert!(Cli::try_parse_from(["includium", "-v"]).is_err());
+    }
+
+    #[test]
+    fn completions_contain_long_flags() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::PowerShell] {
+            let mut buf = Vec::new();
+            clap_complete::generate(shell, &mut Cli::command(), "includium", &mut buf);
+            let script = String::from_utf8(buf).unwrap();
+            for flag in [
+                "output",
+                "include",
+                "recursion-limit",
+                "list-macros",
+                "strict",
+            ] {
+                assert!(script.contains(flag), "{shell} completions lack --{flag}");
+            }
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! `repl` subcommand: process input line by line with one persistent preprocessor
+
+use anyhow::{Context, Result};
+use clap::Args;
+use includium::{
+    FileSystemResolver, Language, PreprocessorConfig, PreprocessorDriver, WarningHandler,
+};
+use std::{
+    fs,
+    io::{self, prelude::*},
+    path::PathBuf,
+    rc::Rc,
+};
+
+use crate::{CompilerValue, TargetValue};
+
+/// File name reported for lines typed at the prompt
+const REPL_FILE: &str = "<repl>";
+
+const HELP: &str = "\
+Lines are preprocessed as they are entered; directives update the macro table
+and other lines print their expansion. A line ending in '\\' or an unclosed
+'(' continues on the next line.
+
+  :macros       list every defined macro
+  :undef NAME   remove a macro
+  :reset        drop user macros and restore the builtins
+  :load FILE    process FILE for its definitions, discarding its output
+  :help         show this help
+  :quit         leave (as does end of input)";
+
+/// Options for `includium repl`
+#[derive(Args)]
+pub struct ReplArgs {
+    /// Target operating system
+    #[arg(
+        short = 't',
+        long,
+        value_enum,
+        default_value = "linux",
+        help = "Target operating system"
+    )]
+    target: TargetValue,
+
+    /// Compiler dialect
+    #[arg(
+        short = 'c',
+        long,
+        value_enum,
+        default_value = "gcc",
+        help = "Compiler dialect for predefined macros"
+    )]
+    compiler: CompilerValue,
+
+    /// Add include directory
+    #[arg(
+        short = 'I',
+        long = "include",
+        value_name = "DIR",
+        help = "Add directory to include search path"
+    )]
+    include_dirs: Vec<PathBuf>,
+
+    /// Ignore include paths from the environment
+    #[arg(long, help = "Ignore CPATH, C_INCLUDE_PATH and CPLUS_INCLUDE_PATH")]
+    no_env_includes: bool,
+
+    /// Define a macro
+    #[arg(
+        short = 'D',
+        long = "define",
+        value_name = "NAME[=VALUE]",
+        help = "Define a macro (value defaults to 1)"
+    )]
+    defines: Vec<String>,
+}
+
+/// A command entered at the prompt with a leading `:`
+#[derive(Debug, PartialEq, Eq)]
+pub enum MetaCommand {
+    /// `:macros`
+    Macros,
+    /// `:undef NAME`
+    Undef(String),
+    /// `:reset`
+    Reset,
+    /// `:load FILE`
+    Load(PathBuf),
+    /// `:help`
+    Help,
+    /// `:quit` or `:q`
+    Quit,
+}
+
+/// Parse a meta-command, or return `None` if `line` isn't one
+///
+/// Lines starting with `::`, such as a C++ qualified name, are not
+/// meta-commands.
+pub fn parse_meta_command(line: &str) -> Option<Result<MetaCommand, String>> {
+    let rest = line.trim().strip_prefix(':')?;
+    if rest.starts_with(':') {
+        return None;
+    }
+    let (name, arg) = rest
+        .split_once(char::is_whitespace)
+        .map_or((rest, ""), |(name, arg)| (name, arg.trim()));
+    let command = match (name, arg) {
+        ("macros", "") => MetaCommand::Macros,
+        ("reset", "") => MetaCommand::Reset,
+        ("help", "") => MetaCommand::Help,
+        ("quit" | "q", "") => MetaCommand::Quit,
+        ("undef", "") => return Some(Err(":undef needs a macro name".to_string())),
+        ("undef", name) if is_identifier(name) => MetaCommand::Undef(name.to_string()),
+        ("undef", name) => return Some(Err(format!("not a macro name: {name}"))),
+        ("load", "") => return Some(Err(":load needs a file name".to_string())),
+        ("load", path) => MetaCommand::Load(PathBuf::from(path)),
+        ("macros" | "reset" | "help" | "quit" | "q", _) => {
+            return Some(Err(format!(":{name} takes no argument")));
+        }
+        _ => return Some(Err(format!("unknown command :{name} (try :help)"))),
+    };
+    Some(Ok(command))
+}
+
+/// Whether `name` is a valid C identifier
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Lines entered so far, held until they form a complete input
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    text: String,
+}
+
+impl LineBuffer {
+    /// Whether earlier lines are waiting for their continuation
+    pub fn is_pending(&self) -> bool {
+        !self.text.is_empty()
+    }
+
+    /// Add a line, returning the collected input once it is complete
+    pub fn push(&mut self, line: &str) -> Option<String> {
+        self.text.push_str(line.trim_end_matches(['\n', '\r']));
+        self.text.push('\n');
+        if needs_continuation(&self.text) {
+            None
+        } else {
+            Some(std::mem::take(&mut self.text))
+        }
+    }
+
+    /// Take whatever has been collected, complete or not
+    pub fn take(&mut self) -> String {
+        std::mem::take(&mut self.text)
+    }
+}
+
+/// Whether `input` stops part-way: its last line ends with a backslash, or it
+/// isn't a directive and has an unclosed `(`, as in a macro call whose
+/// arguments continue on the next line
+fn needs_continuation(input: &str) -> bool {
+    let input = input.trim_end_matches('\n');
+    if input.ends_with('\\') {
+        return true;
+    }
+    !input.trim_start().starts_with('#') && paren_depth(input) > 0
+}
+
+/// Net number of open parentheses outside comments and literals
+fn paren_depth(input: &str) -> isize {
+    let mut depth = 0;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            '"' | '\'' => {
+                while let Some(d) = chars.next() {
+                    match d {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '\n' => break,
+                        d if d == c => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                chars.find(|&d| d == '\n');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut star = false;
+                for d in chars.by_ref() {
+                    if star && d == '/' {
+                        break;
+                    }
+                    star = d == '*';
+                }
+            }
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// A preprocessor kept alive across inputs
+struct Repl {
+    driver: PreprocessorDriver,
+}
+
+impl Repl {
+    fn new(config: &PreprocessorConfig) -> Self {
+        let mut driver = PreprocessorDriver::with_config(config);
+        driver.set_current_file(REPL_FILE.to_string());
+        Repl { driver }
+    }
+
+    /// Preprocess one complete input and print its non-blank output lines
+    fn eval<W: Write>(&mut self, input: &str, out: &mut W) -> Result<()> {
+        match self.driver.process(input) {
+            Ok(output) => {
+                for line in output.lines().filter(|line| !line.trim().is_empty()) {
+                    writeln!(out, "{line}")?;
+                }
+            }
+            Err(e) => eprintln!("Error: {e}"),
+        }
+        Ok(())
+    }
+
+    /// Run a meta-command, returning `false` if the session should end
+    fn run_meta<W: Write>(&mut self, command: MetaCommand, out: &mut W) -> Result<bool> {
+        match command {
+            MetaCommand::Macros => write!(out, "{}", self.driver.dump_macros())?,
+            MetaCommand::Undef(name) => {
+                if !self.driver.get_macros().contains_key(&name) {
+                    eprintln!("Warning: '{name}' is not defined");
+                }
+                self.driver.undef(&name);
+            }
+            MetaCommand::Reset => {
+                self.driver.reset();
+                self.driver.set_current_file(REPL_FILE.to_string());
+            }
+            MetaCommand::Load(path) => self.load(&path),
+            MetaCommand::Help => writeln!(out, "{HELP}")?,
+            MetaCommand::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Process a file for its side effects on the macro table
+    fn load(&mut self, path: &PathBuf) {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Error: failed to read {}: {e}", path.display());
+                return;
+            }
+        };
+        self.driver
+            .set_current_file(path.to_string_lossy().to_string());
+        if let Err(e) = self.driver.process(&content) {
+            eprintln!("Error: {e}");
+        }
+        self.driver.set_current_file(REPL_FILE.to_string());
+    }
+}
+
+/// Run `includium repl` on stdin and stdout
+pub fn run(args: &ReplArgs) -> Result<()> {
+    let config = create_config(args)?;
+    let interactive = atty::is(atty::Stream::Stdin);
+    if interactive {
+        eprintln!(
+            "includium {} repl; :help lists commands",
+            env!("CARGO_PKG_VERSION")
+        );
+    }
+    run_session(
+        &config,
+        io::stdin().lock(),
+        &mut io::stdout().lock(),
+        interactive,
+    )
+}
+
+/// Read inputs from `input` until end of input or `:quit`
+fn run_session<R: BufRead, W: Write>(
+    config: &PreprocessorConfig,
+    input: R,
+    out: &mut W,
+    prompt: bool,
+) -> Result<()> {
+    let mut repl = Repl::new(config);
+    let mut buffer = LineBuffer::default();
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(out, "{}", if buffer.is_pending() { "... " } else { "> " })?;
+            out.flush()?;
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line.context("Failed to read from stdin")?;
+        if !buffer.is_pending() {
+            match parse_meta_command(&line) {
+                Some(Ok(command)) => {
+                    if !repl.run_meta(command, out)? {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Some(Err(message)) => {
+                    eprintln!("Error: {message}");
+                    continue;
+                }
+                None => {}
+            }
+        }
+        if let Some(complete) = buffer.push(&line) {
+            repl.eval(&complete, out)?;
+        }
+    }
+    // Whatever is left at end of input is processed as it stands
+    if buffer.is_pending() {
+        repl.eval(&buffer.take(), out)?;
+    }
+    Ok(())
+}
+
+/// Build the configuration for a session from the command line
+fn create_config(args: &ReplArgs) -> Result<PreprocessorConfig> {
+    let resolver = if args.no_env_includes {
+        FileSystemResolver::new()
+    } else {
+        FileSystemResolver::from_env(Language::C)
+    };
+    let warning_handler: WarningHandler = Rc::new(|message: &str| {
+        eprintln!("Warning: {message}");
+    });
+    let mut builder = PreprocessorConfig::builder()
+        .target(args.target.clone().into())
+        .compiler(args.compiler.clone().into())
+        .include_resolver(resolver.into_resolver())
+        .warning_handler(warning_handler);
+    for define in &args.defines {
+        builder = builder.define(define);
+    }
+    for dir in &args.include_dirs {
+        builder = builder.include_dir(dir);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_commands_parse() {
+        assert_eq!(parse_meta_command(":macros"), Some(Ok(MetaCommand::Macros)));
+        assert_eq!(
+            parse_meta_command("  :reset  "),
+            Some(Ok(MetaCommand::Reset))
+        );
+        assert_eq!(
+            parse_meta_command(":undef FOO"),
+            Some(Ok(MetaCommand::Undef("FOO".to_string())))
+        );
+        assert_eq!(
+            parse_meta_command(":load  include/a b.h "),
+            Some(Ok(MetaCommand::Load(PathBuf::from("include/a b.h"))))
+        );
+        assert_eq!(parse_meta_command(":q"), Some(Ok(MetaCommand::Quit)));
+    }
+
+    #[test]
+    fn non_meta_lines_are_left_alone() {
+        assert_eq!(parse_meta_command("#define X 1"), None);
+        assert_eq!(parse_meta_command("X + 1"), None);
+        assert_eq!(parse_meta_command("::std::size_t n;"), None);
+    }
+
+    #[test]
+    fn bad_meta_commands_are_errors() {
+        for line in [":undef", ":undef 1x", ":load", ":macros all", ":frobnicate"] {
+            assert!(
+                matches!(parse_meta_command(line), Some(Err(_))),
+                "{line} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn backslash_continues_a_line() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(buffer.push("#define MAX(a, b) \\"), None);
+        assert!(buffer.is_pending());
+        assert_eq!(
+            buffer.push("  ((a) > (b) ? (a) : (b))"),
+            Some("#define MAX(a, b) \\\n  ((a) > (b) ? (a) : (b))\n".to_string())
+        );
+        assert!(!buffer.is_pending());
+    }
+
+    #[test]
+    fn unclosed_call_continues_until_balanced() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(buffer.push("MAX(1,"), None);
+        assert_eq!(buffer.push("  (2 * 3)"), None);
+        assert_eq!(buffer.push(")"), Some("MAX(1,\n  (2 * 3)\n)\n".to_string()));
+    }
+
+    #[test]
+    fn parens_in_literals_comments_and_directives_are_ignored() {
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.push("puts(\"(\");").is_some());
+        assert!(buffer.push("c = ')'; /* ( */ // (").is_some());
+        assert!(buffer.push("#define OPEN (").is_some());
+        assert!(buffer.push("x)").is_some());
+    }
+
+    #[test]
+    fn session_keeps_macros_between_inputs() {
+        let config = PreprocessorConfig::builder().build().unwrap();
+        let input = "#define SQ(x) ((x) * (x))\nSQ(2)\nSQ(\n3)\n:undef SQ\nSQ(4)\n:quit\nSQ(5)\n";
+        let mut out = Vec::new();
+        run_session(&config, input.as_bytes(), &mut out, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "((2) * (2))\n((3) * (3))\nSQ(4)\n"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! `--watch` mode: rerun preprocessing whenever the input or one of its includes changes
+
+use anyhow::{Context, Result};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{PreprocessArgs, format_input, run_once};
+
+/// Quiet period after the first change event before rerunning
+const DEBOUNCE: Duration = Duration::from_millis(150);
+
+/// How often the event loop checks whether Ctrl-C was pressed
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Compute the set of files to watch: the input plus everything it included
+pub fn watch_set(input: &Path, dependencies: &[PathBuf]) -> BTreeSet<PathBuf> {
+    std::iter::once(input)
+        .chain(dependencies.iter().map(PathBuf::as_path))
+        .map(fs_canonical)
+        .collect()
+}
+
+/// Canonicalize a path, falling back to the path itself if it doesn't exist
+fn fs_canonical(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// State of a watch session, independent of the file-system notifier
+///
+/// Each call to [`WatchSession::on_change`] reruns preprocessing and refreshes
+/// the watch set from the dependencies of that run.
+pub struct WatchSession<F> {
+    input: PathBuf,
+    rerun: F,
+    watched: BTreeSet<PathBuf>,
+    iteration: usize,
+}
+
+impl<F> WatchSession<F>
+where
+    F: FnMut() -> Result<Vec<PathBuf>>,
+{
+    /// Create a session for `input`; nothing runs until `on_change` is called
+    pub fn new(input: PathBuf, rerun: F) -> Self {
+        let watched = watch_set(&input, &[]);
+        Self {
+            input,
+            rerun,
+            watched,
+            iteration: 0,
+        }
+    }
+
+    /// Rerun preprocessing and return the refreshed watch set
+    ///
+    /// A failed run is reported but keeps the previous dependencies, so a
+    /// transient syntax error doesn't stop the headers from being watched.
+    pub fn on_change(&mut self) -> &BTreeSet<PathBuf> {
+        self.iteration += 1;
+        eprintln!(
+            "--- [{}] preprocessing {} ---",
+            self.iteration,
+            self.input.display()
+        );
+        let start = Instant::now();
+        match (self.rerun)() {
+            Ok(dependencies) => {
+                self.watched = watch_set(&self.input, &dependencies);
+                eprintln!("--- finished in {:?} ---", start.elapsed());
+            }
+            Err(e) => {
+                eprintln!("Error: {e}");
+                eprintln!("--- failed after {:?} ---", start.elapsed());
+            }
+        }
+        &self.watched
+    }
+
+    /// Whether a file-system event touches one of the watched files
+    pub fn is_relevant(&self, event: &Event) -> bool {
+        !matches!(event.kind, EventKind::Access(_))
+            && event
+                .paths
+                .iter()
+                .any(|path| self.watched.contains(&fs_canonical(path)))
+    }
+}
+
+/// Directories to register with the notifier for a set of files
+///
+/// Parent directories are watched instead of the files themselves so editors
+/// that save by renaming a temporary file over the original are still seen.
+fn watch_dirs(files: &BTreeSet<PathBuf>) -> BTreeSet<PathBuf> {
+    files
+        .iter()
+        .filter_map(|file| file.parent().map(Path::to_path_buf))
+        .collect()
+}
+
+/// Run the watch loop until Ctrl-C is pressed
+pub fn watch(cli: &PreprocessArgs) -> Result<()> {
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_flag = Arc::clone(&running);
+    ctrlc::set_handler(move || handler_flag.store(false, Ordering::SeqCst))
+        .context("Failed to install Ctrl-C handler")?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Failed to start file watcher")?;
+    let mut session = WatchSession::new(cli.input.clone(), || run_once(cli));
+    let mut registered = BTreeSet::new();
+
+    if !cli.quiet {
+        eprintln!(
+            "Watching {} for changes (Ctrl-C to exit)",
+            format_input(&cli.input)
+        );
+    }
+
+    loop {
+        let dirs = watch_dirs(session.on_change());
+        for dir in registered.difference(&dirs) {
+            // The directory may already be gone; nothing to clean up then
+            let _ = watcher.unwatch(dir);
+        }
+        for dir in dirs.difference(&registered) {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch {}", dir.display()))?;
+        }
+        registered = dirs;
+
+        // Block until a relevant change arrives or Ctrl-C is pressed
+        loop {
+            if !running.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(Ok(event)) if session.is_relevant(&event) => break,
+                Ok(Err(e)) => eprintln!("Watch error: {e}"),
+                Ok(Ok(_)) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+
+        // Debounce: editors often emit several events for a single save
+        while rx.recv_timeout(DEBOUNCE).is_ok() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn watch_set_contains_input_and_dependencies() {
+        let set = watch_set(
+            Path::new("/nonexistent/main.c"),
+            &[
+                PathBuf::from("/nonexistent/a.h"),
+                PathBuf::from("/nonexistent/a.h"),
+                PathBuf::from("/nonexistent/sub/b.h"),
+            ],
+        );
+        let expected: BTreeSet<PathBuf> = [
+            "/nonexistent/main.c",
+            "/nonexistent/a.h",
+            "/nonexistent/sub/b.h",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(set, expected);
+        assert_eq!(
+            watch_dirs(&set),
+            ["/nonexistent", "/nonexistent/sub"]
+                .into_iter()
+                .map(PathBuf::from)
+                .collect()
+        );
+    }
+
+    #[test]
+    fn on_change_reruns_and_refreshes_watch_set() {
+        let runs = Cell::new(0);
+        let mut session = WatchSession::new(PathBuf::from("/nonexistent/main.c"), || {
+            runs.set(runs.get() + 1);
+            if runs.get() == 2 {
+                anyhow::bail!("syntax error");
+            }
+            Ok(vec![PathBuf::from(format!(
+                "/nonexistent/dep{}.h",
+                runs.get()
+            ))])
+        });
+
+        assert!(
+            session
+                .on_change()
+                .contains(Path::new("/nonexistent/dep1.h"))
+        );
+
+        // A failed run keeps watching the previous dependencies
+        assert!(
+            session
+                .on_change()
+                .contains(Path::new("/nonexistent/dep1.h"))
+        );
+
+        let watched = session.on_change();
+        assert!(watched.contains(Path::new("/nonexistent/dep3.h")));
+        assert!(!watched.contains(Path::new("/nonexistent/dep1.h")));
+        assert_eq!(runs.get(), 3);
+    }
+
+    #[test]
+    fn only_changes_to_watched_files_are_relevant() {
+        let mut session =
+            WatchSession::new(PathBuf::from("/nonexistent/main.c"), || Ok(Vec::new()));
+        session.on_change();
+
+        let modify = EventKind::Modify(notify::event::ModifyKind::Any);
+        let watched = Event::new(modify).add_path(PathBuf::from("/nonexistent/main.c"));
+        let unrelated = Event::new(modify).add_path(PathBuf::from("/nonexistent/other.c"));
+        let access = Event::new(EventKind::Access(notify::event::AccessKind::Any))
+            .add_path(PathBuf::from("/nonexistent/main.c"));
+
+        assert!(session.is_relevant(&watched));
+        assert!(!session.is_relevant(&unrelated));
+        assert!(!session.is_relevant(&access));
+    }
+}
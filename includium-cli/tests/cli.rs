@@ -0,0 +1,596 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run the CLI with `args`, feeding `stdin` to it
+fn run_cli(args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+    run_cli_with_env(args, &[], stdin)
+}
+
+/// Run the CLI with the include path variables replaced by `env`
+///
+/// Setting the variables on the child keeps tests independent of each other
+/// and of the environment `cargo test` runs in.
+fn run_cli_with_env(args: &[&str], env: &[(&str, &str)], stdin: impl AsRef<[u8]>) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_includium-cli"));
+    for var in ["CPATH", "C_INCLUDE_PATH", "CPLUS_INCLUDE_PATH"] {
+        command.env_remove(var);
+    }
+    let mut child = command
+        .envs(env.iter().copied())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to spawn includium-cli");
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(stdin.as_ref())
+        .expect("failed to write stdin");
+    child
+        .wait_with_output()
+        .expect("failed to wait for includium-cli")
+}
+
+#[test]
+fn list_macros_prints_user_and_builtin_defines() {
+    let out = run_cli(
+        &["-", "--list-macros"],
+        "#define ANSWER 42\n#define ADD(a, b) ((a)+(b))\nint x = ANSWER;\n",
+    );
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("#define ANSWER 42\n"));
+    assert!(stdout.contains("#define ADD(a, b) ((a)+(b))\n"));
+    assert!(stdout.contains("#define __linux__ 1\n"));
+    // The preprocessed text itself is not printed
+    assert!(!stdout.contains("int x"));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn list_macros_json_emits_objects() {
+    let out = run_cli(&["-", "--list-macros", "--json"], "#define ANSWER 42\n");
+    assert!(out.status.success());
+    let value: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let macros = value.as_array().unwrap();
+    let answer = macros
+        .iter()
+        .find(|m| m["name"] == "ANSWER")
+        .expect("ANSWER should be listed");
+    assert_eq!(answer["body"], "42");
+    assert_eq!(answer["builtin"], false);
+    assert!(answer["params"].is_null());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn list_macros_json_respects_output_file() {
+    let root = std::env::temp_dir().join(format!("includium-cli-json-list-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let output = root.join("macros.json");
+    std::fs::write(&output, "stale\n").unwrap();
+
+    let out = run_cli(
+        &[
+            "-",
+            "--list-macros",
+            "--json",
+            "-o",
+            output.to_str().unwrap(),
+        ],
+        "#define ANSWER 42\n",
+    );
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+    let value: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&output).unwrap()).unwrap();
+    assert!(
+        value
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|m| m["name"] == "ANSWER")
+    );
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json_errors_carry_the_stable_code() {
+    let out = run_cli(&["-", "--json"], "#include \"missing.h\"\n");
+    assert!(!out.status.success());
+    let value: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(value["success"], false);
+    assert_eq!(value["error"]["code"], "E0001_INCLUDE_NOT_FOUND");
+    assert_eq!(value["error"]["included_path"], "missing.h");
+    assert_eq!(value["error"]["line"], 1);
+}
+
+#[test]
+fn freebsd_target_is_accepted() {
+    let out = run_cli(
+        &["-", "--target", "freebsd"],
+        "#ifdef __FreeBSD__\nfreebsd\n#endif\n",
+    );
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "freebsd\n");
+}
+
+#[test]
+fn bom_prefixed_input_produces_clean_output() {
+    let out = run_cli(&["-"], b"\xEF\xBB\xBF#define A 1\nint x = A;\n");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "int x = 1;\n");
+}
+
+#[test]
+fn bom_prefixed_file_produces_clean_output() {
+    let path = std::env::temp_dir().join(format!("includium-bom-{}.c", std::process::id()));
+    std::fs::write(&path, b"\xEF\xBB\xBFint y = 2;\n").unwrap();
+    let out = run_cli(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "int y = 2;\n");
+}
+
+#[test]
+fn invalid_utf8_input_reports_location() {
+    let out = run_cli(&["-"], b"int a;\n/* caf\xE9 */\n");
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("not valid UTF-8"));
+    assert!(stderr.contains("line 2"));
+}
+
+#[test]
+fn manpage_subcommand_emits_roff() {
+    let out = run_cli(&["manpage"], "");
+    assert!(out.status.success());
+    let page = String::from_utf8(out.stdout).unwrap();
+    assert!(page.contains(".TH includium 1"));
+    assert!(page.contains("recursion\\-limit"));
+}
+
+#[test]
+fn completions_subcommand_is_hidden_from_help() {
+    let out = run_cli(&["--help"], "");
+    assert!(out.status.success());
+    let help = String::from_utf8(out.stdout).unwrap();
+    assert!(!help.contains("completions"));
+    assert!(help.contains("<INPUT>"));
+}
+
+#[test]
+fn define_and_undefine_flags_are_applied() {
+    let out = run_cli(
+        &["-", "-D", "ANSWER=42", "-DFLAG", "-U", "__linux__"],
+        "ANSWER FLAG __linux__\n",
+    );
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap().trim(),
+        "42 1 __linux__"
+    );
+}
+
+#[test]
+fn invalid_configuration_is_reported() {
+    let out = run_cli(&["-", "-D", "1BAD"], "");
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("malformed macro definition '1BAD'"));
+
+    let out = run_cli(&["-", "--recursion-limit", "0"], "");
+    assert!(!out.status.success());
+    assert!(
+        String::from_utf8(out.stderr)
+            .unwrap()
+            .contains("recursion_limit must be greater than zero")
+    );
+}
+
+#[test]
+fn quote_includes_resolve_relative_to_the_including_file() {
+    let root = std::env::temp_dir().join(format!("includium-cli-relative-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("sub/detail")).unwrap();
+    std::fs::write(root.join("main.c"), "#include \"sub/a.h\"\nint main_c;\n").unwrap();
+    std::fs::write(root.join("sub/a.h"), "#include \"detail/b.h\"\n").unwrap();
+    std::fs::write(root.join("sub/detail/b.h"), "int b_h;\n").unwrap();
+
+    let out = run_cli(&[root.join("main.c").to_str().unwrap()], "");
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("int b_h;"));
+    assert!(stdout.contains("int main_c;"));
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn include_path_environment_variables_follow_gcc_order() {
+    let root = std::env::temp_dir().join(format!("includium-cli-env-{}", std::process::id()));
+    for (dir, name) in [
+        ("explicit", "a.h"),
+        ("cpath", "a.h"),
+        ("cpath", "b.h"),
+        ("c_include", "b.h"),
+        ("c_include", "c.h"),
+        ("cplus_include", "c.h"),
+    ] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+        std::fs::write(
+            root.join(dir).join(name),
+            format!("int {dir}_{};\n", &name[..1]),
+        )
+        .unwrap();
+    }
+    std::fs::write(
+        root.join("main.c"),
+        "#include \"a.h\"\n#include <b.h>\n#include <c.h>\n",
+    )
+    .unwrap();
+    std::fs::copy(root.join("main.c"), root.join("main.cpp")).unwrap();
+
+    let explicit = root.join("explicit");
+    let dir = |name: &str| root.join(name).to_str().unwrap().to_string();
+    let env = [
+        ("CPATH", dir("cpath")),
+        ("C_INCLUDE_PATH", dir("c_include")),
+        ("CPLUS_INCLUDE_PATH", dir("cplus_include")),
+    ];
+    let env: Vec<(&str, &str)> = env.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    let stdout = |out: Output| {
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        String::from_utf8(out.stdout).unwrap()
+    };
+
+    // -I beats CPATH, CPATH beats C_INCLUDE_PATH
+    let c = stdout(run_cli_with_env(
+        &[
+            root.join("main.c").to_str().unwrap(),
+            "-I",
+            explicit.to_str().unwrap(),
+        ],
+        &env,
+        "",
+    ));
+    assert!(c.contains("int explicit_a;"));
+    assert!(c.contains("int cpath_b;"));
+    assert!(c.contains("int c_include_c;"));
+
+    // C++ sources use CPLUS_INCLUDE_PATH instead of C_INCLUDE_PATH
+    let cpp = stdout(run_cli_with_env(
+        &[root.join("main.cpp").to_str().unwrap()],
+        &env,
+        "",
+    ));
+    assert!(cpp.contains("int cpath_a;"));
+    assert!(cpp.contains("int cplus_include_c;"));
+    let forced = stdout(run_cli_with_env(
+        &[root.join("main.c").to_str().unwrap(), "-x", "c++"],
+        &env,
+        "",
+    ));
+    assert!(forced.contains("int cplus_include_c;"));
+
+    // --no-env-includes ignores all three
+    let out = run_cli_with_env(
+        &[root.join("main.c").to_str().unwrap(), "--no-env-includes"],
+        &env,
+        "",
+    );
+    assert!(!out.status.success());
+    assert!(
+        String::from_utf8(out.stderr)
+            .unwrap()
+            .contains("include not found: a.h")
+    );
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn show_includes_prints_the_include_tree() {
+    let root = std::env::temp_dir().join(format!("includium-cli-show-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("main.c"), "#include \"a.h\"\n").unwrap();
+    std::fs::write(root.join("a.h"), "#include \"b.h\"\n").unwrap();
+    std::fs::write(root.join("b.h"), "int b;\n").unwrap();
+    let main = root.join("main.c");
+    let a = root.join("a.h");
+    let b = root.join("b.h");
+
+    let out = run_cli(&[main.to_str().unwrap(), "-H"], "");
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        format!(". {}\n.. {}\n", a.display(), b.display())
+    );
+    assert!(String::from_utf8(out.stdout).unwrap().contains("int b;"));
+
+    let out = run_cli(&[main.to_str().unwrap(), "--show-includes=msvc"], "");
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        format!(
+            "Note: including file: {}\nNote: including file:  {}\n",
+            a.display(),
+            b.display()
+        )
+    );
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn standard_follows_std_and_language_flags() {
+    let src = "#if true\nkeyword\n#endif\n";
+    let stdout = |args: &[&str]| String::from_utf8(run_cli(args, src).stdout).unwrap();
+    assert!(!stdout(&["-"]).contains("keyword"));
+    assert!(stdout(&["-", "--std", "c23"]).contains("keyword"));
+    assert!(stdout(&["-", "-x", "c++"]).contains("keyword"));
+    assert!(!stdout(&["-", "-x", "c++", "--std", "c11"]).contains("keyword"));
+}
+
+#[test]
+fn collapse_blank_lines_flag_limits_blank_runs() {
+    let src = "a\n\n\n\nb\n#if 0\nx\n#endif\n\nc\n";
+    let out = run_cli(&["-", "--collapse-blank-lines", "1"], src);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "a\n\nb\n\nc\n");
+}
+
+#[test]
+fn timeout_flag_stops_long_runs() {
+    let src = "int x;\n".repeat(1000);
+    let out = run_cli(&["-", "--timeout", "0"], &src);
+    assert!(!out.status.success());
+    assert!(
+        String::from_utf8(out.stderr)
+            .unwrap()
+            .contains("budget exceeded: deadline passed")
+    );
+
+    let out = run_cli(&["-", "--timeout", "60"], &src);
+    assert!(out.status.success());
+
+    let out = run_cli(&["-", "--timeout", "-1"], "");
+    assert!(!out.status.success());
+}
+
+#[test]
+fn deps_dot_writes_the_include_graph() {
+    let root = std::env::temp_dir().join(format!("includium-cli-dot-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("main.c"), "#include \"a.h\"\n").unwrap();
+    std::fs::write(root.join("a.h"), "#include \"b.h\"\n").unwrap();
+    std::fs::write(root.join("b.h"), "int b;\n").unwrap();
+    let main = root.join("main.c");
+    let dot = root.join("deps.dot");
+
+    let out = run_cli(
+        &[main.to_str().unwrap(), "--deps-dot", dot.to_str().unwrap()],
+        "",
+    );
+    assert!(out.status.success());
+    let graph = std::fs::read_to_string(&dot).unwrap();
+    assert!(graph.starts_with("digraph includes {\n"));
+    assert!(graph.contains(&format!(
+        "\"{}\" -> \"{}\";",
+        main.display(),
+        root.join("a.h").display()
+    )));
+    assert!(graph.contains(&format!(
+        "\"{}\" -> \"{}\";",
+        root.join("a.h").display(),
+        root.join("b.h").display()
+    )));
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn exported_macros_can_be_imported_by_a_later_run() {
+    let root = std::env::temp_dir().join(format!("includium-cli-macros-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let macros = root.join("macros.json");
+    let macros_arg = macros.to_str().unwrap();
+
+    let configure = "#define HAVE_FOO 1\n#define CALL(f, ...) f(__VA_ARGS__)\n";
+    let out = run_cli(&["-", "--export-macros", macros_arg], configure);
+    assert!(out.status.success());
+    let json = std::fs::read_to_string(&macros).unwrap();
+    assert!(json.contains("\"name\": \"CALL\""), "{json}");
+
+    let src = "#if HAVE_FOO\nCALL(g, 1, 2)\n#endif\n";
+    let out = run_cli(&["-", "--import-macros", macros_arg], src);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "g(1,2)\n");
+
+    // -U applies on top of the imported macros
+    let out = run_cli(&["-", "--import-macros", macros_arg, "-U", "HAVE_FOO"], src);
+    assert!(!String::from_utf8(out.stdout).unwrap().contains("g("));
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn size_flags_reject_large_inputs() {
+    let root = std::env::temp_dir().join(format!("includium-cli-size-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("big.h"), "int generated;\n".repeat(200)).unwrap();
+    let dir = root.to_str().unwrap();
+    let src = "#include <big.h>\n";
+
+    let out = run_cli(&["-", "-I", dir, "--max-include-size", "1K"], src);
+    assert!(!out.status.success());
+    assert!(
+        String::from_utf8(out.stderr)
+            .unwrap()
+            .contains("is 3000 bytes, more than the limit of 1024")
+    );
+    assert!(
+        run_cli(&["-", "-I", dir, "--max-include-size", "4k"], src)
+            .status
+            .success()
+    );
+
+    let out = run_cli(&["-", "-I", dir, "--max-total-size", "3000"], src);
+    assert!(!out.status.success());
+    assert!(
+        run_cli(&["-", "-I", dir, "--max-total-size", "1M"], src)
+            .status
+            .success()
+    );
+    assert!(
+        !run_cli(&["-", "--max-total-size", "lots"], "")
+            .status
+            .success()
+    );
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn quoted_includes_resolve_next_to_headers_found_through_include_dirs() {
+    let root = std::env::temp_dir().join(format!("includium-cli-nested-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("inc/sub")).unwrap();
+    std::fs::write(root.join("inc/sub/a.h"), "#include \"b.h\"\n").unwrap();
+    std::fs::write(root.join("inc/sub/b.h"), "int sub_b;\n").unwrap();
+    std::fs::write(root.join("inc/b.h"), "int wrong_b;\n").unwrap();
+    let inc = root.join("inc");
+
+    let out = run_cli(&["-", "-I", inc.to_str().unwrap()], "#include <sub/a.h>\n");
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("int sub_b;"));
+    assert!(!stdout.contains("wrong_b"));
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn failed_run_removes_stale_output() {
+    let root = std::env::temp_dir().join(format!("includium-cli-stale-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let output = root.join("out.i");
+    let output_arg = output.to_str().unwrap();
+    let broken = "int x;\n#error broken\n";
+
+    std::fs::write(&output, "stale\n").unwrap();
+    let out = run_cli(&["-", "-o", output_arg, "--keep-output-on-error"], broken);
+    assert!(!out.status.success());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "stale\n");
+
+    let out = run_cli(&["-", "-o", output_arg], broken);
+    assert!(!out.status.success());
+    assert!(!output.exists());
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn successful_run_replaces_output_atomically() {
+    use std::io::Read;
+
+    let root = std::env::temp_dir().join(format!("includium-cli-atomic-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let output = root.join("out.i");
+    std::fs::write(&output, "old output\n").unwrap();
+    let mut old = std::fs::File::open(&output).unwrap();
+
+    let out = run_cli(
+        &["-", "-o", output.to_str().unwrap()],
+        "#define A 1\nint x = A;\n",
+    );
+    assert!(out.status.success());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "int x = 1;\n");
+
+    // The old file was replaced rather than truncated and rewritten in place
+    let mut previous = String::new();
+    old.read_to_string(&mut previous).unwrap();
+    assert_eq!(previous, "old output\n");
+    // No temporary file is left behind
+    let entries: Vec<_> = std::fs::read_dir(&root).unwrap().collect();
+    assert_eq!(entries.len(), 1);
+
+    let _ = std::fs::remove_dir_all(root);
+}
+
+#[test]
+fn repl_processes_lines_with_persistent_macros() {
+    let dir = std::env::temp_dir().join(format!("includium-repl-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let header = dir.join("defs.h");
+    std::fs::write(&header, "#define ANSWER 42\n").unwrap();
+
+    let input = format!(
+        "#define TWICE(x) \\\n  ((x) + (x))\nTWICE(\n  1)\n:load {}\nANSWER\n:reset\nANSWER\n:bogus\n",
+        header.display()
+    );
+    let output = run_cli(&["repl", "--no-env-includes"], input);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "((1) + (1))\n42\nANSWER\n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown command :bogus"));
+}
+
+#[test]
+fn strip_msvc_extensions_removes_annotations() {
+    let src =
+        "__declspec(dllexport) HRESULT __stdcall Open(_In_z_ LPCWSTR name, _Outptr_ void **out);\n";
+    let args = ["-", "--target", "windows", "--compiler", "msvc"];
+    let output = run_cli(&args, src);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("__declspec(dllexport)"));
+
+    let output = run_cli(&[&args[..], &["--strip-msvc-extensions"]].concat(), src);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        " HRESULT  Open( LPCWSTR name,  void **out);\r\n"
+    );
+}
+
+#[test]
+fn keep_line_directives_reemits_line_directives() {
+    let src = "#line 12 \"parse.y\"\n#define OK 0\nint ok = OK;\nint line = __LINE__;\n";
+    let output = run_cli(&["-", "--keep-line-directives"], src);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "#line 13 \"parse.y\"\nint ok = 0;\nint line = 14;\n"
+    );
+}
+
+#[test]
+fn warn_bare_function_macro_flag_reports_missing_arguments() {
+    let src = "#define MAX(a, b) ((a) > (b) ? (a) : (b))\nint m = MAX;\n";
+    let output = run_cli(&["-", "-W", "--warn-bare-function-macro"], src);
+    assert!(
+        String::from_utf8_lossy(&output.stderr)
+            .contains("function-like macro 'MAX' used without arguments is not expanded")
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "int m = MAX;\n");
+}
@@ -0,0 +1,47 @@
+//! Preprocess multi-megabyte single lines, as found in minified sources
+//!
+//! Run with `cargo bench -p includium`. Time per byte should stay flat as
+//! the line grows; a jump between sizes points at quadratic work per line.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use includium::Preprocessor;
+
+const DEFINES: &str = "#define ADD(a, b) ((a) + (b))\n\
+                       #define ID(x) x\n\
+                       #define SCALE 3\n";
+
+/// One line of about `bytes` bytes mixing plain text, literals, comments
+/// and macro calls
+fn long_line(bytes: usize) -> String {
+    let mut line = String::with_capacity(bytes + 64);
+    let mut i = 0;
+    while line.len() < bytes {
+        line.push_str(&format!(
+            "int v{i} = ADD(x{i}, SCALE) * ID(ADD)(1, {i}); /* c */ s = \"str\\\" {i}\"; "
+        ));
+        i += 1;
+    }
+    line
+}
+
+fn run(input: &str) -> Duration {
+    let start = Instant::now();
+    let output = Preprocessor::new().process(black_box(input));
+    let elapsed = start.elapsed();
+    black_box(output.expect("benchmark input preprocesses"));
+    elapsed
+}
+
+fn main() {
+    for megabytes in [1, 2, 4, 8] {
+        let input = format!("{DEFINES}{}\n", long_line(megabytes << 20));
+        let best = (0..3).map(|_| run(&input)).min().unwrap_or_default();
+        let per_byte = best.as_nanos() as f64 / input.len() as f64;
+        println!(
+            "long line, {megabytes} MiB: {:>8.1} ms ({per_byte:.1} ns/byte)",
+            best.as_secs_f64() * 1000.0
+        );
+    }
+}
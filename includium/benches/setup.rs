@@ -0,0 +1,49 @@
+//! Create a driver per file for many small files
+//!
+//! Run with `cargo bench -p includium`. Compares building each driver from a
+//! `PreprocessorConfig` against reusing one `CompiledConfig`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use includium::{Preprocessor, PreprocessorConfig};
+
+const FILES: usize = 5_000;
+const SOURCE: &str = "#ifdef __linux__\nint x = __INT_MAX__;\n#endif\n";
+
+fn time(mut make: impl FnMut() -> Preprocessor) -> Duration {
+    let start = Instant::now();
+    for _ in 0..FILES {
+        let mut pp = make();
+        black_box(
+            pp.process(black_box(SOURCE))
+                .expect("benchmark input preprocesses"),
+        );
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let config = PreprocessorConfig::builder()
+        .define("VERSION=2")
+        .define("DEBUG")
+        .build()
+        .expect("benchmark configuration is valid");
+    let compiled = config.compile();
+
+    let direct = (0..3)
+        .map(|_| time(|| Preprocessor::with_config(&config)))
+        .min()
+        .unwrap_or_default();
+    let fast = (0..3)
+        .map(|_| time(|| Preprocessor::with_compiled(&compiled)))
+        .min()
+        .unwrap_or_default();
+    for (name, best) in [("with_config", direct), ("with_compiled", fast)] {
+        println!(
+            "{FILES} files, {name:<13}: {:>8.1} ms ({:.1} us/file)",
+            best.as_secs_f64() * 1000.0,
+            best.as_secs_f64() * 1e6 / FILES as f64
+        );
+    }
+}
@@ -8,8 +8,13 @@ thread_local! {
     static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
 }
 
-use crate::config::{Compiler, LineEnding, PreprocessorConfig, Target};
+use crate::config::{
+    BuiltinGroups, ByteOrder, Compiler, DefinedFromMacro, IncludeSizePolicy, LineEnding,
+    PathSeparator, PreprocessorConfig, Standard, Target,
+};
 use crate::driver::PreprocessorDriver;
+use crate::engine::is_valid_identifier;
+use crate::error::PreprocessError;
 
 /// Opaque C handle. Thin wrapper - all logic lives in `PreprocessorDriver`.
 #[repr(C)]
@@ -19,7 +24,7 @@ pub struct includium_ctx(PreprocessorDriver);
 #[repr(C)]
 #[allow(non_camel_case_types)]
 pub struct includium_config {
-    /// Target OS: 0=Linux, 1=Windows, 2=MacOS
+    /// Target OS: 0=Linux, 1=Windows, 2=MacOS, 3=FreeBSD
     pub target: c_int,
     /// Compiler: 0=GCC, 1=Clang, 2=MSVC
     pub compiler: c_int,
@@ -48,6 +53,7 @@ fn preprocessor_config_from_c(
         0 => Target::Linux,
         1 => Target::Windows,
         2 => Target::MacOS,
+        3 => Target::FreeBSD,
         _ => return Err("Invalid target value"),
     };
     let compiler = match config.compiler {
@@ -66,6 +72,40 @@ fn preprocessor_config_from_c(
         include_resolver: None,
         warning_handler: None,
         line_ending: LineEnding::LF,
+        strict: false,
+        defines: Vec::new(),
+        undefines: Vec::new(),
+        include_dirs: Vec::new(),
+        concat_string_literals: false,
+        on_include_enter: None,
+        prelude: Vec::new(),
+        suppress_prelude_output: false,
+        expansion_trace_handler: None,
+        defined_from_macro: DefinedFromMacro::Honor,
+        standard: Standard::C17,
+        collapse_blank_lines: None,
+        include_path_separator: PathSeparator::Preserve,
+        file_macro_separator: PathSeparator::Preserve,
+        max_iterations: None,
+        deadline: None,
+        max_macro_args: 4096,
+        max_expansion_tokens: 1_000_000,
+        system_headers_quiet: true,
+        byte_order: ByteOrder::Little,
+        max_include_bytes: None,
+        max_total_bytes: None,
+        include_size_policy: IncludeSizePolicy::Error,
+        record_include_graph: false,
+        lossy_utf8: false,
+        warn_unused_macro_params: false,
+        builtin_macro_groups: BuiltinGroups::new(),
+        expr_identifier_resolver: None,
+        warn_undef: false,
+        builtin_rewrites: Vec::new(),
+        initial_file: None,
+        passthrough_line_directives: false,
+        warn_bare_function_macro: false,
+        max_include_depth: 200,
     };
     if let Some(handler) = config.warning_handler {
         let handler_rc = Rc::new(move |msg: &str| {
@@ -122,6 +162,9 @@ pub unsafe extern "C" fn includium_free(ctx: *mut includium_ctx) {
 
 /// Process C code and return the preprocessed result (C API)
 ///
+/// Input that isn't valid UTF-8 is rejected with an error naming the line
+/// and column of the first bad byte.
+///
 /// # Safety
 /// - The `pp` pointer must be valid and created by `includium_new`
 /// - The `input` pointer must point to a valid null-terminated C string
@@ -135,17 +178,46 @@ pub unsafe extern "C" fn includium_process(
         return ptr::null_mut();
     }
 
-    let Ok(input_str) = (unsafe { CStr::from_ptr(input).to_str() }) else {
-        set_last_error("Invalid UTF-8 input");
+    let input = unsafe { CStr::from_ptr(input) };
+    let driver = unsafe { &mut (*ctx).0 };
+    into_c_result(driver.process_bytes(input.to_bytes()))
+}
+
+/// Process `len` bytes of C code and return the preprocessed result (C API)
+///
+/// Unlike `includium_process` the input may contain NUL bytes, which are
+/// dropped with a warning naming their line. With `lossy` non-zero, invalid
+/// UTF-8 in this input is replaced with U+FFFD instead of being rejected;
+/// later calls on `ctx` are unaffected.
+///
+/// # Safety
+/// - The `ctx` pointer must be valid and created by `includium_new`
+/// - The `input` pointer must point to at least `len` readable bytes
+/// - The returned string must be freed with `includium_free_result`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn includium_process_bytes(
+    ctx: *mut includium_ctx,
+    input: *const c_char,
+    len: usize,
+    lossy: c_int,
+) -> *mut c_char {
+    if ctx.is_null() || input.is_null() {
         return ptr::null_mut();
-    };
+    }
+
+    let input = unsafe { std::slice::from_raw_parts(input.cast::<u8>(), len) };
     let driver = unsafe { &mut (*ctx).0 };
-    match driver.process(input_str) {
+    into_c_result(driver.process_bytes_with(input, lossy != 0))
+}
+
+/// Hand a processing result to C, recording the error message on failure
+fn into_c_result(result: Result<String, PreprocessError>) -> *mut c_char {
+    match result {
         Ok(result) => {
             if let Ok(cstr) = CString::new(result) {
                 cstr.into_raw()
             } else {
-                set_last_error("Result contains invalid UTF-8");
+                set_last_error("Result contains a NUL byte");
                 ptr::null_mut()
             }
         }
@@ -156,6 +228,38 @@ pub unsafe extern "C" fn includium_process(
     }
 }
 
+/// Define an object-like macro (C API)
+///
+/// Line breaks in `body` become spaces, so the macro always expands on one
+/// output line. Returns 0 on success and -1 on error.
+///
+/// # Safety
+/// - The `ctx` pointer must be valid and created by `includium_new`
+/// - The `name` and `body` pointers must point to valid null-terminated C strings
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn includium_define(
+    ctx: *mut includium_ctx,
+    name: *const c_char,
+    body: *const c_char,
+) -> c_int {
+    if ctx.is_null() || name.is_null() || body.is_null() {
+        return -1;
+    }
+
+    let (name, body) = unsafe { (CStr::from_ptr(name), CStr::from_ptr(body)) };
+    let (Ok(name_str), Ok(body_str)) = (name.to_str(), body.to_str()) else {
+        set_last_error("Invalid UTF-8 input");
+        return -1;
+    };
+    if !is_valid_identifier(name_str) {
+        set_last_error("Invalid macro name");
+        return -1;
+    }
+    let driver = unsafe { &mut (*ctx).0 };
+    driver.define(name_str, None, body_str, false);
+    0
+}
+
 /// Free a result string returned by C API
 ///
 /// # Safety
@@ -1,7 +1,19 @@
-use std::rc::Rc;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use crate::context::PreprocessorContext;
+use crate::engine;
+use crate::error::ConfigError;
+use crate::macro_def::MacroTable;
 
 /// Kind of include directive
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum IncludeKind {
     /// Local include with quotes: #include "file.h"
     Local,
@@ -12,18 +24,115 @@ pub enum IncludeKind {
 /// Context for include resolution
 #[derive(Clone, Debug, Default)]
 pub struct IncludeContext {
-    /// Stack of currently included files for cycle detection and context
+    /// Files that included the one containing the `#include`, outermost first
     pub include_stack: Vec<String>,
     /// List of include directories to search
     pub include_dirs: Vec<String>,
+    /// Name of the file containing the `#include` directive
+    pub including_file: String,
+    /// Directory of the including file, or `None` when it isn't a real path
+    /// (such as `<stdin>`)
+    pub including_dir: Option<String>,
+}
+
+/// Result of resolving an `#include`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IncludeResolution {
+    /// The header was found with this content
+    Content(String),
+    /// The header was found with this content and a stable identity, such as
+    /// a content hash or canonical path
+    ///
+    /// `#pragma once` treats every include with the same identity as the same
+    /// file, however it is spelled; without an identity it goes by the name.
+    Identified {
+        /// Header content
+        content: String,
+        /// Identity shared by every name the header is served under
+        identity: String,
+    },
+    /// The header was read from the file at `path`
+    ///
+    /// The bytes are decoded like input to
+    /// [`PreprocessorDriver::process_bytes`](crate::PreprocessorDriver::process_bytes):
+    /// invalid UTF-8 is an error unless [`PreprocessorConfig::lossy_utf8`] is
+    /// set.
+    File {
+        /// File the header was read from
+        path: String,
+        /// Raw header content
+        content: Vec<u8>,
+    },
+    /// The header exists but couldn't be read; the include is an error with
+    /// this message
+    Unreadable(String),
+    /// The header doesn't exist; the include is an error
+    NotFound,
+    /// The header exists but is deliberately left out, e.g. a stubbed system
+    /// header; the include produces no output and no error
+    Skip,
+}
+
+impl From<Option<String>> for IncludeResolution {
+    /// `Some` is found content and `None` is not found, as resolvers returned
+    /// before [`IncludeResolution`] existed
+    fn from(content: Option<String>) -> Self {
+        content.map_or(IncludeResolution::NotFound, IncludeResolution::Content)
+    }
+}
+
+impl From<String> for IncludeResolution {
+    fn from(content: String) -> Self {
+        IncludeResolution::Content(content)
+    }
 }
 
 /// Type alias for include resolver function
-pub type IncludeResolver = Rc<dyn Fn(&str, IncludeKind, &IncludeContext) -> Option<String>>;
+pub type IncludeResolver = Rc<dyn Fn(&str, IncludeKind, &IncludeContext) -> IncludeResolution>;
+
+/// Type alias for include hook function
+///
+/// Receives the path of the included file, its nesting depth (1 for a file
+/// included by the main file) and the include kind.
+pub type IncludeHook = Rc<dyn Fn(&str, usize, IncludeKind)>;
+
+/// A single macro expansion, reported to an [`ExpansionTraceHandler`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpansionEvent {
+    /// Name of the expanded macro
+    pub name: String,
+    /// Argument texts for a function-like macro, `None` for an object-like one
+    pub arguments: Option<Vec<String>>,
+    /// Replacement after argument substitution and `##` pasting, before the
+    /// result is rescanned (expansions found by rescanning get their own events)
+    pub result: String,
+    /// File being processed
+    pub file: String,
+    /// Line being processed
+    pub line: usize,
+    /// Recursion depth of the expansion, 0 for a macro invoked directly on a source line
+    pub depth: usize,
+}
+
+/// Type alias for macro expansion trace handler function
+pub type ExpansionTraceHandler = Rc<dyn Fn(&ExpansionEvent)>;
 
 /// Type alias for warning handler function
 pub type WarningHandler = Rc<dyn Fn(&str)>;
 
+/// Type alias for the function giving `#if` values to identifiers that aren't
+/// macros (see [`PreprocessorConfig::expr_identifier_resolver`])
+pub type ExprIdentifierResolver = Rc<dyn Fn(&str) -> Option<i64>>;
+
+/// Source processed before the main input (see [`PreprocessorConfig::prelude`])
+#[derive(Clone, Debug, PartialEq)]
+pub enum PreludeItem {
+    /// A file resolved like `#include "path"`, so it may include others
+    File(String),
+    /// Literal preprocessing text, such as a generated block of `#define`s
+    Text(String),
+}
+
 /// Target operating system for preprocessing
 #[derive(Clone, Debug)]
 pub enum Target {
@@ -33,6 +142,236 @@ pub enum Target {
     Windows,
     /// macOS operating system
     MacOS,
+    /// FreeBSD operating system
+    FreeBSD,
+}
+
+/// Source language being preprocessed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Language {
+    /// C
+    #[default]
+    C,
+    /// C++
+    Cpp,
+}
+
+impl Language {
+    /// Guess the language from a file extension, defaulting to C
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("cc" | "cp" | "cpp" | "cxx" | "c++" | "C" | "hh" | "hpp" | "hxx" | "ii") => {
+                Language::Cpp
+            }
+            _ => Language::C,
+        }
+    }
+}
+
+/// Language standard, which decides the keywords available in `#if` expressions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Standard {
+    /// ISO C90
+    C89,
+    /// ISO C99
+    C99,
+    /// ISO C11
+    C11,
+    /// ISO C17
+    #[default]
+    C17,
+    /// ISO C23
+    C23,
+    /// ISO C++98
+    Cpp98,
+    /// ISO C++11
+    Cpp11,
+    /// ISO C++14
+    Cpp14,
+    /// ISO C++17
+    Cpp17,
+    /// ISO C++20
+    Cpp20,
+    /// ISO C++23
+    Cpp23,
+}
+
+impl Standard {
+    /// The default standard for a language: C17 for C, C++17 for C++
+    #[must_use]
+    pub const fn default_for(language: Language) -> Self {
+        match language {
+            Language::C => Standard::C17,
+            Language::Cpp => Standard::Cpp17,
+        }
+    }
+
+    /// The language this standard belongs to
+    #[must_use]
+    pub const fn language(self) -> Language {
+        match self {
+            Standard::C89 | Standard::C99 | Standard::C11 | Standard::C17 | Standard::C23 => {
+                Language::C
+            }
+            _ => Language::Cpp,
+        }
+    }
+
+    /// Whether `true` and `false` are keywords evaluating to 1 and 0 in `#if`
+    #[must_use]
+    pub const fn has_boolean_literals(self) -> bool {
+        matches!(self, Standard::C23) || matches!(self.language(), Language::Cpp)
+    }
+}
+
+/// What to do when macro expansion in an `#if` or `#elif` produces `defined`
+///
+/// The standard leaves this undefined; GCC and Clang honor the operator
+/// while MSVC treats it differently, so portable code should avoid it.
+///
+/// Outside `#if` and `#elif`, `defined` is an ordinary identifier: after
+/// `#define HAS defined(FOO)`, a code line `HAS` gives `defined(FOO)`. The
+/// operand of `defined` is never expanded, even on a code line, so the text
+/// stays the same whatever `FOO` is. Such macros are only meaningful in
+/// conditionals, where `#if HAS` evaluates the operator as configured here.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DefinedFromMacro {
+    /// Evaluate `defined` as if it had been written directly
+    #[default]
+    Honor,
+    /// Evaluate it, but report a warning through the warning handler
+    Warn,
+    /// Reject the directive with an error
+    Error,
+}
+
+/// Which separator to use in paths written in include directives or
+/// reported by `__FILE__`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PathSeparator {
+    /// Keep paths as written
+    #[default]
+    Preserve,
+    /// Use `/`, which every supported platform accepts
+    Forward,
+    /// Use the separator of the platform the preprocessor runs on
+    Native,
+}
+
+impl PathSeparator {
+    /// Rewrite the `/` and `\` separators in `path`
+    #[must_use]
+    pub fn apply(self, path: &str) -> String {
+        let separator = match self {
+            PathSeparator::Preserve => return path.to_string(),
+            PathSeparator::Forward => '/',
+            #[cfg(feature = "std")]
+            PathSeparator::Native => std::path::MAIN_SEPARATOR,
+            #[cfg(not(feature = "std"))]
+            PathSeparator::Native => '/',
+        };
+        path.chars()
+            .map(|c| if c == '/' || c == '\\' { separator } else { c })
+            .collect()
+    }
+}
+
+/// Rewrites installed by [`PreprocessorConfig::with_msvc_compat_stubs`]
+const MSVC_COMPAT_STUBS: &[(&str, Option<&str>)] = &[
+    ("__declspec(x)", None),
+    ("__cdecl", None),
+    ("__stdcall", None),
+    ("__fastcall", None),
+    ("__thiscall", None),
+    ("__vectorcall", None),
+    ("__forceinline", Some("inline")),
+    ("__ptr32", None),
+    ("__ptr64", None),
+    ("__unaligned", None),
+    ("__w64", None),
+    ("_In_", None),
+    ("_In_opt_", None),
+    ("_In_z_", None),
+    ("_In_opt_z_", None),
+    ("_Inout_", None),
+    ("_Inout_opt_", None),
+    ("_Out_", None),
+    ("_Out_opt_", None),
+    ("_Outptr_", None),
+    ("_Outptr_opt_", None),
+    ("_Ret_maybenull_", None),
+    ("_Check_return_", None),
+    ("_Must_inspect_result_", None),
+    ("_Printf_format_string_", None),
+    ("_Reserved_", None),
+    ("_In_reads_(n)", None),
+    ("_In_reads_opt_(n)", None),
+    ("_In_reads_bytes_(n)", None),
+    ("_Out_writes_(n)", None),
+    ("_Out_writes_opt_(n)", None),
+    ("_Out_writes_bytes_(n)", None),
+    ("_Success_(expr)", None),
+];
+
+/// Groups of builtin macros a configuration predefines, see
+/// [`PreprocessorConfig::builtin_macro_groups`]
+///
+/// Macros given with [`PreprocessorConfigBuilder::define`] are not part of any
+/// group and are always defined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuiltinGroups {
+    /// Operating system and byte order macros such as `__linux__` and `__BYTE_ORDER__`
+    pub target: bool,
+    /// Compiler identification macros such as `__GNUC__` and `_MSC_VER`
+    pub compiler: bool,
+    /// Function-like stubs for compiler intrinsics, so `__builtin_expect(e, c)`
+    /// becomes `(e)` and `__builtin_constant_p(x)` becomes `0`
+    pub intrinsics: bool,
+    /// Type size and limit macros such as `__SIZEOF_LONG__` and `__INT_MAX__`
+    pub sizeof: bool,
+}
+
+impl BuiltinGroups {
+    /// Every group enabled, the default
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            target: true,
+            compiler: true,
+            intrinsics: true,
+            sizeof: true,
+        }
+    }
+}
+
+impl Default for BuiltinGroups {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What to do when included text goes over
+/// [`PreprocessorConfig::max_include_bytes`] or
+/// [`PreprocessorConfig::max_total_bytes`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IncludeSizePolicy {
+    /// Reject the include with a limit error
+    #[default]
+    Error,
+    /// Report a warning through the warning handler and process the file anyway
+    Warn,
+}
+
+/// Byte order of the target, reported through `__BYTE_ORDER__`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// Least significant byte first, as on x86 and most ARM systems
+    #[default]
+    Little,
+    /// Most significant byte first
+    Big,
 }
 
 /// Line ending style for output
@@ -47,6 +386,18 @@ pub enum LineEnding {
     CR,
 }
 
+impl LineEnding {
+    /// The line terminator as a string
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::LF => "\n",
+            LineEnding::CRLF => "\r\n",
+            LineEnding::CR => "\r",
+        }
+    }
+}
+
 /// Compiler dialect for preprocessing
 #[derive(Clone, Debug)]
 pub enum Compiler {
@@ -59,6 +410,7 @@ pub enum Compiler {
 }
 
 /// Configuration for the C preprocessor
+#[derive(Clone)]
 pub struct PreprocessorConfig {
     /// Target operating system
     pub target: Target,
@@ -72,6 +424,124 @@ pub struct PreprocessorConfig {
     pub warning_handler: Option<WarningHandler>,
     /// Line ending style for output
     pub line_ending: LineEnding,
+    /// Turn conformance diagnostics into hard errors
+    ///
+    /// When `false` (the default) the following are reported through the
+    /// warning handler and processing continues; when `true` they fail with a
+    /// `PreprocessError`:
+    ///
+    /// - redefinition of a macro with a different replacement list or parameters
+    /// - `##` pasting that does not form a valid preprocessing token
+    /// - `##` at either end of a replacement list
+    /// - unknown preprocessing directives in active code
+    /// - null characters in the input, outside system headers
+    pub strict: bool,
+    /// Object-like macros defined after the builtins, as `(name, value)` pairs
+    pub defines: Vec<(String, String)>,
+    /// Macros removed after `defines` have been applied
+    pub undefines: Vec<String>,
+    /// Include directories handed to the resolver via [`IncludeContext::include_dirs`]
+    pub include_dirs: Vec<String>,
+    /// Merge adjacent string literals in the output, as translation phase 6 does
+    ///
+    /// Off by default because it is strictly a compiler step rather than
+    /// preprocessing, but tools that consume the output often expect it.
+    pub concat_string_literals: bool,
+    /// Called just before an included file is processed, in include order
+    pub on_include_enter: Option<IncludeHook>,
+    /// Text processed before the main input, in order
+    ///
+    /// Macros defined here are visible to the main input. Their output is
+    /// prepended to the result unless [`PreprocessorConfig::suppress_prelude_output`]
+    /// is set.
+    pub prelude: Vec<PreludeItem>,
+    /// Drop the output of [`PreprocessorConfig::prelude`], keeping only its macros
+    pub suppress_prelude_output: bool,
+    /// Called for every macro expansion, for tracing and debugging
+    pub expansion_trace_handler: Option<ExpansionTraceHandler>,
+    /// How `defined` produced by macro expansion in `#if` is treated
+    pub defined_from_macro: DefinedFromMacro,
+    /// Language standard, which decides the keywords available in `#if` expressions
+    pub standard: Standard,
+    /// Keep at most this many consecutive blank or whitespace-only output lines
+    /// (`None` keeps them all)
+    pub collapse_blank_lines: Option<usize>,
+    /// Separator used in include paths passed to the resolver
+    ///
+    /// MSVC sources often write `#include "sub\header.h"`; `Forward` lets such
+    /// includes resolve on other platforms.
+    pub include_path_separator: PathSeparator,
+    /// Separator used in the path reported by `__FILE__`
+    pub file_macro_separator: PathSeparator,
+    /// Stop with a budget error after this many lines and macro expansions
+    /// (`None` for no limit)
+    pub max_iterations: Option<u64>,
+    /// Stop with a budget error once this moment has passed (`None` for no deadline)
+    #[cfg(feature = "std")]
+    pub deadline: Option<Instant>,
+    /// Maximum number of arguments in a macro call
+    pub max_macro_args: usize,
+    /// Maximum number of tokens, counting whitespace, that a line or macro
+    /// may expand to
+    pub max_expansion_tokens: usize,
+    /// Treat headers included with angle brackets as system headers, whose
+    /// warnings are suppressed as after `#pragma GCC system_header`
+    pub system_headers_quiet: bool,
+    /// Byte order of the target
+    pub byte_order: ByteOrder,
+    /// Largest included file, in bytes, accepted as is (`None` for no limit)
+    ///
+    /// Checked as soon as the resolver returns the file's contents; what happens
+    /// to a larger file depends on [`PreprocessorConfig::include_size_policy`].
+    pub max_include_bytes: Option<usize>,
+    /// Largest amount of input, in bytes, read for one translation unit, counting
+    /// the main input and every included file (`None` for no limit)
+    pub max_total_bytes: Option<usize>,
+    /// What to do when an include goes over `max_include_bytes` or `max_total_bytes`
+    pub include_size_policy: IncludeSizePolicy,
+    /// Record which file includes which during `process`, for
+    /// [`PreprocessorDriver::include_graph_dot`](crate::PreprocessorDriver::include_graph_dot)
+    pub record_include_graph: bool,
+    /// Replace invalid UTF-8 in byte input with U+FFFD, with a warning, instead of
+    /// failing with an error that points at the first bad byte
+    ///
+    /// Applies to [`PreprocessorDriver::process_bytes`](crate::PreprocessorDriver::process_bytes)
+    /// and to the file functions such as [`process_file`](crate::process_file).
+    pub lossy_utf8: bool,
+    /// Warn when a function-like macro's replacement list never mentions one of
+    /// its named parameters, as in `#define F(x) 1`
+    pub warn_unused_macro_params: bool,
+    /// Which groups of builtin macros the configuration predefines
+    pub builtin_macro_groups: BuiltinGroups,
+    /// Gives values to identifiers in `#if` expressions that aren't macros
+    /// Consulted before an unknown identifier is taken as 0; returning `None`
+    /// keeps the 0.
+    pub expr_identifier_resolver: Option<ExprIdentifierResolver>,
+    /// Warn when an identifier that isn't a macro is evaluated as 0 in `#if`,
+    /// like GCC's `-Wundef`
+    pub warn_undef: bool,
+    /// Builtin macros that strip or rewrite compiler-specific syntax
+    /// Each entry is a macro name, with a parameter list for a function-like
+    /// macro as in `__declspec(x)`, and its replacement, `None` for nothing.
+    /// They are defined whatever [`Self::builtin_macro_groups`] says.
+    pub builtin_rewrites: Vec<(String, Option<String>)>,
+    /// Name of the main input, reported by `__FILE__` and in diagnostics
+    /// `None` keeps `<stdin>`.
+    pub initial_file: Option<String>,
+    /// Whether `#line` directives are kept in the output
+    ///
+    /// The directive is re-emitted before the next output line, and output
+    /// after it stays in step with the source: lines that produced no output
+    /// and included headers are followed by a fresh `#line`.
+    pub passthrough_line_directives: bool,
+    /// Whether a function-like macro name without an argument list warns
+    ///
+    /// The name is left unexpanded either way; the warning catches `MAX`
+    /// written for `MAX(a, b)`.
+    pub warn_bare_function_macro: bool,
+    /// Maximum nesting depth of `#include`, as a fallback for cycles that
+    /// can't be detected by path
+    pub max_include_depth: usize,
 }
 
 impl Default for PreprocessorConfig {
@@ -81,6 +551,12 @@ impl Default for PreprocessorConfig {
 }
 
 impl PreprocessorConfig {
+    /// Start building a validated configuration
+    #[must_use]
+    pub fn builder() -> PreprocessorConfigBuilder {
+        PreprocessorConfigBuilder::new()
+    }
+
     /// Create configuration for Linux + GCC
     #[must_use]
     pub const fn for_linux() -> Self {
@@ -91,6 +567,41 @@ impl PreprocessorConfig {
             include_resolver: None,
             warning_handler: None,
             line_ending: LineEnding::LF,
+            strict: false,
+            defines: Vec::new(),
+            undefines: Vec::new(),
+            include_dirs: Vec::new(),
+            concat_string_literals: false,
+            on_include_enter: None,
+            prelude: Vec::new(),
+            suppress_prelude_output: false,
+            expansion_trace_handler: None,
+            defined_from_macro: DefinedFromMacro::Honor,
+            standard: Standard::C17,
+            collapse_blank_lines: None,
+            include_path_separator: PathSeparator::Preserve,
+            file_macro_separator: PathSeparator::Preserve,
+            max_iterations: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            max_macro_args: 4096,
+            max_expansion_tokens: 1_000_000,
+            system_headers_quiet: true,
+            byte_order: ByteOrder::Little,
+            max_include_bytes: None,
+            max_total_bytes: None,
+            include_size_policy: IncludeSizePolicy::Error,
+            record_include_graph: false,
+            lossy_utf8: false,
+            warn_unused_macro_params: false,
+            builtin_macro_groups: BuiltinGroups::new(),
+            expr_identifier_resolver: None,
+            warn_undef: false,
+            builtin_rewrites: Vec::new(),
+            initial_file: None,
+            passthrough_line_directives: false,
+            warn_bare_function_macro: false,
+            max_include_depth: 200,
         }
     }
 
@@ -104,6 +615,41 @@ impl PreprocessorConfig {
             include_resolver: None,
             warning_handler: None,
             line_ending: LineEnding::CRLF,
+            strict: false,
+            defines: Vec::new(),
+            undefines: Vec::new(),
+            include_dirs: Vec::new(),
+            concat_string_literals: false,
+            on_include_enter: None,
+            prelude: Vec::new(),
+            suppress_prelude_output: false,
+            expansion_trace_handler: None,
+            defined_from_macro: DefinedFromMacro::Honor,
+            standard: Standard::C17,
+            collapse_blank_lines: None,
+            include_path_separator: PathSeparator::Preserve,
+            file_macro_separator: PathSeparator::Preserve,
+            max_iterations: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            max_macro_args: 4096,
+            max_expansion_tokens: 1_000_000,
+            system_headers_quiet: true,
+            byte_order: ByteOrder::Little,
+            max_include_bytes: None,
+            max_total_bytes: None,
+            include_size_policy: IncludeSizePolicy::Error,
+            record_include_graph: false,
+            lossy_utf8: false,
+            warn_unused_macro_params: false,
+            builtin_macro_groups: BuiltinGroups::new(),
+            expr_identifier_resolver: None,
+            warn_undef: false,
+            builtin_rewrites: Vec::new(),
+            initial_file: None,
+            passthrough_line_directives: false,
+            warn_bare_function_macro: false,
+            max_include_depth: 200,
         }
     }
 
@@ -117,6 +663,89 @@ impl PreprocessorConfig {
             include_resolver: None,
             warning_handler: None,
             line_ending: LineEnding::LF,
+            strict: false,
+            defines: Vec::new(),
+            undefines: Vec::new(),
+            include_dirs: Vec::new(),
+            concat_string_literals: false,
+            on_include_enter: None,
+            prelude: Vec::new(),
+            suppress_prelude_output: false,
+            expansion_trace_handler: None,
+            defined_from_macro: DefinedFromMacro::Honor,
+            standard: Standard::C17,
+            collapse_blank_lines: None,
+            include_path_separator: PathSeparator::Preserve,
+            file_macro_separator: PathSeparator::Preserve,
+            max_iterations: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            max_macro_args: 4096,
+            max_expansion_tokens: 1_000_000,
+            system_headers_quiet: true,
+            byte_order: ByteOrder::Little,
+            max_include_bytes: None,
+            max_total_bytes: None,
+            include_size_policy: IncludeSizePolicy::Error,
+            record_include_graph: false,
+            lossy_utf8: false,
+            warn_unused_macro_params: false,
+            builtin_macro_groups: BuiltinGroups::new(),
+            expr_identifier_resolver: None,
+            warn_undef: false,
+            builtin_rewrites: Vec::new(),
+            initial_file: None,
+            passthrough_line_directives: false,
+            warn_bare_function_macro: false,
+            max_include_depth: 200,
+        }
+    }
+
+    /// Create configuration for FreeBSD + Clang
+    #[must_use]
+    pub const fn for_freebsd() -> Self {
+        Self {
+            target: Target::FreeBSD,
+            compiler: Compiler::Clang,
+            recursion_limit: 128,
+            include_resolver: None,
+            warning_handler: None,
+            line_ending: LineEnding::LF,
+            strict: false,
+            defines: Vec::new(),
+            undefines: Vec::new(),
+            include_dirs: Vec::new(),
+            concat_string_literals: false,
+            on_include_enter: None,
+            prelude: Vec::new(),
+            suppress_prelude_output: false,
+            expansion_trace_handler: None,
+            defined_from_macro: DefinedFromMacro::Honor,
+            standard: Standard::C17,
+            collapse_blank_lines: None,
+            include_path_separator: PathSeparator::Preserve,
+            file_macro_separator: PathSeparator::Preserve,
+            max_iterations: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            max_macro_args: 4096,
+            max_expansion_tokens: 1_000_000,
+            system_headers_quiet: true,
+            byte_order: ByteOrder::Little,
+            max_include_bytes: None,
+            max_total_bytes: None,
+            include_size_policy: IncludeSizePolicy::Error,
+            record_include_graph: false,
+            lossy_utf8: false,
+            warn_unused_macro_params: false,
+            builtin_macro_groups: BuiltinGroups::new(),
+            expr_identifier_resolver: None,
+            warn_undef: false,
+            builtin_rewrites: Vec::new(),
+            initial_file: None,
+            passthrough_line_directives: false,
+            warn_bare_function_macro: false,
+            max_include_depth: 200,
         }
     }
 
@@ -140,4 +769,826 @@ impl PreprocessorConfig {
         self.line_ending = ending;
         self
     }
+
+    /// Enable or disable strict conformance mode (see [`PreprocessorConfig::strict`])
+    #[must_use]
+    pub const fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Keep only the macro side effects of the prelude, not its output
+    #[must_use]
+    pub const fn with_suppress_prelude_output(mut self, suppress_prelude_output: bool) -> Self {
+        self.suppress_prelude_output = suppress_prelude_output;
+        self
+    }
+
+    /// Set the items processed before the main input
+    #[must_use]
+    pub fn with_prelude(mut self, prelude: Vec<PreludeItem>) -> Self {
+        self.prelude = prelude;
+        self
+    }
+
+    /// Set a hook called just before each included file is processed
+    #[must_use]
+    pub fn with_on_include_enter(mut self, hook: IncludeHook) -> Self {
+        self.on_include_enter = Some(hook);
+        self
+    }
+
+    /// Enable or disable merging adjacent string literals in the output
+    #[must_use]
+    pub const fn with_concat_string_literals(mut self, concat_string_literals: bool) -> Self {
+        self.concat_string_literals = concat_string_literals;
+        self
+    }
+
+    /// Set a handler called for every macro expansion
+    #[must_use]
+    pub fn with_expansion_trace_handler(mut self, handler: ExpansionTraceHandler) -> Self {
+        self.expansion_trace_handler = Some(handler);
+        self
+    }
+
+    /// Set how `defined` produced by macro expansion in `#if` is treated
+    #[must_use]
+    pub const fn with_defined_from_macro(mut self, defined_from_macro: DefinedFromMacro) -> Self {
+        self.defined_from_macro = defined_from_macro;
+        self
+    }
+
+    /// Set the language standard
+    #[must_use]
+    pub const fn with_standard(mut self, standard: Standard) -> Self {
+        self.standard = standard;
+        self
+    }
+
+    /// Keep at most `max` consecutive blank output lines
+    #[must_use]
+    pub const fn with_collapse_blank_lines(mut self, max: usize) -> Self {
+        self.collapse_blank_lines = Some(max);
+        self
+    }
+
+    /// Set the separator used in include paths passed to the resolver
+    #[must_use]
+    pub const fn with_include_path_separator(
+        mut self,
+        include_path_separator: PathSeparator,
+    ) -> Self {
+        self.include_path_separator = include_path_separator;
+        self
+    }
+
+    /// Set the separator used in the path reported by `__FILE__`
+    #[must_use]
+    pub const fn with_file_macro_separator(mut self, file_macro_separator: PathSeparator) -> Self {
+        self.file_macro_separator = file_macro_separator;
+        self
+    }
+
+    /// Stop with a budget error after `max` lines and macro expansions
+    #[must_use]
+    pub const fn with_max_iterations(mut self, max: u64) -> Self {
+        self.max_iterations = Some(max);
+        self
+    }
+
+    /// Stop with a budget error once `deadline` has passed
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub const fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Set the maximum number of arguments in a macro call
+    #[must_use]
+    pub const fn with_max_macro_args(mut self, max_macro_args: usize) -> Self {
+        self.max_macro_args = max_macro_args;
+        self
+    }
+
+    /// Set the maximum number of tokens a line or macro may expand to
+    #[must_use]
+    pub const fn with_max_expansion_tokens(mut self, max_expansion_tokens: usize) -> Self {
+        self.max_expansion_tokens = max_expansion_tokens;
+        self
+    }
+
+    /// Set whether headers included with angle brackets are treated as system
+    /// headers, whose warnings are suppressed
+    #[must_use]
+    pub const fn with_system_headers_quiet(mut self, system_headers_quiet: bool) -> Self {
+        self.system_headers_quiet = system_headers_quiet;
+        self
+    }
+
+    /// Set the byte order reported by `__BYTE_ORDER__`
+    #[must_use]
+    pub const fn with_byte_order(mut self, byte_order: ByteOrder) -> Self {
+        self.byte_order = byte_order;
+        self
+    }
+
+    /// Set the largest included file, in bytes, accepted as is
+    #[must_use]
+    pub const fn with_max_include_bytes(mut self, max: usize) -> Self {
+        self.max_include_bytes = Some(max);
+        self
+    }
+
+    /// Set the largest amount of input, in bytes, read for one translation unit
+    #[must_use]
+    pub const fn with_max_total_bytes(mut self, max: usize) -> Self {
+        self.max_total_bytes = Some(max);
+        self
+    }
+
+    /// Set what to do when an include goes over a size limit
+    #[must_use]
+    pub const fn with_include_size_policy(
+        mut self,
+        include_size_policy: IncludeSizePolicy,
+    ) -> Self {
+        self.include_size_policy = include_size_policy;
+        self
+    }
+
+    /// Record which file includes which, for `include_graph_dot`
+    #[must_use]
+    pub const fn with_record_include_graph(mut self, record_include_graph: bool) -> Self {
+        self.record_include_graph = record_include_graph;
+        self
+    }
+
+    /// Replace invalid UTF-8 in byte input with U+FFFD instead of failing
+    #[must_use]
+    pub const fn with_lossy_utf8(mut self, lossy_utf8: bool) -> Self {
+        self.lossy_utf8 = lossy_utf8;
+        self
+    }
+
+    /// Warn about function-like macro parameters the body never uses
+    #[must_use]
+    pub const fn with_warn_unused_macro_params(mut self, warn_unused_macro_params: bool) -> Self {
+        self.warn_unused_macro_params = warn_unused_macro_params;
+        self
+    }
+
+    /// Choose which groups of builtin macros are predefined
+    #[must_use]
+    pub const fn with_builtin_macro_groups(mut self, builtin_macro_groups: BuiltinGroups) -> Self {
+        self.builtin_macro_groups = builtin_macro_groups;
+        self
+    }
+
+    /// Give values to identifiers in `#if` expressions that aren't macros
+    #[must_use]
+    pub fn with_expr_identifier_resolver(mut self, resolver: ExprIdentifierResolver) -> Self {
+        self.expr_identifier_resolver = Some(resolver);
+        self
+    }
+
+    /// Warn when an identifier that isn't a macro is evaluated as 0 in `#if`
+    #[must_use]
+    pub const fn with_warn_undef(mut self, warn_undef: bool) -> Self {
+        self.warn_undef = warn_undef;
+        self
+    }
+
+    /// Strip MSVC-specific syntax when the compiler is MSVC
+    ///
+    /// Adds [`Self::builtin_rewrites`] that remove `__declspec(...)`, calling
+    /// conventions such as `__cdecl` and common SAL annotations such as `_In_`
+    /// and `_Out_writes_(n)`, and turn `__forceinline` into `inline`, so the
+    /// output suits parsers that only know standard C. With other compilers
+    /// the configuration is returned unchanged.
+    #[must_use]
+    pub fn with_msvc_compat_stubs(mut self) -> Self {
+        if matches!(self.compiler, Compiler::MSVC) {
+            self.builtin_rewrites.extend(
+                MSVC_COMPAT_STUBS.iter().map(|&(spec, replacement)| {
+                    (spec.to_string(), replacement.map(str::to_string))
+                }),
+            );
+        }
+        self
+    }
+
+    /// Set the builtin macros that strip or rewrite compiler-specific syntax
+    #[must_use]
+    pub fn with_builtin_rewrites(
+        mut self,
+        builtin_rewrites: Vec<(String, Option<String>)>,
+    ) -> Self {
+        self.builtin_rewrites = builtin_rewrites;
+        self
+    }
+
+    /// Name the main input, as reported by `__FILE__` and in diagnostics
+    #[must_use]
+    pub fn with_initial_file(mut self, file: impl Into<String>) -> Self {
+        self.initial_file = Some(file.into());
+        self
+    }
+
+    /// Keep `#line` directives in the output, so tools reading it see the
+    /// positions they declare
+    #[must_use]
+    pub const fn with_passthrough_line_directives(
+        mut self,
+        passthrough_line_directives: bool,
+    ) -> Self {
+        self.passthrough_line_directives = passthrough_line_directives;
+        self
+    }
+
+    /// Warn when a function-like macro name appears without an argument list
+    #[must_use]
+    pub const fn with_warn_bare_function_macro(mut self, warn_bare_function_macro: bool) -> Self {
+        self.warn_bare_function_macro = warn_bare_function_macro;
+        self
+    }
+
+    /// Prepare this configuration for creating many drivers
+    ///
+    /// The builtin and configured macros are tokenized once here instead of
+    /// each time a driver is created; see [`CompiledConfig`].
+    #[must_use]
+    pub fn compile(&self) -> CompiledConfig {
+        let mut context = PreprocessorContext::new();
+        context.apply_config(self);
+        CompiledConfig {
+            config: self.clone(),
+            macros: context.configured_macros,
+        }
+    }
+
+    /// Set the maximum nesting depth of `#include`
+    #[must_use]
+    pub const fn with_max_include_depth(mut self, max_include_depth: usize) -> Self {
+        self.max_include_depth = max_include_depth;
+        self
+    }
+}
+
+/// A configuration with its macro table already built
+///
+/// Made by [`PreprocessorConfig::compile`]. A driver created with
+/// [`PreprocessorDriver::with_compiled`](crate::PreprocessorDriver::with_compiled)
+/// copies the prepared macros rather than defining every builtin again,
+/// which keeps setup cheap when each of many files gets its own driver.
+#[derive(Clone)]
+pub struct CompiledConfig {
+    pub(crate) config: PreprocessorConfig,
+    pub(crate) macros: MacroTable,
+}
+
+impl CompiledConfig {
+    /// The configuration this was compiled from
+    #[must_use]
+    pub const fn config(&self) -> &PreprocessorConfig {
+        &self.config
+    }
+
+    /// The builtin and configured macros every driver starts with
+    #[must_use]
+    pub const fn macros(&self) -> &MacroTable {
+        &self.macros
+    }
+}
+
+/// Builder for [`PreprocessorConfig`] that validates the result
+///
+/// Unset fields take the defaults of the `for_*` constructor matching the
+/// target, so `builder().target(Target::Windows)` gets MSVC and CRLF.
+#[derive(Default)]
+pub struct PreprocessorConfigBuilder {
+    target: Option<Target>,
+    compiler: Option<Compiler>,
+    line_ending: Option<LineEnding>,
+    recursion_limit: Option<usize>,
+    include_resolver: Option<IncludeResolver>,
+    warning_handler: Option<WarningHandler>,
+    strict: bool,
+    defines: Vec<String>,
+    undefines: Vec<String>,
+    #[cfg(feature = "std")]
+    include_dirs: Vec<PathBuf>,
+    #[cfg(feature = "std")]
+    require_existing_include_dirs: bool,
+    concat_string_literals: bool,
+    on_include_enter: Option<IncludeHook>,
+    prelude: Vec<PreludeItem>,
+    suppress_prelude_output: bool,
+    expansion_trace_handler: Option<ExpansionTraceHandler>,
+    defined_from_macro: DefinedFromMacro,
+    standard: Standard,
+    collapse_blank_lines: Option<usize>,
+    include_path_separator: PathSeparator,
+    file_macro_separator: PathSeparator,
+    max_iterations: Option<u64>,
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+    max_macro_args: Option<usize>,
+    max_expansion_tokens: Option<usize>,
+    system_headers_quiet: Option<bool>,
+    byte_order: ByteOrder,
+    max_include_bytes: Option<usize>,
+    max_total_bytes: Option<usize>,
+    include_size_policy: IncludeSizePolicy,
+    record_include_graph: bool,
+    lossy_utf8: bool,
+    warn_unused_macro_params: bool,
+    builtin_macro_groups: BuiltinGroups,
+    expr_identifier_resolver: Option<ExprIdentifierResolver>,
+    warn_undef: bool,
+    builtin_rewrites: Vec<(String, Option<String>)>,
+    msvc_compat_stubs: bool,
+    initial_file: Option<String>,
+    passthrough_line_directives: bool,
+    warn_bare_function_macro: bool,
+    max_include_depth: Option<usize>,
+}
+
+impl PreprocessorConfigBuilder {
+    /// Create a builder with every field at its default
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the target operating system
+    #[must_use]
+    pub const fn target(mut self, target: Target) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Set the compiler dialect
+    #[must_use]
+    pub const fn compiler(mut self, compiler: Compiler) -> Self {
+        self.compiler = Some(compiler);
+        self
+    }
+
+    /// Set the line ending style for output
+    #[must_use]
+    pub const fn line_ending(mut self, ending: LineEnding) -> Self {
+        self.line_ending = Some(ending);
+        self
+    }
+
+    /// Set the maximum recursion depth for macro expansion
+    #[must_use]
+    pub const fn recursion_limit(mut self, limit: usize) -> Self {
+        self.recursion_limit = Some(limit);
+        self
+    }
+
+    /// Set the include resolver
+    #[must_use]
+    pub fn include_resolver(mut self, resolver: IncludeResolver) -> Self {
+        self.include_resolver = Some(resolver);
+        self
+    }
+
+    /// Set the warning handler
+    #[must_use]
+    pub fn warning_handler(mut self, handler: WarningHandler) -> Self {
+        self.warning_handler = Some(handler);
+        self
+    }
+
+    /// Enable or disable strict conformance mode
+    #[must_use]
+    pub const fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Keep only the macro side effects of the prelude, not its output
+    #[must_use]
+    pub const fn suppress_prelude_output(mut self, suppress_prelude_output: bool) -> Self {
+        self.suppress_prelude_output = suppress_prelude_output;
+        self
+    }
+
+    /// Set the items processed before the main input
+    #[must_use]
+    pub fn prelude(mut self, prelude: Vec<PreludeItem>) -> Self {
+        self.prelude = prelude;
+        self
+    }
+
+    /// Set a hook called just before each included file is processed
+    #[must_use]
+    pub fn on_include_enter(mut self, hook: IncludeHook) -> Self {
+        self.on_include_enter = Some(hook);
+        self
+    }
+
+    /// Enable or disable merging adjacent string literals in the output
+    #[must_use]
+    pub const fn concat_string_literals(mut self, concat_string_literals: bool) -> Self {
+        self.concat_string_literals = concat_string_literals;
+        self
+    }
+
+    /// Set a handler called for every macro expansion
+    #[must_use]
+    pub fn expansion_trace_handler(mut self, handler: ExpansionTraceHandler) -> Self {
+        self.expansion_trace_handler = Some(handler);
+        self
+    }
+
+    /// Set how `defined` produced by macro expansion in `#if` is treated
+    #[must_use]
+    pub const fn defined_from_macro(mut self, defined_from_macro: DefinedFromMacro) -> Self {
+        self.defined_from_macro = defined_from_macro;
+        self
+    }
+
+    /// Set the language standard
+    #[must_use]
+    pub const fn standard(mut self, standard: Standard) -> Self {
+        self.standard = standard;
+        self
+    }
+
+    /// Keep at most `max` consecutive blank output lines
+    #[must_use]
+    pub const fn collapse_blank_lines(mut self, max: usize) -> Self {
+        self.collapse_blank_lines = Some(max);
+        self
+    }
+
+    /// Set the separator used in include paths passed to the resolver
+    #[must_use]
+    pub const fn include_path_separator(mut self, include_path_separator: PathSeparator) -> Self {
+        self.include_path_separator = include_path_separator;
+        self
+    }
+
+    /// Set the separator used in the path reported by `__FILE__`
+    #[must_use]
+    pub const fn file_macro_separator(mut self, file_macro_separator: PathSeparator) -> Self {
+        self.file_macro_separator = file_macro_separator;
+        self
+    }
+
+    /// Stop with a budget error after `max` lines and macro expansions
+    #[must_use]
+    pub const fn max_iterations(mut self, max: u64) -> Self {
+        self.max_iterations = Some(max);
+        self
+    }
+
+    /// Stop with a budget error once `deadline` has passed
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub const fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Set the maximum number of arguments in a macro call
+    #[must_use]
+    pub const fn max_macro_args(mut self, max_macro_args: usize) -> Self {
+        self.max_macro_args = Some(max_macro_args);
+        self
+    }
+
+    /// Set the maximum number of tokens a line or macro may expand to
+    #[must_use]
+    pub const fn max_expansion_tokens(mut self, max_expansion_tokens: usize) -> Self {
+        self.max_expansion_tokens = Some(max_expansion_tokens);
+        self
+    }
+
+    /// Set whether headers included with angle brackets are treated as system
+    /// headers, whose warnings are suppressed
+    #[must_use]
+    pub const fn system_headers_quiet(mut self, system_headers_quiet: bool) -> Self {
+        self.system_headers_quiet = Some(system_headers_quiet);
+        self
+    }
+
+    /// Set the byte order reported by `__BYTE_ORDER__`
+    #[must_use]
+    pub const fn byte_order(mut self, byte_order: ByteOrder) -> Self {
+        self.byte_order = byte_order;
+        self
+    }
+
+    /// Set the largest included file, in bytes, accepted as is
+    #[must_use]
+    pub const fn max_include_bytes(mut self, max: usize) -> Self {
+        self.max_include_bytes = Some(max);
+        self
+    }
+
+    /// Set the largest amount of input, in bytes, read for one translation unit
+    #[must_use]
+    pub const fn max_total_bytes(mut self, max: usize) -> Self {
+        self.max_total_bytes = Some(max);
+        self
+    }
+
+    /// Set what to do when an include goes over a size limit
+    #[must_use]
+    pub const fn include_size_policy(mut self, include_size_policy: IncludeSizePolicy) -> Self {
+        self.include_size_policy = include_size_policy;
+        self
+    }
+
+    /// Record which file includes which, for `include_graph_dot`
+    #[must_use]
+    pub const fn record_include_graph(mut self, record_include_graph: bool) -> Self {
+        self.record_include_graph = record_include_graph;
+        self
+    }
+
+    /// Replace invalid UTF-8 in byte input with U+FFFD instead of failing
+    #[must_use]
+    pub const fn lossy_utf8(mut self, lossy_utf8: bool) -> Self {
+        self.lossy_utf8 = lossy_utf8;
+        self
+    }
+
+    /// Warn about function-like macro parameters the body never uses
+    #[must_use]
+    pub const fn warn_unused_macro_params(mut self, warn_unused_macro_params: bool) -> Self {
+        self.warn_unused_macro_params = warn_unused_macro_params;
+        self
+    }
+
+    /// Choose which groups of builtin macros are predefined
+    #[must_use]
+    pub const fn builtin_macro_groups(mut self, builtin_macro_groups: BuiltinGroups) -> Self {
+        self.builtin_macro_groups = builtin_macro_groups;
+        self
+    }
+
+    /// Give values to identifiers in `#if` expressions that aren't macros
+    #[must_use]
+    pub fn expr_identifier_resolver(mut self, resolver: ExprIdentifierResolver) -> Self {
+        self.expr_identifier_resolver = Some(resolver);
+        self
+    }
+
+    /// Warn when an identifier that isn't a macro is evaluated as 0 in `#if`
+    #[must_use]
+    pub const fn warn_undef(mut self, warn_undef: bool) -> Self {
+        self.warn_undef = warn_undef;
+        self
+    }
+
+    /// Set the builtin macros that strip or rewrite compiler-specific syntax
+    #[must_use]
+    pub fn builtin_rewrites(mut self, builtin_rewrites: Vec<(String, Option<String>)>) -> Self {
+        self.builtin_rewrites = builtin_rewrites;
+        self
+    }
+
+    /// Add the rewrites of [`PreprocessorConfig::with_msvc_compat_stubs`]
+    /// when the compiler is MSVC
+    #[must_use]
+    pub const fn msvc_compat_stubs(mut self, enable: bool) -> Self {
+        self.msvc_compat_stubs = enable;
+        self
+    }
+
+    /// Name the main input, as reported by `__FILE__` and in diagnostics
+    #[must_use]
+    pub fn initial_file(mut self, file: impl Into<String>) -> Self {
+        self.initial_file = Some(file.into());
+        self
+    }
+
+    /// Keep `#line` directives in the output, so tools reading it see the
+    /// positions they declare
+    #[must_use]
+    pub const fn passthrough_line_directives(mut self, passthrough_line_directives: bool) -> Self {
+        self.passthrough_line_directives = passthrough_line_directives;
+        self
+    }
+
+    /// Warn when a function-like macro name appears without an argument list
+    #[must_use]
+    pub const fn warn_bare_function_macro(mut self, warn_bare_function_macro: bool) -> Self {
+        self.warn_bare_function_macro = warn_bare_function_macro;
+        self
+    }
+
+    /// Set the maximum nesting depth of `#include`
+    #[must_use]
+    pub const fn max_include_depth(mut self, max_include_depth: usize) -> Self {
+        self.max_include_depth = Some(max_include_depth);
+        self
+    }
+
+    /// Define a macro from a `NAME` or `NAME=VALUE` string, like `-D`
+    #[must_use]
+    pub fn define(mut self, definition: impl Into<String>) -> Self {
+        self.defines.push(definition.into());
+        self
+    }
+
+    /// Undefine a macro by name, like `-U`
+    #[must_use]
+    pub fn undefine(mut self, name: impl Into<String>) -> Self {
+        self.undefines.push(name.into());
+        self
+    }
+
+    /// Add an include directory
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub fn include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Reject include directories that don't exist on disk
+    #[cfg(feature = "std")]
+    #[must_use]
+    pub const fn require_existing_include_dirs(mut self, require: bool) -> Self {
+        self.require_existing_include_dirs = require;
+        self
+    }
+
+    /// Include directories as strings, without duplicates
+    #[cfg(feature = "std")]
+    fn checked_include_dirs(&self, warnings: &mut Vec<String>) -> Result<Vec<String>, ConfigError> {
+        let mut include_dirs: Vec<String> = Vec::new();
+        for dir in &self.include_dirs {
+            if self.require_existing_include_dirs && !dir.is_dir() {
+                return Err(ConfigError::MissingIncludeDir(
+                    dir.to_string_lossy().into_owned(),
+                ));
+            }
+            let dir = dir.to_string_lossy().into_owned();
+            if include_dirs.contains(&dir) {
+                warnings.push(format!("include directory '{dir}' given more than once"));
+            } else {
+                include_dirs.push(dir);
+            }
+        }
+        Ok(include_dirs)
+    }
+
+    /// Validate and build the configuration
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ConfigError`] describing the first invalid setting.
+    pub fn build(self) -> Result<PreprocessorConfig, ConfigError> {
+        self.build_with_warnings().map(|(config, _)| config)
+    }
+
+    /// Validate and build the configuration, also returning warnings about
+    /// settings that are valid but probably unintended
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ConfigError`] describing the first invalid setting.
+    pub fn build_with_warnings(self) -> Result<(PreprocessorConfig, Vec<String>), ConfigError> {
+        let mut warnings = Vec::new();
+
+        let recursion_limit = self.recursion_limit.unwrap_or(128);
+        if recursion_limit == 0 {
+            return Err(ConfigError::ZeroLimit("recursion_limit"));
+        }
+        if self.max_iterations == Some(0) {
+            return Err(ConfigError::ZeroLimit("max_iterations"));
+        }
+        let max_macro_args = self.max_macro_args.unwrap_or(4096);
+        if max_macro_args == 0 {
+            return Err(ConfigError::ZeroLimit("max_macro_args"));
+        }
+        let max_expansion_tokens = self.max_expansion_tokens.unwrap_or(1_000_000);
+        if max_expansion_tokens == 0 {
+            return Err(ConfigError::ZeroLimit("max_expansion_tokens"));
+        }
+        let max_include_depth = self.max_include_depth.unwrap_or(200);
+        if max_include_depth == 0 {
+            return Err(ConfigError::ZeroLimit("max_include_depth"));
+        }
+
+        let mut defines: Vec<(String, String)> = Vec::new();
+        for definition in &self.defines {
+            let (name, value) = definition
+                .split_once('=')
+                .unwrap_or((definition.as_str(), "1"));
+            if !engine::is_valid_identifier(name) {
+                return Err(ConfigError::MalformedDefine(definition.clone()));
+            }
+            match defines.iter().find(|(existing, _)| existing == name) {
+                Some((_, previous)) if previous != value => {
+                    return Err(ConfigError::ConflictingDefine {
+                        name: name.to_string(),
+                        first: previous.clone(),
+                        second: value.to_string(),
+                    });
+                }
+                Some(_) => {}
+                None => defines.push((name.to_string(), value.to_string())),
+            }
+        }
+
+        let mut undefines: Vec<String> = Vec::new();
+        for name in &self.undefines {
+            if !engine::is_valid_identifier(name) {
+                return Err(ConfigError::MalformedDefine(name.clone()));
+            }
+            if defines.iter().any(|(defined, _)| defined == name) {
+                return Err(ConfigError::DefineUndefineConflict(name.clone()));
+            }
+            if !undefines.contains(name) {
+                undefines.push(name.clone());
+            }
+        }
+
+        #[cfg(feature = "std")]
+        let include_dirs = self.checked_include_dirs(&mut warnings)?;
+        #[cfg(not(feature = "std"))]
+        let include_dirs = Vec::new();
+
+        for (spec, _) in &self.builtin_rewrites {
+            if engine::parse_define(spec).is_none_or(|define| {
+                !engine::is_valid_identifier(&define.name) || !define.body.is_empty()
+            }) {
+                return Err(ConfigError::MalformedDefine(spec.clone()));
+            }
+        }
+
+        let base = match self.target.unwrap_or(Target::Linux) {
+            Target::Linux => PreprocessorConfig::for_linux(),
+            Target::Windows => PreprocessorConfig::for_windows(),
+            Target::MacOS => PreprocessorConfig::for_macos(),
+            Target::FreeBSD => PreprocessorConfig::for_freebsd(),
+        };
+        let compiler = self.compiler.unwrap_or(base.compiler);
+        if matches!(compiler, Compiler::MSVC) && !matches!(base.target, Target::Windows) {
+            warnings.push(format!(
+                "MSVC compiler with a {:?} target; MSVC only targets Windows",
+                base.target
+            ));
+        }
+
+        let config = PreprocessorConfig {
+            target: base.target,
+            compiler,
+            recursion_limit,
+            include_resolver: self.include_resolver,
+            warning_handler: self.warning_handler,
+            line_ending: self.line_ending.unwrap_or(base.line_ending),
+            strict: self.strict,
+            defines,
+            undefines,
+            include_dirs,
+            concat_string_literals: self.concat_string_literals,
+            on_include_enter: self.on_include_enter,
+            prelude: self.prelude,
+            suppress_prelude_output: self.suppress_prelude_output,
+            expansion_trace_handler: self.expansion_trace_handler,
+            defined_from_macro: self.defined_from_macro,
+            standard: self.standard,
+            collapse_blank_lines: self.collapse_blank_lines,
+            include_path_separator: self.include_path_separator,
+            file_macro_separator: self.file_macro_separator,
+            max_iterations: self.max_iterations,
+            #[cfg(feature = "std")]
+            deadline: self.deadline,
+            max_macro_args,
+            max_expansion_tokens,
+            system_headers_quiet: self.system_headers_quiet.unwrap_or(true),
+            byte_order: self.byte_order,
+            max_include_bytes: self.max_include_bytes,
+            max_total_bytes: self.max_total_bytes,
+            include_size_policy: self.include_size_policy,
+            record_include_graph: self.record_include_graph,
+            lossy_utf8: self.lossy_utf8,
+            warn_unused_macro_params: self.warn_unused_macro_params,
+            builtin_macro_groups: self.builtin_macro_groups,
+            expr_identifier_resolver: self.expr_identifier_resolver,
+            warn_undef: self.warn_undef,
+            builtin_rewrites: self.builtin_rewrites,
+            initial_file: self.initial_file,
+            passthrough_line_directives: self.passthrough_line_directives,
+            warn_bare_function_macro: self.warn_bare_function_macro,
+            max_include_depth,
+        };
+        let config = if self.msvc_compat_stubs {
+            config.with_msvc_compat_stubs()
+        } else {
+            config
+        };
+        Ok((config, warnings))
+    }
 }
@@ -1,10 +1,21 @@
-use std::collections::{HashMap, HashSet};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::RefCell;
 
-use crate::config::{Compiler, IncludeResolver, LineEnding, Target, WarningHandler};
-use crate::macro_def::Macro;
+use crate::config::{
+    ByteOrder, CompiledConfig, Compiler, DefinedFromMacro, ExpansionTraceHandler,
+    ExprIdentifierResolver, IncludeHook, IncludeResolver, IncludeSizePolicy, LineEnding,
+    PathSeparator, PreludeItem, Standard, Target, WarningHandler,
+};
+use crate::event::SourceLocation;
+use crate::macro_def::{Macro, MacroKind, MacroSet, MacroTable};
+use crate::output::Recording;
 
-use crate::{PreprocessorConfig, engine};
-use std::rc::Rc;
+use crate::{HashMap, HashSet, PreprocessorConfig, engine};
+use alloc::rc::Rc;
+#[cfg(feature = "std")]
+use std::time::Instant;
 
 /// State for conditional compilation directives
 #[derive(Clone, Debug)]
@@ -13,35 +24,97 @@ pub struct ConditionalState {
     pub is_active: bool,
     /// Whether any branch in this #if/#endif block has been taken already
     pub any_branch_taken: bool,
+    /// Where the opening `#if`/`#ifdef`/`#ifndef` is
+    pub origin: SourceLocation,
+    /// Text of the opening directive, for diagnostics
+    pub directive: String,
 }
 
 impl ConditionalState {
     /// Create a new conditional state for an #if/#ifdef/#ifndef
-    pub const fn new(active: bool) -> Self {
+    pub const fn new(active: bool, origin: SourceLocation, directive: String) -> Self {
         Self {
             is_active: active,
             any_branch_taken: active,
+            origin,
+            directive,
         }
     }
 }
 
+/// Counters describing the work done by a preprocessor
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PreprocessStats {
+    /// `#if`/`#elif` expressions answered from the expression cache
+    pub expression_cache_hits: usize,
+    /// `#if`/`#elif` expressions that had to be expanded and evaluated
+    pub expression_cache_misses: usize,
+    /// Macro invocations expanded, including nested ones
+    pub macro_expansions: usize,
+    /// Bytes of input read, counting the main input and every included file
+    pub bytes_read: usize,
+}
+
 /// Context containing all state for preprocessor operations
 ///
 /// This struct holds all mutable state needed during preprocessing,
 /// making it easy to test and reuse the preprocessor logic.
 pub struct PreprocessorContext {
     /// Defined macros
-    pub macros: HashMap<String, Macro>,
+    pub macros: MacroTable,
+
+    /// Counter bumped whenever the macro table changes
+    pub macro_generation: u64,
+
+    /// Results of `#if`/`#elif` expressions, keyed by expression text and
+    /// valid only while the stored generation matches `macro_generation`
+    pub expression_cache: HashMap<String, (u64, bool)>,
+
+    /// Set while evaluating an expression whose result depends on more than
+    /// the macro table (e.g. `__LINE__`) or that produced a diagnostic
+    pub expression_uncacheable: bool,
+
+    /// Work counters exposed through `PreprocessorDriver::stats`
+    pub stats: PreprocessStats,
+
+    /// Lines and macro expansions processed in the current `process` call,
+    /// counted against `max_iterations`
+    pub iterations: u64,
+
+    /// Bytes of input read in the current `process` call, counted against
+    /// `max_total_bytes`
+    pub unit_bytes: usize,
+
+    /// Where warnings and dependencies are recorded during `process_full`
+    pub(crate) recording: Option<Rc<RefCell<Recording>>>,
 
     /// Macros temporarily disabled during expansion (to prevent recursion)
     pub disabled_macros: HashSet<String>,
 
-    /// Files included with #pragma once
+    /// Names of the macros currently being expanded, outermost first
+    pub expansion_stack: Vec<String>,
+
+    /// Files marked with #pragma once in the current `process` call, keyed
+    /// by canonical or normalized path
     pub included_once: HashSet<String>,
 
-    /// Stack of currently included files for cycle detection
+    /// Identifiers banned by `#pragma GCC poison`
+    pub poisoned: HashSet<String>,
+
+    /// Answers given to each predicate by `#assert`
+    pub assertions: HashMap<String, HashSet<String>>,
+
+    /// Whether `#pragma GCC system_header` marked the rest of the current file
+    /// as a system header, suppressing conformance diagnostics and poison checks
+    pub in_system_header: bool,
+
+    /// Files that included the current one, outermost first
     pub include_stack: Vec<String>,
 
+    /// `#pragma once` keys of the files in `include_stack`, for finding
+    /// include cycles however the headers are spelled
+    pub include_keys: Vec<String>,
+
     /// Custom include resolver function
     pub include_resolver: Option<IncludeResolver>,
 
@@ -51,6 +124,9 @@ pub struct PreprocessorContext {
     /// Current file name for error reporting and __FILE__ macro
     pub current_file: String,
 
+    /// Next value of `__COUNTER__`, starting from 0 in each `process` call
+    pub counter: u64,
+
     /// Current line number for __LINE__ macro
     pub current_line: usize,
 
@@ -65,6 +141,113 @@ pub struct PreprocessorContext {
 
     /// Line ending style for output denormalization
     pub line_ending: LineEnding,
+
+    /// Whether conformance diagnostics are errors rather than warnings
+    pub strict: bool,
+
+    /// Include directories passed to the include resolver
+    pub include_dirs: Vec<String>,
+
+    /// Whether adjacent string literals are merged in the output
+    pub concat_string_literals: bool,
+
+    /// Hook called before an included file is processed
+    pub on_include_enter: Option<IncludeHook>,
+
+    /// Items processed before the main input at the top level
+    pub prelude: Vec<PreludeItem>,
+
+    /// Whether prelude output is dropped
+    pub suppress_prelude_output: bool,
+
+    /// Handler called for every macro expansion
+    pub expansion_trace_handler: Option<ExpansionTraceHandler>,
+
+    /// How `defined` produced by macro expansion in `#if` is treated
+    pub defined_from_macro: DefinedFromMacro,
+
+    /// Language standard used for `#if` expressions
+    pub standard: Standard,
+
+    /// Maximum run of blank output lines, if limited
+    pub collapse_blank_lines: Option<usize>,
+
+    /// Separator used in include paths passed to the resolver
+    pub include_path_separator: PathSeparator,
+
+    /// Separator used in the path reported by `__FILE__`
+    pub file_macro_separator: PathSeparator,
+
+    /// Maximum lines and macro expansions per `process` call, if limited
+    pub max_iterations: Option<u64>,
+
+    /// Moment after which processing stops with a budget error, if set
+    #[cfg(feature = "std")]
+    pub deadline: Option<Instant>,
+
+    /// Maximum number of arguments in a macro call
+    pub max_macro_args: usize,
+
+    /// Maximum number of tokens a line or macro may expand to
+    pub max_expansion_tokens: usize,
+
+    /// Whether `<...>` includes are treated as system headers
+    pub system_headers_quiet: bool,
+
+    /// Macro table as left by the last `apply_config`, restored by `reset`
+    pub(crate) configured_macros: MacroTable,
+
+    /// Largest included file, in bytes, accepted as is
+    pub max_include_bytes: Option<usize>,
+
+    /// Largest amount of input, in bytes, read for one translation unit
+    pub max_total_bytes: Option<usize>,
+
+    /// What to do when an include goes over a size limit
+    pub include_size_policy: IncludeSizePolicy,
+
+    /// Record include edges for `include_graph_dot`
+    pub record_include_graph: bool,
+
+    /// `(including file, included file)` pairs seen in the current `process`
+    /// call when `record_include_graph` is set, in first-seen order
+    pub include_edges: Vec<(String, String)>,
+
+    /// Replace invalid UTF-8 in byte input instead of failing
+    pub lossy_utf8: bool,
+
+    /// Warn about function-like macro parameters the body never uses
+    pub warn_unused_macro_params: bool,
+
+    /// Values for otherwise unknown identifiers in `#if` expressions
+    pub expr_identifier_resolver: Option<ExprIdentifierResolver>,
+
+    /// Whether `#if` warns about identifiers that aren't macros
+    pub warn_undef: bool,
+
+    /// Identity the resolver gave the file being processed, which
+    /// `#pragma once` records instead of its name
+    pub file_identity: Option<String>,
+
+    /// Name the main input starts with, restored by [`Self::reset`]
+    pub initial_file: Option<String>,
+
+    /// Whether `#line` directives are kept in the output
+    pub passthrough_line_directives: bool,
+
+    /// Once a `#line` has been passed through, the file and line the output
+    /// continues with; `Some(None)` until the first line after the directive
+    pub line_sync: Option<Option<(String, usize)>>,
+
+    /// Whether a function-like macro name without arguments warns
+    pub warn_bare_function_macro: bool,
+
+    /// Open `#pragma pack(push)` entries, innermost last, with their
+    /// identifier and where they were pushed
+    pub pack_stack: Vec<(Option<String>, SourceLocation)>,
+
+    /// Maximum nesting depth of `#include`
+    pub max_include_depth: usize,
 }
 
 impl Default for PreprocessorContext {
@@ -78,34 +261,227 @@ impl PreprocessorContext {
     #[must_use]
     pub fn new() -> Self {
         PreprocessorContext {
-            macros: HashMap::new(),
+            macros: MacroTable::new(),
+            macro_generation: 0,
+            expression_cache: HashMap::new(),
+            expression_uncacheable: false,
+            stats: PreprocessStats::default(),
+            iterations: 0,
+            unit_bytes: 0,
+            recording: None,
             disabled_macros: HashSet::new(),
+            expansion_stack: Vec::new(),
             included_once: HashSet::new(),
+            poisoned: HashSet::new(),
+            assertions: HashMap::new(),
+            in_system_header: false,
             include_stack: Vec::new(),
+            include_keys: Vec::new(),
             include_resolver: None,
             conditional_stack: Vec::new(),
             current_file: "<stdin>".to_string(),
+            counter: 0,
             current_line: 1,
             recursion_limit: 128,
             compiler: Compiler::GCC,
             warning_handler: None,
             line_ending: LineEnding::LF,
+            strict: false,
+            include_dirs: Vec::new(),
+            concat_string_literals: false,
+            on_include_enter: None,
+            prelude: Vec::new(),
+            suppress_prelude_output: false,
+            expansion_trace_handler: None,
+            defined_from_macro: DefinedFromMacro::Honor,
+            standard: Standard::C17,
+            collapse_blank_lines: None,
+            include_path_separator: PathSeparator::Preserve,
+            file_macro_separator: PathSeparator::Preserve,
+            max_iterations: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            max_macro_args: 4096,
+            max_expansion_tokens: 1_000_000,
+            system_headers_quiet: true,
+            configured_macros: MacroTable::new(),
+            max_include_bytes: None,
+            max_total_bytes: None,
+            include_size_policy: IncludeSizePolicy::Error,
+            record_include_graph: false,
+            include_edges: Vec::new(),
+            lossy_utf8: false,
+            warn_unused_macro_params: false,
+            expr_identifier_resolver: None,
+            warn_undef: false,
+            file_identity: None,
+            initial_file: None,
+            passthrough_line_directives: false,
+            line_sync: None,
+            warn_bare_function_macro: false,
+            pack_stack: Vec::new(),
+            max_include_depth: 200,
         }
     }
 
     /// Apply configuration to the context
     pub fn apply_config(&mut self, config: &PreprocessorConfig) {
+        self.apply_settings(config);
+
+        // Builtins from a previously applied configuration don't carry over
+        self.clear_builtins();
+        let groups = config.builtin_macro_groups;
+        if groups.target {
+            self.define_target_macros(&config.target);
+            self.define_byte_order_macros(config.byte_order);
+        }
+        if groups.compiler {
+            self.define_compiler_macros(&config.compiler);
+        }
+        if groups.intrinsics {
+            self.stub_compiler_intrinsics();
+        }
+        if groups.sizeof {
+            self.define_sizeof_stubs(&config.target);
+            self.define_limit_macros(&config.target);
+        }
+        for (spec, replacement) in &config.builtin_rewrites {
+            // Validated by the builder; skip anything malformed set directly
+            if let Some(define) = engine::parse_define(spec) {
+                let body = replacement.as_deref().unwrap_or_default();
+                self.define_builtin(
+                    define.name.as_str(),
+                    define.params,
+                    body,
+                    define.is_variadic,
+                );
+            }
+        }
+
+        for (name, value) in &config.defines {
+            self.define(name.as_str(), None, value.as_str(), false);
+        }
+        for name in &config.undefines {
+            self.undef(name);
+        }
+        self.configured_macros = self.macros.clone();
+    }
+
+    /// Apply a compiled configuration to the context
+    ///
+    /// Gives the same result as [`Self::apply_config`] with the configuration
+    /// it was compiled from, copying the prepared macros instead of defining
+    /// them again.
+    pub fn apply_compiled(&mut self, compiled: &CompiledConfig) {
+        let config = &compiled.config;
+        self.apply_settings(config);
+
+        self.clear_builtins();
+        for name in &config.undefines {
+            self.undef(name);
+        }
+        for (name, mac) in compiled.macros.as_map() {
+            if mac.is_builtin
+                && let Some(existing) = self.macros.get(name)
+            {
+                if !existing.same_definition(mac)
+                    && let Some(handler) = &self.warning_handler
+                {
+                    handler(&format!(
+                        "builtin macro '{name}' not defined: keeping the user definition"
+                    ));
+                }
+                continue;
+            }
+            self.macros.insert(name.clone(), mac.clone());
+        }
+        self.macro_generation += 1;
+        self.configured_macros = self.macros.clone();
+    }
+
+    /// Copy the settings of `config`, leaving the macro table alone
+    fn apply_settings(&mut self, config: &PreprocessorConfig) {
         self.compiler = config.compiler.clone();
         self.recursion_limit = config.recursion_limit;
         self.include_resolver.clone_from(&config.include_resolver);
         self.warning_handler.clone_from(&config.warning_handler);
         self.line_ending = config.line_ending.clone();
+        self.strict = config.strict;
+        self.include_dirs.clone_from(&config.include_dirs);
+        self.concat_string_literals = config.concat_string_literals;
+        self.on_include_enter.clone_from(&config.on_include_enter);
+        self.prelude.clone_from(&config.prelude);
+        self.suppress_prelude_output = config.suppress_prelude_output;
+        self.expansion_trace_handler
+            .clone_from(&config.expansion_trace_handler);
+        self.defined_from_macro = config.defined_from_macro;
+        self.standard = config.standard;
+        self.collapse_blank_lines
+            .clone_from(&config.collapse_blank_lines);
+        self.include_path_separator = config.include_path_separator;
+        self.file_macro_separator = config.file_macro_separator;
+        self.max_iterations = config.max_iterations;
+        #[cfg(feature = "std")]
+        {
+            self.deadline = config.deadline;
+        }
+        self.max_macro_args = config.max_macro_args;
+        self.max_expansion_tokens = config.max_expansion_tokens;
+        self.system_headers_quiet = config.system_headers_quiet;
+        self.max_include_bytes = config.max_include_bytes;
+        self.max_total_bytes = config.max_total_bytes;
+        self.include_size_policy = config.include_size_policy;
+        self.record_include_graph = config.record_include_graph;
+        self.lossy_utf8 = config.lossy_utf8;
+        self.warn_unused_macro_params = config.warn_unused_macro_params;
+        self.expr_identifier_resolver
+            .clone_from(&config.expr_identifier_resolver);
+        self.warn_undef = config.warn_undef;
+        self.initial_file.clone_from(&config.initial_file);
+        if let Some(file) = &config.initial_file {
+            self.current_file.clone_from(file);
+        }
+        self.passthrough_line_directives = config.passthrough_line_directives;
+        self.warn_bare_function_macro = config.warn_bare_function_macro;
+        self.max_include_depth = config.max_include_depth;
+    }
 
-        self.define_target_macros(&config.target);
-        self.define_compiler_macros(&config.compiler);
-
-        self.stub_compiler_intrinsics();
-        self.define_sizeof_stubs();
+    /// Forget the state left by previous inputs
+    ///
+    /// The macro table goes back to what the last applied configuration
+    /// defined, and include, conditional and expansion state is cleared.
+    /// Settings such as the resolver, compiler and limits are kept.
+    pub fn reset(&mut self) {
+        self.clear_user_macros();
+        // Bring back configured defines and builtins the input undefined
+        for (name, mac) in self.configured_macros.as_map() {
+            if !self.macros.contains_key(name) {
+                self.macros.insert(name.clone(), mac.clone());
+            }
+        }
+        self.macro_generation += 1;
+        self.expression_cache.clear();
+        self.iterations = 0;
+        self.unit_bytes = 0;
+        self.counter = 0;
+        self.disabled_macros.clear();
+        self.expansion_stack.clear();
+        self.included_once.clear();
+        self.include_edges.clear();
+        self.poisoned.clear();
+        self.assertions.clear();
+        self.in_system_header = false;
+        self.include_stack.clear();
+        self.include_keys.clear();
+        self.conditional_stack.clear();
+        self.current_file = self
+            .initial_file
+            .clone()
+            .unwrap_or_else(|| "<stdin>".to_string());
+        self.file_identity = None;
+        self.current_line = 1;
+        self.line_sync = None;
+        self.pack_stack.clear();
     }
 
     fn define_target_macros(&mut self, target: &Target) {
@@ -126,9 +502,26 @@ impl PreprocessorContext {
                 self.define_builtin("TARGET_OS_MAC", None, "1", false);
                 self.define_builtin("__LP64__", None, "1", false);
             }
+            Target::FreeBSD => {
+                self.define_builtin("__FreeBSD__", None, "14", false);
+                self.define_builtin("__unix__", None, "1", false);
+                self.define_builtin("__LP64__", None, "1", false);
+            }
         }
     }
 
+    fn define_byte_order_macros(&mut self, byte_order: ByteOrder) {
+        self.define_builtin("__ORDER_LITTLE_ENDIAN__", None, "1234", false);
+        self.define_builtin("__ORDER_BIG_ENDIAN__", None, "4321", false);
+        self.define_builtin("__ORDER_PDP_ENDIAN__", None, "3412", false);
+        let order = match byte_order {
+            ByteOrder::Little => "__ORDER_LITTLE_ENDIAN__",
+            ByteOrder::Big => "__ORDER_BIG_ENDIAN__",
+        };
+        self.define_builtin("__BYTE_ORDER__", None, order, false);
+        self.define_builtin("__FLOAT_WORD_ORDER__", None, order, false);
+    }
+
     fn define_compiler_macros(&mut self, compiler: &Compiler) {
         match compiler {
             Compiler::GCC => {
@@ -156,22 +549,73 @@ impl PreprocessorContext {
     }
 
     fn stub_compiler_intrinsics(&mut self) {
-        // Stub __builtin_* macros to prevent errors
-        self.define_builtin("__builtin_expect", None, "", false);
-        self.define_builtin("__builtin_unreachable", None, "", false);
-        self.define_builtin("__builtin_va_start", None, "", false);
-        self.define_builtin("__builtin_va_arg", None, "", false);
-        self.define_builtin("__builtin_va_end", None, "", false);
+        // Function-like stubs that keep the output compilable by compilers
+        // without these builtins; `__builtin_bswap32` and others without a
+        // side-effect-free portable form are left for the compiler
+        let params = |names: &[&str]| Some(names.iter().map(ToString::to_string).collect());
+        self.define_builtin("__builtin_expect", params(&["exp", "c"]), "(exp)", false);
+        self.define_builtin("__builtin_constant_p", params(&["x"]), "0", false);
+        self.define_builtin(
+            "__builtin_offsetof",
+            params(&["t", "m"]),
+            "((size_t)&((t*)0)->m)",
+            false,
+        );
+        self.define_builtin("__builtin_unreachable", params(&[]), "", false);
+        self.define_builtin(
+            "__builtin_va_start",
+            params(&["ap", "last"]),
+            "((void)0)",
+            false,
+        );
+        self.define_builtin(
+            "__builtin_va_arg",
+            params(&["ap", "type"]),
+            "((type)0)",
+            false,
+        );
+        self.define_builtin("__builtin_va_end", params(&["ap"]), "((void)0)", false);
     }
 
-    fn define_sizeof_stubs(&mut self) {
-        // Define common sizeof values as stubs
+    fn define_sizeof_stubs(&mut self, target: &Target) {
+        // Type sizes for the target's data model: LP64 on Linux and macOS,
+        // LLP64 on 64-bit Windows
+        let (long, wchar_t, long_double) = match target {
+            Target::Linux | Target::MacOS | Target::FreeBSD => ("8", "4", "16"),
+            Target::Windows => ("4", "2", "8"),
+        };
+        self.define_builtin("__SIZEOF_SHORT__", None, "2", false);
         self.define_builtin("__SIZEOF_INT__", None, "4", false);
-        self.define_builtin("__SIZEOF_LONG__", None, "8", false);
+        self.define_builtin("__SIZEOF_LONG__", None, long, false);
         self.define_builtin("__SIZEOF_LONG_LONG__", None, "8", false);
+        self.define_builtin("__SIZEOF_FLOAT__", None, "4", false);
+        self.define_builtin("__SIZEOF_DOUBLE__", None, "8", false);
+        self.define_builtin("__SIZEOF_LONG_DOUBLE__", None, long_double, false);
         self.define_builtin("__SIZEOF_POINTER__", None, "8", false);
         self.define_builtin("__SIZEOF_SIZE_T__", None, "8", false);
         self.define_builtin("__SIZEOF_PTRDIFF_T__", None, "8", false);
+        self.define_builtin("__SIZEOF_WCHAR_T__", None, wchar_t, false);
+    }
+
+    fn define_limit_macros(&mut self, target: &Target) {
+        // Written without suffixes so `#if` can compare them; unsigned maxima
+        // such as `__SIZE_MAX__` don't fit the signed 64-bit `#if` arithmetic
+        // and are left out
+        let (long_max, wchar_max) = match target {
+            Target::Linux | Target::MacOS | Target::FreeBSD => {
+                ("9223372036854775807", "2147483647")
+            }
+            Target::Windows => ("2147483647", "65535"),
+        };
+        self.define_builtin("__CHAR_BIT__", None, "8", false);
+        self.define_builtin("__SCHAR_MAX__", None, "127", false);
+        self.define_builtin("__SHRT_MAX__", None, "32767", false);
+        self.define_builtin("__INT_MAX__", None, "2147483647", false);
+        self.define_builtin("__LONG_MAX__", None, long_max, false);
+        self.define_builtin("__LONG_LONG_MAX__", None, "9223372036854775807", false);
+        self.define_builtin("__WCHAR_MAX__", None, wchar_max, false);
+        self.define_builtin("__PTRDIFF_MAX__", None, "9223372036854775807", false);
+        self.define_builtin("__INTMAX_MAX__", None, "9223372036854775807", false);
     }
 
     /// Define a preprocessor macro
@@ -185,6 +629,10 @@ impl PreprocessorContext {
         self.define_macro(name, params, body, is_variadic, false);
     }
 
+    /// Define a builtin macro, unless the user already defined the name
+    ///
+    /// A conflicting user definition is kept and reported through the
+    /// warning handler.
     fn define_builtin<S: AsRef<str>>(
         &mut self,
         name: S,
@@ -192,9 +640,51 @@ impl PreprocessorContext {
         body: S,
         is_variadic: bool,
     ) {
+        if let Some(existing) = self.macros.get(name.as_ref())
+            && !existing.is_builtin
+        {
+            let builtin = Macro {
+                params,
+                body: Rc::new(engine::tokenize_line(body.as_ref())),
+                is_variadic,
+                definition_location: None,
+                is_builtin: true,
+            };
+            if !existing.same_definition(&builtin)
+                && let Some(handler) = &self.warning_handler
+            {
+                handler(&format!(
+                    "builtin macro '{}' not defined: keeping the user definition",
+                    name.as_ref()
+                ));
+            }
+            return;
+        }
         self.define_macro(name, params, body, is_variadic, true);
     }
 
+    /// Remove every builtin macro, leaving user definitions in place
+    pub fn clear_builtins(&mut self) {
+        self.remove_macros_where(|mac| mac.is_builtin);
+    }
+
+    /// Remove every macro that isn't a builtin, leaving builtins in place
+    pub fn clear_user_macros(&mut self) {
+        self.remove_macros_where(|mac| !mac.is_builtin);
+    }
+
+    fn remove_macros_where(&mut self, predicate: impl Fn(&Macro) -> bool) {
+        let names: Vec<String> = self
+            .macros
+            .keys()
+            .filter(|name| predicate(&self.macros[name.as_str()]))
+            .cloned()
+            .collect();
+        for name in names {
+            self.undef(&name);
+        }
+    }
+
     fn define_macro<S: AsRef<str>>(
         &mut self,
         name: S,
@@ -203,8 +693,16 @@ impl PreprocessorContext {
         is_variadic: bool,
         is_builtin: bool,
     ) {
-        let stripped_body = engine::strip_comments(body.as_ref());
+        // A body is one logical line: line breaks passed through the API
+        // become spaces, as they would after line splicing
+        let mut stripped_body = engine::strip_comments(body.as_ref());
+        if stripped_body.contains(['\r', '\n']) {
+            stripped_body = engine::split_lines(&stripped_body)
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
         let body_tokens = engine::tokenize_line(&stripped_body);
+        self.macro_generation += 1;
         self.macros.insert(
             name.as_ref().to_string(),
             Macro {
@@ -221,20 +719,53 @@ impl PreprocessorContext {
         );
     }
 
+    /// Define every macro of `set`, replacing definitions of the same names
+    ///
+    /// Builtins stay builtins, so applying a configuration later replaces them.
+    pub fn import_macros(&mut self, set: &MacroSet) {
+        for entry in &set.macros {
+            self.define_macro(
+                entry.name.as_str(),
+                entry.params.clone(),
+                entry.body.as_str(),
+                entry.variadic,
+                entry.builtin,
+            );
+        }
+    }
+
+    /// Replace the whole macro table with the macros of `set`
+    pub fn load_macros(&mut self, set: &MacroSet) {
+        self.remove_macros_where(|_| true);
+        self.import_macros(set);
+    }
+
     /// Remove a macro definition
     pub fn undef(&mut self, name: &str) {
-        self.macros.remove(name);
+        if self.macros.remove(name).is_some() {
+            self.macro_generation += 1;
+        }
     }
 
-    /// Check if a macro is defined
+    /// Check if a macro is defined, counting the dynamic predefined macros
+    /// such as `__LINE__`
     #[must_use]
     pub fn is_defined(&self, name: &str) -> bool {
-        self.macros.contains_key(name)
+        self.macros.contains_key(name) || engine::DYNAMIC_MACROS.contains(&name)
+    }
+
+    /// What sort of macro `name` is, or `None` if it isn't defined
+    #[must_use]
+    pub fn macro_kind(&self, name: &str) -> Option<MacroKind> {
+        if engine::DYNAMIC_MACROS.contains(&name) {
+            return Some(MacroKind::Dynamic);
+        }
+        self.macros.get(name).map(Macro::kind)
     }
 
     /// Get a reference to the defined macros
     #[must_use]
     pub fn get_macros(&self) -> &HashMap<String, Macro> {
-        &self.macros
+        self.macros.as_map()
     }
 }
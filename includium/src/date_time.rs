@@ -1,11 +1,30 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+
+#[cfg(feature = "std")]
 use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Format the current date as "Mmm dd yyyy" for __DATE__ macro
-pub fn format_date() -> String {
-    let now = SystemTime::now()
+/// Seconds since the Unix epoch
+#[cfg(feature = "std")]
+fn seconds_since_epoch() -> Option<u64> {
+    let since_epoch = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or_default();
-    let total_seconds = now.as_secs();
+    Some(since_epoch.as_secs())
+}
+
+/// Without `std` there is no clock, so `__DATE__` and `__TIME__` expand to
+/// the placeholders GCC uses when the date is unknown
+#[cfg(not(feature = "std"))]
+const fn seconds_since_epoch() -> Option<u64> {
+    None
+}
+
+/// Format the current date as "Mmm dd yyyy" for __DATE__ macro
+pub fn format_date() -> String {
+    let Some(total_seconds) = seconds_since_epoch() else {
+        return "??? ?? ????".to_string();
+    };
     let days_since_epoch = total_seconds / 86400;
     let mut year = 1970;
     let mut days_remaining = days_since_epoch;
@@ -53,13 +72,12 @@ pub fn format_date() -> String {
 
 /// Format the current time as "hh:mm:ss" for __TIME__ macro
 pub fn format_time() -> String {
-    use std::time::SystemTime;
-
     // For now, use a simple approach that gets local time
     // This matches gcc/clang behavior better than UTC
-    let now = SystemTime::now();
-    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
-    let total_seconds = since_epoch.as_secs() as i64;
+    let Some(total_seconds) = seconds_since_epoch() else {
+        return "??:??:??".to_string();
+    };
+    let total_seconds = total_seconds as i64;
 
     // TODO: Adjust for local timezone (simplified - assumes 2 hour offset for CET)
     // In a real implementation, this should use proper timezone detection
@@ -1,20 +1,62 @@
-use crate::config::{IncludeContext, IncludeKind, PreprocessorConfig};
-use crate::context::{ConditionalState, PreprocessorContext};
-use crate::engine;
+use crate::config::{
+    CompiledConfig, Compiler, DefinedFromMacro, ExpansionEvent, IncludeContext, IncludeKind,
+    IncludeResolution, IncludeSizePolicy, PathSeparator, PreludeItem, PreprocessorConfig,
+};
+use crate::context::{ConditionalState, PreprocessStats, PreprocessorContext};
+use crate::engine::{self, DefineDirective, PragmaSegment};
 use crate::error::PreprocessError;
-use crate::macro_def::Macro;
-use crate::token::{ExprToken, Token};
-use std::collections::{HashMap, HashSet};
-use std::path::Path;
-use std::rc::Rc;
+use crate::event::{PpEvent, SourceLocation};
+use crate::expr::{ExprLookup, ExprToken};
+use crate::macro_def::{Macro, MacroKind, MacroSet, MacroTable};
+use crate::output::{Dependency, Diagnostic, ProcessOutput, Recording};
+use crate::stream::TokenStream;
+use crate::token::Token;
+use crate::{HashMap, HashSet};
+use alloc::borrow::{Cow, ToOwned};
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
+use std::io::Write;
+#[cfg(feature = "std")]
+use std::path::{Component, Path, PathBuf};
+#[cfg(feature = "std")]
+use std::time::Instant;
 
 type MacroArguments = Vec<Vec<Token>>;
 
+/// Receiver for output lines as they are produced
+type LineSink<'s> = dyn FnMut(OutputLine<'_>) + 's;
+
+/// A single line of preprocessed output, passed to [`PreprocessorDriver::process_with`]
+///
+/// The borrowed fields are only valid for the duration of the callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputLine<'a> {
+    /// Text of the line, without a line terminator
+    pub text: &'a str,
+    /// File the line came from
+    pub file: &'a str,
+    /// Line number in `file` (1-based)
+    pub line: usize,
+    /// Whether the line came from an included file rather than the main input
+    pub from_include: bool,
+    /// Tokens the text was built from, if it came from macro expansion
+    pub(crate) tokens: Option<&'a [Token]>,
+}
+
 /// Parameters for macro expansion
 struct MacroExpansionParams<'a> {
     tokens: &'a [Token],
     i: usize,
     depth: usize,
+    /// Whether `tokens` is a replacement list being rescanned, which the
+    /// source after the macro call may continue
+    replacement: bool,
     out: &'a mut Vec<Token>,
     ctx: &'a DiagnosticContext,
 }
@@ -39,6 +81,74 @@ impl DiagnosticContext {
             source_line,
         }
     }
+
+    /// File and line, without the source text
+    fn location(&self) -> SourceLocation {
+        SourceLocation {
+            file: self.file.clone(),
+            line: self.line,
+        }
+    }
+
+    /// Tokens of the source line that aren't whitespace, with their columns
+    fn columns(&self) -> Vec<(usize, Token)> {
+        self.source_line
+            .as_deref()
+            .map(engine::tokenize_line_with_columns)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, token)| !engine::token_to_string(token).trim().is_empty())
+            .collect()
+    }
+
+    /// Column just past the end of the source line
+    fn end_column(&self) -> usize {
+        self.source_line
+            .as_ref()
+            .map_or(1, |line| line.trim_end().chars().count() + 1)
+    }
+
+    /// Column of the first non-blank character, the `#` of a directive
+    fn start_column(&self) -> usize {
+        self.columns().first().map_or(1, |(column, _)| *column)
+    }
+
+    /// Column of a directive's first operand, the token after its name, or
+    /// just past the end of the line if it has none
+    fn operand_column(&self) -> usize {
+        self.columns()
+            .get(2)
+            .map_or_else(|| self.end_column(), |(column, _)| *column)
+    }
+
+    /// Column of the first whole token spelled `text`, if the line has one
+    fn token_column(&self, text: &str) -> Option<usize> {
+        self.columns()
+            .into_iter()
+            .find(|(_, token)| engine::token_to_string(token) == text)
+            .map(|(column, _)| column)
+    }
+
+    /// Map a 1-based character offset in a directive's expression, after
+    /// macro expansion, back to a column of the source line
+    ///
+    /// If expansion changed the text, the column of the first matching
+    /// character after the start of the expression is used, or the start of
+    /// the expression when there is none.
+    fn expression_column(&self, expr: &str, offset: usize) -> usize {
+        let start = self.operand_column();
+        let Some(line) = &self.source_line else {
+            return start;
+        };
+        let source: String = line.chars().skip(start - 1).collect();
+        if source.starts_with(expr) {
+            return start + offset.saturating_sub(1);
+        }
+        expr.chars()
+            .nth(offset.saturating_sub(1))
+            .and_then(|ch| source.chars().position(|c| c == ch))
+            .map_or(start, |position| start + position)
+    }
 }
 
 /// Public API driver for C preprocessing
@@ -55,6 +165,55 @@ impl Default for PreprocessorDriver {
     }
 }
 
+/// A driver whose macro-table changes are undone when it is dropped
+///
+/// Returned by [`PreprocessorDriver::scoped`]. It dereferences to the
+/// underlying driver, so every driver method is available. Defines, undefs,
+/// `#pragma once`, `#pragma GCC poison` and `#assert` effects made through it
+/// are rolled back on drop; scopes can be nested and unwind innermost first.
+pub struct ScopedDriver<'a> {
+    driver: &'a mut PreprocessorDriver,
+    included_once: HashSet<String>,
+    poisoned: HashSet<String>,
+    assertions: HashMap<String, HashSet<String>>,
+}
+
+impl Deref for ScopedDriver<'_> {
+    type Target = PreprocessorDriver;
+
+    fn deref(&self) -> &PreprocessorDriver {
+        self.driver
+    }
+}
+
+impl DerefMut for ScopedDriver<'_> {
+    fn deref_mut(&mut self) -> &mut PreprocessorDriver {
+        self.driver
+    }
+}
+
+impl Drop for ScopedDriver<'_> {
+    fn drop(&mut self) {
+        let context = &mut self.driver.context;
+        context.macros.pop_scope();
+        context.macro_generation += 1;
+        context.included_once = core::mem::take(&mut self.included_once);
+        context.poisoned = core::mem::take(&mut self.poisoned);
+        context.assertions = core::mem::take(&mut self.assertions);
+    }
+}
+
+/// File name reported for prelude text and prelude include errors
+const PRELUDE_FILE: &str = "<prelude>";
+
+/// Whether the input's last line has a terminator, in any line ending style
+fn ends_with_newline(input: &str) -> bool {
+    input.ends_with('\n') || input.ends_with('\r')
+}
+
+/// How many iterations pass between checks of the deadline
+const DEADLINE_CHECK_INTERVAL: u64 = 64;
+
 impl PreprocessorDriver {
     /// Create a new preprocessor instance with default configuration
     #[must_use]
@@ -72,18 +231,67 @@ impl PreprocessorDriver {
         driver
     }
 
+    /// Create a preprocessor from a compiled configuration
+    ///
+    /// Behaves like [`Self::with_config`] with the configuration that was
+    /// compiled, but copies the prepared macros instead of defining them.
+    #[must_use]
+    pub fn with_compiled(compiled: &CompiledConfig) -> Self {
+        let mut driver = Self::new();
+        driver.apply_compiled(compiled);
+        driver
+    }
+
     /// Apply configuration to the preprocessor
+    ///
+    /// Builtin macros from a previously applied configuration are replaced;
+    /// macros the user defined are kept, and a builtin never overwrites them.
     pub fn apply_config(&mut self, config: &PreprocessorConfig) {
         self.context.apply_config(config);
     }
 
+    /// Apply a compiled configuration to the preprocessor
+    ///
+    /// Same as [`Self::apply_config`] with the configuration that was compiled.
+    pub fn apply_compiled(&mut self, compiled: &CompiledConfig) {
+        self.context.apply_compiled(compiled);
+    }
+
+    /// Remove every builtin target, compiler, intrinsic and sizeof macro,
+    /// leaving user definitions in place
+    pub fn clear_builtins(&mut self) {
+        self.context.clear_builtins();
+    }
+
+    /// Clear the state left by previous inputs so the driver can be reused
+    ///
+    /// Macros defined by the source or through [`Self::define`] are dropped
+    /// and the builtins and defines of the last applied configuration are
+    /// restored. `#pragma once` records, open conditionals and the current
+    /// file and line are reset; the resolver, compiler and limits are kept.
+    pub fn reset(&mut self) {
+        self.context.reset();
+    }
+
+    /// Remove every macro defined by the source or through [`Self::define`],
+    /// leaving the builtin target, compiler, intrinsic and sizeof macros
+    pub fn clear_user_macros(&mut self) {
+        self.context.clear_user_macros();
+    }
+
     /// Add a custom include resolver function
+    ///
+    /// The function may return an [`IncludeResolution`], or an `Option<String>`
+    /// where `None` means the header was not found.
     #[must_use]
-    pub fn with_include_resolver<F>(mut self, f: F) -> Self
+    pub fn with_include_resolver<F, R>(mut self, f: F) -> Self
     where
-        F: Fn(&str, IncludeKind, &IncludeContext) -> Option<String> + 'static,
+        F: Fn(&str, IncludeKind, &IncludeContext) -> R + 'static,
+        R: Into<IncludeResolution>,
     {
-        self.context.include_resolver = Some(Rc::new(f));
+        self.context.include_resolver = Some(Rc::new(move |path, kind, context| {
+            f(path, kind, context).into()
+        }));
         self
     }
 
@@ -92,12 +300,19 @@ impl PreprocessorDriver {
         self.context.recursion_limit = limit;
     }
 
+    /// Set whether byte input with invalid UTF-8 is decoded lossily
+    pub fn set_lossy_utf8(&mut self, lossy: bool) {
+        self.context.lossy_utf8 = lossy;
+    }
+
     /// Set the current file name for error reporting
     pub fn set_current_file(&mut self, file: String) {
         self.context.current_file = file;
     }
 
     /// Define a preprocessor macro
+    ///
+    /// Line breaks in `body` become spaces, so the macro expands on one line.
     pub fn define<S: AsRef<str>>(
         &mut self,
         name: S,
@@ -119,18 +334,105 @@ impl PreprocessorDriver {
         self.context.get_macros()
     }
 
-    /// Check if a macro is defined
+    /// Snapshot every defined macro, builtins included and flagged as such
+    #[must_use]
+    pub fn export_macros(&self) -> MacroSet {
+        MacroSet::from_table(&self.context.macros)
+    }
+
+    /// Define every macro of a set made by [`Self::export_macros`], replacing
+    /// current definitions of the same names
+    pub fn import_macros(&mut self, set: &MacroSet) {
+        self.context.import_macros(set);
+    }
+
+    /// Replace the whole macro table with a snapshot made by
+    /// [`Self::export_macros`], restoring the macro state it was taken from
+    ///
+    /// Unlike [`Self::import_macros`], macros missing from the snapshot are
+    /// removed, builtins included.
+    pub fn load_macros(&mut self, set: &MacroSet) {
+        self.context.load_macros(set);
+    }
+
+    /// Check if a macro is defined, as `#ifdef` does
+    ///
+    /// The dynamic predefined macros such as `__LINE__` count as defined.
     #[must_use]
     pub fn is_defined(&self, name: &str) -> bool {
         self.context.is_defined(name)
     }
 
+    /// What sort of macro `name` is, or `None` if it isn't defined
+    ///
+    /// The dynamic predefined macros `__LINE__`, `__FILE__`, `__DATE__`,
+    /// `__TIME__` and `__COUNTER__` are always defined.
+    #[must_use]
+    pub fn macro_kind(&self, name: &str) -> Option<MacroKind> {
+        self.context.macro_kind(name)
+    }
+
+    /// Open a scope whose macro-table changes are undone when it is dropped
+    ///
+    /// Useful for asking "what would this file produce if `FOO` were defined?"
+    /// without disturbing a long-lived driver. Only the entries changed inside
+    /// the scope are saved, not the whole table.
+    pub fn scoped(&mut self) -> ScopedDriver<'_> {
+        self.context.macros.push_scope();
+        ScopedDriver {
+            included_once: self.context.included_once.clone(),
+            poisoned: self.context.poisoned.clone(),
+            assertions: self.context.assertions.clone(),
+            driver: self,
+        }
+    }
+
+    /// Counters for the work done so far, accumulated across `process` calls
+    #[must_use]
+    pub fn stats(&self) -> PreprocessStats {
+        self.context.stats
+    }
+
+    /// Render the includes of the last `process` call as a Graphviz DOT graph
+    ///
+    /// Each edge points from the including file to the included one. Edges
+    /// are only recorded when [`PreprocessorConfig::record_include_graph`] is
+    /// set, so without it the graph is empty.
+    #[must_use]
+    pub fn include_graph_dot(&self) -> String {
+        let quote = |name: &str| format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut dot = String::from("digraph includes {\n");
+        for (from, to) in &self.context.include_edges {
+            dot.push_str(&format!("    {} -> {};\n", quote(from), quote(to)));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render every defined macro as a `#define` line, sorted by name
+    ///
+    /// Builtins and user definitions are both included, so the result reflects
+    /// the effective macro table after processing.
+    #[must_use]
+    pub fn dump_macros(&self) -> String {
+        let mut names: Vec<&String> = self.context.macros.keys().collect();
+        names.sort();
+
+        let mut out = String::new();
+        for name in names {
+            out.push_str(&self.context.macros[name].to_define_string(name));
+            out.push('\n');
+        }
+        out
+    }
+
     /// Create a directive error with location information
-    fn directive_error(&self, directive: &str, ctx: &DiagnosticContext) -> PreprocessError {
-        let column = ctx
-            .source_line
-            .as_ref()
-            .map_or(1, |line| Self::calculate_column(line, directive));
+    fn directive_error(
+        &self,
+        directive: &str,
+        column: usize,
+        ctx: &DiagnosticContext,
+    ) -> PreprocessError {
         let mut error =
             PreprocessError::malformed_directive(ctx.file.clone(), ctx.line, directive.to_string())
                 .with_column(column);
@@ -140,12 +442,38 @@ impl PreprocessorDriver {
         error
     }
 
+    /// Create a size limit error with location information
+    fn limit_error(&self, details: &str, ctx: &DiagnosticContext) -> PreprocessError {
+        let mut error =
+            PreprocessError::limit_exceeded(ctx.file.clone(), ctx.line, details.to_string());
+        if let Some(ref source) = ctx.source_line {
+            error = error.with_source_line(source.clone());
+        }
+        error
+    }
+
+    /// Report input over a size limit as the include size policy asks
+    fn report_oversized_input(
+        &self,
+        details: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<(), PreprocessError> {
+        match self.context.include_size_policy {
+            IncludeSizePolicy::Error => Err(self.limit_error(details, ctx)),
+            IncludeSizePolicy::Warn => {
+                self.emit_warning(details, ctx);
+                Ok(())
+            }
+        }
+    }
+
     /// Create a conditional error with location information
-    fn conditional_error(&self, details: &str, ctx: &DiagnosticContext) -> PreprocessError {
-        let column = ctx
-            .source_line
-            .as_ref()
-            .map_or(1, |line| Self::calculate_column(line, details));
+    fn conditional_error(
+        &self,
+        details: &str,
+        column: usize,
+        ctx: &DiagnosticContext,
+    ) -> PreprocessError {
         let mut error =
             PreprocessError::conditional_error(ctx.file.clone(), ctx.line, details.to_owned())
                 .with_column(column);
@@ -156,11 +484,12 @@ impl PreprocessorDriver {
     }
 
     /// Create a generic error with location information
-    fn generic_error(&self, message: &str, ctx: &DiagnosticContext) -> PreprocessError {
-        let column = ctx
-            .source_line
-            .as_ref()
-            .map_or(1, |line| Self::calculate_column(line, message));
+    fn generic_error(
+        &self,
+        message: &str,
+        column: usize,
+        ctx: &DiagnosticContext,
+    ) -> PreprocessError {
         let mut error = PreprocessError::other(ctx.file.clone(), ctx.line, message.to_string())
             .with_column(column);
         if let Some(ref source) = ctx.source_line {
@@ -170,11 +499,7 @@ impl PreprocessorDriver {
     }
 
     /// Create an include error with location information
-    fn include_error(&self, path: &str, ctx: &DiagnosticContext) -> PreprocessError {
-        let column = ctx
-            .source_line
-            .as_ref()
-            .map_or(1, |line| Self::calculate_column(line, path));
+    fn include_error(&self, path: &str, column: usize, ctx: &DiagnosticContext) -> PreprocessError {
         let mut error =
             PreprocessError::include_not_found(ctx.file.clone(), ctx.line, path.to_string())
                 .with_column(column);
@@ -184,74 +509,864 @@ impl PreprocessorDriver {
         error
     }
 
-    /// Calculate the character-based column position of a substring in a line
+    /// Report a warning through the configured handler, prefixed with its location
+    ///
+    /// Nothing is reported inside a system header.
+    fn emit_warning(&self, message: &str, ctx: &DiagnosticContext) {
+        if self.context.in_system_header {
+            return;
+        }
+        self.record_warning(message, ctx);
+        if let Some(ref handler) = self.context.warning_handler {
+            handler(&format!("{}:{}: {message}", ctx.file, ctx.line));
+        }
+    }
+
+    /// Whether an [`Self::events`] call is collecting events
+    fn records_events(&self) -> bool {
+        self.context
+            .recording
+            .as_ref()
+            .is_some_and(|recording| recording.borrow().events.is_some())
+    }
+
+    /// Add an event to the stream of an [`Self::events`] call, if one is running
+    fn record_event(&self, event: PpEvent) {
+        if let Some(recording) = &self.context.recording
+            && let Some(events) = &mut recording.borrow_mut().events
+        {
+            events.push(event);
+        }
+    }
+
+    /// Keep a warning for the [`ProcessOutput`] of a `process_full` call
+    fn record_warning(&self, message: &str, ctx: &DiagnosticContext) {
+        if let Some(recording) = &self.context.recording {
+            recording.borrow_mut().warnings.push(Diagnostic {
+                message: message.to_string(),
+                location: SourceLocation {
+                    file: ctx.file.clone(),
+                    line: ctx.line,
+                },
+            });
+        }
+    }
+
+    /// Report a conformance problem: a hard error in strict mode, otherwise a warning
     ///
-    /// Returns the 1-based character index where the substring starts.
-    /// This is best-effort positioning and may not be exact for repeated substrings.
-    fn calculate_column(line: &str, substr: &str) -> usize {
-        if substr.is_empty() {
-            return 1;
+    /// Nothing is reported inside a system header.
+    fn conformance_diagnostic(
+        &self,
+        message: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<(), PreprocessError> {
+        if self.context.in_system_header {
+            return Ok(());
         }
-        if let Some(byte_pos) = line.find(substr) {
-            // Convert byte position to character position
-            return line[..byte_pos].chars().count() + 1;
+        if self.context.strict {
+            return Err(self.generic_error(message, ctx.start_column(), ctx));
         }
-        // If not found, position at end of line
-        line.chars().count() + 1
+        self.emit_warning(message, ctx);
+        Ok(())
+    }
+
+    /// Remove NUL bytes from the input, reporting each line that has them
+    ///
+    /// In strict mode a NUL byte is an error pointing at it instead.
+    fn drop_null_characters<'a>(&self, input: &'a str) -> Result<Cow<'a, str>, PreprocessError> {
+        if !input.contains('\0') {
+            return Ok(Cow::Borrowed(input));
+        }
+        for (index, line) in input.split('\n').enumerate() {
+            let Some(offset) = line.find('\0') else {
+                continue;
+            };
+            let ctx = DiagnosticContext::new(
+                self.context.current_file.clone(),
+                index + 1,
+                Some(line.replace('\0', " ")),
+            );
+            if self.context.strict && !self.context.in_system_header {
+                let column = line[..offset].chars().count() + 1;
+                return Err(self.generic_error("null character in input", column, &ctx));
+            }
+            self.emit_warning("null character(s) ignored", &ctx);
+        }
+        Ok(Cow::Owned(input.replace('\0', "")))
     }
 
     /// Process the input C code and return the preprocessed result
     ///
+    /// The output ends with a line terminator exactly when the input does.
+    ///
     /// # Errors
     /// Returns `PreprocessError` if there's a malformed directive,
     /// macro recursion limit is exceeded, or conditional blocks are unterminated.
     pub fn process(&mut self, input: &str) -> Result<String, PreprocessError> {
+        let mut result = String::new();
+        self.process_with(input, |line| {
+            result.push_str(line.text);
+            result.push('\n');
+        })?;
+        if !ends_with_newline(input) {
+            result.pop();
+        }
+        Ok(engine::denormalize_output(
+            &result,
+            &self.context.line_ending,
+        ))
+    }
+
+    /// Process C code given as bytes, such as the contents of a file in a
+    /// legacy encoding
+    ///
+    /// Input that isn't valid UTF-8 is an error pointing at the first bad
+    /// byte, unless [`PreprocessorConfig::lossy_utf8`] is set; then invalid
+    /// sequences become U+FFFD with a warning and the rest of the input is
+    /// processed normally.
+    ///
+    /// # Errors
+    /// Returns `PreprocessError` for invalid UTF-8 unless `lossy_utf8` is set,
+    /// and under the same conditions as [`Self::process`].
+    pub fn process_bytes(&mut self, input: &[u8]) -> Result<String, PreprocessError> {
+        self.process_bytes_with(input, self.context.lossy_utf8)
+    }
+
+    /// [`Self::process_bytes`] with `lossy` in place of the configured
+    /// `lossy_utf8`, for callers that choose per input
+    pub(crate) fn process_bytes_with(
+        &mut self,
+        input: &[u8],
+        lossy: bool,
+    ) -> Result<String, PreprocessError> {
+        let text = self.decode_input(input, &self.context.current_file, lossy)?;
+        self.process(&text)
+    }
+
+    /// Turn the bytes of `file` into text, replacing invalid UTF-8 if `lossy`
+    /// and rejecting it otherwise
+    fn decode_input<'a>(
+        &self,
+        input: &'a [u8],
+        file: &str,
+        lossy: bool,
+    ) -> Result<Cow<'a, str>, PreprocessError> {
+        let error = match core::str::from_utf8(input) {
+            Ok(text) => return Ok(Cow::Borrowed(text)),
+            Err(error) => error,
+        };
+        // Locate the first bad byte; everything before it is valid
+        let valid = String::from_utf8_lossy(&input[..error.valid_up_to()]);
+        let line_start = valid.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[line_start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(input.len(), |i| line_start + i);
+        let ctx = DiagnosticContext::new(
+            file.to_string(),
+            valid.matches('\n').count() + 1,
+            Some(String::from_utf8_lossy(&input[line_start..line_end]).into_owned()),
+        );
+        if lossy {
+            self.emit_warning("invalid UTF-8 replaced with U+FFFD", &ctx);
+            Ok(Cow::Owned(String::from_utf8_lossy(input).into_owned()))
+        } else {
+            let column = valid[line_start..].chars().count() + 1;
+            Err(self.generic_error("invalid UTF-8 in input", column, &ctx))
+        }
+    }
+
+    /// Process the input C code, returning the output together with the
+    /// warnings reported, the headers read and the work counters
+    ///
+    /// Warnings still reach the configured warning handler as well.
+    ///
+    /// # Errors
+    /// Returns `PreprocessError` under the same conditions as [`Self::process`].
+    pub fn process_full(&mut self, input: &str) -> Result<ProcessOutput, PreprocessError> {
+        let recording = Rc::new(RefCell::new(Recording::default()));
+        self.context.recording = Some(Rc::clone(&recording));
+        let result = self.process(input);
+        self.context.recording = None;
+        let text = result?;
+
+        let Recording {
+            warnings,
+            dependencies,
+            ..
+        } = recording.take();
+        Ok(ProcessOutput {
+            text,
+            warnings,
+            dependencies,
+            stats: self.stats(),
+        })
+    }
+
+    /// Process the input C code, returning what happened as a sequence of events
+    ///
+    /// Directives that take effect, and every conditional directive, give the
+    /// same event as in [`Self::parse_events`]. Entering and leaving an
+    /// include, taking a conditional group and each output line give an
+    /// event of their own. The input is processed before this returns; if
+    /// processing fails, the error follows the events before it.
+    pub fn events(
+        &mut self,
+        input: &str,
+    ) -> impl Iterator<Item = Result<PpEvent, PreprocessError>> + use<> {
+        let recording = Rc::new(RefCell::new(Recording {
+            events: Some(Vec::new()),
+            ..Recording::default()
+        }));
+        self.context.recording = Some(Rc::clone(&recording));
+        let result = self.process_with(input, |line| {
+            if let Some(events) = &mut recording.borrow_mut().events {
+                events.push(PpEvent::LineEmitted {
+                    text: line.text.to_string(),
+                    location: SourceLocation {
+                        file: line.file.to_string(),
+                        line: line.line,
+                    },
+                });
+            }
+        });
+        self.context.recording = None;
+
+        let events = recording.take().events.unwrap_or_default();
+        events.into_iter().map(Ok).chain(result.err().map(Err))
+    }
+
+    /// Process the input C code and return the output as typed tokens
+    ///
+    /// Displaying the returned stream gives the same text as [`Self::process`],
+    /// and tokens formed by `##` pasting stay single tokens instead of being
+    /// lexed again from the text.
+    ///
+    /// # Errors
+    /// Returns `PreprocessError` under the same conditions as [`Self::process`].
+    pub fn process_tokens(&mut self, input: &str) -> Result<TokenStream, PreprocessError> {
+        let ending = self.context.line_ending.as_str();
+        let mut stream = TokenStream::default();
+        self.process_with(input, |line| {
+            stream.push_line(line.text, line.tokens, ending)
+        })?;
+        if !ends_with_newline(input) {
+            stream.pop_newline();
+        }
+        Ok(stream)
+    }
+
+    /// Process the input C code and return the output lines without terminators
+    ///
+    /// Joining the lines with the configured line ending, plus a final one if
+    /// the input ends with a newline, gives the output of [`Self::process`]
+    /// without building it as one string.
+    ///
+    /// # Errors
+    /// Returns `PreprocessError` under the same conditions as [`Self::process`].
+    pub fn process_lines(&mut self, input: &str) -> Result<Vec<String>, PreprocessError> {
+        let mut lines = Vec::new();
+        self.process_with(input, |line| lines.push(line.text.to_string()))?;
+        Ok(lines)
+    }
+
+    /// Process the input C code, passing each output line to `f` as it is produced
+    ///
+    /// Lines from included files are passed in order, interleaved with the
+    /// lines of the including file, so consumers can start work before the
+    /// whole input has been processed. Line text carries no terminator, and
+    /// runs of blank lines are already collapsed if that is configured.
+    ///
+    /// # Errors
+    /// Returns `PreprocessError` under the same conditions as [`Self::process`];
+    /// lines produced before the error have already been passed to `f`.
+    pub fn process_with<F>(&mut self, input: &str, mut f: F) -> Result<(), PreprocessError>
+    where
+        F: FnMut(OutputLine<'_>),
+    {
+        let limit = self.context.collapse_blank_lines;
+        let mut blank_run = 0;
+        let mut sink = |line: OutputLine<'_>| {
+            if let Some(max) = limit {
+                if line.text.trim().is_empty() {
+                    blank_run += 1;
+                    if blank_run > max {
+                        return;
+                    }
+                } else {
+                    blank_run = 0;
+                }
+            }
+            f(line);
+        };
+        // Each top-level call is a separate translation unit
+        self.context.iterations = 0;
+        self.context.unit_bytes = input.len();
+        self.context.stats.bytes_read += input.len();
+        self.context.counter = 0;
+        self.context.included_once.clear();
+        self.context.include_edges.clear();
+        self.context.line_sync = None;
+        self.context.pack_stack.clear();
+        self.process_prelude(&mut sink)?;
+        self.process_source(input, &mut sink)?;
+        self.warn_about_open_packs();
+        Ok(())
+    }
+
+    /// Count one unit of work against the configured budget
+    ///
+    /// Called once per line and once per macro expansion. The clock is only
+    /// read every [`DEADLINE_CHECK_INTERVAL`] iterations to keep this cheap.
+    fn charge_budget(&mut self) -> Result<(), PreprocessError> {
+        self.context.iterations += 1;
+        let iterations = self.context.iterations;
+        let details = if let Some(max) = self.context.max_iterations
+            && iterations > max
+        {
+            format!("more than {max} lines and macro expansions")
+        } else if iterations.is_multiple_of(DEADLINE_CHECK_INTERVAL) && self.deadline_passed() {
+            "deadline passed".to_string()
+        } else {
+            return Ok(());
+        };
+        Err(PreprocessError::budget_exceeded(
+            self.context.current_file.clone(),
+            self.context.current_line,
+            details,
+        ))
+    }
+
+    /// Whether the configured deadline has passed
+    #[cfg(feature = "std")]
+    fn deadline_passed(&self) -> bool {
+        self.context
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Without `std` there is no clock, so there is no deadline either
+    #[cfg(not(feature = "std"))]
+    const fn deadline_passed(&self) -> bool {
+        false
+    }
+
+    /// Process the input C code, writing the output to `writer` as it is produced
+    ///
+    /// Lines are terminated with the configured line ending; as with
+    /// [`Self::process`], the last one only if the input's last line is.
+    ///
+    /// # Errors
+    /// Returns `PreprocessError` under the same conditions as [`Self::process`],
+    /// or an I/O error if writing fails.
+    #[cfg(feature = "std")]
+    pub fn process_to_writer<W: Write>(
+        &mut self,
+        input: &str,
+        mut writer: W,
+    ) -> Result<(), PreprocessError> {
+        let ending = self.context.line_ending.as_str();
+        let mut write_error = None;
+        // Each terminator is written before the next line, so the last one can
+        // be left out when the input has none
+        let mut first = true;
+        self.process_with(input, |line| {
+            if write_error.is_some() {
+                return;
+            }
+            let separator = if first { "" } else { ending };
+            first = false;
+            let written = writer
+                .write_all(separator.as_bytes())
+                .and_then(|()| writer.write_all(line.text.as_bytes()));
+            if let Err(e) = written {
+                write_error = Some(PreprocessError::io_error(
+                    line.file.to_string(),
+                    line.line,
+                    e,
+                ));
+            }
+        })?;
+        if write_error.is_none() && !first && ends_with_newline(input) {
+            write_error = writer
+                .write_all(ending.as_bytes())
+                .err()
+                .map(|e| PreprocessError::io_error(self.context.current_file.clone(), 0, e));
+        }
+        match write_error {
+            Some(e) => Err(e),
+            None => writer
+                .flush()
+                .map_err(|e| PreprocessError::io_error(self.context.current_file.clone(), 0, e)),
+        }
+    }
+
+    /// Fully macro-expand a snippet of text using the current definitions
+    ///
+    /// Directives are not processed, so `text` is treated as a single source
+    /// line even if it contains `#`. This is useful for tooling such as an
+    /// "expand macro under cursor" action after `process` has built up the
+    /// macro table.
+    ///
+    /// # Errors
+    /// Returns `PreprocessError` if expansion fails, e.g. an unterminated
+    /// macro call or the recursion limit being exceeded.
+    pub fn expand_snippet(&mut self, text: &str) -> Result<String, PreprocessError> {
+        let spliced = engine::line_splice(&engine::normalize_input(text));
+        let stripped = engine::strip_comments(&spliced);
+        let ctx = DiagnosticContext::new(
+            self.context.current_file.clone(),
+            self.context.current_line,
+            Some(text.to_string()),
+        );
+        let tokens = engine::tokenize_line(&stripped);
+        let expanded = self.expand_tokens(&tokens, 0, &ctx)?;
+        Ok(engine::tokens_to_string(&expanded))
+    }
+
+    /// Parse the directive structure of `input` into a list of events
+    ///
+    /// Unlike [`Self::process`] this doesn't evaluate conditionals, resolve
+    /// includes or expand macros, and it leaves the macro table untouched;
+    /// every line of the input produces an event except null directives.
+    /// Locations use the current file name.
+    ///
+    /// # Errors
+    /// Returns `PreprocessError` for a malformed `#define`, `#undef`,
+    /// `#include`, `#if`, `#elif` or `#line` directive.
+    pub fn parse_events(&self, input: &str) -> Result<Vec<PpEvent>, PreprocessError> {
         let normalized = engine::normalize_input(input);
-        let spliced = engine::line_splice(&normalized);
-        let pragma_processed = engine::process_pragma(&spliced);
-        let mut out_lines: Vec<String> = Vec::new();
+        let mut events = Vec::new();
+        let mut line_number = 1;
+
+        for logical in engine::splice_logical_lines(&normalized) {
+            let line = logical.text.as_str();
+            let stripped = engine::strip_comments(line);
+            let ctx = DiagnosticContext::new(
+                self.context.current_file.clone(),
+                line_number,
+                Some(line.to_string()),
+            );
+            line_number += logical.physical_lines;
+            let location = SourceLocation {
+                file: ctx.file.clone(),
+                line: ctx.line,
+            };
+
+            let Some(directive) = engine::extract_directive(&stripped) else {
+                let tokens = engine::tokenize_line(&stripped);
+                events.push(PpEvent::TextLine {
+                    tokens: tokens
+                        .iter()
+                        .map(|token| engine::token_to_string(token).to_string())
+                        .collect(),
+                    location,
+                });
+                continue;
+            };
+
+            if let Some(event) = self.directive_event(directive, &ctx)? {
+                events.push(event);
+            }
+        }
+        Ok(events)
+    }
+
+    /// The event describing a directive, as reported by [`Self::parse_events`],
+    /// or `None` for the null directive
+    fn directive_event(
+        &self,
+        directive: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<Option<PpEvent>, PreprocessError> {
+        let (cmd, rest) = engine::split_directive(directive);
+        let rest_string = rest.to_string();
+        let required = |name: &str| {
+            if rest.is_empty() {
+                Err(self.directive_error(name, ctx.operand_column(), ctx))
+            } else {
+                Ok(rest.to_string())
+            }
+        };
+        let location = ctx.location();
+        let event = match cmd {
+            "define" => {
+                let define = self.parse_define(rest, ctx)?;
+                PpEvent::Define {
+                    name: define.name,
+                    params: define.params,
+                    is_variadic: define.is_variadic,
+                    body: engine::tokens_to_string(&define.body),
+                    location,
+                }
+            }
+            "undef" => PpEvent::Undef {
+                name: required("undef")?
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_string(),
+                location,
+            },
+            "include" => {
+                let (path, kind) = self.header_name(rest, ctx)?;
+                PpEvent::Include {
+                    path,
+                    kind,
+                    location,
+                }
+            }
+            "if" => PpEvent::If {
+                expr: required("if")?,
+                location,
+            },
+            "ifdef" => PpEvent::Ifdef {
+                name: rest_string,
+                location,
+            },
+            "ifndef" => PpEvent::Ifndef {
+                name: rest_string,
+                location,
+            },
+            "elif" => PpEvent::Elif {
+                expr: required("elif")?,
+                location,
+            },
+            "else" => PpEvent::Else { location },
+            "endif" => PpEvent::Endif { location },
+            "pragma" => PpEvent::Pragma {
+                text: rest_string,
+                location,
+            },
+            "line" => PpEvent::Line {
+                args: required("line")?,
+                location,
+            },
+            "error" => PpEvent::Error {
+                message: rest_string,
+                location,
+            },
+            "warning" => PpEvent::Warning {
+                message: rest_string,
+                location,
+            },
+            "" => return Ok(None),
+            _ => PpEvent::Unknown {
+                name: cmd.to_string(),
+                rest: rest_string,
+                location,
+            },
+        };
+        Ok(Some(event))
+    }
+
+    /// Process the configured prelude items, passing their output to `sink`
+    /// unless prelude output is suppressed
+    fn process_prelude(&mut self, sink: &mut LineSink<'_>) -> Result<(), PreprocessError> {
+        let main_file = self.context.current_file.clone();
+        let mut discard = |_: OutputLine<'_>| {};
+        let sink: &mut LineSink<'_> = if self.context.suppress_prelude_output {
+            &mut discard
+        } else {
+            sink
+        };
+
+        for item in self.context.prelude.clone() {
+            match item {
+                PreludeItem::File(path) => {
+                    let ctx = DiagnosticContext::new(PRELUDE_FILE.to_string(), 0, None);
+                    self.context.conditional_stack.clear();
+                    if let Some(line) = self.handle_include(&format!("\"{path}\""), &ctx, sink)? {
+                        self.emit_line(sink, &line, 0);
+                    }
+                }
+                PreludeItem::Text(text) => {
+                    self.context.current_file = PRELUDE_FILE.to_string();
+                    let processed = self.process_source(&text, sink);
+                    self.context.current_file.clone_from(&main_file);
+                    processed?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Pass a line of output from the current file to `sink`
+    fn emit_line(&mut self, sink: &mut LineSink<'_>, text: &str, line: usize) {
+        self.emit_tokens(sink, text, None, line);
+    }
+
+    /// Send a line of output together with the tokens it was built from
+    ///
+    /// After a passed-through `#line`, a line that doesn't continue where the
+    /// output left off is preceded by a `#line` giving its position.
+    fn emit_tokens(
+        &mut self,
+        sink: &mut LineSink<'_>,
+        text: &str,
+        tokens: Option<&[Token]>,
+        line: usize,
+    ) {
+        let from_include = !self.context.include_stack.is_empty();
+        if let Some(next) = &mut self.context.line_sync {
+            let file = &self.context.current_file;
+            if !matches!(next, Some((next_file, next_line)) if next_file == file && *next_line == line)
+            {
+                let directive = format!("#line {line} {}", engine::quote_file_name(file));
+                sink(OutputLine {
+                    text: &directive,
+                    file,
+                    line,
+                    from_include,
+                    tokens: None,
+                });
+            }
+            *next = Some((file.clone(), line + 1));
+        }
+        sink(OutputLine {
+            text,
+            file: &self.context.current_file,
+            line,
+            from_include,
+            tokens,
+        });
+    }
+
+    /// Process one source text with the current file and macro state
+    fn process_source(
+        &mut self,
+        input: &str,
+        sink: &mut LineSink<'_>,
+    ) -> Result<(), PreprocessError> {
+        let input = self.drop_null_characters(input)?;
+        let normalized = engine::normalize_input(&input);
+        let lines = engine::splice_logical_lines(&normalized);
+        let emitted = Cell::new(false);
+        let mut sink = |line: OutputLine<'_>| {
+            emitted.set(true);
+            sink(line);
+        };
         self.context.conditional_stack.clear();
         self.context.current_line = 1;
 
-        for current_line_str in pragma_processed.lines() {
-            let stripped_line = engine::strip_comments(current_line_str);
+        let mut index = 0;
+        while index < lines.len() {
+            let logical = &lines[index];
+            let current_line_str = logical.text.as_str();
+            let mut span = logical.physical_lines;
+            index += 1;
+            self.charge_budget()?;
+            let mut stripped_line = engine::strip_comments(current_line_str);
             let ctx = DiagnosticContext::new(
                 self.context.current_file.clone(),
                 self.context.current_line,
                 Some(current_line_str.to_string()),
             );
+            self.warn_about_splices(logical, &ctx);
 
-            if let Some(directive) = Self::extract_directive(&stripped_line) {
+            if let Some(directive) = engine::extract_directive(&stripped_line) {
                 // Line is a directive - handle it and never emit the raw text,
                 // even when the directive produces no output (e.g. #define, #undef).
-                if let Some(content) = self.handle_directive(directive, &ctx)? {
-                    out_lines.push(content);
+                if let Some(content) = self.handle_directive(directive, &ctx, &mut sink)? {
+                    self.emit_line(&mut sink, &content, ctx.line);
                 }
             } else if self.can_emit_line() {
-                let tokens = engine::tokenize_line(&stripped_line);
+                let mut tokens = engine::tokenize_line(&stripped_line);
+                // A macro call whose arguments continue on later lines
+                while let Some(name) = self.unterminated_invocation(&tokens)
+                    && index < lines.len()
+                {
+                    let next = engine::strip_comments(&lines[index].text);
+                    if engine::extract_directive(&next).is_some() {
+                        let directive_ctx = DiagnosticContext::new(
+                            ctx.file.clone(),
+                            self.context.current_line + span,
+                            Some(lines[index].text.clone()),
+                        );
+                        return Err(self.generic_error(
+                            &format!(
+                                "preprocessing directive within macro argument list of '{name}' (invocation starts at {}:{})",
+                                ctx.file, ctx.line
+                            ),
+                            directive_ctx.start_column(),
+                            &directive_ctx,
+                        ));
+                    }
+                    stripped_line.push(' ');
+                    stripped_line.push_str(&next);
+                    tokens = engine::tokenize_line(&stripped_line);
+                    self.context.current_line += span;
+                    let ctx = DiagnosticContext::new(
+                        ctx.file.clone(),
+                        self.context.current_line,
+                        Some(lines[index].text.clone()),
+                    );
+                    self.warn_about_splices(&lines[index], &ctx);
+                    span = lines[index].physical_lines;
+                    index += 1;
+                }
+                self.check_poisoned(&tokens, &ctx)?;
                 let expanded_tokens = self.expand_tokens(&tokens, 0, &ctx)?;
-                let reconstructed = engine::tokens_to_string(&expanded_tokens);
-                out_lines.push(reconstructed);
+                self.emit_expanded_line(&expanded_tokens, ctx.line, &mut sink);
             }
-            self.context.current_line += 1;
+            self.context.current_line += span;
         }
 
         if !self.context.conditional_stack.is_empty() {
-            let ctx = DiagnosticContext::new("<end of input>".to_string(), 0, None);
-            return Err(self.conditional_error("unterminated #if/#ifdef/#ifndef", &ctx));
+            return Err(self.unterminated_conditionals_error());
+        }
+
+        // Even an empty source produces one (empty) line of output
+        if !emitted.get() {
+            let line = self.context.current_line;
+            self.emit_line(&mut sink, "", line);
+        }
+        Ok(())
+    }
+
+    /// Warn about splices GCC warns about in a logical line starting at `ctx`
+    ///
+    /// A `//` comment ending in a backslash swallows the next line, which is
+    /// reported only outside skipped blocks; a backslash separated from the
+    /// newline by spaces or tabs is always reported.
+    fn warn_about_splices(&self, line: &engine::LogicalLine, ctx: &DiagnosticContext) {
+        let at = |offset: usize| {
+            DiagnosticContext::new(ctx.file.clone(), ctx.line + offset, ctx.source_line.clone())
+        };
+        for &offset in &line.spaced_splices {
+            self.emit_warning("backslash and newline separated by space", &at(offset));
+        }
+        if self.can_emit_line() {
+            for &offset in &line.comment_splices {
+                self.emit_warning("multi-line comment", &at(offset));
+            }
+        }
+    }
+
+    /// Error for conditionals still open at the end of a file, naming where
+    /// each was opened, innermost first
+    ///
+    /// The error is placed at the innermost opening directive. At the end of
+    /// an included file it also names the includer, since the includer can't
+    /// close the header's conditionals.
+    fn unterminated_conditionals_error(&self) -> PreprocessError {
+        let open = &self.context.conditional_stack;
+        let describe = |state: &ConditionalState| {
+            format!(
+                "{} opened at {}:{}",
+                state.directive, state.origin.file, state.origin.line
+            )
+        };
+        let mut details = match open.as_slice() {
+            [state] => format!("unterminated {}", describe(state)),
+            _ => format!(
+                "{} unterminated conditionals, innermost first: {}",
+                open.len(),
+                open.iter()
+                    .rev()
+                    .map(describe)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+        };
+        if let Some(includer) = self.context.include_stack.last() {
+            details.push_str(&format!(
+                " (end of '{}', included from '{includer}'; conditionals must be closed in the file that opens them)",
+                self.context.current_file
+            ));
         }
+        let innermost = &open[open.len() - 1];
+        let ctx = DiagnosticContext::new(
+            innermost.origin.file.clone(),
+            innermost.origin.line,
+            Some(innermost.directive.clone()),
+        );
+        self.conditional_error(&details, ctx.start_column(), &ctx)
+    }
 
-        let result = out_lines.join("\n") + "\n";
+    /// Name of a function-like macro whose argument list is still open at the
+    /// end of `tokens`, meaning the call continues on the next line
+    fn unterminated_invocation(&self, tokens: &[Token]) -> Option<String> {
+        let mut i = 0;
+        while i < tokens.len() {
+            let Token::Identifier(name) = &tokens[i] else {
+                i += 1;
+                continue;
+            };
+            let is_function_like = self
+                .context
+                .macros
+                .get(name)
+                .is_some_and(|mac| mac.params.is_some());
+            let open = self.find_next_non_whitespace(tokens, i + 1);
+            if !is_function_like
+                || !matches!(tokens.get(open), Some(Token::Other(s)) if s.starts_with('('))
+            {
+                i += 1;
+                continue;
+            }
 
-        // Only denormalize at the outer-most call to avoid corrupting internal data flow
-        // (nested includes pass strings back to the parent through `handle_include`).
-        if self.context.include_stack.is_empty() {
-            Ok(engine::denormalize_output(
-                &result,
-                &self.context.line_ending,
-            ))
+            let mut depth = 0usize;
+            let mut closed_at = None;
+            'scan: for (k, token) in tokens.iter().enumerate().skip(open) {
+                if let Token::Other(s) = token {
+                    for ch in s.chars() {
+                        match ch {
+                            '(' => depth += 1,
+                            ')' => {
+                                depth -= 1;
+                                if depth == 0 {
+                                    closed_at = Some(k);
+                                    break 'scan;
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            match closed_at {
+                Some(k) => i = k + 1,
+                None => return Some(name.clone()),
+            }
+        }
+        None
+    }
+
+    /// Emit a macro-expanded line, turning any `_Pragma` operators in it into
+    /// pragmas on their own lines
+    ///
+    /// Expansion never produces directives: text such as `#define X` coming out
+    /// of a macro is emitted verbatim. `_Pragma` is the one exception.
+    fn emit_expanded_line(&mut self, tokens: &[Token], line: usize, sink: &mut LineSink<'_>) {
+        let merged;
+        let tokens = if self.context.concat_string_literals {
+            merged = engine::concat_adjacent_strings(tokens);
+            &merged
         } else {
-            Ok(result)
+            tokens
+        };
+
+        let mut segments = engine::split_pragma_operators(tokens);
+        if let [PragmaSegment::Tokens(tokens)] = segments.as_mut_slice() {
+            self.emit_tokens(sink, &engine::tokens_to_string(tokens), Some(tokens), line);
+            return;
+        }
+
+        for segment in segments {
+            match segment {
+                PragmaSegment::Tokens(tokens) => {
+                    let text = engine::tokens_to_string(&tokens);
+                    if !text.trim().is_empty() {
+                        self.emit_tokens(sink, &text, Some(&tokens), line);
+                    }
+                }
+                PragmaSegment::Pragma(pragma) => {
+                    if let Some(text) = self.handle_pragma(&pragma) {
+                        self.emit_line(sink, &text, line);
+                    }
+                }
+            }
         }
     }
 
@@ -266,32 +1381,28 @@ impl PreprocessorDriver {
         true
     }
 
-    fn extract_directive(line: &str) -> Option<&str> {
-        let trimmed = line.trim_start();
-        trimmed.strip_prefix('#').map(str::trim)
-    }
-
     fn handle_directive(
         &mut self,
         directive: &str,
         ctx: &DiagnosticContext,
+        sink: &mut LineSink<'_>,
     ) -> Result<Option<String>, PreprocessError> {
-        let mut parts = directive.splitn(2, char::is_whitespace);
-        let cmd = parts.next().unwrap_or("").trim();
-        let rest = parts.next().unwrap_or("").trim();
+        let (cmd, rest) = engine::split_directive(directive);
+        let opens_group = matches!(cmd, "if" | "ifdef" | "ifndef" | "elif" | "else");
+        // A malformed directive is reported by its handler instead
+        if self.records_events()
+            && (opens_group || cmd == "endif" || self.can_emit_line())
+            && let Ok(Some(event)) = self.directive_event(directive, ctx)
+        {
+            self.record_event(event);
+        }
 
-        match cmd {
+        let result = match cmd {
             "define" => self.handle_define(rest, ctx),
             "undef" => self.handle_undef(rest, ctx),
-            "include" => self.handle_include(rest, ctx),
-            "ifdef" => {
-                self.handle_ifdef(rest);
-                Ok(None)
-            }
-            "ifndef" => {
-                self.handle_ifndef(rest);
-                Ok(None)
-            }
+            "include" => self.handle_include(rest, ctx, sink),
+            "ifdef" => self.handle_ifdef(rest, ctx).map(|()| None),
+            "ifndef" => self.handle_ifndef(rest, ctx).map(|()| None),
             "if" => self.handle_if(rest, ctx),
             "elif" => self.handle_elif(rest, ctx),
             "else" => self.handle_else(ctx),
@@ -302,111 +1413,263 @@ impl PreprocessorDriver {
                 Ok(None)
             }
             "line" => self.handle_line(rest, ctx),
-            "pragma" => Ok(self.handle_pragma(rest)),
-            _ => Ok(None),
+            "assert" | "unassert" if self.assertions_enabled() => {
+                self.handle_assertion(cmd, rest, ctx)
+            }
+            // MSVC has neither, so they fall through to the unknown-directive policy
+            "ident" | "sccs" if !matches!(self.context.compiler, Compiler::MSVC) => {
+                self.handle_ident(cmd, rest, ctx)
+            }
+            "pragma" if self.can_emit_line() => Ok(self.handle_pragma(rest)),
+            "pragma" => Ok(None),
+            // The null directive: a lone `#` on a line
+            "" => Ok(None),
+            _ => {
+                if self.can_emit_line() {
+                    self.conformance_diagnostic(
+                        &format!("invalid preprocessing directive #{cmd}"),
+                        ctx,
+                    )?;
+                }
+                Ok(None)
+            }
+        };
+        if opens_group && result.is_ok() && self.can_emit_line() {
+            self.record_event(PpEvent::ConditionalTaken {
+                location: ctx.location(),
+            });
         }
+        result
     }
 
-    fn handle_define(
+    /// Whether `#assert`, `#unassert` and `#predicate(answer)` are supported,
+    /// which only GCC does
+    const fn assertions_enabled(&self) -> bool {
+        matches!(self.context.compiler, Compiler::GCC)
+    }
+
+    /// Handle `#assert predicate(answer)` and `#unassert predicate[(answer)]`
+    ///
+    /// `#unassert` without an answer removes every answer to the predicate.
+    fn handle_assertion(
         &mut self,
+        cmd: &str,
         rest: &str,
         ctx: &DiagnosticContext,
     ) -> Result<Option<String>, PreprocessError> {
         if !self.can_emit_line() {
             return Ok(None);
         }
+        self.emit_warning(&format!("#{cmd} is a deprecated GCC extension"), ctx);
 
-        let rest = rest.trim_start();
-        if rest.is_empty() {
-            return Err(self.directive_error("define", ctx));
+        let Some((predicate, answer)) = engine::parse_assertion(rest) else {
+            return Err(self.directive_error(cmd, ctx.operand_column(), ctx));
+        };
+        match (cmd, answer) {
+            ("assert", Some(answer)) => {
+                self.context
+                    .assertions
+                    .entry(predicate)
+                    .or_default()
+                    .insert(answer);
+            }
+            ("assert", None) => {
+                return Err(self.generic_error(
+                    "missing '(' after predicate",
+                    ctx.operand_column(),
+                    ctx,
+                ));
+            }
+            (_, Some(answer)) => {
+                if let Some(answers) = self.context.assertions.get_mut(&predicate) {
+                    answers.remove(&answer);
+                    if answers.is_empty() {
+                        self.context.assertions.remove(&predicate);
+                    }
+                }
+            }
+            (_, None) => {
+                self.context.assertions.remove(&predicate);
+            }
         }
+        // Cached `#if` results may test the predicate
+        self.context.macro_generation += 1;
+        Ok(None)
+    }
 
-        let mut chars = rest.chars().peekable();
-        let mut name = String::new();
-        while let Some(&c) = chars.peek() {
-            if c.is_alphanumeric() || c == '_' {
-                name.push(c);
-                chars.next();
-            } else {
-                break;
+    /// Replace `#predicate(answer)` and `#predicate` tests in an `#if`
+    /// expression with 1 or 0
+    ///
+    /// Answers are not macro-expanded, matching GCC.
+    fn resolve_assertion_tests(
+        &mut self,
+        tokens: Vec<Token>,
+        ctx: &DiagnosticContext,
+    ) -> Vec<Token> {
+        if !tokens
+            .iter()
+            .any(|t| matches!(t, Token::Other(s) if s == "#"))
+        {
+            return tokens;
+        }
+        let mut out = Vec::with_capacity(tokens.len());
+        let mut i = 0;
+        while i < tokens.len() {
+            let name_idx = self.find_next_non_whitespace(&tokens, i + 1);
+            let (Token::Other(hash), Some(Token::Identifier(predicate))) =
+                (&tokens[i], tokens.get(name_idx))
+            else {
+                out.push(tokens[i].clone());
+                i += 1;
+                continue;
+            };
+            if hash != "#" {
+                out.push(tokens[i].clone());
+                i += 1;
+                continue;
             }
+
+            let open = self.find_next_non_whitespace(&tokens, name_idx + 1);
+            let close = matches!(tokens.get(open), Some(Token::Other(s)) if s == "(")
+                .then(|| {
+                    (open + 1..tokens.len())
+                        .find(|&k| matches!(&tokens[k], Token::Other(s) if s == ")"))
+                })
+                .flatten();
+            let answers = self.context.assertions.get(predicate);
+            let holds = match close {
+                Some(close) => {
+                    let answer = engine::tokens_to_string(&tokens[open + 1..close]);
+                    let answer = answer.split_whitespace().collect::<Vec<_>>().join(" ");
+                    i = close + 1;
+                    answers.is_some_and(|answers| answers.contains(&answer))
+                }
+                None => {
+                    i = name_idx + 1;
+                    answers.is_some()
+                }
+            };
+            out.push(Token::Other(if holds { "1" } else { "0" }.to_string()));
+
+            self.context.expression_uncacheable = true;
+            self.emit_warning("assertions are a deprecated extension", ctx);
         }
+        out
+    }
 
-        if name.is_empty() {
-            return Err(self.directive_error("define", ctx));
+    fn handle_define(
+        &mut self,
+        rest: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<Option<String>, PreprocessError> {
+        if !self.can_emit_line() {
+            return Ok(None);
         }
 
-        while let Some(&c) = chars.peek() {
-            if c.is_whitespace() {
-                chars.next();
-            } else {
-                break;
+        let define = self.parse_define(rest, ctx)?;
+        self.check_poisoned(&engine::tokenize_line(&define.name), ctx)?;
+        self.check_poisoned(&define.body, ctx)?;
+        let is_paste = |token: &&Token| matches!(token, Token::Other(s) if s.trim() == "##");
+        let mut significant = define
+            .body
+            .iter()
+            .filter(|token| !engine::token_to_string(token).trim().is_empty());
+        if significant.next().is_some_and(|first| is_paste(&first))
+            || significant.next_back().is_some_and(|last| is_paste(&last))
+        {
+            self.conformance_diagnostic(
+                "'##' cannot appear at either end of a macro expansion",
+                ctx,
+            )?;
+        }
+        if self.context.warn_unused_macro_params {
+            for param in define.params.iter().flatten() {
+                if !define
+                    .body
+                    .iter()
+                    .any(|token| matches!(token, Token::Identifier(id) if id == param))
+                {
+                    self.emit_warning(
+                        &format!(
+                            "parameter '{param}' of macro '{}' is never used",
+                            define.name
+                        ),
+                        ctx,
+                    );
+                }
             }
         }
+        let name = define.name;
+        let mac = Macro {
+            params: define.params,
+            body: Rc::new(define.body),
+            is_variadic: define.is_variadic,
+            definition_location: Some((
+                self.context.current_file.clone(),
+                self.context.current_line,
+            )),
+            is_builtin: false,
+        };
 
-        let mut params: Option<Vec<String>> = None;
-        let mut is_variadic = false;
-
-        if let Some(&'(') = chars.peek() {
-            chars.next();
-            let mut param = String::new();
-            let mut params_vec = Vec::new();
+        if let Some(existing) = self.context.macros.get(&name)
+            && !existing.same_definition(&mac)
+        {
+            let previous = match &existing.definition_location {
+                Some((file, line)) => format!("previous definition at {file}:{line}"),
+                None => "previously defined as a builtin".to_string(),
+            };
+            // Switching between object-like and function-like is usually a
+            // refactoring that missed a definition, so it is named
+            let kind_change = match (&existing.params, &mac.params) {
+                (None, Some(_)) => " as a function-like macro",
+                (Some(_), None) => " as an object-like macro",
+                _ => "",
+            };
+            self.conformance_diagnostic(
+                &format!("'{name}' redefined{kind_change} ({previous})"),
+                ctx,
+            )?;
+        }
 
-            loop {
-                match chars.peek() {
-                    None => return Err(self.directive_error("define", ctx)),
-                    Some(&')') => {
-                        if !param.trim().is_empty() {
-                            params_vec.push(param.trim().to_string());
-                        }
-                        chars.next();
-                        break;
-                    }
-                    Some(&',') => {
-                        params_vec.push(param.trim().to_string());
-                        param.clear();
-                        chars.next();
-                    }
-                    Some(&'.') => {
-                        is_variadic = true;
-                        chars.next();
-                        if chars.peek() == Some(&'.') {
-                            chars.next();
-                            if chars.peek() == Some(&'.') {
-                                chars.next();
-                                break;
-                            }
-                        }
-                    }
-                    Some(&c) => {
-                        param.push(c);
-                        chars.next();
-                    }
-                }
-            }
-            params = Some(params_vec);
-        }
-
-        let body_str: String = chars.collect();
-        let stripped = engine::strip_comments(&body_str);
-        let stripped_body = stripped.trim();
-        let body_tokens = engine::tokenize_line(stripped_body);
-        self.context.macros.insert(
-            name,
-            Macro {
-                params,
-                body: Rc::new(body_tokens),
-                is_variadic,
-                definition_location: Some((
-                    self.context.current_file.clone(),
-                    self.context.current_line,
-                )),
-                is_builtin: false,
-            },
-        );
+        self.context.macro_generation += 1;
+        self.context.macros.insert(name, mac);
         Ok(None)
     }
 
+    /// Parse the text following `#define`, rejecting parameter lists that
+    /// name `__VA_ARGS__` or repeat a parameter
+    fn parse_define(
+        &self,
+        rest: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<DefineDirective, PreprocessError> {
+        let Some(define) = engine::parse_define(rest) else {
+            return Err(self.directive_error("define", ctx.operand_column(), ctx));
+        };
+        let params = define.params.as_deref().unwrap_or_default();
+        for (index, param) in params.iter().enumerate() {
+            let earlier = params[..index].iter().filter(|p| *p == param).count();
+            let problem = if param == "__VA_ARGS__" {
+                "__VA_ARGS__ used as a parameter name".to_string()
+            } else if earlier > 0 {
+                format!("duplicate parameter '{param}'")
+            } else {
+                continue;
+            };
+            // Point at this occurrence, skipping the macro name and earlier
+            // parameters spelled the same way
+            let skip = earlier + usize::from(define.name == *param);
+            let column = ctx
+                .columns()
+                .into_iter()
+                .filter(|(_, token)| matches!(token, Token::Identifier(id) if id == param))
+                .nth(skip)
+                .map_or_else(|| ctx.operand_column(), |(column, _)| column);
+            return Err(self.directive_error(&format!("define ({problem})"), column, ctx));
+        }
+        Ok(define)
+    }
+
     fn handle_undef(
         &mut self,
         rest: &str,
@@ -418,131 +1681,434 @@ impl PreprocessorDriver {
 
         let name = rest.split_whitespace().next().unwrap_or("");
         if name.is_empty() {
-            Err(self.directive_error("undef", ctx))
+            Err(self.directive_error("undef", ctx.operand_column(), ctx))
         } else {
             self.context.undef(name);
             Ok(None)
         }
     }
 
+    /// Parse the header name of an `#include`, rejecting an empty name or a
+    /// missing closing `"` or `>`
+    fn header_name(
+        &self,
+        rest: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<(String, IncludeKind), PreprocessError> {
+        let written = rest.trim();
+        match engine::parse_include(rest) {
+            Some((name, _)) if name.is_empty() => Err(self.directive_error(
+                &format!("empty filename in #include {written}"),
+                ctx.operand_column(),
+                ctx,
+            )),
+            Some(parsed) => Ok(parsed),
+            None => {
+                let closing = match written.chars().next() {
+                    Some('"') => '"',
+                    Some('<') => '>',
+                    _ => return Err(self.directive_error("include", ctx.operand_column(), ctx)),
+                };
+                if written[1..].contains(closing) {
+                    return Err(self.directive_error("include", ctx.operand_column(), ctx));
+                }
+                Err(self.directive_error(
+                    &format!("missing terminating {closing} character in #include {written}"),
+                    ctx.end_column(),
+                    ctx,
+                ))
+            }
+        }
+    }
+
+    /// Warn about characters whose meaning in a header name is undefined
+    ///
+    /// The standard leaves `'`, `"`, `\`, `//` and `/*` in a header name
+    /// undefined. A backslash isn't reported for MSVC, or when a path
+    /// separator is configured for includes, since it's a directory separator
+    /// there.
+    fn check_header_name(&self, name: &str, written: &str, ctx: &DiagnosticContext) {
+        let backslash_is_separator = matches!(self.context.compiler, Compiler::MSVC)
+            || self.context.include_path_separator != PathSeparator::Preserve;
+        let found = ["'", "\"", "\\", "//", "/*"].into_iter().find(|sequence| {
+            name.contains(sequence) && !(*sequence == "\\" && backslash_is_separator)
+        });
+        if let Some(sequence) = found {
+            self.emit_warning(
+                &format!("header name {written} contains {sequence} (undefined behavior)"),
+                ctx,
+            );
+        }
+    }
+
+    /// Process an included file, streaming its lines to `sink`
+    ///
+    /// The `#include` line itself produces an empty line after the included
+    /// text, which is returned for the caller to emit.
     fn handle_include(
         &mut self,
         rest: &str,
         ctx: &DiagnosticContext,
+        sink: &mut LineSink<'_>,
     ) -> Result<Option<String>, PreprocessError> {
         if !self.can_emit_line() {
             return Ok(None);
         }
 
-        let trimmed = rest.trim();
-        let (path, kind) =
-            if trimmed.starts_with('"') && trimmed.ends_with('"') && trimmed.len() >= 2 {
-                (
-                    Some(trimmed[1..(trimmed.len() - 1)].to_string()),
-                    IncludeKind::Local,
-                )
-            } else if trimmed.starts_with('<') && trimmed.ends_with('>') && trimmed.len() >= 2 {
-                (
-                    Some(trimmed[1..(trimmed.len() - 1)].to_string()),
-                    IncludeKind::System,
-                )
-            } else {
-                (None, IncludeKind::Local) // dummy
-            };
-
-        let Some(p) = path else {
-            return Err(self.directive_error("include", ctx));
-        };
+        let (p, kind) = self.header_name(rest, ctx)?;
+        self.check_header_name(&p, rest.trim(), ctx);
+        let p = self.context.include_path_separator.apply(&p);
 
         let context = IncludeContext {
             include_stack: self.context.include_stack.clone(),
-            include_dirs: Vec::new(),
+            include_dirs: self.context.include_dirs.clone(),
+            including_file: self.context.current_file.clone(),
+            including_dir: Self::including_dir(&self.context.current_file),
         };
 
         let Some(resolver) = &self.context.include_resolver else {
-            return Err(self.include_error(&p, ctx));
+            return Err(self.include_error(&p, ctx.operand_column(), ctx));
         };
 
-        let Some(content) = resolver(&p, kind.clone(), &context) else {
-            return Err(self.include_error(&p, ctx));
+        let (content, identity, found) = match resolver(&p, kind.clone(), &context) {
+            IncludeResolution::Content(content) => (content, None, None),
+            IncludeResolution::Identified { content, identity } => (content, Some(identity), None),
+            IncludeResolution::File { path, content } => {
+                let text = self.decode_input(&content, &path, self.context.lossy_utf8)?;
+                (text.into_owned(), None, Some(path))
+            }
+            IncludeResolution::Unreadable(message) => {
+                return Err(self.generic_error(
+                    &format!("cannot read '{p}': {message}"),
+                    ctx.operand_column(),
+                    ctx,
+                ));
+            }
+            IncludeResolution::NotFound => {
+                return Err(self.include_error(&p, ctx.operand_column(), ctx));
+            }
+            IncludeResolution::Skip => return Ok(Some(String::new())),
         };
+        if let Some(max) = self.context.max_include_bytes
+            && content.len() > max
+        {
+            let details = format!(
+                "included file '{p}' is {} bytes, more than the limit of {max}",
+                content.len()
+            );
+            self.report_oversized_input(&details, ctx)?;
+        }
 
-        // Check for cycles
-        if self.context.include_stack.contains(&p) {
-            return Err(self.generic_error(&format!("Include cycle detected for '{p}'"), ctx));
+        // A header read from disk goes by the path it was found at; for other
+        // resolvers, a quoted include names a file next to the includer if
+        // one exists there
+        let resolved_path = found.unwrap_or_else(|| {
+            if kind == IncludeKind::Local {
+                Self::file_next_to(&self.context.current_file, &p).unwrap_or_else(|| p.clone())
+            } else {
+                p.clone()
+            }
+        });
+
+        // Check for cycles by file identity, however the header is spelled.
+        // A file may include itself once, so a guarded one stops there; going
+        // deeper is a common mistake (a missing guard) with its own message
+        let key = identity
+            .clone()
+            .unwrap_or_else(|| Self::once_key(&resolved_path));
+        let current_key = self
+            .context
+            .file_identity
+            .clone()
+            .unwrap_or_else(|| Self::once_key(&self.context.current_file));
+        if self.context.include_keys.contains(&key) {
+            let message = if key == current_key {
+                format!("file includes itself: '{p}'")
+            } else {
+                format!("Include cycle detected for '{p}'")
+            };
+            return Err(self.generic_error(&message, ctx.operand_column(), ctx));
+        }
+        let max_depth = self.context.max_include_depth;
+        if self.context.include_stack.len() >= max_depth {
+            return Err(self.generic_error(
+                &format!("#include nested more than {max_depth} levels deep"),
+                ctx.operand_column(),
+                ctx,
+            ));
         }
 
-        // Check for #pragma once
-        if content.contains("#pragma once") && self.context.included_once.contains(&p) {
-            return Ok(Some(String::new()));
+        if let Some(recording) = &self.context.recording {
+            let dependencies = &mut recording.borrow_mut().dependencies;
+            if !dependencies.iter().any(|dep| dep.path == resolved_path) {
+                dependencies.push(Dependency {
+                    path: resolved_path.clone(),
+                    kind: kind.clone(),
+                    included_from: ctx.location(),
+                });
+            }
+        }
+
+        if self.context.record_include_graph {
+            let edge = (self.context.current_file.clone(), resolved_path.clone());
+            if !self.context.include_edges.contains(&edge) {
+                self.context.include_edges.push(edge);
+            }
+        }
+
+        // Skip files that marked themselves with #pragma once (or _Pragma("once")),
+        // however they are spelled; the skipped include produces no output
+        if self.context.included_once.contains(&key) {
+            return Ok(None);
+        }
+
+        let before = self.context.unit_bytes;
+        self.context.unit_bytes += content.len();
+        self.context.stats.bytes_read += content.len();
+        if let Some(max) = self.context.max_total_bytes
+            && self.context.unit_bytes > max
+            && before <= max
+        {
+            let details = format!(
+                "including '{p}' ({} bytes) brings the input to {} bytes, more than the limit of {max}",
+                content.len(),
+                self.context.unit_bytes
+            );
+            self.report_oversized_input(&details, ctx)?;
         }
 
-        // Push current file to include stack BEFORE resolving path for proper context
         self.context
             .include_stack
             .push(self.context.current_file.clone());
+        self.context.include_keys.push(current_key);
 
-        // For local includes, try to resolve the actual file path
-        // This ensures __FILE__ shows the correct relative path
-        let resolved_path = if kind == IncludeKind::Local {
-            self.context
-                .include_stack
-                .last()
-                .and_then(|including_file| Path::new(including_file).parent())
-                .map(|parent_dir| parent_dir.join(&p))
-                .filter(|candidate| candidate.exists())
-                .map_or_else(
-                    || p.clone(),
-                    |candidate| candidate.to_string_lossy().to_string(),
-                )
-        } else {
-            p.clone()
-        };
-
+        if let Some(hook) = &self.context.on_include_enter {
+            hook(
+                &resolved_path,
+                self.context.include_stack.len(),
+                kind.clone(),
+            );
+        }
+        // Files included from a system header are system headers too
+        let in_system_header = self.context.in_system_header
+            || (self.context.system_headers_quiet && kind == IncludeKind::System);
+
+        let path = resolved_path.clone();
+        self.record_event(PpEvent::IncludeEntered {
+            path: path.clone(),
+            location: ctx.location(),
+        });
         let mut nested = Self {
             context: PreprocessorContext {
                 macros: self.context.macros.clone(),
+                macro_generation: self.context.macro_generation,
+                expression_cache: core::mem::take(&mut self.context.expression_cache),
+                expression_uncacheable: false,
+                stats: self.context.stats,
+                iterations: self.context.iterations,
+                unit_bytes: self.context.unit_bytes,
+                recording: self.context.recording.clone(),
                 include_resolver: self.context.include_resolver.clone(),
                 recursion_limit: self.context.recursion_limit,
                 included_once: self.context.included_once.clone(),
+                poisoned: self.context.poisoned.clone(),
+                assertions: self.context.assertions.clone(),
+                in_system_header,
                 include_stack: self.context.include_stack.clone(),
+                include_keys: self.context.include_keys.clone(),
                 disabled_macros: HashSet::new(),
+                expansion_stack: Vec::new(),
                 conditional_stack: Vec::new(),
+                counter: self.context.counter,
                 current_line: 1,
                 current_file: resolved_path,
                 compiler: self.context.compiler.clone(),
                 warning_handler: self.context.warning_handler.clone(),
                 line_ending: self.context.line_ending.clone(),
+                strict: self.context.strict,
+                include_dirs: self.context.include_dirs.clone(),
+                concat_string_literals: self.context.concat_string_literals,
+                on_include_enter: self.context.on_include_enter.clone(),
+                prelude: self.context.prelude.clone(),
+                suppress_prelude_output: self.context.suppress_prelude_output,
+                expansion_trace_handler: self.context.expansion_trace_handler.clone(),
+                defined_from_macro: self.context.defined_from_macro,
+                standard: self.context.standard,
+                collapse_blank_lines: self.context.collapse_blank_lines,
+                include_path_separator: self.context.include_path_separator,
+                file_macro_separator: self.context.file_macro_separator,
+                max_iterations: self.context.max_iterations,
+                #[cfg(feature = "std")]
+                deadline: self.context.deadline,
+                max_macro_args: self.context.max_macro_args,
+                max_expansion_tokens: self.context.max_expansion_tokens,
+                system_headers_quiet: self.context.system_headers_quiet,
+                configured_macros: MacroTable::new(),
+                max_include_bytes: self.context.max_include_bytes,
+                max_total_bytes: self.context.max_total_bytes,
+                include_size_policy: self.context.include_size_policy,
+                record_include_graph: self.context.record_include_graph,
+                include_edges: core::mem::take(&mut self.context.include_edges),
+                lossy_utf8: self.context.lossy_utf8,
+                warn_unused_macro_params: self.context.warn_unused_macro_params,
+                expr_identifier_resolver: self.context.expr_identifier_resolver.clone(),
+                warn_undef: self.context.warn_undef,
+                file_identity: Some(key),
+                initial_file: self.context.initial_file.clone(),
+                passthrough_line_directives: self.context.passthrough_line_directives,
+                line_sync: self.context.line_sync.clone(),
+                warn_bare_function_macro: self.context.warn_bare_function_macro,
+                pack_stack: core::mem::take(&mut self.context.pack_stack),
+                max_include_depth: self.context.max_include_depth,
             },
         };
 
-        let process_result = nested.process(&content);
-        self.context.include_stack.pop();
+        let process_result = nested.process_source(&content, sink);
+        self.context.include_stack.pop();
+        self.context.include_keys.pop();
+        self.context.macro_generation = nested.context.macro_generation;
+        self.context.expression_cache = nested.context.expression_cache;
+        self.context.stats = nested.context.stats;
+        self.context.iterations = nested.context.iterations;
+        self.context.unit_bytes = nested.context.unit_bytes;
+        self.context.counter = nested.context.counter;
+        self.context.include_edges = nested.context.include_edges;
+        self.context.line_sync = nested.context.line_sync;
+        self.context.pack_stack = nested.context.pack_stack;
+
+        process_result?;
+        self.context.macros = nested.context.macros;
+        self.context.included_once = nested.context.included_once;
+        self.context.poisoned = nested.context.poisoned;
+        self.context.assertions = nested.context.assertions;
+        self.record_event(PpEvent::IncludeLeft {
+            path,
+            location: ctx.location(),
+        });
+
+        Ok(Some(String::new()))
+    }
+
+    /// Key under which `#pragma once` records a file
+    ///
+    /// Files on disk are keyed by their canonical path; other names are
+    /// normalized lexically, so `./a.h` and `dir/../a.h` both match `a.h`.
+    #[cfg(feature = "std")]
+    fn once_key(file: &str) -> String {
+        if let Ok(canonical) = std::fs::canonicalize(file) {
+            return canonical.to_string_lossy().to_string();
+        }
+        let mut normalized = PathBuf::new();
+        for component in Path::new(file).components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir
+                    if matches!(
+                        normalized.components().next_back(),
+                        Some(Component::Normal(_))
+                    ) =>
+                {
+                    normalized.pop();
+                }
+                other => normalized.push(other),
+            }
+        }
+        normalized.to_string_lossy().to_string()
+    }
+
+    /// Without `std` there is no disk to look at, so every name is
+    /// normalized lexically
+    #[cfg(not(feature = "std"))]
+    fn once_key(file: &str) -> String {
+        let mut normalized: Vec<&str> = Vec::new();
+        for component in file.split('/') {
+            match component {
+                "." => {}
+                "" if !normalized.is_empty() => {}
+                ".." if normalized
+                    .last()
+                    .is_some_and(|last| !last.is_empty() && *last != "..") =>
+                {
+                    normalized.pop();
+                }
+                other => normalized.push(other),
+            }
+        }
+        normalized.join("/")
+    }
+
+    /// `header` in the directory of `file`, if it exists there
+    #[cfg(feature = "std")]
+    fn file_next_to(file: &str, header: &str) -> Option<String> {
+        Path::new(file)
+            .parent()
+            .map(|parent_dir| parent_dir.join(header))
+            .filter(|candidate| candidate.exists())
+            .map(|candidate| candidate.to_string_lossy().to_string())
+    }
 
-        let processed = process_result?;
-        self.context.macros = nested.context.macros;
+    /// Without `std` there is no disk to look at
+    #[cfg(not(feature = "std"))]
+    const fn file_next_to(_file: &str, _header: &str) -> Option<String> {
+        None
+    }
 
-        if content.contains("#pragma once") {
-            self.context.included_once.insert(p);
+    /// Directory of the file being processed, if its name looks like a path
+    fn including_dir(file: &str) -> Option<String> {
+        if file.is_empty() || file.starts_with('<') {
+            return None;
         }
+        #[cfg(feature = "std")]
+        let parent = Path::new(file).parent()?.to_string_lossy().into_owned();
+        #[cfg(not(feature = "std"))]
+        let parent = match file.rsplit_once('/') {
+            Some(("", _)) => "/".to_string(),
+            Some((dir, _)) => dir.to_string(),
+            None => String::new(),
+        };
+        if parent.is_empty() {
+            Some(".".to_string())
+        } else {
+            Some(parent)
+        }
+    }
 
-        Ok(Some(processed))
+    /// Open a conditional block at the directive described by `ctx`
+    fn push_conditional(&mut self, active: bool, ctx: &DiagnosticContext) {
+        let origin = SourceLocation {
+            file: ctx.file.clone(),
+            line: ctx.line,
+        };
+        let directive = ctx.source_line.as_deref().unwrap_or_default().trim();
+        self.context.conditional_stack.push(ConditionalState::new(
+            active,
+            origin,
+            directive.to_string(),
+        ));
     }
 
-    fn handle_ifdef(&mut self, rest: &str) {
+    fn handle_ifdef(&mut self, rest: &str, ctx: &DiagnosticContext) -> Result<(), PreprocessError> {
         let name = rest.trim();
+        if self.can_emit_line() {
+            self.check_poisoned_condition(name, ctx)?;
+        }
         let defined = self.is_defined(name);
-        self.context
-            .conditional_stack
-            .push(ConditionalState::new(defined));
+        self.push_conditional(defined, ctx);
+        Ok(())
     }
 
-    fn handle_ifndef(&mut self, rest: &str) {
+    fn handle_ifndef(
+        &mut self,
+        rest: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<(), PreprocessError> {
         let name = rest.trim();
+        if self.can_emit_line() {
+            self.check_poisoned_condition(name, ctx)?;
+        }
         let defined = self.is_defined(name);
-        self.context
-            .conditional_stack
-            .push(ConditionalState::new(!defined));
+        self.push_conditional(!defined, ctx);
+        Ok(())
     }
 
     fn handle_if(
@@ -550,10 +2116,11 @@ impl PreprocessorDriver {
         rest: &str,
         ctx: &DiagnosticContext,
     ) -> Result<Option<String>, PreprocessError> {
-        let evaluated = self.evaluate_expression(rest, ctx)?;
-        self.context
-            .conditional_stack
-            .push(ConditionalState::new(evaluated));
+        if self.can_emit_line() {
+            self.check_poisoned_condition(rest, ctx)?;
+        }
+        let evaluated = self.evaluate_expression("if", rest, ctx)?;
+        self.push_conditional(evaluated, ctx);
         Ok(None)
     }
 
@@ -563,15 +2130,13 @@ impl PreprocessorDriver {
         ctx: &DiagnosticContext,
     ) -> Result<Option<String>, PreprocessError> {
         if self.context.conditional_stack.is_empty() {
-            return Err(self.conditional_error("#elif without #if", ctx));
+            return Err(self.conditional_error("#elif without #if", ctx.start_column(), ctx));
         }
 
         let (already_taken, outer_active) = {
-            let last = self
-                .context
-                .conditional_stack
-                .last()
-                .ok_or_else(|| self.conditional_error("#elif without #if", ctx))?;
+            let last = self.context.conditional_stack.last().ok_or_else(|| {
+                self.conditional_error("#elif without #if", ctx.start_column(), ctx)
+            })?;
             let outer_active = self
                 .context
                 .conditional_stack
@@ -587,7 +2152,8 @@ impl PreprocessorDriver {
                 last.is_active = false;
             }
         } else {
-            let evaluated = self.evaluate_expression(rest, ctx)?;
+            self.check_poisoned_condition(rest, ctx)?;
+            let evaluated = self.evaluate_expression("elif", rest, ctx)?;
             if let Some(last) = self.context.conditional_stack.last_mut() {
                 last.is_active = evaluated;
                 if evaluated {
@@ -600,15 +2166,13 @@ impl PreprocessorDriver {
 
     fn handle_else(&mut self, ctx: &DiagnosticContext) -> Result<Option<String>, PreprocessError> {
         if self.context.conditional_stack.is_empty() {
-            return Err(self.conditional_error("#else without #if", ctx));
+            return Err(self.conditional_error("#else without #if", ctx.start_column(), ctx));
         }
 
         let (already_taken, outer_active) = {
-            let last = self
-                .context
-                .conditional_stack
-                .last()
-                .ok_or_else(|| self.conditional_error("#else without #if", ctx))?;
+            let last = self.context.conditional_stack.last().ok_or_else(|| {
+                self.conditional_error("#else without #if", ctx.start_column(), ctx)
+            })?;
             let outer_active = self
                 .context
                 .conditional_stack
@@ -628,7 +2192,7 @@ impl PreprocessorDriver {
 
     fn handle_endif(&mut self, ctx: &DiagnosticContext) -> Result<Option<String>, PreprocessError> {
         if self.context.conditional_stack.pop().is_none() {
-            return Err(self.conditional_error("#endif without #if", ctx));
+            return Err(self.conditional_error("#endif without #if", ctx.start_column(), ctx));
         }
         Ok(None)
     }
@@ -644,13 +2208,13 @@ impl PreprocessorDriver {
             } else {
                 format!("#error: {rest}")
             };
-            Err(self.generic_error(&msg, ctx))
+            Err(self.generic_error(&msg, ctx.start_column(), ctx))
         } else {
             Ok(None)
         }
     }
 
-    fn handle_warning(&mut self, rest: &str, _ctx: &DiagnosticContext) {
+    fn handle_warning(&mut self, rest: &str, ctx: &DiagnosticContext) {
         if self.can_emit_line()
             && matches!(
                 self.context.compiler,
@@ -662,6 +2226,7 @@ impl PreprocessorDriver {
             } else {
                 format!("#warning: {rest}")
             };
+            self.record_warning(&msg, ctx);
             if let Some(ref handler) = self.context.warning_handler {
                 handler(&msg);
             }
@@ -679,7 +2244,7 @@ impl PreprocessorDriver {
 
         let parts: Vec<&str> = rest.split_whitespace().collect();
         if parts.is_empty() {
-            return Err(self.directive_error("line", ctx));
+            return Err(self.directive_error("line", ctx.operand_column(), ctx));
         }
 
         if let Ok(line_num) = parts[0].parse::<usize>() {
@@ -693,33 +2258,354 @@ impl PreprocessorDriver {
                 };
                 self.context.current_file = filename.to_string();
             }
+            if self.context.passthrough_line_directives {
+                self.context.line_sync = Some(None);
+            }
         }
         Ok(None)
     }
 
+    /// Expand and evaluate the controlling expression of `#directive`,
+    /// reusing the result of an earlier evaluation of the same text while the
+    /// macro table is unchanged
     fn evaluate_expression(
         &mut self,
+        directive: &str,
+        expr: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<bool, PreprocessError> {
+        let key = expr.trim();
+        if let Some(&(generation, value)) = self.context.expression_cache.get(key)
+            && generation == self.context.macro_generation
+        {
+            self.context.stats.expression_cache_hits += 1;
+            return Ok(value);
+        }
+        self.context.stats.expression_cache_misses += 1;
+
+        // Traced expansions must be reported every time
+        self.context.expression_uncacheable = self.context.expansion_trace_handler.is_some();
+        let value = self.evaluate_uncached_expression(directive, expr, ctx)?;
+        if !self.context.expression_uncacheable {
+            self.context
+                .expression_cache
+                .insert(key.to_string(), (self.context.macro_generation, value));
+        }
+        Ok(value)
+    }
+
+    fn evaluate_uncached_expression(
+        &mut self,
+        directive: &str,
         expr: &str,
         ctx: &DiagnosticContext,
     ) -> Result<bool, PreprocessError> {
-        let tokens = engine::tokenize_line(expr);
+        let mut tokens = engine::tokenize_line(expr);
+        if self.assertions_enabled() {
+            tokens = self.resolve_assertion_tests(tokens, ctx);
+        }
         let expanded = self.expand_tokens(&tokens, 0, ctx)?;
+
+        // More `defined` operators after expansion than before means a macro produced one
+        let count_defined = |tokens: &[Token]| {
+            tokens
+                .iter()
+                .filter(|t| matches!(t, Token::Identifier(id) if id == "defined"))
+                .count()
+        };
+        if count_defined(&expanded) > count_defined(&tokens) {
+            let message = "this use of \"defined\" may not be portable";
+            match self.context.defined_from_macro {
+                DefinedFromMacro::Honor => {}
+                DefinedFromMacro::Warn => {
+                    self.context.expression_uncacheable = true;
+                    self.emit_warning(message, ctx);
+                }
+                DefinedFromMacro::Error => {
+                    return Err(self.generic_error(message, ctx.operand_column(), ctx));
+                }
+            }
+        }
+
         let expr_str = engine::tokens_to_string(&expanded);
         let trimmed = expr_str.trim();
+        if trimmed.is_empty() {
+            return Err(self.empty_expression_error(directive, &tokens, ctx));
+        }
 
         self.parse_expression(trimmed, ctx)
     }
 
+    /// Error for an `#if`/`#elif` whose expression is empty, possibly after
+    /// expansion, naming the macros that expanded to nothing
+    fn empty_expression_error(
+        &self,
+        directive: &str,
+        tokens: &[Token],
+        ctx: &DiagnosticContext,
+    ) -> PreprocessError {
+        let empty_macros: Vec<&str> = tokens
+            .iter()
+            .filter_map(|token| match token {
+                Token::Identifier(id) if self.context.macros.contains_key(id) => Some(id.as_str()),
+                _ => None,
+            })
+            .collect();
+        let Some(first) = empty_macros.first() else {
+            return self.conditional_error(
+                &format!("#{directive} with no expression"),
+                ctx.operand_column(),
+                ctx,
+            );
+        };
+
+        let names = empty_macros
+            .iter()
+            .map(|name| format!("'{name}'"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let verb = if empty_macros.len() == 1 {
+            "expands"
+        } else {
+            "expand"
+        };
+        self.conditional_error(
+            &format!("#{directive} with no expression ({names} {verb} to nothing)"),
+            ctx.token_column(first)
+                .unwrap_or_else(|| ctx.operand_column()),
+            ctx,
+        )
+    }
+
     fn handle_pragma(&mut self, rest: &str) -> Option<String> {
         let trimmed = rest.trim();
         if trimmed == "once" {
-            self.context
-                .included_once
-                .insert(self.context.current_file.clone());
-            None
-        } else {
-            Some(format!("#pragma {rest}"))
+            let key = self
+                .context
+                .file_identity
+                .clone()
+                .unwrap_or_else(|| Self::once_key(&self.context.current_file));
+            self.context.included_once.insert(key);
+            return None;
+        }
+
+        let mut words = trimmed.split_whitespace();
+        let namespace = words.next();
+        if namespace == Some("STDC") {
+            self.check_stdc_pragma(words);
+        } else if let Some(arguments) = trimmed
+            .strip_prefix("pack")
+            .filter(|args| args.is_empty() || args.starts_with(['(', ' ', '\t']))
+        {
+            self.check_pack_pragma(arguments.trim());
+        } else if namespace == Some("GCC") {
+            match words.next() {
+                Some("poison") => self.context.poisoned.extend(words.map(str::to_string)),
+                Some("system_header") if self.context.include_stack.is_empty() => {
+                    let ctx = DiagnosticContext::new(
+                        self.context.current_file.clone(),
+                        self.context.current_line,
+                        None,
+                    );
+                    self.emit_warning("#pragma system_header ignored outside include file", &ctx);
+                }
+                Some("system_header") => self.context.in_system_header = true,
+                _ => {}
+            }
+        }
+        Some(format!("#pragma {rest}"))
+    }
+
+    /// Warn about a malformed or unknown `#pragma STDC` such as
+    /// `FP_CONTRACT`, `FENV_ACCESS` or `CX_LIMITED_RANGE`
+    ///
+    /// The pragmas affect the compiler rather than the preprocessor, so they
+    /// are passed through either way. As the standard requires, their
+    /// operands are never macro-expanded, so a macro named `ON` doesn't
+    /// change them.
+    fn check_stdc_pragma<'w>(&self, mut words: impl Iterator<Item = &'w str>) {
+        let message = match (words.next(), words.next(), words.next()) {
+            (
+                Some("FP_CONTRACT" | "FENV_ACCESS" | "CX_LIMITED_RANGE"),
+                Some("ON" | "OFF" | "DEFAULT"),
+                None,
+            ) => return,
+            // C23 rounding direction pragmas take a direction such as FE_UPWARD
+            (Some("FENV_ROUND" | "FENV_DEC_ROUND"), Some(_), None) => return,
+            (Some(name @ ("FP_CONTRACT" | "FENV_ACCESS" | "CX_LIMITED_RANGE")), _, _) => {
+                format!("expected ON, OFF or DEFAULT after #pragma STDC {name}")
+            }
+            (Some(name), _, _) => format!("unknown pragma STDC {name}"),
+            (None, _, _) => "missing name after #pragma STDC".to_string(),
+        };
+        let ctx = DiagnosticContext::new(
+            self.context.current_file.clone(),
+            self.context.current_line,
+            None,
+        );
+        self.emit_warning(&message, &ctx);
+    }
+
+    /// Validate `#pragma pack` and track its push/pop stack
+    ///
+    /// The pragma sets struct alignment for the compiler and is passed through
+    /// unchanged; a malformed one or a `pop` without a matching `push` only
+    /// warns.
+    fn check_pack_pragma(&mut self, arguments: &str) {
+        let ctx = DiagnosticContext::new(
+            self.context.current_file.clone(),
+            self.context.current_line,
+            None,
+        );
+        let Some(inner) = arguments
+            .strip_prefix('(')
+            .and_then(|args| args.strip_suffix(')'))
+        else {
+            self.emit_warning("expected '(' after #pragma pack", &ctx);
+            return;
+        };
+        let args: Vec<&str> = inner.split(',').map(str::trim).collect();
+        let (action, rest) = match args.as_slice() {
+            [""] | ["show"] => return,
+            [action @ ("push" | "pop"), rest @ ..] => (*action, rest),
+            [alignment] => {
+                self.check_pack_alignment(alignment, &ctx);
+                return;
+            }
+            _ => {
+                self.emit_warning("malformed #pragma pack", &ctx);
+                return;
+            }
+        };
+
+        let (identifier, alignment) = match rest {
+            [] => (None, None),
+            [arg] if arg.starts_with(|c: char| c.is_ascii_digit()) => (None, Some(*arg)),
+            [name] => (Some(*name), None),
+            [name, alignment] => (Some(*name), Some(*alignment)),
+            _ => {
+                self.emit_warning("malformed #pragma pack", &ctx);
+                return;
+            }
+        };
+        if let Some(name) = identifier
+            && !engine::is_valid_identifier(name)
+        {
+            self.emit_warning(
+                &format!(
+                    "expected an identifier or alignment in #pragma pack({action}), found '{name}'"
+                ),
+                &ctx,
+            );
+            return;
+        }
+        if let Some(alignment) = alignment {
+            self.check_pack_alignment(alignment, &ctx);
+        }
+
+        let stack = &mut self.context.pack_stack;
+        if action == "push" {
+            stack.push((identifier.map(str::to_string), ctx.location()));
+            return;
+        }
+        let depth = match identifier {
+            Some(name) => stack
+                .iter()
+                .rposition(|(pushed, _)| pushed.as_deref() == Some(name)),
+            None => stack.len().checked_sub(1),
+        };
+        match depth {
+            Some(depth) => stack.truncate(depth),
+            None => {
+                let pop =
+                    identifier.map_or_else(|| "pop".to_string(), |name| format!("pop, {name}"));
+                self.emit_warning(&format!("#pragma pack({pop}) without matching push"), &ctx);
+            }
+        }
+    }
+
+    /// Warn unless `alignment` is a power of two
+    fn check_pack_alignment(&self, alignment: &str, ctx: &DiagnosticContext) {
+        if !alignment.parse::<usize>().is_ok_and(usize::is_power_of_two) {
+            self.emit_warning(
+                &format!("alignment '{alignment}' in #pragma pack is not a power of two"),
+                ctx,
+            );
+        }
+    }
+
+    /// Warn about each `#pragma pack(push)` left open at the end of the input
+    fn warn_about_open_packs(&mut self) {
+        for (_, location) in core::mem::take(&mut self.context.pack_stack) {
+            let ctx = DiagnosticContext::new(location.file, location.line, None);
+            self.emit_warning("#pragma pack(push) without matching pop", &ctx);
+        }
+    }
+
+    /// Handle `#ident "string"` and `#sccs "string"`
+    ///
+    /// The directive is passed through to the output so the compiler can
+    /// embed the string in the object file.
+    fn handle_ident(
+        &self,
+        cmd: &str,
+        rest: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<Option<String>, PreprocessError> {
+        if !self.can_emit_line() {
+            return Ok(None);
+        }
+
+        let tokens = engine::tokenize_line(rest.trim());
+        match tokens.as_slice() {
+            [Token::StringLiteral(literal)] => Ok(Some(format!("#{cmd} {literal}"))),
+            _ => Err(self.generic_error(
+                &format!("invalid #{cmd} directive: expected a string literal"),
+                ctx.operand_column(),
+                ctx,
+            )),
+        }
+    }
+
+    /// Reject identifiers banned by `#pragma GCC poison`
+    ///
+    /// Only tokens written in the source are checked: a macro defined before
+    /// the identifier was poisoned may still expand to it. System headers are
+    /// exempt.
+    fn check_poisoned(
+        &self,
+        tokens: &[Token],
+        ctx: &DiagnosticContext,
+    ) -> Result<(), PreprocessError> {
+        if self.context.poisoned.is_empty() || self.context.in_system_header {
+            return Ok(());
+        }
+        for token in tokens {
+            if let Token::Identifier(id) = token
+                && self.context.poisoned.contains(id)
+            {
+                return Err(self.generic_error(
+                    &format!("attempt to use poisoned \"{id}\""),
+                    ctx.token_column(id).unwrap_or_else(|| ctx.start_column()),
+                    ctx,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reject poisoned identifiers in the operands of a conditional directive
+    ///
+    /// Only called for directives in active groups, as GCC does.
+    fn check_poisoned_condition(
+        &self,
+        operands: &str,
+        ctx: &DiagnosticContext,
+    ) -> Result<(), PreprocessError> {
+        if self.context.poisoned.is_empty() {
+            return Ok(());
         }
+        self.check_poisoned(&engine::tokenize_line(operands), ctx)
     }
 
     /// Parse a preprocessor expression with full operator support
@@ -731,20 +2617,61 @@ impl PreprocessorDriver {
         expr: &str,
         ctx: &DiagnosticContext,
     ) -> Result<bool, PreprocessError> {
-        let tokens = engine::tokenize_expression(expr)?;
+        // Tokenizer errors carry the offset in `expr`; place them in the source
+        let tokens = engine::tokenize_expression(expr).map_err(|mut err| {
+            err.column = Some(err.column.map_or_else(
+                || ctx.operand_column(),
+                |offset| ctx.expression_column(expr, offset),
+            ));
+            err.file.clone_from(&ctx.file);
+            err.line = ctx.line;
+            err.source_line.clone_from(&ctx.source_line);
+            err
+        })?;
+
+        let tokens = engine::resolve_expression_keywords(tokens, self.context.standard);
+        for (i, token) in tokens.iter().enumerate() {
+            if let ExprToken::Identifier(id) = token
+                && id != "defined"
+                && !engine::is_defined_operand(&tokens, i)
+                && (id == "true" || id == "false" || self.warns_undef(id))
+            {
+                self.context.expression_uncacheable = true;
+                self.emit_warning(&format!("\"{id}\" is not defined, evaluates to 0"), ctx);
+            }
+        }
         let result = self.evaluate_expression_tokens(&tokens, ctx)?;
         Ok(result != 0)
     }
 
+    /// Whether `id`, left in an `#if` expression after expansion, should get
+    /// a [`PreprocessorConfig::warn_undef`] warning
+    fn warns_undef(&self, id: &str) -> bool {
+        self.context.warn_undef
+            && !self.is_defined(id)
+            && self
+                .context
+                .expr_identifier_resolver
+                .as_ref()
+                .is_none_or(|resolve| resolve(id).is_none())
+    }
+
     fn evaluate_expression_tokens(
         &self,
         tokens: &[ExprToken],
         ctx: &DiagnosticContext,
     ) -> Result<i64, PreprocessError> {
-        let result = engine::evaluate_expression_tokens(tokens, |id| self.is_defined(id));
+        let resolver = self.context.expr_identifier_resolver.as_deref();
+        let result = engine::evaluate_expression_tokens(
+            tokens,
+            ExprLookup(
+                |id: &str| self.is_defined(id),
+                |id: &str| resolver.and_then(|resolve| resolve(id)),
+            ),
+        );
         match result {
             Ok(val) => Ok(val),
-            Err(msg) => Err(self.generic_error(&msg, ctx)),
+            Err(msg) => Err(self.generic_error(&msg, ctx.operand_column(), ctx)),
         }
     }
 
@@ -765,13 +2692,29 @@ impl PreprocessorDriver {
         depth: usize,
         ctx: &DiagnosticContext,
     ) -> Result<Vec<Token>, PreprocessError> {
+        self.expand_token_list(tokens, depth, false, ctx)
+    }
+
+    /// Rescan the replacement list of a macro for further expansion
+    fn rescan_replacement(
+        &mut self,
+        tokens: &[Token],
+        depth: usize,
+        ctx: &DiagnosticContext,
+    ) -> Result<Vec<Token>, PreprocessError> {
+        self.expand_token_list(tokens, depth, true, ctx)
+    }
+
+    fn expand_token_list(
+        &mut self,
+        tokens: &[Token],
+        depth: usize,
+        replacement: bool,
+        ctx: &DiagnosticContext,
+    ) -> Result<Vec<Token>, PreprocessError> {
+        self.charge_budget()?;
         if depth > self.context.recursion_limit {
-            return Err(PreprocessError::recursion_limit_exceeded(
-                self.context.current_file.clone(),
-                self.context.current_line,
-                "too deep".to_string(),
-            )
-            .with_source_line(ctx.source_line.clone().unwrap_or_default()));
+            return Err(self.recursion_limit_error(ctx));
         }
 
         let mut out: Vec<Token> = Vec::with_capacity(tokens.len());
@@ -827,13 +2770,16 @@ impl PreprocessorDriver {
                         continue;
                     }
 
-                    if let Some(token) = engine::expand_predefined_macro(&self.context, name) {
+                    if let Some(token) = engine::expand_predefined_macro(&mut self.context, name) {
+                        // The value changes from line to line
+                        self.context.expression_uncacheable = true;
                         out.push(token);
                         i += 1;
                     } else if self.context.macros.contains_key(name)
                         && !self.context.disabled_macros.contains(name)
                     {
                         let mac = self.context.macros[name].clone();
+                        let start = out.len();
                         i = self.handle_macro_invocation(
                             &mac,
                             name,
@@ -841,10 +2787,33 @@ impl PreprocessorDriver {
                                 tokens,
                                 i,
                                 depth,
+                                replacement,
                                 out: &mut out,
                                 ctx,
                             },
                         )?;
+                        i = self.expand_trailing_invocation(
+                            tokens,
+                            i,
+                            depth,
+                            replacement,
+                            &mut out,
+                            ctx,
+                        )?;
+                        if out.len().saturating_sub(start) > self.context.max_expansion_tokens {
+                            return Err(self.limit_error(
+                                &format!(
+                                    "expansion of '{name}' produced more than {} tokens",
+                                    self.context.max_expansion_tokens
+                                ),
+                                ctx,
+                            ));
+                        }
+                    } else if self.context.macros.contains_key(name) {
+                        // A macro name met during its own expansion is
+                        // painted: it's never expanded, even on a later rescan
+                        out.push(Token::Other(name.clone()));
+                        i += 1;
                     } else {
                         out.push(tokens[i].clone());
                         i += 1;
@@ -859,6 +2828,98 @@ impl PreprocessorDriver {
         Ok(out)
     }
 
+    /// Expand a function-like macro name left at the end of an expansion
+    /// whose arguments follow the expansion in `tokens`, as in `ID(F)(1)`
+    ///
+    /// Returns the index in `tokens` after the last call consumed.
+    fn expand_trailing_invocation(
+        &mut self,
+        tokens: &[Token],
+        mut i: usize,
+        depth: usize,
+        replacement: bool,
+        out: &mut Vec<Token>,
+        ctx: &DiagnosticContext,
+    ) -> Result<usize, PreprocessError> {
+        loop {
+            let Some(last) = out
+                .iter()
+                .rposition(|t| !matches!(t, Token::Other(s) if s.chars().all(char::is_whitespace)))
+            else {
+                return Ok(i);
+            };
+            let Token::Identifier(callee) = &out[last] else {
+                return Ok(i);
+            };
+            if self.context.disabled_macros.contains(callee) {
+                return Ok(i);
+            }
+            let Some(mac) = self
+                .context
+                .macros
+                .get(callee)
+                .filter(|mac| mac.params.is_some())
+                .cloned()
+            else {
+                return Ok(i);
+            };
+            let open = self.find_next_non_whitespace(tokens, i);
+            if !matches!(tokens.get(open), Some(Token::Other(s)) if s.starts_with('(')) {
+                return Ok(i);
+            }
+
+            let callee = callee.clone();
+            out.truncate(last);
+            // The call is parsed in place: the invocation only looks past the
+            // name's index, and the token before `(` is never itself a `(`
+            i = self.handle_macro_invocation(
+                &mac,
+                &callee,
+                MacroExpansionParams {
+                    tokens,
+                    i: open - 1,
+                    depth,
+                    replacement,
+                    out,
+                    ctx,
+                },
+            )?;
+        }
+    }
+
+    /// Build the error for expansion nested deeper than the recursion limit,
+    /// naming the chain of macros being expanded and any cycle in it
+    fn recursion_limit_error(&self, ctx: &DiagnosticContext) -> PreprocessError {
+        // Names shown at each end of a long chain
+        const SHOWN: usize = 4;
+
+        let limit = self.context.recursion_limit;
+        let stack = &self.context.expansion_stack;
+        let mut details = format!("expansion nested more than {limit} levels");
+        if !stack.is_empty() {
+            let chain = if stack.len() > 2 * SHOWN {
+                format!(
+                    "{} -> ... -> {}",
+                    stack[..SHOWN].join(" -> "),
+                    stack[stack.len() - SHOWN..].join(" -> ")
+                )
+            } else {
+                stack.join(" -> ")
+            };
+            details.push_str(&format!(" while expanding {chain}"));
+        }
+        if let Some(cycle) = engine::expansion_cycle(stack) {
+            details.push_str(&format!(" (macro cycle {})", cycle.join(" -> ")));
+        }
+        PreprocessError::recursion_limit_exceeded(
+            self.context.current_file.clone(),
+            self.context.current_line,
+            details,
+        )
+        .with_source_line(ctx.source_line.clone().unwrap_or_default())
+        .with_expansion_stack(stack.clone())
+    }
+
     fn handle_macro_invocation(
         &mut self,
         mac: &Macro,
@@ -870,30 +2931,123 @@ impl PreprocessorDriver {
             let is_function_like_invocation = next_non_whitespace < params.tokens.len()
                 && matches!(&params.tokens[next_non_whitespace], Token::Other(s) if s.trim_start().starts_with('(') || s == "(");
             if is_function_like_invocation {
-                self.handle_function_like_macro(mac, name, params)
+                self.context.expansion_stack.push(name.to_string());
+                let result = self.handle_function_like_macro(mac, name, params);
+                self.context.expansion_stack.pop();
+                result
             } else {
-                // Function-like macro without ( is not expanded
+                // Function-like macro without ( is not expanded. At the end
+                // of a replacement list the arguments may still follow the
+                // macro call, so only a name followed by something else is
+                // known to be bare.
+                if self.context.warn_bare_function_macro
+                    && !(params.replacement && next_non_whitespace == params.tokens.len())
+                {
+                    self.warn_bare_function_macro(name, mac, params.ctx);
+                }
                 params.out.push(Token::Identifier(name.to_string()));
                 Ok(params.i + 1)
             }
         } else {
             self.context.disabled_macros.insert(name.to_string());
-            let result = self.handle_object_like_macro(mac, params.depth, params.out, params.ctx);
+            self.context.expansion_stack.push(name.to_string());
+            let result =
+                self.handle_object_like_macro(mac, name, params.depth, params.out, params.ctx);
+            self.context.expansion_stack.pop();
             self.context.disabled_macros.remove(name);
             result?;
             Ok(params.i + 1)
         }
     }
 
+    /// Warn that a function-like macro name has no argument list
+    fn warn_bare_function_macro(&self, name: &str, mac: &Macro, ctx: &DiagnosticContext) {
+        let defined_at = mac
+            .definition_location
+            .as_ref()
+            .map(|(file, line)| format!(" (defined at {file}:{line})"))
+            .unwrap_or_default();
+        self.emit_warning(
+            &format!(
+                "function-like macro '{name}' used without arguments is not expanded{defined_at}"
+            ),
+            ctx,
+        );
+    }
+
+    /// Apply `##` pasting, diagnosing pastes that don't form a valid token
+    ///
+    /// Pasting a dynamic predefined macro such as `__LINE__` uses its name
+    /// rather than its value, which is almost never intended, so it is
+    /// reported with a hint to add a level of indirection.
+    fn paste_tokens(
+        &self,
+        tokens: &[Token],
+        ctx: &DiagnosticContext,
+    ) -> Result<Vec<Token>, PreprocessError> {
+        for (idx, token) in tokens.iter().enumerate() {
+            if let Token::Identifier(name) = token
+                && engine::DYNAMIC_MACROS.contains(&name.as_str())
+                && engine::is_paste_operand(tokens, idx)
+            {
+                self.emit_warning(
+                    &format!(
+                        "pasting \"{name}\" uses its name, not its value; \
+                         expand it through another macro before pasting"
+                    ),
+                    ctx,
+                );
+            }
+        }
+        let mut invalid = Vec::new();
+        let pasted = engine::apply_token_pasting(tokens, |left, right| {
+            invalid.push(format!(
+                "pasting \"{left}\" and \"{right}\" does not give a valid preprocessing token"
+            ));
+        });
+        for message in invalid {
+            self.conformance_diagnostic(&message, ctx)?;
+        }
+        Ok(pasted)
+    }
+
+    /// Count an expansion and report it to the configured trace handler, if any
+    fn trace_expansion(
+        &mut self,
+        name: &str,
+        args: Option<&[Vec<Token>]>,
+        result: &[Token],
+        depth: usize,
+        ctx: &DiagnosticContext,
+    ) {
+        self.context.stats.macro_expansions += 1;
+        if let Some(handler) = &self.context.expansion_trace_handler {
+            handler(&ExpansionEvent {
+                name: name.to_string(),
+                arguments: args.map(|args| {
+                    args.iter()
+                        .map(|arg| engine::tokens_to_string(arg).trim().to_string())
+                        .collect()
+                }),
+                result: engine::tokens_to_string(result).trim().to_string(),
+                file: ctx.file.clone(),
+                line: ctx.line,
+                depth,
+            });
+        }
+    }
+
     fn handle_object_like_macro(
         &mut self,
         mac: &Macro,
+        name: &str,
         depth: usize,
         out: &mut Vec<Token>,
         ctx: &DiagnosticContext,
     ) -> Result<(), PreprocessError> {
-        let pasted = engine::apply_token_pasting(&mac.body);
-        let expanded = self.expand_tokens(&pasted, depth + 1, ctx)?;
+        let pasted = self.paste_tokens(&mac.body, ctx)?;
+        self.trace_expansion(name, None, &pasted, depth, ctx);
+        let expanded = self.rescan_replacement(&pasted, depth + 1, ctx)?;
         out.extend(expanded);
         Ok(())
     }
@@ -904,9 +3058,6 @@ impl PreprocessorDriver {
         name: &str,
         params: MacroExpansionParams,
     ) -> Result<usize, PreprocessError> {
-        // Disable the macro at the very start to prevent recursion during argument parsing
-        self.context.disabled_macros.insert(name.to_string());
-
         let paren_token_index =
             params
                 .tokens
@@ -924,36 +3075,23 @@ impl PreprocessorDriver {
                 });
 
         let Some(paren_idx) = paren_token_index else {
-            self.context.disabled_macros.remove(name);
             return Ok(params.i + 1);
         };
 
-        let (args, end_idx) = {
-            let parse_result =
-                self.parse_macro_arguments(params.tokens, paren_idx, mac, params.ctx);
-            match parse_result {
-                Ok(args) => args,
-                Err(e) => {
-                    self.context.disabled_macros.remove(name);
-                    return Err(e);
-                }
-            }
-        };
+        let (args, end_idx) =
+            self.parse_macro_arguments(params.tokens, paren_idx, mac, params.ctx)?;
+        self.validate_macro_arguments(mac, name, &args, params.ctx)?;
 
-        let substituted = {
-            let replace_result =
-                self.replace_macro_parameters(mac, name, &args, params.depth + 1, params.ctx);
-            match replace_result {
-                Ok(substituted) => substituted,
-                Err(e) => {
-                    self.context.disabled_macros.remove(name);
-                    return Err(e);
-                }
-            }
-        };
+        // The macro is still enabled while its arguments are expanded, so
+        // nested calls such as `INC(INC(1))` expand
+        let substituted =
+            self.replace_macro_parameters(mac, name, &args, params.depth + 1, params.ctx)?;
+        let pasted = self.paste_tokens(&substituted, params.ctx)?;
+        self.trace_expansion(name, Some(&args), &pasted, params.depth, params.ctx);
 
-        let pasted = engine::apply_token_pasting(&substituted);
-        let expanded_res = self.expand_tokens(&pasted, params.depth + 1, params.ctx);
+        // Disable the macro while rescanning its replacement to prevent recursion
+        self.context.disabled_macros.insert(name.to_string());
+        let expanded_res = self.rescan_replacement(&pasted, params.depth + 1, params.ctx);
 
         // Clean up disabled_macros before returning or propagating error
         self.context.disabled_macros.remove(name);
@@ -964,6 +3102,46 @@ impl PreprocessorDriver {
         Ok(end_idx)
     }
 
+    /// Check that a function-like macro call has one argument per parameter
+    ///
+    /// A variadic macro needs at least one argument per named parameter; any
+    /// further arguments become `__VA_ARGS__`.
+    fn validate_macro_arguments(
+        &self,
+        mac: &Macro,
+        name: &str,
+        args: &[Vec<Token>],
+        ctx: &DiagnosticContext,
+    ) -> Result<(), PreprocessError> {
+        let expected = mac.params.as_ref().map_or(0, Vec::len);
+        // `F()` parses as a single empty argument, which is zero arguments
+        // for a macro without named parameters
+        let given = if expected == 0 && matches!(args, [arg] if arg.is_empty()) {
+            0
+        } else {
+            args.len()
+        };
+
+        let details = if given < expected {
+            let at_least = if mac.is_variadic { "at least " } else { "" };
+            format!(
+                "macro \"{name}\" requires {at_least}{expected} arguments, but only {given} given"
+            )
+        } else if given > expected && !mac.is_variadic {
+            format!("macro \"{name}\" passed {given} arguments, but takes just {expected}")
+        } else {
+            return Ok(());
+        };
+
+        let column = ctx.token_column(name).unwrap_or_else(|| ctx.start_column());
+        let mut error = PreprocessError::macro_arg_mismatch(ctx.file.clone(), ctx.line, details)
+            .with_column(column);
+        if let Some(ref source) = ctx.source_line {
+            error = error.with_source_line(source.clone());
+        }
+        Err(error)
+    }
+
     fn parse_macro_arguments(
         &mut self,
         tokens: &[Token],
@@ -999,6 +3177,16 @@ impl PreprocessorDriver {
                                     if paren_depth == 1 {
                                         args.push(engine::trim_token_whitespace(current_arg));
                                         current_arg = Vec::new();
+                                        // At least one more argument follows the comma
+                                        if args.len() >= self.context.max_macro_args {
+                                            return Err(self.limit_error(
+                                                &format!(
+                                                    "macro call has more than {} arguments",
+                                                    self.context.max_macro_args
+                                                ),
+                                                ctx,
+                                            ));
+                                        }
                                     } else {
                                         current_arg.push(Token::Other(",".to_string()));
                                     }
@@ -1045,6 +3233,20 @@ impl PreprocessorDriver {
 
         // Helpers
         let is_param = |id: &str| params_list.iter().position(|p| p == id);
+        // A `##` written in an argument is an ordinary token, not the paste
+        // operator, so it is split in two to keep pasting from seeing it
+        let shield = |tokens: Vec<Token>| {
+            tokens.into_iter().flat_map(|token| match token {
+                Token::Other(s) if s.trim() == "##" => {
+                    let at = s.find("##").unwrap_or_default() + 1;
+                    vec![
+                        Token::Other(s[..at].to_string()),
+                        Token::Other(s[at..].to_string()),
+                    ]
+                }
+                token => vec![token],
+            })
+        };
         let escape_arg = |ts: &[Token]| {
             ts.iter()
                 .map(engine::token_to_string)
@@ -1053,7 +3255,7 @@ impl PreprocessorDriver {
                 .replace('"', "\\\"")
         };
 
-        while let Some((_idx, body_t)) = body_iter.next() {
+        while let Some((idx, body_t)) = body_iter.next() {
             match body_t {
                 // # param stringification
                 Token::Other(s) if s.trim() == "#" => {
@@ -1077,21 +3279,38 @@ impl PreprocessorDriver {
                 }
 
                 Token::Identifier(id) => {
+                    // Arguments are fully expanded before substitution,
+                    // except where they are pasted with `##`
+                    let pasted = engine::is_paste_operand(&mac.body, idx);
                     if let Some(pos) = is_param(id) {
-                        let expanded = self.expand_tokens(&args[pos], depth + 1, ctx)?;
-                        replaced.extend(expanded);
+                        if pasted {
+                            replaced.extend(shield(engine::paste_operand(args[pos].clone())));
+                        } else {
+                            let expanded = self.expand_tokens(&args[pos], depth + 1, ctx)?;
+                            replaced.extend(shield(expanded));
+                        }
                         continue;
                     }
 
                     if id == "__VA_ARGS__" && mac.is_variadic {
                         let start = params_list.len();
-                        for idx in start..args.len() {
-                            let expanded = self.expand_tokens(&args[idx], depth + 1, ctx)?;
-                            replaced.extend(expanded);
-                            if idx + 1 < args.len() {
-                                replaced.push(Token::Other(",".into()));
+                        let mut variadic = Vec::new();
+                        for arg_idx in start..args.len() {
+                            if pasted {
+                                variadic.extend(args[arg_idx].iter().cloned());
+                            } else {
+                                let expanded =
+                                    self.expand_tokens(&args[arg_idx], depth + 1, ctx)?;
+                                variadic.extend(expanded);
                             }
+                            if arg_idx + 1 < args.len() {
+                                variadic.push(Token::Other(",".into()));
+                            }
+                        }
+                        if pasted {
+                            variadic = engine::paste_operand(variadic);
                         }
+                        replaced.extend(shield(variadic));
                         continue;
                     }
 
@@ -1,10 +1,16 @@
-use std::iter::Peekable;
-use std::str::Chars;
-
-use crate::config::LineEnding;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::iter::Peekable;
+use core::str::Chars;
+
+use crate::config::{IncludeKind, Language, LineEnding, Standard};
 use crate::context::PreprocessorContext;
 use crate::error::PreprocessError;
-use crate::token::{ExprToken, Token, is_identifier_continue, is_identifier_start};
+pub(crate) use crate::expr::evaluate_expression_tokens;
+use crate::expr::{self, ExprToken};
+use crate::token::{Token, is_identifier_continue, is_identifier_start};
 
 /// Pure preprocessing engine containing stateless logic
 ///
@@ -89,6 +95,10 @@ fn parse_whitespace(it: &mut Peekable<Chars>) -> Token {
 }
 
 /// Tokenize a line of source code into tokens
+///
+/// Whitespace is kept as tokens, so for a line without comments
+/// `tokens_to_string(&tokenize_line(line)) == line`. Comments become a single
+/// space.
 pub fn tokenize_line(line: &str) -> Vec<Token> {
     let mut tokens = Vec::new();
     let mut it = line.chars().peekable();
@@ -124,509 +134,87 @@ pub fn tokenize_line(line: &str) -> Vec<Token> {
     tokens
 }
 
-/// Parse a number token from the character iterator
-fn parse_number(ch: char, chars: &mut Peekable<Chars>) -> Result<ExprToken, PreprocessError> {
-    let mut num = String::new();
-    num.push(ch);
-    while let Some(&d) = chars.peek() {
-        if d.is_ascii_digit() {
-            num.push(d);
-            chars.next();
-        } else {
-            break;
-        }
-    }
-
-    num.parse::<i64>().map(ExprToken::Number).map_err(|_| {
-        PreprocessError::other(
-            "<expression>".to_string(),
-            0,
-            format!("Invalid number: {num}"),
-        )
-    })
-}
-
-/// Parse an identifier token from the character iterator
-fn parse_expression_identifier(ch: char, chars: &mut Peekable<Chars>) -> ExprToken {
-    let mut ident = String::new();
-    ident.push(ch);
-    while let Some(&c) = chars.peek() {
-        if c.is_alphanumeric() || c == '_' {
-            ident.push(c);
-            chars.next();
-        } else {
-            break;
-        }
-    }
-    ExprToken::Identifier(ident)
-}
-
-/// Parse a two-character operator from the character iterator
-fn parse_two_char_operator(
-    first: char,
-    chars: &mut Peekable<Chars>,
-) -> Result<ExprToken, PreprocessError> {
-    match first {
-        '!' => {
-            if let Some(&'=') = chars.peek() {
-                chars.next();
-                Ok(ExprToken::NotEqual)
-            } else {
-                Ok(ExprToken::Not)
-            }
-        }
-        '=' => {
-            if let Some(&'=') = chars.peek() {
-                chars.next();
-                Ok(ExprToken::Equal)
-            } else {
-                Err(PreprocessError::other(
-                    "<expression>".to_string(),
-                    0,
-                    "Invalid operator: =".to_string(),
-                ))
-            }
-        }
-        '<' => {
-            if let Some(&'=') = chars.peek() {
-                chars.next();
-                Ok(ExprToken::LessEqual)
-            } else if let Some(&'<') = chars.peek() {
-                chars.next();
-                Ok(ExprToken::ShiftLeft)
-            } else {
-                Ok(ExprToken::Less)
-            }
-        }
-        '>' => {
-            if let Some(&'=') = chars.peek() {
-                chars.next();
-                Ok(ExprToken::GreaterEqual)
-            } else if let Some(&'>') = chars.peek() {
-                chars.next();
-                Ok(ExprToken::ShiftRight)
-            } else {
-                Ok(ExprToken::Greater)
-            }
-        }
-        '&' => {
-            if let Some(&'&') = chars.peek() {
-                chars.next();
-                Ok(ExprToken::And)
-            } else {
-                Ok(ExprToken::BitAnd)
-            }
-        }
-        '|' => {
-            if let Some(&'|') = chars.peek() {
-                chars.next();
-                Ok(ExprToken::Or)
-            } else {
-                Ok(ExprToken::BitOr)
-            }
-        }
-        _ => Err(PreprocessError::other(
-            "<expression>".to_string(),
-            0,
-            format!("Invalid operator: {first}"),
-        )),
-    }
-}
-
-/// Tokenize expression string into expression tokens
-pub fn tokenize_expression(expr: &str) -> Result<Vec<ExprToken>, PreprocessError> {
+/// Tokenize a line like [`tokenize_line`], pairing each token with the
+/// 1-based character column where it starts in `line`
+///
+/// Columns account for comments, which [`tokenize_line`] collapses to a
+/// single space.
+pub fn tokenize_line_with_columns(line: &str) -> Vec<(usize, Token)> {
+    let mut rest = line;
+    let mut column = 1;
     let mut tokens = Vec::new();
-    let mut chars = expr.chars().peekable();
-
-    while let Some(ch) = chars.next() {
-        let token = match ch {
-            '0'..='9' => parse_number(ch, &mut chars)?,
-            'a'..='z' | 'A'..='Z' | '_' => parse_expression_identifier(ch, &mut chars),
-            '(' => ExprToken::LParen,
-            ')' => ExprToken::RParen,
-            '~' => ExprToken::BitNot,
-            '^' => ExprToken::BitXor,
-            '+' => ExprToken::Plus,
-            '-' => ExprToken::Minus,
-            '*' => ExprToken::Multiply,
-            '/' => ExprToken::Divide,
-            '%' => ExprToken::Modulo,
-            c if c.is_whitespace() => continue,
-            '!' | '=' | '<' | '>' | '&' | '|' => parse_two_char_operator(ch, &mut chars)?,
-            _ => {
-                return Err(PreprocessError::other(
-                    "<expression>".to_string(),
-                    0,
-                    format!("Invalid character: {ch}"),
-                ));
-            }
+    for token in tokenize_line(line) {
+        let text = token_to_string(&token);
+        let len = if rest.starts_with(text) {
+            text.len()
+        } else if rest.starts_with("//") {
+            rest.len()
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            comment.find("*/").map_or(rest.len(), |end| end + 4)
+        } else {
+            rest.chars().next().map_or(0, char::len_utf8)
         };
-        tokens.push(token);
+        tokens.push((column, token));
+        column += rest[..len].chars().count();
+        rest = &rest[len..];
     }
-
-    Ok(tokens)
+    tokens
 }
 
-/// Evaluate a preprocessor expression from tokens
+/// Tokenize expression string into expression tokens
 ///
-/// # Errors
-/// Returns an error message if the expression is malformed.
-pub fn evaluate_expression_tokens<F>(tokens: &[ExprToken], is_defined: F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut pos = 0;
-    let result = parse_or(tokens, &mut pos, &is_defined)?;
-    if pos != tokens.len() {
-        return Err("Unexpected tokens at end of expression".to_string());
-    }
-    Ok(result)
-}
-
-fn parse_or<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_and(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::Or => {
-                *pos += 1;
-                let right = parse_and(tokens, pos, is_defined)?;
-                left = i64::from(left != 0 || right != 0);
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_and<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_bit_or(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::And => {
-                *pos += 1;
-                let right = parse_bit_or(tokens, pos, is_defined)?;
-                left = i64::from(left != 0 && right != 0);
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_bit_or<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_bit_xor(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::BitOr => {
-                *pos += 1;
-                let right = parse_bit_xor(tokens, pos, is_defined)?;
-                left |= right;
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_bit_xor<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_bit_and(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::BitXor => {
-                *pos += 1;
-                let right = parse_bit_and(tokens, pos, is_defined)?;
-                left ^= right;
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_bit_and<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_equality(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::BitAnd => {
-                *pos += 1;
-                let right = parse_equality(tokens, pos, is_defined)?;
-                left &= right;
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_equality<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_comparison(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::Equal => {
-                *pos += 1;
-                let right = parse_comparison(tokens, pos, is_defined)?;
-                left = i64::from(left == right);
-            }
-            ExprToken::NotEqual => {
-                *pos += 1;
-                let right = parse_comparison(tokens, pos, is_defined)?;
-                left = i64::from(left != right);
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_comparison<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_shift(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::Less => {
-                *pos += 1;
-                let right = parse_shift(tokens, pos, is_defined)?;
-                left = i64::from(left < right);
-            }
-            ExprToken::LessEqual => {
-                *pos += 1;
-                let right = parse_shift(tokens, pos, is_defined)?;
-                left = i64::from(left <= right);
-            }
-            ExprToken::Greater => {
-                *pos += 1;
-                let right = parse_shift(tokens, pos, is_defined)?;
-                left = i64::from(left > right);
-            }
-            ExprToken::GreaterEqual => {
-                *pos += 1;
-                let right = parse_shift(tokens, pos, is_defined)?;
-                left = i64::from(left >= right);
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_shift<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_additive(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::ShiftLeft => {
-                *pos += 1;
-                let right = parse_additive(tokens, pos, is_defined)?;
-                left = left.wrapping_shl(right as u32);
-            }
-            ExprToken::ShiftRight => {
-                *pos += 1;
-                let right = parse_additive(tokens, pos, is_defined)?;
-                left = left.wrapping_shr(right as u32);
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_additive<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_multiplicative(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::Plus => {
-                *pos += 1;
-                let right = parse_multiplicative(tokens, pos, is_defined)?;
-                left = left.wrapping_add(right);
-            }
-            ExprToken::Minus => {
-                *pos += 1;
-                let right = parse_multiplicative(tokens, pos, is_defined)?;
-                left = left.wrapping_sub(right);
-            }
-            _ => break,
-        }
-    }
-    Ok(left)
-}
-
-fn parse_multiplicative<F>(
-    tokens: &[ExprToken],
-    pos: &mut usize,
-    is_defined: &F,
-) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    let mut left = parse_unary(tokens, pos, is_defined)?;
-    while *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::Multiply => {
-                *pos += 1;
-                let right = parse_unary(tokens, pos, is_defined)?;
-                left = left.wrapping_mul(right);
-            }
-            ExprToken::Divide => {
-                *pos += 1;
-                let right = parse_unary(tokens, pos, is_defined)?;
-                if right == 0 {
-                    return Err("Division by zero".to_string());
-                }
-                left = left.wrapping_div(right);
-            }
-            ExprToken::Modulo => {
-                *pos += 1;
-                let right = parse_unary(tokens, pos, is_defined)?;
-                if right == 0 {
-                    return Err("Modulo by zero".to_string());
-                }
-                left = left.wrapping_rem(right);
-            }
-            _ => break,
+/// Errors carry the 1-based offset of a bad character as their column.
+pub fn tokenize_expression(expr: &str) -> Result<Vec<ExprToken>, PreprocessError> {
+    expr::tokenize_expression(expr).map_err(|err| {
+        let error = PreprocessError::other("<expression>".to_string(), 0, err.message);
+        match err.offset {
+            Some(offset) => error.with_column(offset),
+            None => error,
         }
-    }
-    Ok(left)
+    })
 }
 
-fn parse_unary<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    if *pos < tokens.len() {
-        match tokens[*pos] {
-            ExprToken::Not => {
-                *pos += 1;
-                let expr = parse_unary(tokens, pos, is_defined)?;
-                return Ok(i64::from(expr == 0));
-            }
-            ExprToken::BitNot => {
-                *pos += 1;
-                let expr = parse_unary(tokens, pos, is_defined)?;
-                return Ok(!expr);
-            }
-            ExprToken::Minus => {
-                *pos += 1;
-                let expr = parse_unary(tokens, pos, is_defined)?;
-                return Ok(expr.wrapping_neg());
-            }
-            ExprToken::Plus => {
-                *pos += 1;
-                let expr = parse_unary(tokens, pos, is_defined)?;
-                return Ok(expr);
-            }
-            _ => {}
-        }
+/// Whether `tokens[i]` is the operand of a `defined` operator
+pub(crate) fn is_defined_operand(tokens: &[ExprToken], i: usize) -> bool {
+    let is_defined = |j: usize| matches!(&tokens[j], ExprToken::Identifier(id) if id == "defined");
+    match i {
+        0 => false,
+        1 => is_defined(0),
+        _ => is_defined(i - 1) || (matches!(tokens[i - 1], ExprToken::LParen) && is_defined(i - 2)),
     }
-    parse_primary(tokens, pos, is_defined)
 }
 
-/// Parse the defined operator: defined identifier or defined(identifier)
-fn parse_defined_operator<F>(
-    tokens: &[ExprToken],
-    pos: &mut usize,
-    is_defined: &F,
-) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    // Check for defined(identifier) form
-    if *pos < tokens.len() && matches!(tokens[*pos], ExprToken::LParen) {
-        *pos += 1;
-
-        // Expect identifier after (
-        if *pos >= tokens.len() {
-            return Err("Expected identifier after defined(".to_string());
-        }
-
-        let id = match &tokens[*pos] {
-            ExprToken::Identifier(id) => {
-                *pos += 1;
-                id.clone()
-            }
-            _ => return Err("Expected identifier after defined(".to_string()),
+/// Resolve identifiers that are keywords in `#if` under `standard`
+///
+/// `true` and `false` become 1 and 0 in C23 and C++; in C++ the alternative
+/// operator spellings (`and`, `or`, `not`, `bitand`, `bitor`, `xor`, `compl`,
+/// `not_eq`) become their operators. Operands of `defined` are left alone.
+pub(crate) fn resolve_expression_keywords(
+    tokens: Vec<ExprToken>,
+    standard: Standard,
+) -> Vec<ExprToken> {
+    let cpp = standard.language() == Language::Cpp;
+    let mut resolved = Vec::with_capacity(tokens.len());
+    for (i, token) in tokens.iter().enumerate() {
+        let replacement = match token {
+            ExprToken::Identifier(id) if !is_defined_operand(&tokens, i) => match id.as_str() {
+                "true" if standard.has_boolean_literals() => Some(ExprToken::Number(1)),
+                "false" if standard.has_boolean_literals() => Some(ExprToken::Number(0)),
+                "and" if cpp => Some(ExprToken::And),
+                "or" if cpp => Some(ExprToken::Or),
+                "not" if cpp => Some(ExprToken::Not),
+                "not_eq" if cpp => Some(ExprToken::NotEqual),
+                "bitand" if cpp => Some(ExprToken::BitAnd),
+                "bitor" if cpp => Some(ExprToken::BitOr),
+                "xor" if cpp => Some(ExprToken::BitXor),
+                "compl" if cpp => Some(ExprToken::BitNot),
+                _ => None,
+            },
+            _ => None,
         };
-
-        // Expect closing )
-        if *pos >= tokens.len() || !matches!(tokens[*pos], ExprToken::RParen) {
-            return Err("Expected ) after defined(identifier".to_string());
-        }
-        *pos += 1;
-
-        Ok(i64::from(is_defined(&id)))
-    }
-    // Check for defined identifier form
-    else if *pos < tokens.len() {
-        match &tokens[*pos] {
-            ExprToken::Identifier(id) => {
-                let defined = is_defined(id);
-                *pos += 1;
-                Ok(i64::from(defined))
-            }
-            _ => Err("defined must be followed by identifier or (identifier)".to_string()),
-        }
-    } else {
-        Err("defined must be followed by identifier or (identifier)".to_string())
-    }
-}
-
-fn parse_primary<F>(tokens: &[ExprToken], pos: &mut usize, is_defined: &F) -> Result<i64, String>
-where
-    F: Fn(&str) -> bool,
-{
-    if *pos >= tokens.len() {
-        return Err("Unexpected end of expression".to_string());
-    }
-
-    match &tokens[*pos] {
-        ExprToken::Number(val) => {
-            *pos += 1;
-            Ok(*val)
-        }
-        ExprToken::Identifier(ident) => {
-            *pos += 1;
-            if ident == "defined" {
-                parse_defined_operator(tokens, pos, is_defined)
-            } else {
-                // Preprocessor treats undefined identifiers as 0
-                Ok(0)
-            }
-        }
-        ExprToken::LParen => {
-            *pos += 1;
-            let val = parse_or(tokens, pos, is_defined)?;
-            if *pos >= tokens.len() || !matches!(tokens[*pos], ExprToken::RParen) {
-                return Err("Expected )".to_string());
-            }
-            *pos += 1;
-            Ok(val)
-        }
-        _ => Err("Expected number or identifier".to_string()),
+        resolved.push(replacement.unwrap_or_else(|| token.clone()));
     }
+    resolved
 }
 
 /// Check if a string end character is escaped (odd number of backslashes)
@@ -665,13 +253,36 @@ fn handle_block_comment(chars: &mut Peekable<Chars>, result: &mut String) {
     }
 }
 
+/// Byte length of a line's leading `#include "name"` or `#include <name>`,
+/// up to and including the closing delimiter
+///
+/// A backslash in a header name is a path separator, not an escape, so
+/// `"sub\"` ends at the second quote.
+fn include_header_name_end(line: &str) -> Option<usize> {
+    let directive = line.trim_start().strip_prefix('#')?.trim_start();
+    let rest = ["include_next", "include", "import"]
+        .iter()
+        .find_map(|name| directive.strip_prefix(name))?;
+    let header = rest.trim_start();
+    let close = match header.chars().next()? {
+        '"' => '"',
+        '<' => '>',
+        _ => return None,
+    };
+    let end = header[1..].find(close)? + 2;
+    Some(line.len() - header.len() + end)
+}
+
 /// Strip comments from a string, replacing with spaces, but not inside strings
+/// or the header name of an include directive
 pub fn strip_comments(input: &str) -> String {
     if !input.contains('/') {
         return input.to_string();
     }
 
-    let mut result = String::with_capacity(input.len());
+    let (header, input) = input.split_at(include_header_name_end(input).unwrap_or(0));
+    let mut result = String::with_capacity(header.len() + input.len());
+    result.push_str(header);
     let mut chars = input.chars().peekable();
     let mut in_string = false;
     let mut quote_char = '\0';
@@ -708,6 +319,40 @@ pub fn normalize_input(input: &str) -> String {
     input.replace("\r\n", "\n").replace('\r', "\n")
 }
 
+/// Find a repeating cycle at the top of a macro expansion stack
+///
+/// Returns the stack from the previous occurrence of the innermost macro up
+/// to and including the innermost macro, e.g. `A, B, C, A`.
+pub fn expansion_cycle(stack: &[String]) -> Option<&[String]> {
+    let (last, rest) = stack.split_last()?;
+    let start = rest.iter().rposition(|name| name == last)?;
+    Some(&stack[start..])
+}
+
+/// Split `input` into lines, treating `\r\n`, bare `\r` and bare `\n` as
+/// line breaks.
+///
+/// Like [`str::lines`], a final line ending doesn't produce an empty line.
+pub fn split_lines(input: &str) -> impl Iterator<Item = &str> {
+    let mut rest = input;
+    core::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let Some(end) = rest.find(['\r', '\n']) else {
+            return Some(core::mem::take(&mut rest));
+        };
+        let line = &rest[..end];
+        let width = if rest[end..].starts_with("\r\n") {
+            2
+        } else {
+            1
+        };
+        rest = &rest[end + width..];
+        Some(line)
+    })
+}
+
 /// Convert `\n` in output to the configured line ending style.
 ///
 /// The input is expected to be `\n`-separated (as produced by `normalize_input`
@@ -760,118 +405,413 @@ pub fn line_splice(input: &str) -> String {
     out
 }
 
-/// Check if we found _Pragma token at position i
-const fn is_pragma_start(chars: &[char], i: usize) -> bool {
-    if i + 7 > chars.len() {
-        return false;
+/// A source line after line splicing, with the physical lines it came from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogicalLine {
+    /// Text with every splice removed
+    pub text: String,
+    /// Number of physical lines joined into this one
+    pub physical_lines: usize,
+    /// Physical lines, counted from the first, whose backslash is followed
+    /// by spaces or tabs
+    pub spaced_splices: Vec<usize>,
+    /// Physical lines, counted from the first, whose `//` comment continues
+    /// onto the next line
+    pub comment_splices: Vec<usize>,
+}
+
+/// Split `input`, which must use `\n` line endings, into logical lines
+///
+/// Unlike [`line_splice`], a backslash followed only by spaces or tabs also
+/// splices, as in GCC. Each logical line records how many physical lines it
+/// spans so line numbers stay in step with the source.
+pub fn splice_logical_lines(input: &str) -> Vec<LogicalLine> {
+    let mut lines = Vec::new();
+    let mut current: Option<LogicalLine> = None;
+    let mut pieces = input.split('\n').peekable();
+    while let Some(piece) = pieces.next() {
+        let last = pieces.peek().is_none();
+        if last && piece.is_empty() {
+            break;
+        }
+        let line = current.get_or_insert_with(LogicalLine::default);
+        let offset = line.physical_lines;
+        line.physical_lines += 1;
+        let trimmed = piece.trim_end_matches([' ', '\t']);
+        match trimmed.strip_suffix('\\') {
+            Some(body) if !last => {
+                if trimmed.len() < piece.len() {
+                    line.spaced_splices.push(offset);
+                }
+                line.text.push_str(body);
+                if ends_in_line_comment(&line.text) {
+                    line.comment_splices.push(offset);
+                }
+            }
+            _ => {
+                line.text.push_str(piece);
+                lines.extend(current.take());
+            }
+        }
     }
+    lines.extend(current);
+    lines
+}
 
-    let pragma_chars = ['_', 'P', 'r', 'a', 'g', 'm', 'a'];
-    let mut j = 0;
-    while j < 7 {
-        if chars[i + j] != pragma_chars[j] {
-            return false;
+/// Whether `line` ends inside a `//` comment
+fn ends_in_line_comment(line: &str) -> bool {
+    let mut chars = line[include_header_name_end(line).unwrap_or(0)..].chars();
+    let mut quote = None;
+    while let Some(ch) = chars.next() {
+        match (quote, ch) {
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(q), _) if ch == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, '/') if chars.as_str().starts_with('/') => return true,
+            (None, '/') if chars.as_str().starts_with('*') => {
+                let rest = &chars.as_str()[1..];
+                match rest.find("*/") {
+                    Some(end) => chars = rest[end + 2..].chars(),
+                    None => return false,
+                }
+            }
+            (None, _) => {}
         }
-        j += 1;
     }
-    true
+    false
 }
 
-/// Skip whitespace to find opening parenthesis
-fn find_pragma_paren(chars: &[char], start: usize) -> Option<usize> {
-    let mut j = start;
-    while j < chars.len() && chars[j].is_whitespace() {
-        j += 1;
+/// Return the text of a directive line after the `#`, or `None` for other lines
+///
+/// A line starting with the `##` operator is ordinary text, not a directive.
+pub fn extract_directive(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with("##") {
+        return None;
     }
-    if j < chars.len() && chars[j] == '(' {
-        Some(j)
-    } else {
-        None
+    trimmed.strip_prefix('#').map(str::trim)
+}
+
+/// Quote a file name as a string literal, as `__FILE__` and `#line` spell it
+pub fn quote_file_name(file: &str) -> String {
+    format!("\"{}\"", file.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Split directive text into the directive name and the rest of the line
+pub fn split_directive(directive: &str) -> (&str, &str) {
+    let mut parts = directive.splitn(2, char::is_whitespace);
+    let cmd = parts.next().unwrap_or("").trim();
+    let rest = parts.next().unwrap_or("").trim();
+    (cmd, rest)
+}
+
+/// Parse the operand of `#assert` or `#unassert`: a predicate name and an
+/// optional parenthesized answer, whose whitespace is normalized
+///
+/// Returns `None` if the text isn't of that form.
+pub fn parse_assertion(rest: &str) -> Option<(String, Option<String>)> {
+    let rest = rest.trim();
+    let name_end = rest
+        .find(|c: char| !is_identifier_continue(c))
+        .unwrap_or(rest.len());
+    let name = &rest[..name_end];
+    if !is_valid_identifier(name) {
+        return None;
+    }
+    let tail = rest[name_end..].trim_start();
+    if tail.is_empty() {
+        return Some((name.to_string(), None));
     }
+    let answer = tail.strip_prefix('(')?.strip_suffix(')')?;
+    let answer = answer.split_whitespace().collect::<Vec<_>>().join(" ");
+    if answer.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), Some(answer)))
 }
 
-/// Parse the string content inside _Pragma(...)
-fn parse_pragma_string(chars: &[char], start: usize) -> Option<(String, usize)> {
-    let mut j = start;
-    if j >= chars.len() || chars[j] != '"' {
+/// A `#define` directive split into its parts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DefineDirective {
+    /// Macro name
+    pub name: String,
+    /// Parameter names, or `None` for an object-like macro
+    pub params: Option<Vec<String>>,
+    /// Whether the parameter list ends in `...`
+    pub is_variadic: bool,
+    /// Replacement list, with comments stripped and surrounding whitespace trimmed
+    pub body: Vec<Token>,
+}
+
+/// Parse the text following `#define`
+///
+/// A parameter list is only recognized when `(` immediately follows the
+/// name, so `#define X (1)` is object-like. Returns `None` if the name is
+/// missing or the parameter list is malformed.
+pub fn parse_define(rest: &str) -> Option<DefineDirective> {
+    let rest = rest.trim_start();
+    let mut chars = rest.chars().peekable();
+    let mut name = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_alphanumeric() || c == '_' {
+            name.push(c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+
+    if name.is_empty() {
         return None;
     }
-    j += 1;
-
-    let mut string_content = String::new();
-    while j < chars.len() {
-        if chars[j] == '"' {
-            // Check for escape
-            let mut backslash_count = 0;
-            let mut k = j - 1;
-            while k > 0 && chars[k] == '\\' {
-                backslash_count += 1;
-                k -= 1;
-            }
-            if backslash_count % 2 == 0 {
-                // End of string
-                j += 1;
-                return Some((string_content, j));
+
+    let mut params: Option<Vec<String>> = None;
+    let mut is_variadic = false;
+
+    if let Some(&'(') = chars.peek() {
+        chars.next();
+        let mut param = String::new();
+        let mut params_vec = Vec::new();
+
+        loop {
+            match chars.next()? {
+                ')' => {
+                    if !param.trim().is_empty() {
+                        params_vec.push(param.trim().to_string());
+                    }
+                    break;
+                }
+                ',' => {
+                    params_vec.push(param.trim().to_string());
+                    param.clear();
+                }
+                '.' => {
+                    // `...` must be the last thing in the parameter list
+                    if chars.next()? != '.' || chars.next()? != '.' {
+                        return None;
+                    }
+                    is_variadic = true;
+                    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                        chars.next();
+                    }
+                    if chars.next()? != ')' {
+                        return None;
+                    }
+                    break;
+                }
+                c => param.push(c),
             }
-            string_content.push(chars[j]);
-        } else {
-            string_content.push(chars[j]);
         }
-        j += 1;
+        params = Some(params_vec);
     }
-    None
+
+    let body_str: String = chars.collect();
+    let stripped = strip_comments(&body_str);
+    Some(DefineDirective {
+        name,
+        params,
+        is_variadic,
+        body: tokenize_line(stripped.trim()),
+    })
 }
 
-/// Find and consume closing parenthesis
-fn consume_pragma_closing_paren(chars: &[char], start: usize) -> Option<usize> {
-    let mut j = start;
-    while j < chars.len() && chars[j].is_whitespace() {
-        j += 1;
-    }
-    if j < chars.len() && chars[j] == ')' {
-        Some(j + 1)
+/// Parse the operand of `#include` into the header name and include kind
+pub fn parse_include(rest: &str) -> Option<(String, IncludeKind)> {
+    let trimmed = rest.trim();
+    let kind = if trimmed.starts_with('"') && trimmed.ends_with('"') {
+        IncludeKind::Local
+    } else if trimmed.starts_with('<') && trimmed.ends_with('>') {
+        IncludeKind::System
     } else {
-        None
+        return None;
+    };
+    if trimmed.len() < 2 {
+        return None;
     }
+    Some((trimmed[1..(trimmed.len() - 1)].to_string(), kind))
 }
 
-/// Process a single _Pragma occurrence
-fn process_single_pragma(chars: &[char], i: usize, result: &mut String) -> Option<usize> {
-    // Find opening parenthesis
-    let paren_pos = find_pragma_paren(chars, i + 7)?;
-    let string_start = paren_pos + 1;
+/// A piece of an expanded line, split around `_Pragma` operators
+pub enum PragmaSegment {
+    /// Ordinary tokens to emit as text
+    Tokens(Vec<Token>),
+    /// The destringized operand of a `_Pragma("...")` operator
+    Pragma(String),
+}
 
-    // Parse string content
-    let (string_content, string_end) = parse_pragma_string(chars, string_start)?;
+/// Parse a `_Pragma ( string-literal )` operator starting at `tokens[start]`
+///
+/// Adjacent string literals inside the parentheses are concatenated before
+/// destringizing, so `_Pragma("mess" "age")` reads as `_Pragma("message")`.
+/// Returns the pragma text and the index just past the closing parenthesis,
+/// or `None` if the tokens don't form a complete operator.
+fn parse_pragma_operator(tokens: &[Token], start: usize) -> Option<(String, usize)> {
+    let mut i = start + 1;
+    let next_significant = |i: &mut usize| {
+        while tokens.get(*i).is_some_and(is_whitespace) {
+            *i += 1;
+        }
+        let token = tokens.get(*i)?;
+        *i += 1;
+        Some(token)
+    };
 
-    // Find closing parenthesis
-    let final_pos = consume_pragma_closing_paren(chars, string_end)?;
+    if !matches!(next_significant(&mut i)?, Token::Other(s) if s == "(") {
+        return None;
+    }
 
-    // Replace with #pragma
-    result.push_str("#pragma ");
-    let unescaped = string_content.replace("\\\"", "\"");
-    result.push_str(&unescaped);
+    let mut contents = String::new();
+    let mut literals = 0;
+    loop {
+        match next_significant(&mut i)? {
+            Token::StringLiteral(literal) => {
+                contents.push_str(literal.strip_prefix('"')?.strip_suffix('"')?);
+                literals += 1;
+            }
+            Token::Other(s) if s == ")" && literals > 0 => break,
+            _ => return None,
+        }
+    }
 
-    Some(final_pos)
+    // Destringize: undo the `\"` and `\\` escapes
+    let mut text = String::with_capacity(contents.len());
+    let mut chars = contents.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next @ ('"' | '\\'))) => {
+                text.push(next);
+                chars.next();
+            }
+            _ => text.push(c),
+        }
+    }
+    Some((text, i))
 }
 
-/// Process _Pragma operators in a line, replacing with #pragma directives
-pub fn process_pragma(line: &str) -> String {
-    let mut result = String::with_capacity(line.len());
+/// Split macro-expanded tokens around `_Pragma` operators
+///
+/// `_Pragma` is only recognized after expansion, so macros that expand to it
+/// produce a pragma just like a literal use does. A malformed operator is
+/// left in place as ordinary tokens.
+pub fn split_pragma_operators(tokens: &[Token]) -> Vec<PragmaSegment> {
+    let mut segments = Vec::new();
+    let mut current = Vec::new();
     let mut i = 0;
-    let chars: Vec<char> = line.chars().collect();
 
-    while i < chars.len() {
-        if is_pragma_start(&chars, i)
-            && let Some(new_i) = process_single_pragma(&chars, i, &mut result)
+    while i < tokens.len() {
+        if matches!(&tokens[i], Token::Identifier(name) if name == "_Pragma")
+            && let Some((pragma, next)) = parse_pragma_operator(tokens, i)
         {
-            i = new_i;
+            segments.push(PragmaSegment::Tokens(core::mem::take(&mut current)));
+            segments.push(PragmaSegment::Pragma(pragma));
+            i = next;
             continue;
         }
-        result.push(chars[i]);
+        current.push(tokens[i].clone());
         i += 1;
     }
-    result
+
+    segments.push(PragmaSegment::Tokens(current));
+    segments
+}
+
+/// Encoding prefixes that may precede a string literal
+const STRING_PREFIXES: &[&str] = &["L", "u", "U", "u8"];
+
+/// Split a string literal at `tokens[i]` into its prefix and quoted body
+///
+/// The tokenizer reads `L"a"` as the identifier `L` directly followed by the
+/// literal `"a"`, so a prefix is an identifier token immediately before it.
+fn string_literal_at(tokens: &[Token], i: usize) -> Option<(&str, &str, usize)> {
+    match (&tokens[i], tokens.get(i + 1)) {
+        (Token::Identifier(prefix), Some(Token::StringLiteral(body)))
+            if STRING_PREFIXES.contains(&prefix.as_str()) =>
+        {
+            Some((prefix, body, i + 2))
+        }
+        (Token::StringLiteral(body), _) => Some(("", body, i + 1)),
+        _ => None,
+    }
+}
+
+/// Whether `left` ends in a numeric escape that `right` would extend if the
+/// two literal bodies were simply joined, e.g. `"\x1" "2"` or `"\12" "3"`
+fn escape_would_merge(left: &str, right: &str) -> bool {
+    let Some(first) = right.chars().next() else {
+        return false;
+    };
+
+    // Hex escapes take any number of digits
+    let hex_digits = left
+        .chars()
+        .rev()
+        .take_while(char::is_ascii_hexdigit)
+        .count();
+    if let Some(before) = left[..left.len() - hex_digits].strip_suffix('x')
+        && hex_digits > 0
+        && is_string_end_escaped(before)
+    {
+        return first.is_ascii_hexdigit();
+    }
+
+    // Octal escapes take at most three
+    let is_octal = |c: &char| ('0'..='7').contains(c);
+    let octal_digits = left.chars().rev().take_while(is_octal).count();
+    (1..3).contains(&octal_digits)
+        && is_string_end_escaped(&left[..left.len() - octal_digits])
+        && is_octal(&first)
+}
+
+/// Merge adjacent string literals separated only by whitespace
+///
+/// A prefixed literal absorbs unprefixed neighbours (`L"a" "b"` becomes
+/// `L"ab"`). Literals with two different prefixes, or whose joined bodies would
+/// change the meaning of a numeric escape, are left apart.
+pub fn concat_adjacent_strings(tokens: &[Token]) -> Vec<Token> {
+    let mut out = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let Some((prefix, body, mut next)) = string_literal_at(tokens, i) else {
+            out.push(tokens[i].clone());
+            i += 1;
+            continue;
+        };
+
+        let mut prefix = prefix.to_string();
+        let mut contents = body[1..body.len() - 1].to_string();
+        loop {
+            let mut j = next;
+            while tokens.get(j).is_some_and(is_whitespace) {
+                j += 1;
+            }
+            let Some((next_prefix, next_body, after)) =
+                tokens.get(j).and(string_literal_at(tokens, j))
+            else {
+                break;
+            };
+            let next_contents = &next_body[1..next_body.len() - 1];
+            let compatible = next_prefix.is_empty() || prefix.is_empty() || next_prefix == prefix;
+            if !compatible || escape_would_merge(&contents, next_contents) {
+                break;
+            }
+            if prefix.is_empty() {
+                prefix = next_prefix.to_string();
+            }
+            contents.push_str(next_contents);
+            next = after;
+        }
+
+        if !prefix.is_empty() {
+            out.push(Token::Identifier(prefix));
+        }
+        out.push(Token::StringLiteral(format!("\"{contents}\"")));
+        i = next;
+    }
+    out
 }
 
 /// Convert a token to its string representation for concatenation
@@ -896,7 +836,27 @@ pub fn tokens_to_string(tokens: &[Token]) -> String {
 
 /// Check if a token is whitespace
 fn is_whitespace(token: &Token) -> bool {
-    matches!(token, Token::Other(s) if s.chars().all(char::is_whitespace))
+    matches!(token, Token::Other(s) if !s.is_empty() && s.chars().all(char::is_whitespace))
+}
+
+/// Stand-in for an empty macro argument that is a `##` operand (C11 6.10.3.3)
+///
+/// Pasting a placemarker with a token gives that token, and any left over
+/// after pasting are removed.
+pub(crate) const PLACEMARKER: Token = Token::Other(String::new());
+
+fn is_placemarker(token: &Token) -> bool {
+    matches!(token, Token::Other(s) if s.is_empty())
+}
+
+/// A macro argument substituted as a `##` operand, or a placemarker if it
+/// is empty
+pub(crate) fn paste_operand(tokens: Vec<Token>) -> Vec<Token> {
+    if tokens.iter().all(is_whitespace) {
+        vec![PLACEMARKER]
+    } else {
+        tokens
+    }
 }
 
 /// Trim whitespace tokens from the beginning and end of a token sequence
@@ -931,7 +891,7 @@ fn concatenate_tokens(left: &Token, right: &Token) -> Token {
 }
 
 /// Check if a string forms a valid C identifier
-fn is_valid_identifier(s: &str) -> bool {
+pub(crate) fn is_valid_identifier(s: &str) -> bool {
     if s.is_empty() {
         return false;
     }
@@ -990,8 +950,66 @@ fn find_next_non_whitespace_token(tokens: &[Token], start: usize) -> Option<usiz
     }
 }
 
+/// Whether the token at `idx` is an operand of a `##` operator
+///
+/// Macro arguments used as paste operands are substituted without being
+/// macro-expanded first.
+pub fn is_paste_operand(tokens: &[Token], idx: usize) -> bool {
+    let is_paste = |i: usize| matches!(&tokens[i], Token::Other(s) if s.trim() == "##");
+    find_prev_non_whitespace_token(tokens, idx).is_some_and(is_paste)
+        || find_next_non_whitespace_token(tokens, idx + 1).is_some_and(is_paste)
+}
+
+/// Punctuators a `##` paste is allowed to produce (C11 6.4.6, plus digraphs)
+pub(crate) const PUNCTUATORS: &[&str] = &[
+    "[", "]", "(", ")", "{", "}", ".", "->", "++", "--", "&", "*", "+", "-", "~", "!", "/", "%",
+    "<<", ">>", "<", ">", "<=", ">=", "==", "!=", "^", "|", "&&", "||", "?", ":", ";", "...", "=",
+    "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|=", ",", "#", "##", "<:", ":>", "<%",
+    "%>", "%:", "%:%:",
+];
+
+/// Check if a string is a preprocessing number (C11 6.4.8)
+fn is_pp_number(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    let starts_ok = matches!(bytes, [b'0'..=b'9', ..] | [b'.', b'0'..=b'9', ..]);
+    if !starts_ok {
+        return false;
+    }
+    let mut prev = b'\0';
+    for &b in bytes {
+        let ok = b.is_ascii_alphanumeric()
+            || b == b'_'
+            || b == b'.'
+            || ((b == b'+' || b == b'-') && matches!(prev, b'e' | b'E' | b'p' | b'P'));
+        if !ok {
+            return false;
+        }
+        prev = b;
+    }
+    true
+}
+
+/// Check whether the result of a `##` paste is a single valid preprocessing token
+pub fn is_valid_pasted_token(s: &str) -> bool {
+    s.is_empty()
+        || is_valid_identifier(s)
+        || is_pp_number(s)
+        || PUNCTUATORS.contains(&s)
+        || matches!(
+            tokenize_line(s).as_slice(),
+            [Token::StringLiteral(_) | Token::CharLiteral(_)]
+        )
+}
+
 /// Apply token pasting (##) to a sequence of tokens
-pub fn apply_token_pasting(tokens: &[Token]) -> Vec<Token> {
+///
+/// `on_invalid` is called with the left and right operands of every paste
+/// whose result is not a valid preprocessing token; the concatenation is
+/// still performed so permissive callers get GCC-like output.
+pub fn apply_token_pasting<F>(tokens: &[Token], mut on_invalid: F) -> Vec<Token>
+where
+    F: FnMut(&str, &str),
+{
     let mut result = Vec::new();
     let mut i = 0;
 
@@ -1008,7 +1026,22 @@ pub fn apply_token_pasting(tokens: &[Token]) -> Vec<Token> {
 
                 // Find next non-whitespace token in input
                 if let Some(next_idx) = find_next_non_whitespace_token(tokens, i + 1) {
+                    if is_placemarker(&tokens[next_idx]) {
+                        i = next_idx + 1;
+                        continue;
+                    }
+                    if is_placemarker(&result[p_idx]) {
+                        result[p_idx] = tokens[next_idx].clone();
+                        i = next_idx + 1;
+                        continue;
+                    }
                     let concatenated = concatenate_tokens(&result[p_idx], &tokens[next_idx]);
+                    if !is_valid_pasted_token(token_to_string(&concatenated)) {
+                        on_invalid(
+                            token_to_string(&result[p_idx]),
+                            token_to_string(&tokens[next_idx]),
+                        );
+                    }
                     result[p_idx] = concatenated;
                     i = next_idx + 1;
                     continue;
@@ -1021,21 +1054,38 @@ pub fn apply_token_pasting(tokens: &[Token]) -> Vec<Token> {
         }
         i += 1;
     }
+    result.retain(|token| !is_placemarker(token));
     result
 }
 
-/// Expand predefined macros (__LINE__, __FILE__, __DATE__, __TIME__)
-pub fn expand_predefined_macro(context: &PreprocessorContext, name: &str) -> Option<Token> {
+/// Predefined macros whose value is computed where they are expanded
+pub const DYNAMIC_MACROS: &[&str] = &[
+    "__LINE__",
+    "__FILE__",
+    "__DATE__",
+    "__TIME__",
+    "__COUNTER__",
+];
+
+/// Expand predefined macros (__LINE__, __FILE__, __DATE__, __TIME__, __COUNTER__)
+///
+/// Each expansion of `__COUNTER__` advances the context's counter.
+pub fn expand_predefined_macro(context: &mut PreprocessorContext, name: &str) -> Option<Token> {
     use crate::date_time::{format_date, format_time};
 
     match name {
         "__LINE__" => Some(Token::Other(context.current_line.to_string())),
-        "__FILE__" => Some(Token::StringLiteral(format!(
-            "\"{}\"",
-            context.current_file
-        ))),
+        "__FILE__" => {
+            let file = context.file_macro_separator.apply(&context.current_file);
+            Some(Token::StringLiteral(quote_file_name(&file)))
+        }
         "__DATE__" => Some(Token::StringLiteral(format!("\"{}\"", format_date()))),
         "__TIME__" => Some(Token::StringLiteral(format!("\"{}\"", format_time()))),
+        "__COUNTER__" => {
+            let value = context.counter;
+            context.counter += 1;
+            Some(Token::Other(value.to_string()))
+        }
         _ => None,
     }
 }
@@ -1,9 +1,20 @@
-use std::error;
-use std::fmt;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::error;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::io;
 
 /// Semantic error kinds that can occur during preprocessing
+///
+/// New kinds may be added in minor releases; match on [`Self::code`] or keep
+/// a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum PreprocessErrorKind {
     /// Include file not found
     IncludeNotFound(String),
@@ -15,12 +26,53 @@ pub enum PreprocessErrorKind {
     RecursionLimitExceeded(String),
     /// Conditional compilation error
     ConditionalError(String),
+    /// The iteration budget or deadline ran out; the error location shows how
+    /// far processing got
+    BudgetExceeded(String),
+    /// A macro call had too many arguments or an expansion produced too many
+    /// tokens
+    LimitExceeded(String),
     /// I/O error (e.g., file reading/writing)
+    #[cfg(feature = "std")]
     Io(io::Error),
     /// Other preprocessing error
     Other(String),
 }
 
+impl PreprocessErrorKind {
+    /// Stable identifier for this kind of error
+    ///
+    /// Codes never change meaning and are never reused, so they are safe to
+    /// match on or store:
+    ///
+    /// | Code | Kind |
+    /// |------|------|
+    /// | `E0001_INCLUDE_NOT_FOUND` | [`Self::IncludeNotFound`] |
+    /// | `E0002_MALFORMED_DIRECTIVE` | [`Self::MalformedDirective`] |
+    /// | `E0003_MACRO_ARG_MISMATCH` | [`Self::MacroArgMismatch`] |
+    /// | `E0004_RECURSION_LIMIT_EXCEEDED` | [`Self::RecursionLimitExceeded`] |
+    /// | `E0005_CONDITIONAL_ERROR` | [`Self::ConditionalError`] |
+    /// | `E0006_BUDGET_EXCEEDED` | [`Self::BudgetExceeded`] |
+    /// | `E0007_LIMIT_EXCEEDED` | [`Self::LimitExceeded`] |
+    /// | `E0008_IO` | [`Self::Io`] |
+    /// | `E0009_OTHER` | [`Self::Other`] |
+    #[must_use]
+    pub const fn code(&self) -> &'static str {
+        match self {
+            PreprocessErrorKind::IncludeNotFound(_) => "E0001_INCLUDE_NOT_FOUND",
+            PreprocessErrorKind::MalformedDirective(_) => "E0002_MALFORMED_DIRECTIVE",
+            PreprocessErrorKind::MacroArgMismatch(_) => "E0003_MACRO_ARG_MISMATCH",
+            PreprocessErrorKind::RecursionLimitExceeded(_) => "E0004_RECURSION_LIMIT_EXCEEDED",
+            PreprocessErrorKind::ConditionalError(_) => "E0005_CONDITIONAL_ERROR",
+            PreprocessErrorKind::BudgetExceeded(_) => "E0006_BUDGET_EXCEEDED",
+            PreprocessErrorKind::LimitExceeded(_) => "E0007_LIMIT_EXCEEDED",
+            #[cfg(feature = "std")]
+            PreprocessErrorKind::Io(_) => "E0008_IO",
+            PreprocessErrorKind::Other(_) => "E0009_OTHER",
+        }
+    }
+}
+
 /// Errors that can occur during preprocessing, with location information
 #[derive(Debug)]
 pub struct PreprocessError {
@@ -34,6 +86,9 @@ pub struct PreprocessError {
     pub column: Option<usize>,
     /// Optional source line content for context display
     pub source_line: Option<String>,
+    /// Macros being expanded when the error occurred, outermost first; only
+    /// filled in for recursion limit errors
+    pub expansion_stack: Box<[String]>,
 }
 
 impl PreprocessError {
@@ -46,6 +101,7 @@ impl PreprocessError {
             line,
             column: None,
             source_line: None,
+            expansion_stack: Box::default(),
         }
     }
 
@@ -58,6 +114,7 @@ impl PreprocessError {
             line,
             column: None,
             source_line: None,
+            expansion_stack: Box::default(),
         }
     }
 
@@ -70,6 +127,7 @@ impl PreprocessError {
             line,
             column: None,
             source_line: None,
+            expansion_stack: Box::default(),
         }
     }
 
@@ -82,6 +140,7 @@ impl PreprocessError {
             line,
             column: None,
             source_line: None,
+            expansion_stack: Box::default(),
         }
     }
 
@@ -94,10 +153,38 @@ impl PreprocessError {
             line,
             column: None,
             source_line: None,
+            expansion_stack: Box::default(),
+        }
+    }
+
+    /// Create a budget exceeded error
+    #[inline]
+    pub fn budget_exceeded(file: String, line: usize, details: String) -> Self {
+        PreprocessError {
+            kind: PreprocessErrorKind::BudgetExceeded(details),
+            file,
+            line,
+            column: None,
+            source_line: None,
+            expansion_stack: Box::default(),
+        }
+    }
+
+    /// Create a size limit exceeded error
+    #[inline]
+    pub fn limit_exceeded(file: String, line: usize, details: String) -> Self {
+        PreprocessError {
+            kind: PreprocessErrorKind::LimitExceeded(details),
+            file,
+            line,
+            column: None,
+            source_line: None,
+            expansion_stack: Box::default(),
         }
     }
 
     /// Create an I/O error
+    #[cfg(feature = "std")]
     #[inline]
     pub fn io_error(file: String, line: usize, error: io::Error) -> Self {
         PreprocessError {
@@ -106,6 +193,7 @@ impl PreprocessError {
             line,
             column: None,
             source_line: None,
+            expansion_stack: Box::default(),
         }
     }
 
@@ -118,6 +206,28 @@ impl PreprocessError {
             line,
             column: None,
             source_line: None,
+            expansion_stack: Box::default(),
+        }
+    }
+
+    /// Stable identifier for the error kind; see [`PreprocessErrorKind::code`]
+    #[must_use]
+    pub const fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    /// Whether an `#include` named a file the resolver couldn't find
+    #[must_use]
+    pub const fn is_include_not_found(&self) -> bool {
+        matches!(self.kind, PreprocessErrorKind::IncludeNotFound(_))
+    }
+
+    /// The path written in the `#include` directive that couldn't be found
+    #[must_use]
+    pub fn included_path(&self) -> Option<&str> {
+        match &self.kind {
+            PreprocessErrorKind::IncludeNotFound(path) => Some(path),
+            _ => None,
         }
     }
 
@@ -134,6 +244,13 @@ impl PreprocessError {
         self.source_line = Some(source_line);
         self
     }
+
+    /// Set the stack of macros being expanded
+    #[must_use]
+    pub fn with_expansion_stack(mut self, expansion_stack: Vec<String>) -> Self {
+        self.expansion_stack = expansion_stack.into_boxed_slice();
+        self
+    }
 }
 
 impl fmt::Display for PreprocessError {
@@ -156,17 +273,25 @@ impl fmt::Display for PreprocessError {
             PreprocessErrorKind::ConditionalError(details) => {
                 format!("conditional error: {details}")
             }
+            PreprocessErrorKind::BudgetExceeded(details) => {
+                format!("budget exceeded: {details}")
+            }
+            PreprocessErrorKind::LimitExceeded(details) => {
+                format!("limit exceeded: {details}")
+            }
+            #[cfg(feature = "std")]
             PreprocessErrorKind::Io(err) => {
                 format!("I/O error: {err}")
             }
             PreprocessErrorKind::Other(msg) => msg.clone(),
         };
+        let code = self.code();
 
         if is_fake_location {
             // For internal/synthetic locations, show brief error with context for maintainers
             write!(
                 f,
-                "preprocessor error ({}:{}): {message}",
+                "preprocessor error ({}:{}): {message} [{code}]",
                 self.file, self.line
             )?;
         } else {
@@ -175,7 +300,7 @@ impl fmt::Display for PreprocessError {
             } else {
                 format!("{}:{}", self.file, self.line)
             };
-            write!(f, "{loc}: {message}")?;
+            write!(f, "{loc}: {message} [{code}]")?;
         }
 
         if let (Some(col), Some(source_line)) = (self.column, &self.source_line) {
@@ -191,15 +316,63 @@ impl fmt::Display for PreprocessError {
 impl error::Error for PreprocessError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match &self.kind {
+            #[cfg(feature = "std")]
             PreprocessErrorKind::Io(err) => Some(err),
             _ => None,
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for PreprocessError {
     fn from(err: io::Error) -> Self {
         // For I/O errors without specific location context, use generic location
         PreprocessError::io_error("<internal>".to_string(), 0, err)
     }
 }
+
+/// Errors found while validating a [`PreprocessorConfig`](crate::PreprocessorConfig)
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// A limit that must be positive was set to zero
+    ZeroLimit(&'static str),
+    /// A define string isn't `NAME` or `NAME=VALUE` with a valid identifier
+    MalformedDefine(String),
+    /// The same macro was defined twice with different values
+    ConflictingDefine {
+        /// Macro name
+        name: String,
+        /// Value from the first definition
+        first: String,
+        /// Value from the conflicting definition
+        second: String,
+    },
+    /// The same macro was both defined and undefined
+    DefineUndefineConflict(String),
+    /// An include directory doesn't exist
+    MissingIncludeDir(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::ZeroLimit(name) => write!(f, "{name} must be greater than zero"),
+            ConfigError::MalformedDefine(definition) => {
+                write!(f, "malformed macro definition '{definition}'")
+            }
+            ConfigError::ConflictingDefine {
+                name,
+                first,
+                second,
+            } => write!(f, "macro '{name}' defined as both '{first}' and '{second}'"),
+            ConfigError::DefineUndefineConflict(name) => {
+                write!(f, "macro '{name}' is both defined and undefined")
+            }
+            ConfigError::MissingIncludeDir(dir) => {
+                write!(f, "include directory '{dir}' does not exist")
+            }
+        }
+    }
+}
+
+impl error::Error for ConfigError {}
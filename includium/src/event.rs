@@ -0,0 +1,164 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::config::IncludeKind;
+
+/// Where a preprocessing event occurred
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// Source file name
+    pub file: String,
+    /// Line number (1-based)
+    pub line: usize,
+}
+
+/// A structural event in a source file, as returned by
+/// [`PreprocessorDriver::parse_events`](crate::PreprocessorDriver::parse_events)
+/// and [`PreprocessorDriver::events`](crate::PreprocessorDriver::events)
+///
+/// Events from `parse_events` describe what the preprocessor sees without
+/// acting on it: conditionals are not evaluated, includes are not resolved
+/// and text lines are not macro-expanded. Only `events` reports the
+/// include, branch and output-line variants.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PpEvent {
+    /// `#define NAME body` or `#define NAME(params) body`
+    Define {
+        /// Macro name
+        name: String,
+        /// Parameter names, or `None` for an object-like macro
+        params: Option<Vec<String>>,
+        /// Whether the parameter list ends in `...`
+        is_variadic: bool,
+        /// Replacement list as source text
+        body: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#undef NAME`
+    Undef {
+        /// Macro name
+        name: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#include "path"` or `#include <path>`
+    Include {
+        /// Header name between the delimiters
+        path: String,
+        /// Whether the header was written with quotes or angle brackets
+        kind: IncludeKind,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#if expr`
+    If {
+        /// Unevaluated controlling expression
+        expr: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#ifdef NAME`
+    Ifdef {
+        /// Macro name
+        name: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#ifndef NAME`
+    Ifndef {
+        /// Macro name
+        name: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#elif expr`
+    Elif {
+        /// Unevaluated controlling expression
+        expr: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#else`
+    Else {
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#endif`
+    Endif {
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#pragma text`
+    Pragma {
+        /// Text after `#pragma`
+        text: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#line number ["file"]`
+    Line {
+        /// Text after `#line`
+        args: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#error message`
+    Error {
+        /// Text after `#error`
+        message: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// `#warning message`
+    Warning {
+        /// Text after `#warning`
+        message: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// A directive the preprocessor doesn't recognize
+    Unknown {
+        /// Directive name
+        name: String,
+        /// Text after the directive name
+        rest: String,
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// Processing of an included file starts
+    IncludeEntered {
+        /// Name of the file, as reported by `__FILE__` inside it
+        path: String,
+        /// Location of the `#include`
+        location: SourceLocation,
+    },
+    /// Processing of an included file finished
+    IncludeLeft {
+        /// Name of the file, as reported by `__FILE__` inside it
+        path: String,
+        /// Location of the `#include`
+        location: SourceLocation,
+    },
+    /// The group of the preceding `#if`, `#ifdef`, `#ifndef`, `#elif` or
+    /// `#else` is taken
+    ConditionalTaken {
+        /// Location of the directive
+        location: SourceLocation,
+    },
+    /// A line of output
+    LineEmitted {
+        /// Output text, without a line terminator
+        text: String,
+        /// Source location the line was produced from
+        location: SourceLocation,
+    },
+    /// A line of ordinary source text
+    TextLine {
+        /// Token texts of the line; concatenating them gives the line back
+        /// with comments replaced by spaces
+        tokens: Vec<String>,
+        /// Location of the line
+        location: SourceLocation,
+    },
+}
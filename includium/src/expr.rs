@@ -0,0 +1,612 @@
+//! Evaluation of `#if` expressions
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::iter::Peekable;
+use core::str::Chars;
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ExprToken {
+    Number(i64),
+    Identifier(String),
+    LParen,
+    RParen,
+    Not,
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    Modulo,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
+}
+
+/// A tokenizer error, with the 1-based character offset of the bad
+/// character in the expression when there is one
+#[derive(Debug)]
+pub(crate) struct ExprError {
+    pub(crate) message: String,
+    pub(crate) offset: Option<usize>,
+}
+
+impl ExprError {
+    const fn new(message: String) -> Self {
+        Self {
+            message,
+            offset: None,
+        }
+    }
+}
+
+/// Evaluate a preprocessor expression that has already been macro-expanded
+///
+/// `is_defined` answers the `defined` operator; any other identifier left in
+/// the expression evaluates to 0, as in `#if`. Only decimal integer literals
+/// without suffixes are understood.
+///
+/// ```
+/// let value = includium::evaluate_expression("defined(X) && 2 * 3 > 5", |name| name == "X");
+/// assert_eq!(value, Ok(1));
+/// ```
+///
+/// # Errors
+/// Returns an error message if the expression is malformed.
+pub fn evaluate_expression<F>(expr: &str, is_defined: F) -> Result<i64, String>
+where
+    F: Fn(&str) -> bool,
+{
+    let tokens = tokenize_expression(expr).map_err(|err| err.message)?;
+    evaluate_expression_tokens(&tokens, is_defined)
+}
+
+/// Parse a number token from the character iterator
+fn parse_number(ch: char, chars: &mut Peekable<Chars>) -> Result<ExprToken, ExprError> {
+    let mut num = String::new();
+    num.push(ch);
+    while let Some(&d) = chars.peek() {
+        if d.is_ascii_digit() {
+            num.push(d);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+
+    num.parse::<i64>()
+        .map(ExprToken::Number)
+        .map_err(|_| ExprError::new(format!("Invalid number: {num}")))
+}
+
+/// Parse an identifier token from the character iterator
+fn parse_expression_identifier(ch: char, chars: &mut Peekable<Chars>) -> ExprToken {
+    let mut ident = String::new();
+    ident.push(ch);
+    while let Some(&c) = chars.peek() {
+        if c.is_alphanumeric() || c == '_' {
+            ident.push(c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    ExprToken::Identifier(ident)
+}
+
+/// Parse a two-character operator from the character iterator
+fn parse_two_char_operator(
+    first: char,
+    chars: &mut Peekable<Chars>,
+) -> Result<ExprToken, ExprError> {
+    match first {
+        '!' => {
+            if let Some(&'=') = chars.peek() {
+                chars.next();
+                Ok(ExprToken::NotEqual)
+            } else {
+                Ok(ExprToken::Not)
+            }
+        }
+        '=' => {
+            if let Some(&'=') = chars.peek() {
+                chars.next();
+                Ok(ExprToken::Equal)
+            } else {
+                Err(ExprError::new("Invalid operator: =".to_string()))
+            }
+        }
+        '<' => {
+            if let Some(&'=') = chars.peek() {
+                chars.next();
+                Ok(ExprToken::LessEqual)
+            } else if let Some(&'<') = chars.peek() {
+                chars.next();
+                Ok(ExprToken::ShiftLeft)
+            } else {
+                Ok(ExprToken::Less)
+            }
+        }
+        '>' => {
+            if let Some(&'=') = chars.peek() {
+                chars.next();
+                Ok(ExprToken::GreaterEqual)
+            } else if let Some(&'>') = chars.peek() {
+                chars.next();
+                Ok(ExprToken::ShiftRight)
+            } else {
+                Ok(ExprToken::Greater)
+            }
+        }
+        '&' => {
+            if let Some(&'&') = chars.peek() {
+                chars.next();
+                Ok(ExprToken::And)
+            } else {
+                Ok(ExprToken::BitAnd)
+            }
+        }
+        '|' => {
+            if let Some(&'|') = chars.peek() {
+                chars.next();
+                Ok(ExprToken::Or)
+            } else {
+                Ok(ExprToken::BitOr)
+            }
+        }
+        _ => Err(ExprError::new(format!("Invalid operator: {first}"))),
+    }
+}
+
+/// Tokenize expression string into expression tokens
+pub(crate) fn tokenize_expression(expr: &str) -> Result<Vec<ExprToken>, ExprError> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        let token = match ch {
+            '0'..='9' => parse_number(ch, &mut chars)?,
+            'a'..='z' | 'A'..='Z' | '_' => parse_expression_identifier(ch, &mut chars),
+            '(' => ExprToken::LParen,
+            ')' => ExprToken::RParen,
+            '~' => ExprToken::BitNot,
+            '^' => ExprToken::BitXor,
+            '+' => ExprToken::Plus,
+            '-' => ExprToken::Minus,
+            '*' => ExprToken::Multiply,
+            '/' => ExprToken::Divide,
+            '%' => ExprToken::Modulo,
+            c if c.is_whitespace() => continue,
+            '!' | '=' | '<' | '>' | '&' | '|' => parse_two_char_operator(ch, &mut chars)?,
+            _ => {
+                // Column of the bad character within the expression
+                let column = expr.chars().count() - chars.count();
+                return Err(ExprError {
+                    message: format!("Invalid character: {ch}"),
+                    offset: Some(column),
+                });
+            }
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+/// What the evaluator knows about the identifiers in an expression
+pub(crate) trait ExprEnv {
+    /// Whether `name` is a macro, for the `defined` operator
+    fn is_defined(&self, name: &str) -> bool;
+
+    /// Value of an identifier that isn't a macro, or `None` to use 0
+    fn identifier_value(&self, _name: &str) -> Option<i64> {
+        None
+    }
+}
+
+impl<F: Fn(&str) -> bool> ExprEnv for F {
+    fn is_defined(&self, name: &str) -> bool {
+        self(name)
+    }
+}
+
+/// An [`ExprEnv`] from a `defined` test and a lookup for other identifiers
+pub(crate) struct ExprLookup<D, V>(pub D, pub V);
+
+impl<D, V> ExprEnv for ExprLookup<D, V>
+where
+    D: Fn(&str) -> bool,
+    V: Fn(&str) -> Option<i64>,
+{
+    fn is_defined(&self, name: &str) -> bool {
+        (self.0)(name)
+    }
+
+    fn identifier_value(&self, name: &str) -> Option<i64> {
+        (self.1)(name)
+    }
+}
+
+/// Evaluate a preprocessor expression from tokens
+///
+/// # Errors
+/// Returns an error message if the expression is malformed.
+pub(crate) fn evaluate_expression_tokens<E>(tokens: &[ExprToken], env: E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut pos = 0;
+    let result = parse_or(tokens, &mut pos, &env)?;
+    if pos != tokens.len() {
+        return Err("Unexpected tokens at end of expression".to_string());
+    }
+    Ok(result)
+}
+
+fn parse_or<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_and(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::Or => {
+                *pos += 1;
+                let right = parse_and(tokens, pos, env)?;
+                left = i64::from(left != 0 || right != 0);
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_and<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_bit_or(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::And => {
+                *pos += 1;
+                let right = parse_bit_or(tokens, pos, env)?;
+                left = i64::from(left != 0 && right != 0);
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_bit_or<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_bit_xor(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::BitOr => {
+                *pos += 1;
+                let right = parse_bit_xor(tokens, pos, env)?;
+                left |= right;
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_bit_xor<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_bit_and(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::BitXor => {
+                *pos += 1;
+                let right = parse_bit_and(tokens, pos, env)?;
+                left ^= right;
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_bit_and<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_equality(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::BitAnd => {
+                *pos += 1;
+                let right = parse_equality(tokens, pos, env)?;
+                left &= right;
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_equality<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_comparison(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::Equal => {
+                *pos += 1;
+                let right = parse_comparison(tokens, pos, env)?;
+                left = i64::from(left == right);
+            }
+            ExprToken::NotEqual => {
+                *pos += 1;
+                let right = parse_comparison(tokens, pos, env)?;
+                left = i64::from(left != right);
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_comparison<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_shift(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::Less => {
+                *pos += 1;
+                let right = parse_shift(tokens, pos, env)?;
+                left = i64::from(left < right);
+            }
+            ExprToken::LessEqual => {
+                *pos += 1;
+                let right = parse_shift(tokens, pos, env)?;
+                left = i64::from(left <= right);
+            }
+            ExprToken::Greater => {
+                *pos += 1;
+                let right = parse_shift(tokens, pos, env)?;
+                left = i64::from(left > right);
+            }
+            ExprToken::GreaterEqual => {
+                *pos += 1;
+                let right = parse_shift(tokens, pos, env)?;
+                left = i64::from(left >= right);
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_shift<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_additive(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::ShiftLeft => {
+                *pos += 1;
+                let right = parse_additive(tokens, pos, env)?;
+                left = left.wrapping_shl(right as u32);
+            }
+            ExprToken::ShiftRight => {
+                *pos += 1;
+                let right = parse_additive(tokens, pos, env)?;
+                left = left.wrapping_shr(right as u32);
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_additive<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_multiplicative(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::Plus => {
+                *pos += 1;
+                let right = parse_multiplicative(tokens, pos, env)?;
+                left = left.wrapping_add(right);
+            }
+            ExprToken::Minus => {
+                *pos += 1;
+                let right = parse_multiplicative(tokens, pos, env)?;
+                left = left.wrapping_sub(right);
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_multiplicative<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    let mut left = parse_unary(tokens, pos, env)?;
+    while *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::Multiply => {
+                *pos += 1;
+                let right = parse_unary(tokens, pos, env)?;
+                left = left.wrapping_mul(right);
+            }
+            ExprToken::Divide => {
+                *pos += 1;
+                let right = parse_unary(tokens, pos, env)?;
+                if right == 0 {
+                    return Err("Division by zero".to_string());
+                }
+                left = left.wrapping_div(right);
+            }
+            ExprToken::Modulo => {
+                *pos += 1;
+                let right = parse_unary(tokens, pos, env)?;
+                if right == 0 {
+                    return Err("Modulo by zero".to_string());
+                }
+                left = left.wrapping_rem(right);
+            }
+            _ => break,
+        }
+    }
+    Ok(left)
+}
+
+fn parse_unary<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    if *pos < tokens.len() {
+        match tokens[*pos] {
+            ExprToken::Not => {
+                *pos += 1;
+                let expr = parse_unary(tokens, pos, env)?;
+                return Ok(i64::from(expr == 0));
+            }
+            ExprToken::BitNot => {
+                *pos += 1;
+                let expr = parse_unary(tokens, pos, env)?;
+                return Ok(!expr);
+            }
+            ExprToken::Minus => {
+                *pos += 1;
+                let expr = parse_unary(tokens, pos, env)?;
+                return Ok(expr.wrapping_neg());
+            }
+            ExprToken::Plus => {
+                *pos += 1;
+                let expr = parse_unary(tokens, pos, env)?;
+                return Ok(expr);
+            }
+            _ => {}
+        }
+    }
+    parse_primary(tokens, pos, env)
+}
+
+/// Parse the defined operator: defined identifier or defined(identifier)
+fn parse_defined_operator<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    // Check for defined(identifier) form
+    if *pos < tokens.len() && matches!(tokens[*pos], ExprToken::LParen) {
+        *pos += 1;
+
+        // Expect identifier after (
+        if *pos >= tokens.len() {
+            return Err("Expected identifier after defined(".to_string());
+        }
+
+        let id = match &tokens[*pos] {
+            ExprToken::Identifier(id) => {
+                *pos += 1;
+                id.clone()
+            }
+            _ => return Err("Expected identifier after defined(".to_string()),
+        };
+
+        // Expect closing )
+        if *pos >= tokens.len() || !matches!(tokens[*pos], ExprToken::RParen) {
+            return Err("Expected ) after defined(identifier".to_string());
+        }
+        *pos += 1;
+
+        Ok(i64::from(env.is_defined(&id)))
+    }
+    // Check for defined identifier form
+    else if *pos < tokens.len() {
+        match &tokens[*pos] {
+            ExprToken::Identifier(id) => {
+                let defined = env.is_defined(id);
+                *pos += 1;
+                Ok(i64::from(defined))
+            }
+            _ => Err("defined must be followed by identifier or (identifier)".to_string()),
+        }
+    } else {
+        Err("defined must be followed by identifier or (identifier)".to_string())
+    }
+}
+
+fn parse_primary<E>(tokens: &[ExprToken], pos: &mut usize, env: &E) -> Result<i64, String>
+where
+    E: ExprEnv,
+{
+    if *pos >= tokens.len() {
+        return Err("Unexpected end of expression".to_string());
+    }
+
+    match &tokens[*pos] {
+        ExprToken::Number(val) => {
+            *pos += 1;
+            Ok(*val)
+        }
+        ExprToken::Identifier(ident) => {
+            *pos += 1;
+            if ident == "defined" {
+                parse_defined_operator(tokens, pos, env)
+            } else {
+                // Preprocessor treats undefined identifiers as 0
+                Ok(env.identifier_value(ident).unwrap_or(0))
+            }
+        }
+        ExprToken::LParen => {
+            *pos += 1;
+            let val = parse_or(tokens, pos, env)?;
+            if *pos >= tokens.len() || !matches!(tokens[*pos], ExprToken::RParen) {
+                return Err("Expected )".to_string());
+            }
+            *pos += 1;
+            Ok(val)
+        }
+        _ => Err("Expected number or identifier".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::evaluate_expression;
+
+    #[test]
+    fn evaluates_without_std() {
+        let defined = |name: &str| name == "FOO";
+        assert_eq!(evaluate_expression("1 + 2 * 3", defined), Ok(7));
+        assert_eq!(evaluate_expression("(1 << 4) | 3", defined), Ok(19));
+        assert_eq!(
+            evaluate_expression("defined FOO && !defined(BAR)", defined),
+            Ok(1)
+        );
+        assert_eq!(evaluate_expression("UNKNOWN", defined), Ok(0));
+        assert!(evaluate_expression("1 +", defined).is_err());
+        assert!(evaluate_expression("1 $ 2", defined).is_err());
+    }
+}
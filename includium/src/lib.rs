@@ -1,3 +1,4 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![warn(missing_docs)]
 #![warn(clippy::unwrap_used)]
 #![warn(clippy::expect_used)]
@@ -19,13 +20,19 @@
 //! - **Stringification** (`#`) and **token pasting** (`##`) operators
 //! - **Full conditional compilation** with nested `#if`, `#ifdef`, `#ifndef`, `#else`, `#elif`, `#endif` blocks
 //! - **Include processing** with custom resolvers and `#pragma once` support
-//! - **Predefined macros**: `__FILE__`, `__LINE__`, `__DATE__`, `__TIME__`
+//! - **Predefined macros**: `__FILE__`, `__LINE__`, `__DATE__`, `__TIME__`, `__COUNTER__`
 //! - **Built-in compiler intrinsics** and sizeof stubs
-//! - **Target-specific preprocessing** for Linux, Windows, and macOS
+//! - **`#pragma GCC poison`** to ban identifiers such as unsafe functions
+//! - **GCC assertions** (`#assert`, `#unassert`, `#if #machine(x86)`) for legacy code
+//! - **`#ident` and `#sccs`** version strings passed through to the output (GCC and Clang)
+//! - **Target-specific preprocessing** for Linux, Windows, macOS, and FreeBSD
 //! - **Compiler-specific macro definitions** (GCC, Clang, MSVC)
 //! - **Comprehensive error reporting** with location context and source line display
 //! - **Include cycle detection** and recursion protection
 //! - **Expression evaluation** with full operator precedence
+//! - **Typed token output** through `process_tokens`, with spans into the output text
+//! - **Macro table export and import** through `export_macros`, `import_macros`
+//!   and `load_macros`, serializable with the `serde` feature
 //! - **C FFI API** for integration with other languages
 //!
 //! ## Quick Start
@@ -106,6 +113,9 @@
 //! // macOS + Clang
 //! let macos_config = PreprocessorConfig::for_macos().with_compiler(Compiler::Clang);
 //!
+//! // FreeBSD + Clang
+//! let freebsd_config = PreprocessorConfig::for_freebsd();
+//!
 //! // Custom configuration with warning handler
 //! let warning_handler = Rc::new(|msg: &str| {
 //!     eprintln!("Warning: {}", msg);
@@ -176,11 +186,37 @@
 //! println!("{}", result);
 //! ```
 //!
+//! As in the C standard, macro arguments are fully expanded before they are
+//! substituted, except where they are operands of `#` or `##`, so pasting
+//! the expansion of an argument takes a second macro level
+//! (`#define CAT(a, b) CAT_I(a, b)`). The replacement is then rescanned
+//! together with the rest of the line, so `ID(F)(1)` calls `F`.
+//!
+//! An empty argument pasted with `##` disappears, so `CAT(, x)` gives `x`.
+//!
+//! Known difference from GCC: a call whose macro name comes from an
+//! expansion isn't continued onto the next source line.
+//!
+//! `#` and `##` are operators only in the replacement list of a macro: in
+//! ordinary lines and in arguments they are plain tokens, so `ID(a ## b)`
+//! gives `a ## b` and `# ## #` pastes to a literal `##`.
+//!
 //! ## C API
 //!
 //! Includium also provides a C FFI API for integration with other languages.
-//! See the `c_api` module for available C functions.
+//! See the `c_api` module for available C functions. The shared library that
+//! `include/includium.h` describes is built by the `includium-capi` crate in
+//! the same workspace.
+//!
+//! ## `no_std`
+//!
+//! Without the default `std` feature the crate needs only `alloc`. File I/O,
+//! the C API and deadlines are left out, and `__DATE__` and `__TIME__`
+//! expand to `"??? ?? ????"` and `"??:??:??"` as GCC does without a clock.
+
+extern crate alloc;
 
+#[cfg(feature = "std")]
 mod c_api;
 mod config;
 mod context;
@@ -188,25 +224,48 @@ mod date_time;
 mod driver;
 mod engine;
 mod error;
+mod event;
+mod expr;
 mod macro_def;
+mod output;
+#[cfg(feature = "std")]
+mod resolver;
+mod stream;
 mod token;
 
 pub use config::{
-    Compiler, IncludeContext, IncludeKind, IncludeResolver, LineEnding, PreprocessorConfig, Target,
-    WarningHandler,
+    BuiltinGroups, ByteOrder, CompiledConfig, Compiler, DefinedFromMacro, ExpansionEvent,
+    ExpansionTraceHandler, ExprIdentifierResolver, IncludeContext, IncludeHook, IncludeKind,
+    IncludeResolution, IncludeResolver, IncludeSizePolicy, Language, LineEnding, PathSeparator,
+    PreludeItem, PreprocessorConfig, PreprocessorConfigBuilder, Standard, Target, WarningHandler,
 };
-pub use context::PreprocessorContext;
-pub use driver::PreprocessorDriver;
-pub use error::{PreprocessError, PreprocessErrorKind};
+pub use context::{PreprocessStats, PreprocessorContext};
+pub use driver::{OutputLine, PreprocessorDriver, ScopedDriver};
+pub use error::{ConfigError, PreprocessError, PreprocessErrorKind};
+pub use event::{PpEvent, SourceLocation};
+pub use expr::evaluate_expression;
+pub use output::{Dependency, Diagnostic, ProcessOutput};
+#[cfg(feature = "std")]
+pub use resolver::FileSystemResolver;
+pub use stream::{PpToken, PpTokenKind, Span, TokenStream};
 
 // Token, ExprToken, Macro are internal or accessible via PreprocessorDriver methods if needed,
 // but Macro struct is public so it can be returned by get_macros.
-pub use macro_def::Macro;
+pub use macro_def::{Macro, MacroEntry, MacroKind, MacroSet, MacroTable};
 
 // Re-export Preprocessor as alias to PreprocessorDriver for backward compatibility
 pub use PreprocessorDriver as Preprocessor;
 
+// Without std there is no hasher, so the ordered collections stand in
+#[cfg(not(feature = "std"))]
+pub(crate) use alloc::collections::{BTreeMap as HashMap, BTreeSet as HashSet};
+#[cfg(feature = "std")]
+pub(crate) use std::collections::{HashMap, HashSet};
+
+use alloc::string::String;
+#[cfg(feature = "std")]
 use std::fs;
+#[cfg(feature = "std")]
 use std::path::Path;
 
 /// Preprocess C code with the given configuration.
@@ -224,37 +283,99 @@ pub fn process<S: AsRef<str>>(
     driver.process(input.as_ref())
 }
 
+/// Preprocess C code with the given configuration, returning the output
+/// together with the warnings, dependencies and work counters
+///
+/// # Errors
+/// Returns `PreprocessError` under the same conditions as [`process`].
+pub fn process_full<S: AsRef<str>>(
+    input: S,
+    config: &PreprocessorConfig,
+) -> Result<ProcessOutput, PreprocessError> {
+    let mut driver = PreprocessorDriver::new();
+    driver.apply_config(config);
+    driver.process_full(input.as_ref())
+}
+
+/// Preprocess C code given as bytes with the given configuration
+///
+/// See [`PreprocessorDriver::process_bytes`] for how invalid UTF-8 is handled.
+///
+/// # Errors
+/// Returns `PreprocessError` for invalid UTF-8 unless
+/// [`PreprocessorConfig::lossy_utf8`] is set, and under the same conditions
+/// as [`process`].
+pub fn process_bytes<B: AsRef<[u8]>>(
+    input: B,
+    config: &PreprocessorConfig,
+) -> Result<String, PreprocessError> {
+    let mut driver = PreprocessorDriver::new();
+    driver.apply_config(config);
+    driver.process_bytes(input.as_ref())
+}
+
 /// Preprocess a C file and write the result to another file
 ///
+/// The file is read as bytes; invalid UTF-8 is handled as
+/// [`PreprocessorConfig::lossy_utf8`] asks.
+///
 /// # Errors
 /// Returns `PreprocessError` if the input file cannot be read,
 /// the output file cannot be written, or if preprocessing fails.
+#[cfg(feature = "std")]
 pub fn process_file<P: AsRef<Path>>(
     input_path: P,
     output_path: P,
     config: &PreprocessorConfig,
 ) -> Result<(), PreprocessError> {
-    let input = fs::read_to_string(input_path)?;
-    let output = process(&input, config)?;
+    let output = process_bytes(fs::read(input_path)?, config)?;
+    fs::write(output_path, output)?;
+    Ok(())
+}
+
+/// Preprocess a C file that may not be valid UTF-8, such as old code with
+/// Latin-1 comments, and write the result to another file
+///
+/// Invalid sequences become U+FFFD whatever [`PreprocessorConfig::lossy_utf8`]
+/// says, and a warning is reported for the first one.
+///
+/// # Errors
+/// Returns `PreprocessError` if the input file cannot be read,
+/// the output file cannot be written, or if preprocessing fails.
+#[cfg(feature = "std")]
+pub fn process_file_lossy<P: AsRef<Path>>(
+    input_path: P,
+    output_path: P,
+    config: &PreprocessorConfig,
+) -> Result<(), PreprocessError> {
+    let mut driver = PreprocessorDriver::new();
+    driver.apply_config(config);
+    driver.set_lossy_utf8(true);
+    let output = driver.process_bytes(&fs::read(input_path)?)?;
     fs::write(output_path, output)?;
     Ok(())
 }
 
 /// Preprocess a C file and return the result as a string
 ///
+/// Invalid UTF-8 is handled as [`PreprocessorConfig::lossy_utf8`] asks.
+///
 /// # Errors
 /// Returns `PreprocessError` if the file cannot be read or if preprocessing fails.
+#[cfg(feature = "std")]
 pub fn preprocess_c_file_to_string<P: AsRef<Path>>(
     input_path: P,
     config: &PreprocessorConfig,
 ) -> Result<String, PreprocessError> {
-    let input = fs::read_to_string(input_path)?;
-    process(&input, config)
+    process_bytes(fs::read(input_path)?, config)
 }
 
 #[cfg(test)]
+#[cfg(feature = "std")]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn simple_object_macro() {
@@ -295,6 +416,60 @@ int x = FOO;
         assert!(out.contains("42"));
     }
 
+    #[test]
+    fn comments_are_stripped_from_directives() {
+        let src = r#"#include "x.h" // trailing
+#include /* c */ <x.h>
+#define FOO 1 /* comment */
+#ifdef FOO /* c */
+foo FOO
+#endif /* done */
+#ifndef BAR // c
+bar
+#endif
+#if FOO /* x */ + 1 == 2 // y
+if
+#endif
+#line 10 /* c */ "z.c" // x
+__LINE__ __FILE__
+"#;
+        let mut pp = Preprocessor::new().with_include_resolver(|p, _kind, _context| {
+            (p == "x.h").then(|| "int x_h;\n".to_string())
+        });
+        let out = pp.process(src).unwrap();
+        assert_eq!(out, "int x_h;\n\nint x_h;\n\nfoo 1\nbar\nif\n10 \"z.c\"\n");
+    }
+
+    #[test]
+    fn ident_and_sccs_pass_through() {
+        let src = "#ident \"$Id: a.c 1 $\" // rev\n#sccs  \"@(#)a.c\"\n#if 0\n#ident 42\n#endif\n";
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        assert_eq!(out, "#ident \"$Id: a.c 1 $\"\n#sccs \"@(#)a.c\"\n");
+    }
+
+    #[test]
+    fn ident_requires_a_single_string_literal() {
+        for src in ["#ident\n", "#ident VERSION\n", "#sccs \"a\" \"b\"\n"] {
+            let err = Preprocessor::new().process(src).unwrap_err();
+            assert!(
+                err.to_string().contains("expected a string literal"),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn ident_is_an_unknown_directive_for_msvc() {
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_windows());
+        let out = pp.process("#ident \"v1\"\nx\n").unwrap();
+        assert_eq!(out, "x\r\n");
+        assert_eq!(
+            warnings.borrow().as_slice(),
+            ["<stdin>:1: invalid preprocessing directive #ident"]
+        );
+    }
+
     #[test]
     fn conditional_compilation_ifdef() {
         let src = r#"
@@ -371,6 +546,65 @@ int x = 1;
         assert!(out.contains("int x = 1;"));
     }
 
+    #[test]
+    fn expression_with_trailing_comment() {
+        let src = "#if 1 // yes\na\n#endif\n#if 1 /* yes */\nb\n#elif 0 // no\n#endif\n";
+        let mut pp = Preprocessor::new();
+        assert_eq!(pp.process(src).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn expression_with_trailing_garbage_errors() {
+        for src in ["#if 1 2\n#endif\n", "#if 0 // no\n#elif 1 x\n#endif\n"] {
+            let mut pp = Preprocessor::new();
+            let err = pp.process(src).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("Unexpected tokens at end of expression"),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn expression_chained_object_macros() {
+        let src = r#"
+#define A B
+#define B C
+#define C 3
+#if A == 3 && defined(A) && B * C == 9
+int x = 1;
+#endif
+"#;
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        assert!(out.contains("int x = 1;"));
+    }
+
+    #[test]
+    fn expression_macro_keeps_its_parentheses() {
+        let src = r#"
+#define MAX (1<<8)
+#define HALF (MAX / 2)
+#define SUM 1 + 2
+#if MAX > 100 && HALF == 128 && -HALF < 0
+int x = 1;
+#endif
+#if SUM * 2 == 5
+int y = 2;
+#endif
+#if (SUM) * 2 == 6
+int z = 3;
+#endif
+"#;
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        assert!(out.contains("int x = 1;"));
+        // An unparenthesized body splices into the expression as written
+        assert!(out.contains("int y = 2;"));
+        assert!(out.contains("int z = 3;"));
+    }
+
     #[test]
     fn comment_stripping() {
         let src = r#"
@@ -435,6 +669,118 @@ int y = x;
         assert!(out.contains("int y = x;"));
     }
 
+    #[test]
+    fn pragma_once_is_per_process_call_and_keyed_by_normalized_path() {
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _kind, _context| {
+            matches!(path, "once.h" | "./once.h" | "sub/../once.h")
+                .then(|| "#pragma once\nint once;\n".to_string())
+        });
+
+        let src =
+            "#include \"once.h\"\n#include \"./once.h\"\n#include \"sub/../once.h\"\nint end;\n";
+        let first = pp.process(src).unwrap();
+        assert_eq!(first, "int once;\n\nint end;\n");
+
+        // A new call is a new translation unit
+        let second = pp.process("#include \"once.h\"\n").unwrap();
+        assert_eq!(second, "int once;\n\n");
+    }
+
+    #[test]
+    fn pragma_once_uses_the_resolver_identity() {
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _kind, _context| {
+            let content = "#pragma once\nint config;\n".to_string();
+            match path {
+                "config.h" | "alias/config.h" => IncludeResolution::Identified {
+                    content,
+                    identity: "config-v1".to_string(),
+                },
+                "copy.h" => IncludeResolution::Content(content),
+                _ => IncludeResolution::NotFound,
+            }
+        });
+
+        let src = "#include \"config.h\"\n#include \"alias/config.h\"\n#include \"copy.h\"\n";
+        let out = pp.process(src).unwrap();
+        // The alias shares the identity; the plain copy is keyed by its name
+        assert_eq!(out.matches("int config;").count(), 2);
+    }
+
+    #[test]
+    fn token_stream_displays_like_process() {
+        let src = "#define CAT(a, b) a ## b
+#define STR(x) #x
+#define INC(x) x++
+int CAT(va, r1) = 0x1F + 1.5e-3 + .5;
+char *s = STR(a  \"b\"); char c = '\\n';
+INC(i); p->x <<= CAT(-, =) 2;
+_Pragma(\"once\") $ @
+#pragma pack(1)
+";
+        let mut pp = Preprocessor::new();
+        let expected = pp.process(src).unwrap();
+        for input in [src, src.trim_end()] {
+            let expected = pp.process(input).unwrap();
+            assert_eq!(pp.process_tokens(input).unwrap().to_string(), expected);
+        }
+
+        let config = PreprocessorConfig::builder()
+            .line_ending(LineEnding::CRLF)
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        let stream = pp.process_tokens(src).unwrap();
+        assert_eq!(stream.to_string(), pp.process(src).unwrap());
+        assert_eq!(stream.to_string().replace("\r\n", "\n"), expected);
+    }
+
+    #[test]
+    fn token_stream_classifies_tokens_with_spans() {
+        let mut pp = Preprocessor::new();
+        pp.process("#define CAT(a, b) a ## b\n#define FOO FOO\n#define F(x) x\n#define X X\n")
+            .unwrap();
+        let stream = pp
+            .process_tokens("x = CAT(-, >) 0x1F+1e+5; s = \"hi\" 'c' $; FOO F(FOO) X;\nnext\n")
+            .unwrap();
+        let tokens: Vec<(PpTokenKind, &str)> = stream
+            .significant()
+            .map(|token| (token.kind, token.text.as_str()))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (PpTokenKind::Identifier, "x"),
+                (PpTokenKind::Punctuator, "="),
+                (PpTokenKind::Punctuator, "->"),
+                (PpTokenKind::Number, "0x1F"),
+                (PpTokenKind::Punctuator, "+"),
+                (PpTokenKind::Number, "1e+5"),
+                (PpTokenKind::Punctuator, ";"),
+                (PpTokenKind::Identifier, "s"),
+                (PpTokenKind::Punctuator, "="),
+                (PpTokenKind::StringLiteral, "\"hi\""),
+                (PpTokenKind::CharLiteral, "'c'"),
+                (PpTokenKind::Other, "$"),
+                (PpTokenKind::Punctuator, ";"),
+                (PpTokenKind::Identifier, "FOO"),
+                (PpTokenKind::Identifier, "FOO"),
+                (PpTokenKind::Identifier, "X"),
+                (PpTokenKind::Punctuator, ";"),
+                (PpTokenKind::Identifier, "next"),
+            ]
+        );
+
+        let text = stream.to_string();
+        for token in &stream {
+            assert_eq!(&text[token.span.start..token.span.end], token.text);
+        }
+        let next = stream.significant().last().unwrap();
+        assert_eq!((next.span.line, next.span.column), (2, 1));
+        let string = &stream.significant().nth(9).unwrap().span;
+        assert_eq!((string.line, string.column), (1, 23));
+        assert_eq!(stream.last().unwrap().kind, PpTokenKind::Newline);
+    }
+
     #[test]
     fn pragma_operator() {
         let src = r#"
@@ -448,6 +794,93 @@ int x = 1;
         // Check that pragma once was handled (no duplicate includes, but since no include, just check no error)
     }
 
+    #[test]
+    fn pragma_once_from_macro_expansion() {
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _kind, _context| {
+            if path == "header.h" {
+                Some("#define ONCE _Pragma(\"once\")\nONCE\nint x = 42;\n".to_string())
+            } else {
+                None
+            }
+        });
+        let src = "#include \"header.h\"\n#include \"header.h\"\n";
+        let out = pp.process(src).unwrap();
+        assert_eq!(out.matches("int x = 42;").count(), 1);
+        assert!(!out.contains("_Pragma"));
+    }
+
+    #[test]
+    fn pragma_operator_from_macro_gets_its_own_line() {
+        let src = "#define WARN _Pragma(\"message(\\\"hi\\\")\") int y;\nWARN\n";
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        let lines: Vec<&str> = out.lines().map(str::trim).collect();
+        assert_eq!(lines, vec!["#pragma message(\"hi\")", "int y;"]);
+    }
+
+    #[test]
+    fn pragma_operator_built_by_stringizing() {
+        let src = r#"
+#define DO_PRAGMA(x) _Pragma(#x)
+#define MESSAGE(m) DO_PRAGMA(message(m))
+DO_PRAGMA(pack(1))
+struct packed { char c; int i; };
+MESSAGE("done")
+DO_PRAGMA(pack())
+"#;
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        let lines: Vec<&str> = out
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "#pragma pack(1)",
+                "struct packed { char c; int i; };",
+                "#pragma message(\"done\")",
+                "#pragma pack()",
+            ]
+        );
+    }
+
+    #[test]
+    fn pragma_operator_concatenates_adjacent_strings() {
+        let src = r#"_Pragma("mess" "age")
+_Pragma( "GCC "  /* comment */ "visibility" "(push)" )
+_Pragma("a\\b " "\"q\"")
+"#;
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "#pragma message",
+                "#pragma GCC visibility(push)",
+                r#"#pragma a\b "q""#
+            ]
+        );
+    }
+
+    #[test]
+    fn macro_expanding_to_directive_text_is_not_a_directive() {
+        let src = r#"
+#define MAKE_DEFINE # define X 1
+MAKE_DEFINE
+#ifdef X
+int defined_x;
+#endif
+"#;
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        assert!(out.contains("# define X 1"));
+        assert!(!out.contains("defined_x"));
+        assert!(!pp.is_defined("X"));
+    }
+
     #[test]
     fn conditional_compilation_elif() {
         let src = r#"
@@ -491,6 +924,50 @@ int x;
         assert!(pp.process(src).is_ok());
     }
 
+    #[test]
+    fn line_directives_pass_through_lex_output() {
+        let src = r#"#include "tokens.h"
+#line 3 "scan.l"
+int yylex(void) { return __LINE__; }
+#define YY_BUF 16
+char buf[YY_BUF];
+#line 40 "lex.yy.c"
+int yywrap(void) { return __LINE__; }
+"#;
+        let config = PreprocessorConfig::for_linux().with_passthrough_line_directives(true);
+        let mut pp = PreprocessorDriver::with_config(&config).with_include_resolver(
+            |path, _kind, _context| {
+                (path == "tokens.h").then(|| {
+                    "enum { EOF_TOKEN };\n#line 7 \"tokens.y\"\nenum { NUM = __LINE__ };\n"
+                        .to_string()
+                })
+            },
+        );
+        let out = pp.process(src).unwrap();
+        assert_eq!(
+            out,
+            r#"enum { EOF_TOKEN };
+#line 7 "tokens.y"
+enum { NUM = 7 };
+#line 1 "<stdin>"
+
+#line 3 "scan.l"
+int yylex(void) { return 3; }
+#line 5 "scan.l"
+char buf[16];
+#line 40 "lex.yy.c"
+int yywrap(void) { return 40; }
+"#
+        );
+
+        // Without the option the directives only move __LINE__
+        let mut pp = PreprocessorDriver::with_config(&PreprocessorConfig::for_linux());
+        let out = pp
+            .process("#line 20 \"scan.l\"\nint a = __LINE__;\n")
+            .unwrap();
+        assert_eq!(out, "int a = 20;\n");
+    }
+
     #[test]
     fn undef_directive() {
         let src = r#"
@@ -654,6 +1131,78 @@ int x = 1;
         assert!(display.contains("unterminated"));
     }
 
+    #[test]
+    fn unterminated_conditionals_name_their_openings() {
+        let src = "#ifdef A\n#endif\n#ifdef OUTER\nint a;\n#if 1 // inner\nint b;\n";
+        let mut pp = Preprocessor::new();
+        pp.set_current_file("main.c".to_string());
+        let error = pp.process(src).unwrap_err();
+        assert_eq!((error.file.as_str(), error.line), ("main.c", 5));
+        assert!(
+            error.to_string().contains(
+                "2 unterminated conditionals, innermost first: #if 1 // inner opened at main.c:5; #ifdef OUTER opened at main.c:3"
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn unterminated_conditional_in_include_is_reported_in_the_header() {
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _kind, _context| {
+            (path == "open.h").then(|| "int h;\n#ifndef GUARD\nint g;\n".to_string())
+        });
+        let error = pp.process("#include \"open.h\"\n").unwrap_err();
+        assert_eq!((error.file.as_str(), error.line), ("open.h", 2));
+        assert!(
+            error.to_string().contains(
+                "unterminated #ifndef GUARD opened at open.h:2 (end of 'open.h', included from '<stdin>'"
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn include_leaving_if_open_for_the_includer_is_an_error() {
+        // The header opens a block it expects the main file to close
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _kind, _context| {
+            (path == "begin.h").then(|| "#if 0\n".to_string())
+        });
+        pp.set_current_file("main.c".to_string());
+        let error = pp
+            .process("#include \"begin.h\"\nhidden\n#endif\nshown\n")
+            .unwrap_err();
+        assert_eq!((error.file.as_str(), error.line), ("begin.h", 1));
+        assert!(
+            error.to_string().contains(
+                "unterminated #if 0 opened at begin.h:1 (end of 'begin.h', included from 'main.c'; conditionals must be closed in the file that opens them)"
+            ),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn conditionals_must_balance_within_each_file() {
+        // Opened in the includer and closed in the header
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _kind, _context| {
+            (path == "close.h").then(|| "#endif\n".to_string())
+        });
+        let error = pp
+            .process("#if 1\n#include \"close.h\"\nint x;\n")
+            .unwrap_err();
+        assert_eq!((error.file.as_str(), error.line), ("close.h", 1));
+        assert!(error.to_string().contains("#endif without #if"), "{error}");
+
+        // Opened in the header and closed in the includer
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _kind, _context| {
+            (path == "open.h").then(|| "#if 1\n".to_string())
+        });
+        let error = pp
+            .process("#include \"open.h\"\nint x;\n#endif\n")
+            .unwrap_err();
+        assert_eq!((error.file.as_str(), error.line), ("open.h", 1));
+        assert!(error.to_string().contains("unterminated #if 1"), "{error}");
+    }
+
     #[test]
     fn elif_without_if_error() {
         // Test #elif without #if error
@@ -937,25 +1486,125 @@ int chained = CHAIN1(test);
 
     #[test]
     fn token_pasting_with_macro_argument_expansion() {
-        // Test token pasting where arguments need macro expansion first
+        // Arguments pasted with ## are not expanded first; a second macro
+        // level is needed to paste the expansion, as in GCC
         let src = r#"
 #define ARG(x) expanded_##x
 #define PASTE(a, b) a##b
+#define XPASTE(a, b) PASTE(a, b)
 
 // Test where argument contains a macro
 #define VALUE test
 int result = PASTE(pre_, VALUE);
+int expanded = XPASTE(pre_, VALUE);
 
 // Test nested token pasting
 #define INNER(x) inner_##x
 #define OUTER(x) PASTE(outer_, INNER(x))
+#define XOUTER(x) XPASTE(outer_, INNER(x))
 int nested = OUTER(item);
+int xnested = XOUTER(item);
+"#;
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+
+        assert!(out.contains("int result = pre_VALUE;"));
+        assert!(out.contains("int expanded = pre_test;"));
+        assert!(out.contains("int nested = outer_INNER(item);"));
+        assert!(out.contains("int xnested = outer_inner_item;"));
+    }
+
+    #[test]
+    fn prescan_expands_higher_order_macros_like_gcc() {
+        let src = r#"#define SQUARE(n) ((n)*(n))
+#define CALL(f, x) f(x)
+#define APPLY(m, ...) m(__VA_ARGS__)
+#define ADD(a, b) ((a)+(b))
+#define CAT(a, b) CAT_I(a, b)
+#define CAT_I(a, b) a ## b
+#define B() _suffix
+#define INC(n) ((n)+1)
+#define ID(x) x
+#define F(x) [x]
+CALL(SQUARE, 4)
+APPLY(ADD, 1, 2)
+APPLY(SQUARE, INC(2))
+CAT(A, B())
+CAT_I(A, B())
+INC(INC(1))
+F(F(2))
+ID(F)(1)
+ID(ID)(5)
+"#;
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            [
+                "((4)*(4))",
+                "((1)+(2))",
+                "((((2)+1))*(((2)+1)))",
+                "A_suffix",
+                "AB()",
+                "((((1)+1))+1)",
+                "[[2]]",
+                "[1]",
+                "ID(5)",
+            ]
+        );
+    }
+
+    #[test]
+    fn painted_macro_names_stay_unexpanded() {
+        // The rescanning example from C11 6.10.3.5
+        let src = r#"#define x 3
+#define f(a) f(x * (a))
+#undef x
+#define x 2
+#define g f
+#define z z[0]
+#define t(a) a
+f(y+1) + f(f(z)) % t(t(g)(0) + t)(1);
+#define SELF(x) SELF
+SELF(1)(2)
+#define FF(x) GG
+#define GG(y) FF
+FF(1)(2)(3)(4)
 "#;
         let mut pp = Preprocessor::new();
         let out = pp.process(src).unwrap();
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            [
+                "f(2 * (y+1)) + f(2 * (f(2 * (z[0])))) % f(2 * (0)) + t(1);",
+                "SELF(2)",
+                "FF",
+            ]
+        );
+    }
 
-        assert!(out.contains("int result = pre_test;"));
-        assert!(out.contains("int nested = outer_inner_item;"));
+    #[test]
+    fn pasting_line_through_indirection_uses_its_value() {
+        let src = "#define PASTE_(a, b) a##b
+#define PASTE(a, b) PASTE_(a, b)
+#define UNIQUE_NAME(prefix) PASTE(prefix, __LINE__)
+int UNIQUE_NAME(tmp);
+int PASTE(var, __LINE__);
+int PASTE_(raw, __LINE__);
+";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let out = pp.process(src).unwrap();
+        assert_eq!(
+            out.lines().collect::<Vec<_>>(),
+            ["int tmp4;", "int var5;", "int raw__LINE__;"]
+        );
+        assert_eq!(
+            *warnings.borrow(),
+            [
+                "<stdin>:6: pasting \"__LINE__\" uses its name, not its value; \
+              expand it through another macro before pasting"
+            ]
+        );
     }
 
     #[test]
@@ -1431,6 +2080,63 @@ LEVEL1(input)
         assert!(out4.contains("final_input"));
     }
 
+    #[test]
+    fn recursion_limit_error_names_the_chain() {
+        let mut src: String = (0..10)
+            .map(|i| format!("#define M{i} M{}\n", i + 1))
+            .collect();
+        src.push_str("M0\n");
+        let mut pp = Preprocessor::new();
+        pp.set_recursion_limit(5);
+        let err = pp.process(&src).unwrap_err();
+
+        assert!(matches!(
+            err.kind,
+            PreprocessErrorKind::RecursionLimitExceeded(_)
+        ));
+        assert_eq!(*err.expansion_stack, ["M0", "M1", "M2", "M3", "M4", "M5"]);
+        let message = err.to_string();
+        assert!(message.contains("more than 5 levels"), "{message}");
+        assert!(
+            message.contains("while expanding M0 -> M1 -> M2 -> M3 -> M4 -> M5"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn recursion_limit_error_shows_a_mutually_recursive_pair() {
+        let src = "#define A(x) B(x)\n#define B(x) A(x)\nA(B(A(B(A(B(A(B(1))))))))\n";
+        let mut pp = Preprocessor::new();
+        assert_eq!(pp.process(src).unwrap(), "A(B(A(B(A(B(A(B(1))))))))\n");
+
+        pp.set_recursion_limit(5);
+        let err = pp.process(src).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains(
+                "more than 5 levels while expanding A -> B -> A (macro cycle A -> B -> A) ["
+            ),
+            "{message}"
+        );
+        assert_eq!(*err.expansion_stack, ["A", "B", "A"]);
+    }
+
+    #[test]
+    fn expansion_cycle_is_found_at_the_top_of_the_stack() {
+        let stack: Vec<String> = ["X", "A", "B", "C", "A"].map(String::from).into();
+        assert_eq!(
+            engine::expansion_cycle(&stack).unwrap(),
+            ["A", "B", "C", "A"]
+        );
+        assert_eq!(engine::expansion_cycle(&stack[..4]), None);
+
+        // Hide sets stop an indirect cycle before it reaches the limit
+        let src = "#define A(x) B(x)\n#define B(x) C(x)\n#define C(x) A(x)\nA(1)\n";
+        let mut pp = Preprocessor::new();
+        pp.set_recursion_limit(5);
+        assert_eq!(pp.process(src).unwrap(), "A(1)\n");
+    }
+
     #[test]
     fn disabled_macros_state_verification() {
         // Direct test that verifies disabled_macros behavior in edge cases
@@ -1480,54 +2186,2966 @@ FRESH_MACRO
         assert!(out2.contains("fresh_value"));
     }
 
-    // -- Line ending and BOM normalization tests --
-
     #[test]
-    fn normalize_input_removes_bom() {
-        let bom = "\u{FEFF}";
-        let input = format!("{bom}#define A 1\nA\n");
-        let result = engine::normalize_input(&input);
-        assert_eq!(result, "#define A 1\nA\n");
+    fn dump_macros_renders_defines() {
+        let src = r#"
+#define PI 3.14
+#define ADD(a, b) ((a)+(b))
+#define EMPTY
+"#;
+        let mut pp = Preprocessor::new();
+        pp.process(src).unwrap();
+        let dump = pp.dump_macros();
+        assert_eq!(
+            dump,
+            "#define ADD(a, b) ((a)+(b))\n#define EMPTY\n#define PI 3.14\n"
+        );
     }
 
-    #[test]
-    fn normalize_input_converts_crlf() {
-        let input = "#define A 1\r\nA\r\n";
-        let result = engine::normalize_input(input);
-        assert_eq!(result, "#define A 1\nA\n");
+    /// Build a preprocessor whose warnings are collected into a shared vector
+    fn collecting_preprocessor(
+        config: PreprocessorConfig,
+    ) -> (Preprocessor, Rc<RefCell<Vec<String>>>) {
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&warnings);
+        let config = config.with_warning_handler(Rc::new(move |msg: &str| {
+            sink.borrow_mut().push(msg.to_string());
+        }));
+        (PreprocessorDriver::with_config(&config), warnings)
     }
 
     #[test]
-    fn normalize_input_converts_bare_cr() {
-        let input = "#define A 1\rA\r";
-        let result = engine::normalize_input(input);
-        assert_eq!(result, "#define A 1\nA\n");
+    fn conflicting_redefinition_warns_when_permissive() {
+        let src = "#define FOO 1\n#define FOO 2\nint x = FOO;\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        let out = pp.process(src).unwrap();
+        assert!(out.contains("int x = 2;"));
+        let warnings = warnings.borrow();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("'FOO' redefined"));
+        assert!(warnings[0].contains("<stdin>:1"));
     }
 
     #[test]
-    fn normalize_input_mixed_endings() {
-        let input = "a\nb\r\nc\r";
-        let result = engine::normalize_input(input);
-        assert_eq!(result, "a\nb\nc\n");
+    fn conflicting_redefinition_errors_when_strict() {
+        let src = "#define FOO 1\n#define FOO 2\n";
+        let (mut pp, _) =
+            collecting_preprocessor(PreprocessorConfig::for_linux().with_strict(true));
+        let err = pp.process(src).unwrap_err();
+        assert!(err.to_string().contains("'FOO' redefined"));
+        assert_eq!(err.line, 2);
     }
 
     #[test]
-    fn normalize_input_no_change_for_lf() {
-        let input = "#define A 1\nA\n";
-        let result = engine::normalize_input(input);
-        assert_eq!(result, input);
+    fn redefinition_across_macro_kinds_is_named() {
+        let src = "#define FOO 1\n#define FOO(x) (x)\n#define BAR(x) x\n#define BAR x\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        pp.process(src).unwrap();
+        assert_eq!(
+            *warnings.borrow(),
+            [
+                "<stdin>:2: 'FOO' redefined as a function-like macro (previous definition at <stdin>:1)",
+                "<stdin>:4: 'BAR' redefined as an object-like macro (previous definition at <stdin>:3)",
+            ]
+        );
     }
 
     #[test]
-    fn denormalize_output_to_crlf() {
-        let input = "a\nb\nc\n";
-        let result = engine::denormalize_output(input, &LineEnding::CRLF);
-        assert_eq!(result, "a\r\nb\r\nc\r\n");
+    fn identical_redefinition_is_allowed() {
+        let src = "#define ADD(a, b) ((a) + (b))\n#define ADD(a, b)   ((a) + (b))  \n";
+        let (mut pp, warnings) =
+            collecting_preprocessor(PreprocessorConfig::for_linux().with_strict(true));
+        assert!(pp.process(src).is_ok());
+        assert!(warnings.borrow().is_empty());
     }
 
     #[test]
-    fn denormalize_output_to_cr() {
-        let input = "a\nb\nc\n";
+    fn unknown_directive_is_a_strict_mode_error() {
+        let src = "#frobnicate now\nint x;\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        assert!(pp.process(src).is_ok());
+        assert!(warnings.borrow()[0].contains("invalid preprocessing directive #frobnicate"));
+
+        let (mut strict, _) =
+            collecting_preprocessor(PreprocessorConfig::for_linux().with_strict(true));
+        assert!(strict.process(src).is_err());
+
+        // Unknown directives in skipped blocks are never diagnosed
+        let skipped = "#if 0\n#frobnicate\n#endif\n";
+        assert!(strict.process(skipped).is_ok());
+    }
+
+    #[test]
+    fn invalid_paste_warns_or_errors() {
+        let src = "#define CAT(a, b) a ## b\nint x CAT(=, +) 5;\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        let out = pp.process(src).unwrap();
+        assert!(out.contains("=+"));
+        assert!(warnings.borrow()[0].contains("does not give a valid preprocessing token"));
+
+        let (mut strict, _) =
+            collecting_preprocessor(PreprocessorConfig::for_linux().with_strict(true));
+        assert!(strict.process(src).is_err());
+
+        // Valid pastes are accepted in strict mode
+        let valid = "#define CAT(a, b) a ## b\nCAT(x, 1) CAT(-, >) CAT(<<, =) CAT(1, e)\n";
+        let out = strict.process(valid).unwrap();
+        assert!(out.contains("x1 -> <<= 1e"));
+    }
+
+    #[test]
+    fn adjacent_string_literals_are_merged_when_enabled() {
+        let src = r#"#define GREETING "hello, " "world"
+const char *a = GREETING "!";
+const wchar_t *b = L"wide " "string";
+const char *c = "x" u8"y";
+const char *d = L"mixed" u"prefixes";
+const char *e = "\x1" "2" "\12" "3" "\123" "4";
+"#;
+        let config = PreprocessorConfig::for_linux().with_concat_string_literals(true);
+        let out = process(src, &config).unwrap();
+        assert!(out.contains(r#"const char *a = "hello, world!";"#));
+        assert!(out.contains(r#"const wchar_t *b = L"wide string";"#));
+        assert!(out.contains(r#"const char *c = u8"xy";"#));
+        assert!(out.contains(r#"const char *d = L"mixed" u"prefixes";"#));
+        assert!(out.contains(r#"const char *e = "\x1" "2\12" "3\1234";"#));
+
+        let out = process(src, &PreprocessorConfig::for_linux()).unwrap();
+        assert!(out.contains(r#"const char *a = "hello, " "world" "!";"#));
+    }
+
+    #[test]
+    fn include_hook_fires_in_include_order_with_depth() {
+        let entered = Rc::new(RefCell::new(Vec::new()));
+        let record = Rc::clone(&entered);
+        let config = PreprocessorConfig::for_linux().with_on_include_enter(Rc::new(
+            move |path: &str, depth, kind| {
+                record.borrow_mut().push((path.to_string(), depth, kind));
+            },
+        ));
+        let mut pp =
+            Preprocessor::with_config(&config).with_include_resolver(|path, _, _| match path {
+                "a.h" => Some("#include \"b.h\"\n#include <d.h>\n".to_string()),
+                "b.h" => Some("#include <c.h>\n".to_string()),
+                "c.h" | "d.h" => Some(String::new()),
+                _ => None,
+            });
+        pp.process("#include \"a.h\"\n#include <d.h>\n").unwrap();
+
+        let expected = vec![
+            ("a.h".to_string(), 1, IncludeKind::Local),
+            ("b.h".to_string(), 2, IncludeKind::Local),
+            ("c.h".to_string(), 3, IncludeKind::System),
+            ("d.h".to_string(), 2, IncludeKind::System),
+            ("d.h".to_string(), 1, IncludeKind::System),
+        ];
+        assert_eq!(*entered.borrow(), expected);
+    }
+
+    fn prelude_resolver() -> IncludeResolver {
+        Rc::new(|path, _, _| match path {
+            "forced.h" => IncludeResolution::Content(
+                "#include \"inner.h\"\n#define FORCED 1\nint forced;\n".to_string(),
+            ),
+            "inner.h" => IncludeResolution::Content("#define INNER 2\n".to_string()),
+            "broken.h" => {
+                IncludeResolution::Content("int ok;\n#error prelude is broken\n".to_string())
+            }
+            _ => IncludeResolution::NotFound,
+        })
+    }
+
+    #[test]
+    fn prelude_text_and_files_run_before_the_main_input() {
+        let config = PreprocessorConfig {
+            include_resolver: Some(prelude_resolver()),
+            prelude: vec![
+                PreludeItem::Text("#define GENERATED 3\nint text;\n".to_string()),
+                PreludeItem::File("forced.h".to_string()),
+            ],
+            ..PreprocessorConfig::for_linux()
+        };
+        let out = process("int main_input = GENERATED + FORCED + INNER;\n", &config).unwrap();
+        let lines: Vec<&str> = out.lines().filter(|l| !l.is_empty()).collect();
+        assert_eq!(
+            lines,
+            vec!["int text;", "int forced;", "int main_input = 3 + 1 + 2;"]
+        );
+
+        let out = process(
+            "int main_input = GENERATED;\n",
+            &config.with_suppress_prelude_output(true),
+        )
+        .unwrap();
+        assert_eq!(out, "int main_input = 3;\n");
+    }
+
+    #[test]
+    fn prelude_errors_name_their_source() {
+        let config = |item| PreprocessorConfig {
+            include_resolver: Some(prelude_resolver()),
+            prelude: vec![item],
+            ..PreprocessorConfig::for_linux()
+        };
+
+        let err = process(
+            "int x;\n",
+            &config(PreludeItem::File("broken.h".to_string())),
+        )
+        .unwrap_err();
+        assert_eq!(err.file, "broken.h");
+        assert_eq!(err.line, 2);
+
+        let err = process(
+            "int x;\n",
+            &config(PreludeItem::File("missing.h".to_string())),
+        )
+        .unwrap_err();
+        assert_eq!(err.file, "<prelude>");
+        assert!(err.to_string().contains("missing.h"));
+
+        let err = process(
+            "int x;\n",
+            &config(PreludeItem::Text("#define\n".to_string())),
+        )
+        .unwrap_err();
+        assert_eq!(err.file, "<prelude>");
+        assert_eq!(err.line, 1);
+    }
+
+    #[test]
+    fn expansion_trace_reports_every_expansion() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&events);
+        let config = PreprocessorConfig::builder()
+            .expansion_trace_handler(Rc::new(move |event: &ExpansionEvent| {
+                sink.borrow_mut().push(event.clone());
+            }))
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        let src = "#define ONE 1\n#define INC(x) ((x) + ONE)\n#define TWICE(x) INC(x) * INC(x)\nint y = TWICE(n);\n";
+        let out = pp.process(src).unwrap();
+        assert!(out.contains("((n) + 1) * ((n) + 1)"), "{out}");
+
+        let events = events.borrow();
+        let names: Vec<&str> = events.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["TWICE", "INC", "ONE", "INC", "ONE"]);
+        assert_eq!(events[0].arguments, Some(vec!["n".to_string()]));
+        assert_eq!(events[0].result, "INC(n) * INC(n)");
+        assert_eq!(events[0].depth, 0);
+        assert_eq!(events[0].line, 4);
+        assert!(events.iter().skip(1).all(|e| e.depth > 0));
+        assert_eq!(events[2].arguments, None);
+    }
+
+    #[test]
+    fn expand_snippet_uses_current_definitions() {
+        let mut pp = PreprocessorDriver::new();
+        pp.process("#define ONE 1\n#define ADD(a, b) ((a) + (b))\n")
+            .unwrap();
+        assert_eq!(pp.expand_snippet("ADD(ONE, x)").unwrap(), "((1) + (x))");
+        // Directives are not interpreted, and undefined names pass through
+        assert_eq!(
+            pp.expand_snippet("#define ONE /* c */ 2").unwrap(),
+            "#define 1   2"
+        );
+        assert!(pp.is_defined("ONE"));
+        assert!(pp.expand_snippet("ADD(1,").is_err());
+    }
+
+    #[test]
+    fn process_with_streams_lines_with_their_origin() {
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _kind, _context| {
+            (path == "inc.h").then(|| "#define V 7\nint in_header = V;\n".to_string())
+        });
+        pp.set_current_file("main.c".to_string());
+        let mut lines = Vec::new();
+        pp.process_with("int a;\n#include \"inc.h\"\nint b = V;\n", |line| {
+            lines.push((
+                line.text.to_string(),
+                line.file.to_string(),
+                line.line,
+                line.from_include,
+            ));
+        })
+        .unwrap();
+
+        let line = |text: &str, file: &str, n, from_include| {
+            (text.to_string(), file.to_string(), n, from_include)
+        };
+        assert_eq!(
+            lines,
+            [
+                line("int a;", "main.c", 1, false),
+                line("int in_header = 7;", "inc.h", 2, true),
+                line("", "main.c", 2, false),
+                line("int b = 7;", "main.c", 3, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn process_to_writer_matches_process() {
+        let src = "#define X 1\nint x = X;\n#pragma pack\n";
+        let config = PreprocessorConfig::builder()
+            .line_ending(LineEnding::CRLF)
+            .build()
+            .unwrap();
+        let expected = PreprocessorDriver::with_config(&config)
+            .process(src)
+            .unwrap();
+        let mut written = Vec::new();
+        PreprocessorDriver::with_config(&config)
+            .process_to_writer(src, &mut written)
+            .unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), expected);
+        assert!(expected.contains("\r\n"));
+    }
+
+    #[test]
+    fn parse_events_reports_structure_without_evaluating() {
+        let mut pp = PreprocessorDriver::new();
+        pp.set_current_file("lib.c".to_string());
+        let src = "#ifndef LIB_H\n#define LIB_H\n#include <stdio.h>\n#define MAX(a, b) ((a) > (b) ? (a) : (b))\n#if defined(X) && X > 2\nint max = MAX(1, 2); // comment\n#else\n#undef MAX\n#endif\n#pragma once\n#endif\n";
+        let events = pp.parse_events(src).unwrap();
+        let loc = |line| SourceLocation {
+            file: "lib.c".to_string(),
+            line,
+        };
+        assert_eq!(
+            events,
+            [
+                PpEvent::Ifndef {
+                    name: "LIB_H".to_string(),
+                    location: loc(1)
+                },
+                PpEvent::Define {
+                    name: "LIB_H".to_string(),
+                    params: None,
+                    is_variadic: false,
+                    body: String::new(),
+                    location: loc(2)
+                },
+                PpEvent::Include {
+                    path: "stdio.h".to_string(),
+                    kind: IncludeKind::System,
+                    location: loc(3)
+                },
+                PpEvent::Define {
+                    name: "MAX".to_string(),
+                    params: Some(vec!["a".to_string(), "b".to_string()]),
+                    is_variadic: false,
+                    body: "((a) > (b) ? (a) : (b))".to_string(),
+                    location: loc(4)
+                },
+                PpEvent::If {
+                    expr: "defined(X) && X > 2".to_string(),
+                    location: loc(5)
+                },
+                PpEvent::TextLine {
+                    tokens: [
+                        "int", " ", "max", " ", "=", " ", "MAX", "(", "1", ",", " ", "2", ")", ";",
+                        "  "
+                    ]
+                    .map(String::from)
+                    .to_vec(),
+                    location: loc(6)
+                },
+                PpEvent::Else { location: loc(7) },
+                PpEvent::Undef {
+                    name: "MAX".to_string(),
+                    location: loc(8)
+                },
+                PpEvent::Endif { location: loc(9) },
+                PpEvent::Pragma {
+                    text: "once".to_string(),
+                    location: loc(10)
+                },
+                PpEvent::Endif { location: loc(11) },
+            ]
+        );
+        // Nothing was defined by parsing
+        assert!(!pp.is_defined("MAX"));
+    }
+
+    #[test]
+    fn events_follow_processing_in_order() {
+        let mut pp = PreprocessorDriver::with_config(
+            &PreprocessorConfig::for_linux().with_initial_file("main.c"),
+        )
+        .with_include_resolver(|path, _kind, _context| {
+            (path == "one.h").then(|| "#define ONE 1\n".to_string())
+        });
+        let src = "#define TWO 2\n#include \"one.h\"\n#if ONE > TWO\nbig\n#else\nint n = ONE + TWO;\n#endif\n";
+        let events: Vec<PpEvent> = pp.events(src).collect::<Result<_, _>>().unwrap();
+        let at = |file: &str, line| SourceLocation {
+            file: file.to_string(),
+            line,
+        };
+        assert_eq!(
+            events,
+            [
+                PpEvent::Define {
+                    name: "TWO".to_string(),
+                    params: None,
+                    is_variadic: false,
+                    body: "2".to_string(),
+                    location: at("main.c", 1),
+                },
+                PpEvent::Include {
+                    path: "one.h".to_string(),
+                    kind: IncludeKind::Local,
+                    location: at("main.c", 2),
+                },
+                PpEvent::IncludeEntered {
+                    path: "one.h".to_string(),
+                    location: at("main.c", 2),
+                },
+                PpEvent::Define {
+                    name: "ONE".to_string(),
+                    params: None,
+                    is_variadic: false,
+                    body: "1".to_string(),
+                    location: at("one.h", 1),
+                },
+                // A file that produces no output still gives one empty line
+                PpEvent::LineEmitted {
+                    text: String::new(),
+                    location: at("one.h", 2),
+                },
+                PpEvent::IncludeLeft {
+                    path: "one.h".to_string(),
+                    location: at("main.c", 2),
+                },
+                PpEvent::LineEmitted {
+                    text: String::new(),
+                    location: at("main.c", 2),
+                },
+                PpEvent::If {
+                    expr: "ONE > TWO".to_string(),
+                    location: at("main.c", 3),
+                },
+                PpEvent::Else {
+                    location: at("main.c", 5),
+                },
+                PpEvent::ConditionalTaken {
+                    location: at("main.c", 5),
+                },
+                PpEvent::LineEmitted {
+                    text: "int n = 1 + 2;".to_string(),
+                    location: at("main.c", 6),
+                },
+                PpEvent::Endif {
+                    location: at("main.c", 7),
+                },
+            ]
+        );
+
+        let mut events = pp.events("#if 1\n#error stop\n#endif\n");
+        assert!(matches!(events.next(), Some(Ok(PpEvent::If { .. }))));
+        assert!(matches!(
+            events.next(),
+            Some(Ok(PpEvent::ConditionalTaken { .. }))
+        ));
+        assert!(matches!(events.next(), Some(Ok(PpEvent::Error { .. }))));
+        assert!(matches!(events.next(), Some(Err(_))));
+        assert!(events.next().is_none());
+    }
+
+    #[test]
+    fn parse_events_rejects_malformed_directives() {
+        let pp = PreprocessorDriver::new();
+        let err = pp.parse_events("int x;\n#include stdio.h\n").unwrap_err();
+        assert!(matches!(
+            err.kind,
+            PreprocessErrorKind::MalformedDirective(_)
+        ));
+        assert_eq!(err.line, 2);
+        assert!(pp.parse_events("#define F(a, b\n").is_err());
+    }
+
+    #[test]
+    fn hash_operators_outside_replacement_lists_are_plain_tokens() {
+        let mut pp = PreprocessorDriver::new();
+        let src = "#define HASH #\n#define CAT a##b\n#define ID(x) x\n\
+                   HASH CAT\nmov r1##r2, [sp]\nID(a ## b) ID(x # y)\n";
+        assert_eq!(
+            pp.process(src).unwrap(),
+            "# ab\nmov r1##r2, [sp]\na ## b x # y\n"
+        );
+
+        // The example from C11 6.10.3.3
+        let src = "#define hash_hash # ## #\n\
+                   #define mkstr(a) # a\n\
+                   #define in_between(a) mkstr(a)\n\
+                   #define join(c, d) in_between(c hash_hash d)\n\
+                   char p[] = join(x, y);\n";
+        assert_eq!(pp.process(src).unwrap(), "char p[] = \"x ## y\";\n");
+    }
+
+    #[test]
+    fn paste_at_either_end_of_a_body_is_diagnosed() {
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        assert_eq!(pp.process("#define F(x) ## x\nF(1)\n").unwrap(), "## 1\n");
+        assert_eq!(
+            *warnings.borrow(),
+            ["<stdin>:1: '##' cannot appear at either end of a macro expansion"]
+        );
+
+        let config = PreprocessorConfig::builder().strict(true).build().unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        assert!(pp.process("#define DHASH ##\n").is_err());
+    }
+
+    #[test]
+    fn empty_paste_operands_are_placemarkers() {
+        let mut pp = PreprocessorDriver::new();
+        let src = "#define CAT(a, b) a##b\n\
+                   #define CAT3(p, a, b) p a##b\n\
+                   #define V(x, ...) x ## __VA_ARGS__\n\
+                   CAT(,x);\nCAT(x,);\nCAT(,);\nCAT3(q,,x);\nV(y);\nCAT(, \"s\");\n";
+        assert_eq!(pp.process(src).unwrap(), "x;\nx;\n;\nq x;\ny;\n\"s\";\n");
+    }
+
+    #[test]
+    fn define_parameter_list_must_follow_the_name() {
+        let mut pp = PreprocessorDriver::new();
+        let out = pp
+            .process("#define X (1)\n#define F(a, ...) a + __VA_ARGS__\nint x = X + F(2, 3);\n")
+            .unwrap();
+        assert!(out.contains("int x = (1) + 2 + 3;"), "{out}");
+    }
+
+    #[test]
+    fn gcc_poison_rejects_later_uses() {
+        let mut pp = PreprocessorDriver::new();
+        let src = "#define OLD_READ gets(buf)\n#pragma GCC poison gets sprintf\nint ok = fgets(buf, n, f);\nchar *s = gets(buf);\n";
+        let err = pp.process(src).unwrap_err();
+        assert!(matches!(&err.kind, PreprocessErrorKind::Other(msg) if msg.contains("\"gets\"")));
+        assert_eq!(err.line, 4);
+
+        // A macro defined before poisoning may still expand to the identifier
+        let mut pp = PreprocessorDriver::new();
+        let out = pp
+            .process("#define OLD_READ gets(buf)\n#pragma GCC poison gets\nOLD_READ;\n")
+            .unwrap();
+        assert!(out.contains("#pragma GCC poison gets"));
+        assert!(out.contains("gets(buf);"));
+
+        // Defining a macro that uses it afterwards is an error, as is a poison
+        // in a skipped branch having no effect
+        let mut pp = PreprocessorDriver::new();
+        assert!(
+            pp.process("#pragma GCC poison gets\n#define READ gets(buf)\n")
+                .is_err()
+        );
+        let mut pp = PreprocessorDriver::new();
+        assert!(
+            pp.process("#if 0\n#pragma GCC poison gets\n#endif\ngets(buf);\n")
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn gcc_poison_applies_to_conditional_directives() {
+        for condition in [
+            "#if gets\n",
+            "#ifdef gets\n",
+            "#ifndef gets\n",
+            "#if 0\n#elif defined(gets)\n",
+        ] {
+            let mut pp = PreprocessorDriver::new();
+            let src = format!("#pragma GCC poison gets\n{condition}#endif\n");
+            let err = pp.process(&src).unwrap_err();
+            assert!(
+                matches!(&err.kind, PreprocessErrorKind::Other(msg) if msg.contains("\"gets\"")),
+                "{condition}: {err}"
+            );
+        }
+
+        // Conditionals in skipped groups are not checked
+        let mut pp = PreprocessorDriver::new();
+        let src = "#pragma GCC poison gets\n#if 0\n#ifdef gets\n#endif\n#elif 1\n#else\n#ifndef gets\n#endif\n#endif\n";
+        assert!(pp.process(src).is_ok());
+    }
+
+    #[test]
+    fn wrong_macro_arity_is_an_error() {
+        let defs = "#define ADD(a, b) ((a) + (b))\n#define ZERO() 0\n";
+        for call in ["ADD(1)", "ADD(1, 2, 3)", "ZERO(1)"] {
+            let mut pp = Preprocessor::new();
+            let err = pp.process(&format!("{defs}int x = {call};\n")).unwrap_err();
+            assert!(
+                matches!(err.kind, PreprocessErrorKind::MacroArgMismatch(_)),
+                "{call}: {err}"
+            );
+            assert_eq!(err.line, 3);
+        }
+
+        let mut pp = Preprocessor::new();
+        let out = pp
+            .process(&format!("{defs}int x = ADD(1, 2) + ZERO();\n"))
+            .unwrap();
+        assert!(out.contains("int x = ((1) + (2)) + 0;"), "{out}");
+    }
+
+    #[test]
+    fn gcc_system_header_suppresses_diagnostics_for_rest_of_file() {
+        let header = "#define LIMIT 1\n#define LIMIT 2\n#pragma GCC system_header\n#define LIMIT 3\nint gets_compat = gets;\n";
+        let resolver: IncludeResolver =
+            Rc::new(move |_path, _kind, _context| header.to_string().into());
+        let config = PreprocessorConfig::builder()
+            .include_resolver(resolver)
+            .build()
+            .unwrap();
+        let (mut pp, warnings) = collecting_preprocessor(config);
+        let out = pp
+            .process("#pragma GCC poison gets\n#include \"sys.h\"\n#define LIMIT 4\n")
+            .unwrap();
+        assert!(out.contains("int gets_compat = gets;"));
+
+        // Only the redefinitions before the pragma and back in the main file warn
+        let warnings = warnings.borrow();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].contains("sys.h:2:"), "{warnings:?}");
+        assert!(warnings[1].starts_with("<stdin>:3:"), "{warnings:?}");
+
+        // Outside an include file the pragma is ignored with a warning
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        pp.process("#pragma GCC system_header\n#define A 1\n#define A 2\n")
+            .unwrap();
+        assert_eq!(warnings.borrow().len(), 2);
+    }
+
+    #[test]
+    fn system_header_pragma_covers_nested_includes_until_return() {
+        let resolver: IncludeResolver = Rc::new(|path, _kind, _context| {
+            match path {
+                "sys.h" => "#pragma GCC system_header\n#include \"inner.h\"\n#if true\n#endif\n",
+                "inner.h" => "#define X 1\n#define X 2\n",
+                _ => "#define Y 1\n#define Y 2\n",
+            }
+            .to_string()
+            .into()
+        });
+        let config = PreprocessorConfig::builder()
+            .include_resolver(resolver)
+            .build()
+            .unwrap();
+        let (mut pp, warnings) = collecting_preprocessor(config);
+        pp.process("#include \"sys.h\"\n#include \"local.h\"\n#define X 3\n")
+            .unwrap();
+
+        let warnings = warnings.borrow();
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].starts_with("local.h:2:"), "{warnings:?}");
+        assert!(warnings[1].starts_with("<stdin>:3:"), "{warnings:?}");
+    }
+
+    #[test]
+    fn angle_includes_are_quiet_system_headers_by_default() {
+        let resolver: IncludeResolver =
+            Rc::new(|_path, _kind, _context| "#define Z 1\n#define Z 2\n".to_string().into());
+        let src = "#include <quiet.h>\n#undef Z\n#include \"noisy.h\"\n";
+        let config = |quiet| {
+            PreprocessorConfig::builder()
+                .include_resolver(Rc::clone(&resolver))
+                .system_headers_quiet(quiet)
+                .build()
+                .unwrap()
+        };
+
+        let (mut pp, warnings) = collecting_preprocessor(config(true));
+        pp.process(src).unwrap();
+        assert_eq!(warnings.borrow().len(), 1, "{:?}", warnings.borrow());
+        assert!(warnings.borrow()[0].starts_with("noisy.h:2:"));
+
+        let (mut pp, warnings) = collecting_preprocessor(config(false));
+        pp.process(src).unwrap();
+        assert_eq!(warnings.borrow().len(), 2, "{:?}", warnings.borrow());
+        assert!(warnings.borrow()[0].starts_with("quiet.h:2:"));
+    }
+
+    #[test]
+    fn errors_carry_stable_codes_and_structured_data() {
+        let mut pp = Preprocessor::new();
+        let err = pp.process("#include \"missing/x.h\"\n").unwrap_err();
+        assert_eq!(err.code(), "E0001_INCLUDE_NOT_FOUND");
+        assert_eq!(err.kind.code(), err.code());
+        assert!(err.is_include_not_found());
+        assert_eq!(err.included_path(), Some("missing/x.h"));
+        assert!(
+            err.to_string().contains("[E0001_INCLUDE_NOT_FOUND]"),
+            "{err}"
+        );
+
+        let cases = [
+            ("#define F(a) a\nF(1, 2)\n", "E0003_MACRO_ARG_MISMATCH"),
+            ("#endif\n", "E0005_CONDITIONAL_ERROR"),
+            ("#error stop\n", "E0009_OTHER"),
+        ];
+        for (src, code) in cases {
+            let err = Preprocessor::new().process(src).unwrap_err();
+            assert_eq!(err.code(), code, "{err}");
+            assert!(!err.is_include_not_found());
+            assert_eq!(err.included_path(), None);
+            assert!(err.to_string().contains(&format!("[{code}]")), "{err}");
+        }
+    }
+
+    #[test]
+    fn macro_arity_errors_name_the_macro_and_counts() {
+        let defs = "#define ADD(a, b) ((a) + (b))\n#define LOG(fmt, level, ...) log(level, fmt, __VA_ARGS__)\n";
+        let cases = [
+            (
+                "int x = ADD(1);",
+                "macro \"ADD\" requires 2 arguments, but only 1 given",
+            ),
+            (
+                "int x = ADD(1, 2, 3);",
+                "macro \"ADD\" passed 3 arguments, but takes just 2",
+            ),
+            (
+                "LOG(\"hi\");",
+                "macro \"LOG\" requires at least 2 arguments, but only 1 given",
+            ),
+        ];
+        for (line, message) in cases {
+            let mut pp = Preprocessor::new();
+            pp.set_current_file("arity.c".to_string());
+            let err = pp.process(&format!("{defs}{line}\n")).unwrap_err();
+            assert!(
+                matches!(&err.kind, PreprocessErrorKind::MacroArgMismatch(m) if m == message),
+                "{err}"
+            );
+            assert_eq!((err.file.as_str(), err.line), ("arity.c", 3));
+            let name_column = line.find(|c: char| c.is_ascii_uppercase()).unwrap() + 1;
+            assert_eq!(err.column, Some(name_column));
+        }
+    }
+
+    #[test]
+    fn macro_arity_accepts_exact_and_variadic_calls() {
+        let src = "#define ADD(a, b) ((a) + (b))\n#define LOG(fmt, ...) log(fmt, __VA_ARGS__)\n#define ANY(...) f(__VA_ARGS__)\nADD(1, 2);\nLOG(\"x\");\nLOG(\"x\", 1, 2);\nANY();\nANY(1, 2);\n";
+        let mut pp = Preprocessor::new();
+        let out = pp.process(src).unwrap();
+        assert!(out.contains("((1) + (2));"), "{out}");
+        assert!(out.contains("log(\"x\", );"), "{out}");
+        assert!(out.contains("log(\"x\", 1,2);"), "{out}");
+        assert!(out.contains("f();"), "{out}");
+        assert!(out.contains("f(1,2);"), "{out}");
+    }
+
+    #[test]
+    fn defined_from_macro_follows_configured_mode() {
+        let src = "#define FOO\n#define D defined(FOO)\n#if D\nyes\n#else\nno\n#endif\n";
+        let run = |mode| {
+            let config = PreprocessorConfig::builder()
+                .defined_from_macro(mode)
+                .build()
+                .unwrap();
+            let (mut pp, warnings) = collecting_preprocessor(config);
+            let result = pp.process(src);
+            let warnings = warnings.borrow().clone();
+            (result, warnings)
+        };
+
+        let (out, warnings) = run(DefinedFromMacro::Honor);
+        assert!(out.unwrap().contains("yes"));
+        assert!(warnings.is_empty());
+
+        let (out, warnings) = run(DefinedFromMacro::Warn);
+        assert!(out.unwrap().contains("yes"));
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains(":3: this use of \"defined\" may not be portable"));
+
+        let (out, _) = run(DefinedFromMacro::Error);
+        let err = out.unwrap_err();
+        assert!(matches!(&err.kind, PreprocessErrorKind::Other(m) if m.contains("\"defined\"")));
+        assert_eq!(err.line, 3);
+
+        // A `defined` written in the directive itself is always fine
+        let (out, warnings) = {
+            let config = PreprocessorConfig::builder()
+                .defined_from_macro(DefinedFromMacro::Error)
+                .build()
+                .unwrap();
+            let (mut pp, warnings) = collecting_preprocessor(config);
+            (pp.process("#if defined(FOO) || D\n#endif\n"), warnings)
+        };
+        assert!(out.is_ok());
+        assert!(warnings.borrow().is_empty());
+    }
+
+    #[test]
+    fn defined_in_macro_body_is_only_evaluated_in_conditionals() {
+        let src = "#define FOO 1\n#define HAS defined(FOO)\nint x = HAS;\n#if HAS\nyes\n#endif\n#undef FOO\nint y = HAS;\n#if HAS\nno\n#endif\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let out = pp.process(src).unwrap();
+        let lines: Vec<&str> = out.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(
+            lines,
+            ["int x = defined(FOO);", "yes", "int y = defined(FOO);"]
+        );
+        assert!(warnings.borrow().is_empty());
+    }
+
+    #[test]
+    fn if_on_macro_expanding_to_nothing() {
+        let defs = "#define FEATURE_ENABLED\n";
+
+        let mut pp = PreprocessorDriver::new();
+        let err = pp
+            .process(&format!("{defs}#if FEATURE_ENABLED\n#endif\n"))
+            .unwrap_err();
+        assert!(
+            matches!(&err.kind, PreprocessErrorKind::ConditionalError(m)
+                if m == "#if with no expression ('FEATURE_ENABLED' expands to nothing)"),
+            "{err}"
+        );
+        assert_eq!((err.line, err.column), (2, Some(5)));
+
+        let mut pp = PreprocessorDriver::new();
+        let err = pp.process("#if 0\n#elif\n#endif\n").unwrap_err();
+        assert!(
+            matches!(&err.kind, PreprocessErrorKind::ConditionalError(m) if m == "#elif with no expression"),
+            "{err}"
+        );
+
+        // The portable idiom relies on unary plus
+        let mut pp = PreprocessorDriver::new();
+        let out = pp
+            .process(&format!(
+                "{defs}#if FEATURE_ENABLED + 0\nplus\n#else\nzero\n#endif\n#if defined FEATURE_ENABLED\ndefined\n#endif\n#if +1 == 1\nunary\n#endif\n"
+            ))
+            .unwrap();
+        assert!(!out.contains("plus"));
+        assert!(out.contains("zero"));
+        assert!(out.contains("defined"));
+        assert!(out.contains("unary"));
+    }
+
+    #[test]
+    fn indentation_survives_leading_macro() {
+        let src = "#define CALL(x) run(x)\n#define EMPTY\n#define N 3\n    CALL(N);\n    N;\n\tEMPTY int a;\n";
+        let out = PreprocessorDriver::new().process(src).unwrap();
+        assert_eq!(out, "    run(3);\n    3;\n\t int a;\n");
+    }
+
+    #[test]
+    fn process_lines_joined_matches_process() {
+        let resolver: IncludeResolver =
+            Rc::new(|_path, _kind, _context| "int from_header;\n".to_string().into());
+        let config = PreprocessorConfig::builder()
+            .include_resolver(resolver)
+            .build()
+            .unwrap();
+        let src = "#define X 1\n#include \"a.h\"\n\nint x = X;\n#if 0\nskipped\n#endif\n";
+        let expected = PreprocessorDriver::with_config(&config)
+            .process(src)
+            .unwrap();
+        let lines = PreprocessorDriver::with_config(&config)
+            .process_lines(src)
+            .unwrap();
+        assert_eq!(lines.join("\n") + "\n", expected);
+        assert!(lines.contains(&"int from_header;".to_string()));
+    }
+
+    #[test]
+    fn expr_identifier_resolver_supplies_unknown_identifiers() {
+        let config = PreprocessorConfig::builder()
+            .expr_identifier_resolver(Rc::new(|name: &str| (name == "WORDSIZE").then_some(64)))
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        let src = "#if WORDSIZE == 64 && OTHER == 0\nwide\n#endif\n#if defined(WORDSIZE)\nmacro\n#endif\n";
+        assert_eq!(pp.process(src).unwrap(), "wide\n");
+
+        // A macro of the same name still takes precedence
+        pp.define("WORDSIZE", None, "32", false);
+        assert_eq!(
+            pp.process("#if WORDSIZE == 32\nnarrow\n#endif\n").unwrap(),
+            "narrow\n"
+        );
+    }
+
+    #[test]
+    fn warn_undef_reports_identifiers_taken_as_zero() {
+        let src = "#if UNKNOWN_FLAG\nyes\n#else\nno\n#endif\n#if defined(OTHER) || KNOWN\n#endif\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        pp.define("KNOWN", None, "1", false);
+        assert_eq!(pp.process(src).unwrap(), "no\n");
+        assert!(warnings.borrow().is_empty());
+
+        let (mut pp, warnings) =
+            collecting_preprocessor(PreprocessorConfig::for_linux().with_warn_undef(true));
+        pp.define("KNOWN", None, "1", false);
+        assert_eq!(pp.process(src).unwrap(), "no\n");
+        assert_eq!(
+            *warnings.borrow(),
+            ["<stdin>:1: \"UNKNOWN_FLAG\" is not defined, evaluates to 0"]
+        );
+    }
+
+    #[test]
+    fn bare_function_macro_warns_only_when_enabled() {
+        let defines = "#define MAX(a, b) ((a) > (b) ? (a) : (b))\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        let src = format!("{defines}int m = MAX;\n");
+        assert_eq!(pp.process(&src).unwrap(), "int m = MAX;\n");
+        assert!(warnings.borrow().is_empty());
+
+        let config = PreprocessorConfig::for_linux().with_warn_bare_function_macro(true);
+        let (mut pp, warnings) = collecting_preprocessor(config);
+        assert_eq!(pp.process(&src).unwrap(), "int m = MAX;\n");
+        assert_eq!(
+            *warnings.borrow(),
+            [
+                "<stdin>:2: function-like macro 'MAX' used without arguments is not expanded (defined at <stdin>:1)"
+            ]
+        );
+    }
+
+    #[test]
+    fn bare_function_macro_warning_skips_operands_and_calls() {
+        let src = "#define MAX(a, b) ((a) > (b) ? (a) : (b))\n\
+                   #define STR(x) #x\n\
+                   #define CAT(a, b) a ## b\n\
+                   #define PICK MAX\n\
+                   const char *s = STR(MAX);\n\
+                   int CAT(MAX, _v);\n\
+                   #if defined(MAX) && defined MAX\n\
+                   int m = PICK(1, 2);\n\
+                   #endif\n";
+        let config = PreprocessorConfig::for_linux().with_warn_bare_function_macro(true);
+        let (mut pp, warnings) = collecting_preprocessor(config);
+        assert_eq!(
+            pp.process(src).unwrap(),
+            "const char *s = \"MAX\";\nint MAX_v;\nint m = ((1) > (2) ? (1) : (2));\n"
+        );
+        assert!(warnings.borrow().is_empty(), "{:?}", warnings.borrow());
+    }
+
+    #[test]
+    fn function_macro_passed_as_argument_warns_only_when_enabled() {
+        let src = "#define MAX(a, b) ((a) > (b) ? (a) : (b))\n\
+                   #define APPLY(f, x, y) f(x, y)\n\
+                   int m = APPLY(MAX, 1, 2);\n";
+        let expected = "int m = ((1) > (2) ? (1) : (2));\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        assert_eq!(pp.process(src).unwrap(), expected);
+        assert!(warnings.borrow().is_empty());
+
+        let config = PreprocessorConfig::for_linux().with_warn_bare_function_macro(true);
+        let (mut pp, warnings) = collecting_preprocessor(config);
+        assert_eq!(pp.process(src).unwrap(), expected);
+        assert_eq!(warnings.borrow().len(), 1);
+        assert!(warnings.borrow()[0].starts_with("<stdin>:3: function-like macro 'MAX'"));
+    }
+
+    #[test]
+    fn boolean_keywords_depend_on_standard() {
+        let src = "#if true\nt\n#endif\n#if HAVE_X == false\nf\n#endif\n";
+        for (standard, keywords) in [
+            (Standard::C89, false),
+            (Standard::C17, false),
+            (Standard::C23, true),
+            (Standard::Cpp98, true),
+            (Standard::Cpp20, true),
+        ] {
+            let config = PreprocessorConfig::builder()
+                .standard(standard)
+                .build()
+                .unwrap();
+            let (mut pp, warnings) = collecting_preprocessor(config);
+            let out = pp.process(src).unwrap();
+            // `HAVE_X == false` holds either way: both sides are 0
+            assert!(out.contains('f'), "{standard:?}: {out}");
+            if keywords {
+                assert!(out.contains('t'), "{standard:?}: {out}");
+                assert!(warnings.borrow().is_empty(), "{standard:?}");
+            } else {
+                assert!(!out.contains('t'), "{standard:?}: {out}");
+                let warnings = warnings.borrow();
+                assert_eq!(warnings.len(), 2, "{standard:?}");
+                assert!(warnings[0].contains("\"true\" is not defined, evaluates to 0"));
+            }
+        }
+    }
+
+    #[test]
+    fn alternative_operators_only_in_cpp() {
+        let src = "#define A 1\n#if A and not B\nyes\n#else\nno\n#endif\n";
+        let run = |standard| {
+            let config = PreprocessorConfig::builder()
+                .standard(standard)
+                .build()
+                .unwrap();
+            PreprocessorDriver::with_config(&config).process(src)
+        };
+        assert!(run(Standard::Cpp17).unwrap().contains("yes"));
+        // In C `and` is an ordinary identifier, leaving a malformed expression
+        assert!(run(Standard::C23).is_err());
+    }
+
+    #[test]
+    fn macro_invocation_spans_lines() {
+        let src = "#define PAIR(a, b) [a|b]\nint x = PAIR(1,\n    2); int y;\nPAIR((3),\n (4));\nline __LINE__\n";
+        let out = PreprocessorDriver::new().process(src).unwrap();
+        assert!(out.contains("int x = [1|2]; int y;"), "{out}");
+        assert!(out.contains("[(3)|(4)];"), "{out}");
+        assert!(out.contains("line 6"), "{out}");
+    }
+
+    #[test]
+    fn error_columns_point_at_the_offending_token() {
+        let column = |src: &str| {
+            let err = PreprocessorDriver::new().process(src).unwrap_err();
+            (err.column, err.to_string())
+        };
+
+        let (col, message) = column("#define F(a x\n");
+        assert_eq!(col, Some(9));
+        assert!(message.ends_with("\n#define F(a x\n        ^"), "{message}");
+
+        assert_eq!(column("#if 1 + $ 2\n#endif\n").0, Some(9));
+        assert_eq!(column("#define ONE 1\n#if ONE + $\n#endif\n").0, Some(11));
+        assert_eq!(column("  #  undef\n").0, Some(11));
+
+        // "include" first appears as the directive name, not the header
+        let (col, message) = column("#include <include>\n");
+        assert_eq!(col, Some(10));
+        assert!(message.starts_with("preprocessor error (<stdin>:1)"));
+    }
+
+    #[test]
+    fn define_rejects_duplicate_parameters() {
+        let err = Preprocessor::new()
+            .process("#define F(x, y, x) x\n")
+            .unwrap_err();
+        assert!(matches!(
+            err.kind,
+            PreprocessErrorKind::MalformedDirective(_)
+        ));
+        assert_eq!(err.column, Some(17));
+        assert!(
+            err.to_string()
+                .contains("malformed directive: define (duplicate parameter 'x')"),
+            "{err}"
+        );
+
+        // The macro name doesn't count as a parameter
+        let mut pp = Preprocessor::new();
+        assert_eq!(pp.process("#define x(x) x\nx(1)\n").unwrap(), "1\n");
+        assert_eq!(
+            pp.process("#define x(x, x) x\n").unwrap_err().column,
+            Some(14)
+        );
+        assert!(
+            Preprocessor::new()
+                .parse_events("#define F(a, a)\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn unused_macro_parameters_warn_only_when_enabled() {
+        let src = "#define F(x) 1\n#define G(x) x\n#define H(a, b, ...) #a __VA_ARGS__\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        pp.process(src).unwrap();
+        assert!(warnings.borrow().is_empty());
+
+        let config = PreprocessorConfig::for_linux().with_warn_unused_macro_params(true);
+        let (mut pp, warnings) = collecting_preprocessor(config);
+        pp.process(src).unwrap();
+        assert_eq!(
+            *warnings.borrow(),
+            [
+                "<stdin>:1: parameter 'x' of macro 'F' is never used",
+                "<stdin>:3: parameter 'b' of macro 'H' is never used",
+            ]
+        );
+    }
+
+    #[test]
+    fn exported_macros_round_trip_into_a_fresh_driver() {
+        let mut configure = PreprocessorDriver::with_config(&PreprocessorConfig::for_linux());
+        configure
+            .process("#define LOG(fmt, ...) printf(fmt, ##__VA_ARGS__)\n#define LEVEL 3\n")
+            .unwrap();
+        let set = configure.export_macros();
+        let log = set.macros.iter().find(|m| m.name == "LOG").unwrap();
+        assert_eq!(log.params.as_deref(), Some(&["fmt".to_string()][..]));
+        assert!(log.variadic && !log.builtin);
+        assert!(
+            set.macros
+                .iter()
+                .any(|m| m.name == "__linux__" && m.builtin)
+        );
+        assert!(set.macros.windows(2).all(|w| w[0].name < w[1].name));
+
+        let src = "LOG(\"%d\", LEVEL) LOG(\"x\")\n#ifdef __linux__\nlinux\n#endif\n";
+        let mut fresh = Preprocessor::new();
+        fresh.import_macros(&set);
+        assert_eq!(fresh.process(src).unwrap(), configure.process(src).unwrap());
+        assert_eq!(fresh.macro_kind("__linux__"), Some(MacroKind::Builtin));
+        assert_eq!(fresh.export_macros(), set);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn macro_snapshot_round_trips_through_serde() {
+        let mut original = PreprocessorDriver::with_config(&PreprocessorConfig::for_linux());
+        original
+            .process("#define MAX(a, b) ((a) > (b) ? (a) : (b))\n#define NAME \"x\" /* c */\n")
+            .unwrap();
+        let json = serde_json::to_string(&MacroSet::from(original.get_macros())).unwrap();
+        assert!(json.contains(r#""body":"((a) > (b) ? (a) : (b))""#));
+
+        let snapshot: MacroSet = serde_json::from_str(&json).unwrap();
+        let mut restored = Preprocessor::new();
+        restored.define("STALE", None, "1", false);
+        restored.load_macros(&snapshot);
+        assert!(!restored.is_defined("STALE"));
+        assert_eq!(restored.export_macros(), original.export_macros());
+
+        let src = "MAX(NAME, 2)\n#ifdef __linux__\nlinux\n#endif\n";
+        assert_eq!(
+            restored.process(src).unwrap(),
+            original.process(src).unwrap()
+        );
+    }
+
+    #[test]
+    fn define_rejects_va_args_as_a_parameter() {
+        for src in [
+            "#define F(__VA_ARGS__) 1\n",
+            "#define F(a, __VA_ARGS__, ...) 1\n",
+        ] {
+            let err = Preprocessor::new().process(src).unwrap_err();
+            assert!(matches!(
+                err.kind,
+                PreprocessErrorKind::MalformedDirective(_)
+            ));
+            assert!(
+                err.to_string()
+                    .contains("define (__VA_ARGS__ used as a parameter name)"),
+                "{err}"
+            );
+        }
+        let mut pp = Preprocessor::new();
+        assert_eq!(
+            pp.process("#define F(a, ...) a __VA_ARGS__\nF(1, 2)\n")
+                .unwrap(),
+            "1 2\n"
+        );
+    }
+
+    #[test]
+    fn dynamic_predefined_macros_count_as_defined() {
+        let src = "#ifdef __FILE__\nyes\n#else\nno\n#endif\n\
+                   #if defined(__LINE__) && defined __COUNTER__ && !defined(__NOPE__)\nall\n#endif\n\
+                   #ifndef __DATE__\nmissing\n#endif\n";
+        let out = Preprocessor::new().process(src).unwrap();
+        assert!(out.contains("yes"));
+        assert!(!out.contains("no"));
+        assert!(out.contains("all"));
+        assert!(!out.contains("missing"));
+
+        let pp = Preprocessor::new();
+        for name in [
+            "__LINE__",
+            "__FILE__",
+            "__DATE__",
+            "__TIME__",
+            "__COUNTER__",
+        ] {
+            assert!(pp.is_defined(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn macro_kind_reports_each_category() {
+        let mut pp = PreprocessorDriver::with_config(&PreprocessorConfig::for_linux());
+        pp.process("#define OBJ 1\n#define FN(a, b) a\n#define VAR(a, ...) a\n#define NONE()\n")
+            .unwrap();
+        assert_eq!(pp.macro_kind("OBJ"), Some(MacroKind::Object));
+        assert_eq!(
+            pp.macro_kind("FN"),
+            Some(MacroKind::Function {
+                arity: 2,
+                variadic: false
+            })
+        );
+        assert_eq!(
+            pp.macro_kind("VAR"),
+            Some(MacroKind::Function {
+                arity: 1,
+                variadic: true
+            })
+        );
+        assert_eq!(
+            pp.macro_kind("NONE"),
+            Some(MacroKind::Function {
+                arity: 0,
+                variadic: false
+            })
+        );
+        assert_eq!(pp.macro_kind("__LINE__"), Some(MacroKind::Dynamic));
+        assert_eq!(pp.macro_kind("__COUNTER__"), Some(MacroKind::Dynamic));
+        assert_eq!(pp.macro_kind("__linux__"), Some(MacroKind::Builtin));
+        assert_eq!(pp.macro_kind("UNDEFINED"), None);
+
+        pp.process("#undef FN\n").unwrap();
+        assert_eq!(pp.macro_kind("FN"), None);
+    }
+
+    #[test]
+    fn counter_increments_across_includes_and_restarts_per_call() {
+        let mut pp = Preprocessor::new().with_include_resolver(|_, _, _| {
+            IncludeResolution::Content("int v__COUNTER__ = __COUNTER__;\n".to_string())
+        });
+        let src = "__COUNTER__ __COUNTER__\n#include \"a.h\"\n__COUNTER__\n";
+        assert_eq!(
+            pp.process(src).unwrap(),
+            "0 1\nint v__COUNTER__ = 2;\n\n3\n"
+        );
+        assert_eq!(pp.process("__COUNTER__\n").unwrap(), "0\n");
+    }
+
+    #[test]
+    fn tokens_record_their_columns() {
+        let columns: Vec<usize> = engine::tokenize_line_with_columns("a /* c */ bé(x) // d")
+            .into_iter()
+            .map(|(column, _)| column)
+            .collect();
+        assert_eq!(columns, [1, 2, 3, 10, 11, 12, 13, 14, 15, 16, 17]);
+    }
+
+    #[test]
+    fn directive_inside_macro_arguments_is_an_error() {
+        let src = "#define FOO(a, b, c) a b c\nFOO(\n#ifdef X\n 1,\n#else\n 2,\n#endif\n 3)\n";
+        let mut pp = PreprocessorDriver::new();
+        pp.set_current_file("args.c".to_string());
+        let err = pp.process(src).unwrap_err();
+        assert!(
+            matches!(&err.kind, PreprocessErrorKind::Other(m)
+                if m == "preprocessing directive within macro argument list of 'FOO' (invocation starts at args.c:2)"),
+            "{err}"
+        );
+        assert_eq!((err.line, err.column), (3, Some(1)));
+    }
+
+    #[test]
+    fn collapse_blank_lines_limits_runs() {
+        let mut src = String::from("first\n#if 0\n");
+        src.push_str(&"skipped\n".repeat(20));
+        src.push_str("#endif\n\n\n   \nsecond\n\nthird\n");
+        let process = |config: PreprocessorConfig| {
+            PreprocessorDriver::with_config(&config)
+                .process_lines(&src)
+                .unwrap()
+        };
+
+        let all = process(PreprocessorConfig::for_linux());
+        assert_eq!(all, ["first", "", "", "   ", "second", "", "third"]);
+
+        let collapsed = process(PreprocessorConfig::for_linux().with_collapse_blank_lines(1));
+        assert_eq!(collapsed, ["first", "", "second", "", "third"]);
+
+        let removed = process(PreprocessorConfig::for_linux().with_collapse_blank_lines(0));
+        assert_eq!(removed, ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn stdc_pragmas_pass_through() {
+        let src = "#pragma STDC FENV_ACCESS ON\n#pragma STDC FP_CONTRACT OFF\n#pragma STDC CX_LIMITED_RANGE DEFAULT\n_Pragma(\"STDC FENV_ROUND FE_UPWARD\")\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let out = pp.process(src).unwrap();
+        assert_eq!(
+            out,
+            "#pragma STDC FENV_ACCESS ON\n#pragma STDC FP_CONTRACT OFF\n#pragma STDC CX_LIMITED_RANGE DEFAULT\n#pragma STDC FENV_ROUND FE_UPWARD\n"
+        );
+        assert!(warnings.borrow().is_empty());
+    }
+
+    #[test]
+    fn stdc_pragma_operands_are_not_expanded() {
+        let src = "#define ON OFF\n#define FP_CONTRACT CONTRACT\n#pragma STDC FP_CONTRACT ON\n#pragma STDC BOGUS ON\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let out = pp.process(src).unwrap();
+        assert_eq!(out, "#pragma STDC FP_CONTRACT ON\n#pragma STDC BOGUS ON\n");
+        assert_eq!(
+            warnings.borrow().as_slice(),
+            ["<stdin>:4: unknown pragma STDC BOGUS"]
+        );
+    }
+
+    #[test]
+    fn malformed_stdc_pragmas_warn_and_pass_through() {
+        let src = "#pragma STDC FENV_ACCESS MAYBE\n#pragma STDC FP_CONTRACT\n#pragma STDC FANCY ON\n#pragma STDC\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let out = pp.process(src).unwrap();
+        assert_eq!(out.lines().count(), 4);
+        assert_eq!(
+            warnings.borrow().as_slice(),
+            [
+                "<stdin>:1: expected ON, OFF or DEFAULT after #pragma STDC FENV_ACCESS",
+                "<stdin>:2: expected ON, OFF or DEFAULT after #pragma STDC FP_CONTRACT",
+                "<stdin>:3: unknown pragma STDC FANCY",
+                "<stdin>:4: missing name after #pragma STDC",
+            ]
+        );
+    }
+
+    #[test]
+    fn balanced_pragma_pack_passes_through_silently() {
+        let header = "#pragma pack(push, hdr, 4)\nstruct h { char c; };\n";
+        let src = "#pragma pack(push, 1)\n#include \"h.h\"\n#pragma pack(pop, hdr)\n#pragma pack(16)\n#pragma pack()\n_Pragma(\"pack(pop)\")\n";
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&warnings);
+        let config =
+            PreprocessorConfig::for_linux().with_warning_handler(Rc::new(move |msg: &str| {
+                sink.borrow_mut().push(msg.to_string());
+            }));
+        let mut pp = PreprocessorDriver::with_config(&config)
+            .with_include_resolver(move |_, _, _| Some(header.to_string()));
+        let out = pp.process(src).unwrap();
+        assert_eq!(
+            out,
+            "#pragma pack(push, 1)\n#pragma pack(push, hdr, 4)\nstruct h { char c; };\n\n#pragma pack(pop, hdr)\n#pragma pack(16)\n#pragma pack()\n#pragma pack(pop)\n"
+        );
+        assert!(warnings.borrow().is_empty(), "{:?}", warnings.borrow());
+    }
+
+    #[test]
+    fn unbalanced_or_malformed_pragma_pack_warns_and_passes_through() {
+        let src = "#pragma pack(pop)\n#pragma pack(push, 3)\n#pragma pack(pop, missing)\n#pragma pack(push, 1 2)\n#pragma pack 4\n#pragma pack(push, outer)\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let out = pp.process(src).unwrap();
+        assert_eq!(out, src);
+        assert_eq!(
+            warnings.borrow().as_slice(),
+            [
+                "<stdin>:1: #pragma pack(pop) without matching push",
+                "<stdin>:2: alignment '3' in #pragma pack is not a power of two",
+                "<stdin>:3: #pragma pack(pop, missing) without matching push",
+                "<stdin>:4: alignment '1 2' in #pragma pack is not a power of two",
+                "<stdin>:5: expected '(' after #pragma pack",
+                "<stdin>:2: #pragma pack(push) without matching pop",
+                "<stdin>:4: #pragma pack(push) without matching pop",
+                "<stdin>:6: #pragma pack(push) without matching pop",
+            ]
+        );
+
+        // Each call is a translation unit of its own
+        warnings.borrow_mut().clear();
+        pp.process("#pragma pack(pop)\n").unwrap();
+        assert_eq!(
+            warnings.borrow().as_slice(),
+            ["<stdin>:1: #pragma pack(pop) without matching push"]
+        );
+    }
+
+    #[test]
+    fn pragma_region_passes_through_silently() {
+        let src = "#pragma region Helpers\nint helper(void);\n#pragma endregion\n_Pragma(\"region Generated\")\n";
+        for config in [
+            PreprocessorConfig::for_windows().with_line_ending(LineEnding::LF),
+            PreprocessorConfig::builder().strict(true).build().unwrap(),
+        ] {
+            let (mut pp, warnings) = collecting_preprocessor(config);
+            let out = pp.process(src).unwrap();
+            assert_eq!(
+                out,
+                "#pragma region Helpers\nint helper(void);\n#pragma endregion\n#pragma region Generated\n"
+            );
+            assert!(warnings.borrow().is_empty());
+        }
+    }
+
+    #[test]
+    fn include_resolution_skip_suppresses_an_include_without_error() {
+        let (pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let mut pp = pp.with_include_resolver(|path, _, _| match path {
+            "windows.h" => IncludeResolution::Skip,
+            "present.h" => IncludeResolution::Content("int present;\n".to_string()),
+            _ => IncludeResolution::NotFound,
+        });
+        let result = pp
+            .process("#include <windows.h>\n#include \"present.h\"\nint main;\n")
+            .unwrap();
+        assert!(!result.contains("windows"));
+        assert!(result.contains("int present;"));
+        assert!(result.contains("int main;"));
+        assert!(pp.process("#include \"missing.h\"\n").is_err());
+        assert!(warnings.borrow().is_empty());
+    }
+
+    #[test]
+    fn malformed_header_names_get_specific_errors() {
+        let mut pp = Preprocessor::new().with_include_resolver(|_, _, _| Some(String::new()));
+        for (src, message, column) in [
+            ("#include \"\"\n", "empty filename in #include \"\"", 10),
+            ("#include <>\n", "empty filename in #include <>", 10),
+            (
+                "#include \"unterminated\n",
+                "missing terminating \" character in #include \"unterminated",
+                23,
+            ),
+            (
+                "#include <foo.h\n",
+                "missing terminating > character in #include <foo.h",
+                16,
+            ),
+        ] {
+            let err = pp.process(src).unwrap_err();
+            assert!(
+                matches!(&err.kind, PreprocessErrorKind::MalformedDirective(m) if m == message),
+                "{src}: {err:?}"
+            );
+            assert_eq!(err.code(), "E0002_MALFORMED_DIRECTIVE", "{src}");
+            assert_eq!(err.column, Some(column), "{src}");
+        }
+
+        let err = pp.process("#include \"a.h\" extra\n").unwrap_err();
+        assert!(matches!(
+            err.kind,
+            PreprocessErrorKind::MalformedDirective(_)
+        ));
+        let err = pp.parse_events("#include <>\n").unwrap_err();
+        assert!(
+            matches!(&err.kind, PreprocessErrorKind::MalformedDirective(m) if m.starts_with("empty filename"))
+        );
+        assert_eq!(err.code(), "E0002_MALFORMED_DIRECTIVE");
+    }
+
+    #[test]
+    fn undefined_header_name_characters_warn_but_still_resolve() {
+        let (pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        let mut pp =
+            pp.with_include_resolver(|path, _, _| Some(format!("int from_{};\n", path.len())));
+        let src = "#include \"it's.h\"\n#include <dir\\file.h>\n#include <a//b.h>\n#include \"c/*d.h\"\n#include <plain.h>\n";
+        let out = pp.process(src).unwrap();
+        assert_eq!(out.matches("int from_").count(), 5);
+        assert_eq!(
+            warnings.borrow().as_slice(),
+            [
+                "<stdin>:1: header name \"it's.h\" contains ' (undefined behavior)",
+                "<stdin>:2: header name <dir\\file.h> contains \\ (undefined behavior)",
+                "<stdin>:3: header name <a//b.h> contains // (undefined behavior)",
+                "<stdin>:4: header name \"c/*d.h\" contains /* (undefined behavior)",
+            ]
+        );
+
+        // Backslashes are directory separators for MSVC
+        let (pp, warnings) = collecting_preprocessor(
+            PreprocessorConfig::for_windows().with_line_ending(LineEnding::LF),
+        );
+        let mut pp = pp.with_include_resolver(|_, _, _| Some(String::new()));
+        pp.process("#include \"sub\\header.h\"\n").unwrap();
+        assert!(warnings.borrow().is_empty());
+    }
+
+    #[test]
+    fn include_resolver_closures_may_still_return_option() {
+        let mut pp = Preprocessor::new()
+            .with_include_resolver(|path, _, _| (path == "a.h").then(|| "int a;\n".to_string()));
+        assert!(pp.process("#include \"a.h\"\n").unwrap().contains("int a;"));
+        assert!(pp.process("#include \"b.h\"\n").is_err());
+    }
+
+    #[test]
+    fn expression_cache_is_invalidated_by_macro_changes() {
+        let mut pp = Preprocessor::new();
+        let src = "#if defined(FOO)\nfirst\n#endif\n#define FOO\n#if defined(FOO)\nsecond\n#endif\n#undef FOO\n#if defined(FOO)\nthird\n#endif\n";
+        let result = pp.process(src).unwrap();
+        assert!(!result.contains("first"));
+        assert!(result.contains("second"));
+        assert!(!result.contains("third"));
+        assert_eq!(pp.stats().expression_cache_hits, 0);
+        assert_eq!(pp.stats().expression_cache_misses, 3);
+    }
+
+    #[test]
+    fn repeated_guard_expressions_are_evaluated_once() {
+        let guard = "#if defined(_WIN32) && !defined(__CYGWIN__)\nint win;\n#endif\n";
+        let header = guard.repeat(50);
+        let mut pp = Preprocessor::new().with_include_resolver(move |_, _, _| Some(header.clone()));
+        let result = pp
+            .process("#include \"config.h\"\n#include \"config.h\"\n")
+            .unwrap();
+        assert!(!result.contains("int win;"));
+        let stats = pp.stats();
+        assert_eq!(stats.expression_cache_misses, 1);
+        assert_eq!(stats.expression_cache_hits, 99);
+    }
+
+    #[test]
+    fn expressions_using_line_are_not_cached() {
+        let mut pp = Preprocessor::new();
+        let result = pp
+            .process("#if __LINE__ == 1\none\n#endif\n#if __LINE__ == 1\nagain\n#endif\n")
+            .unwrap();
+        assert!(result.contains("one"));
+        assert!(!result.contains("again"));
+        assert_eq!(pp.stats().expression_cache_hits, 0);
+    }
+
+    #[test]
+    fn scoped_driver_sees_extra_defines_and_rolls_them_back() {
+        let mut pp = Preprocessor::new();
+        pp.process("#define BASE 1\n#define SHADOWED old\n")
+            .unwrap();
+        let src = "#ifdef FOO\nfoo on\n#endif\nBASE SHADOWED\n";
+        {
+            let mut scope = pp.scoped();
+            scope.define("FOO", None, "", false);
+            let result = scope
+                .process("#undef BASE\n#define SHADOWED new\n")
+                .unwrap();
+            assert!(result.trim().is_empty());
+            let result = scope.process(src).unwrap();
+            assert!(result.contains("foo on"));
+            assert!(result.contains("BASE new"));
+        }
+        assert!(!pp.is_defined("FOO"));
+        let result = pp.process(src).unwrap();
+        assert!(!result.contains("foo on"));
+        assert!(result.contains("1 old"));
+    }
+
+    #[test]
+    fn nested_scopes_unwind_in_order() {
+        let mut pp = Preprocessor::new();
+        pp.define("LEVEL", None, "0", false);
+        {
+            let mut outer = pp.scoped();
+            outer.define("LEVEL", None, "1", false);
+            {
+                let mut inner = outer.scoped();
+                inner.define("LEVEL", None, "2", false);
+                inner.define("INNER", None, "", false);
+                assert_eq!(inner.expand_snippet("LEVEL").unwrap(), "2");
+            }
+            assert!(!outer.is_defined("INNER"));
+            assert_eq!(outer.expand_snippet("LEVEL").unwrap(), "1");
+        }
+        assert_eq!(pp.expand_snippet("LEVEL").unwrap(), "0");
+    }
+
+    #[test]
+    fn scoped_driver_restores_pragma_once_state() {
+        let mut pp = Preprocessor::new()
+            .with_include_resolver(|_, _, _| Some("#pragma once\nint once;\n".to_string()));
+        pp.scoped().process("#include \"once.h\"\n").unwrap();
+        let result = pp.process("#include \"once.h\"\n").unwrap();
+        assert!(result.contains("int once;"));
+    }
+
+    #[test]
+    fn reset_drops_user_macros_but_keeps_config_and_resolver() {
+        let config = PreprocessorConfig::builder()
+            .compiler(Compiler::Clang)
+            .include_resolver(Rc::new(|_, _, _| {
+                "#pragma once\nint header;\n".to_string().into()
+            }))
+            .build()
+            .unwrap();
+        let mut pp = Preprocessor::with_config(&config);
+        pp.define("CLI", None, "1", false);
+        pp.process("#define FOO 1\n#undef __clang__\n#include \"h.h\"\n")
+            .unwrap();
+
+        pp.reset();
+        assert!(!pp.is_defined("FOO"));
+        assert!(!pp.is_defined("CLI"));
+        assert!(pp.is_defined("__clang__"));
+        let result = pp.process("#include \"h.h\"\nFOO __LINE__\n").unwrap();
+        assert!(result.contains("int header;"));
+        assert!(result.contains("FOO 2"));
+    }
+
+    #[test]
+    fn compiled_config_matches_with_config() {
+        let config = PreprocessorConfig::builder()
+            .compiler(Compiler::Clang)
+            .define("VERSION=2")
+            .define("__clang__=override")
+            .undefine("__GNUC__")
+            .build()
+            .unwrap();
+        let compiled = config.compile();
+        let direct = Preprocessor::with_config(&config);
+        let fast = Preprocessor::with_compiled(&compiled);
+        assert_eq!(direct.get_macros(), fast.get_macros());
+        assert_eq!(fast.get_macros(), compiled.macros().as_map());
+
+        let input = "VERSION __clang__ __GNUC__ __x86_64__\n#undef VERSION\nVERSION\n";
+        let mut direct = direct;
+        let mut fast = fast;
+        assert_eq!(direct.process(input).unwrap(), fast.process(input).unwrap());
+        direct.reset();
+        fast.reset();
+        assert_eq!(direct.get_macros(), fast.get_macros());
+    }
+
+    #[test]
+    fn applying_compiled_config_keeps_user_macros() {
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&warnings);
+        let config =
+            PreprocessorConfig::for_linux().with_warning_handler(Rc::new(move |msg: &str| {
+                sink.borrow_mut().push(msg.to_string());
+            }));
+        let mut pp = Preprocessor::new();
+        pp.define("__linux__", None, "2", false);
+        pp.define("MINE", None, "1", false);
+        pp.apply_compiled(&config.compile());
+        assert_eq!(pp.process("__linux__ MINE\n").unwrap(), "2 1\n");
+        assert!(warnings.borrow().iter().any(|w| w.contains("'__linux__'")));
+    }
+
+    #[test]
+    fn clear_user_macros_keeps_builtins() {
+        let mut pp = Preprocessor::with_config(&PreprocessorConfig::for_linux());
+        pp.process("#define PI 3.14\n").unwrap();
+        pp.define("VERSION", None, "2", false);
+
+        pp.clear_user_macros();
+        assert!(!pp.is_defined("PI"));
+        assert!(!pp.is_defined("VERSION"));
+        assert!(pp.is_defined("__linux__"));
+        assert!(pp.is_defined("__GNUC__"));
+    }
+
+    #[test]
+    fn direct_self_include_has_specific_message() {
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _, _| match path {
+            "self.h" => Some("int self_h;\n#include \"self.h\"\n".to_string()),
+            "guarded.h" => Some(
+                "#ifndef GUARDED_H\n#define GUARDED_H\n#include \"guarded.h\"\nint guarded;\n#endif\n"
+                    .to_string(),
+            ),
+            _ => None,
+        });
+        let err = pp.process("#include \"self.h\"\n").unwrap_err();
+        assert!(err.to_string().contains("file includes itself: 'self.h'"));
+
+        let result = pp.process("#include \"guarded.h\"\n").unwrap();
+        assert!(result.contains("int guarded;"));
+    }
+
+    #[test]
+    fn process_full_reports_text_warnings_dependencies_and_stats() {
+        let config = PreprocessorConfig::builder()
+            .include_resolver(Rc::new(|path, _, _| match path {
+                "defs.h" => IncludeResolution::Content(
+                    "#define TWICE(x) ((x) * 2)\n#define BASE 21\n".to_string(),
+                ),
+                _ => IncludeResolution::NotFound,
+            }))
+            .build()
+            .unwrap();
+        let src =
+            "#include \"defs.h\"\n#include \"defs.h\"\n#warning check me\nint x = TWICE(BASE);\n";
+        let output = process_full(src, &config).unwrap();
+
+        assert!(output.text.contains("int x = ((21) * 2);"));
+        assert_eq!(
+            output.warnings,
+            vec![Diagnostic {
+                message: "#warning: check me".to_string(),
+                location: SourceLocation {
+                    file: "<stdin>".to_string(),
+                    line: 3,
+                },
+            }]
+        );
+        assert_eq!(
+            output.dependencies,
+            vec![Dependency {
+                path: "defs.h".to_string(),
+                kind: IncludeKind::Local,
+                included_from: SourceLocation {
+                    file: "<stdin>".to_string(),
+                    line: 1,
+                },
+            }]
+        );
+        assert_eq!(output.stats.macro_expansions, 2);
+        assert_eq!(output.text, process(src, &config).unwrap());
+    }
+
+    #[test]
+    fn macros_compare_by_definition_not_location() {
+        let mut first = Preprocessor::new();
+        first
+            .process("#define SQUARE(x) ((x) * (x))\n#define ONE 1\n")
+            .unwrap();
+        let mut second = Preprocessor::new();
+        second
+            .process("\n\n#define SQUARE(x) ((x) * (x))\n#define ONE 2\n")
+            .unwrap();
+
+        assert_eq!(first.get_macros()["SQUARE"], second.get_macros()["SQUARE"]);
+        assert_ne!(first.get_macros()["ONE"], second.get_macros()["ONE"]);
+
+        second
+            .process("#undef SQUARE\n#define SQUARE(y) ((y) * (y))\n")
+            .unwrap();
+        assert_ne!(first.get_macros()["SQUARE"], second.get_macros()["SQUARE"]);
+    }
+
+    #[test]
+    fn backslash_include_paths_resolve_with_forward_separator() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = Rc::clone(&seen);
+        let config = PreprocessorConfig::default()
+            .with_include_path_separator(PathSeparator::Forward)
+            .with_file_macro_separator(PathSeparator::Forward);
+        let mut pp = Preprocessor::with_config(&config).with_include_resolver(move |path, _, _| {
+            record.borrow_mut().push(path.to_string());
+            match path {
+                "sub/header.h" | "dir/mixed/x.h" => Some("const char *f = __FILE__;\n".to_string()),
+                "trailing/" => Some("int trailing;\n".to_string()),
+                _ => None,
+            }
+        });
+        let src = "#include \"sub\\header.h\"\n#include <dir\\mixed/x.h>\n#include \"trailing\\\" // not an escape\n";
+        let result = pp.process(src).unwrap();
+
+        assert_eq!(
+            *seen.borrow(),
+            vec!["sub/header.h", "dir/mixed/x.h", "trailing/"]
+        );
+        assert!(result.contains("const char *f = \"sub/header.h\";"));
+        assert!(result.contains("const char *f = \"dir/mixed/x.h\";"));
+        assert!(result.contains("int trailing;"));
+    }
+
+    #[test]
+    fn file_macro_escapes_preserved_backslashes() {
+        let mut pp = Preprocessor::new().with_include_resolver(|path, _, _| {
+            (path == "sub\\header.h").then(|| "const char *f = __FILE__;\n".to_string())
+        });
+        let result = pp.process("#include \"sub\\header.h\"\n").unwrap();
+        assert!(result.contains(r#"const char *f = "sub\\header.h";"#));
+    }
+
+    #[test]
+    fn macro_renders_as_define_string() {
+        let mut pp = Preprocessor::new();
+        pp.process("#define ANSWER 42\n#define ADD(a, b) ((a) + (b))\n#define LOG(fmt, ...) printf(fmt, __VA_ARGS__)\n#define EMPTY\n")
+            .unwrap();
+        let render = |name: &str| pp.get_macros()[name].to_define_string(name);
+        assert_eq!(render("ANSWER"), "#define ANSWER 42");
+        assert_eq!(render("ADD"), "#define ADD(a, b) ((a) + (b))");
+        assert_eq!(
+            render("LOG"),
+            "#define LOG(fmt, ...) printf(fmt, __VA_ARGS__)"
+        );
+        assert_eq!(render("EMPTY"), "#define EMPTY");
+    }
+
+    #[test]
+    fn exponential_expansion_hits_the_token_limit() {
+        // Each level doubles the expansion, so D24 would be 2^24 tokens
+        let mut src = String::from("#define D0 x\n");
+        for level in 1..=24 {
+            src.push_str(&format!("#define D{level} D{0} D{0}\n", level - 1));
+        }
+        src.push_str("D24\n");
+        let config = PreprocessorConfig::builder()
+            .max_expansion_tokens(10_000)
+            .build()
+            .unwrap();
+        let err = PreprocessorDriver::with_config(&config)
+            .process(&src)
+            .unwrap_err();
+        assert!(matches!(err.kind, PreprocessErrorKind::LimitExceeded(_)));
+        assert!(
+            err.to_string().contains("produced more than 10000 tokens"),
+            "{err}"
+        );
+
+        let config = PreprocessorConfig::builder()
+            .max_expansion_tokens(10)
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        pp.define("D2", None, "x x x x x x", false);
+        let err = pp.process("D2\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("limit exceeded: expansion of 'D2' produced more than 10 tokens ["),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn token_limit_applies_to_each_expansion_not_the_line() {
+        let config = PreprocessorConfig::builder()
+            .max_expansion_tokens(10)
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        pp.define("ONE", None, "1", false);
+        pp.define("F", Some(vec!["x".to_string()]), "x", false);
+        pp.define("G", Some(vec!["y".to_string()]), "y", false);
+        let line = "ONE F(G)(2) ".repeat(100);
+        let out = pp.process(&format!("{line}\n")).unwrap();
+        assert_eq!(out, format!("{}\n", "1 2 ".repeat(100)));
+    }
+
+    #[test]
+    fn oversized_include_fails_or_warns_by_policy() {
+        let generated = "int generated;\n".repeat(1000);
+        let resolve = move |path: &str, _kind, _context: &IncludeContext| {
+            if path == "big.h" {
+                IncludeResolution::Content(generated.clone())
+            } else {
+                IncludeResolution::Content("int small;\n".to_string())
+            }
+        };
+        let src = "#include \"small.h\"\n#include \"big.h\"\n";
+
+        let config = PreprocessorConfig::for_linux().with_max_include_bytes(1024);
+        let mut pp =
+            PreprocessorDriver::with_config(&config).with_include_resolver(resolve.clone());
+        let err = pp.process(src).unwrap_err();
+        assert!(matches!(err.kind, PreprocessErrorKind::LimitExceeded(_)));
+        assert_eq!(err.line, 2);
+        assert!(
+            err.to_string()
+                .contains("included file 'big.h' is 15000 bytes, more than the limit of 1024"),
+            "{err}"
+        );
+
+        let (pp, warnings) =
+            collecting_preprocessor(config.with_include_size_policy(IncludeSizePolicy::Warn));
+        let mut pp = pp.with_include_resolver(resolve);
+        let out = pp.process(src).unwrap();
+        assert_eq!(out.matches("int generated;").count(), 1000);
+        assert_eq!(
+            *warnings.borrow(),
+            ["<stdin>:2: included file 'big.h' is 15000 bytes, more than the limit of 1024"]
+        );
+        assert_eq!(pp.stats().bytes_read, src.len() + 11 + 15000);
+    }
+
+    #[test]
+    fn total_input_size_is_limited_per_translation_unit() {
+        let resolve = |_: &str, _kind, _context: &IncludeContext| {
+            IncludeResolution::Content("int header_variable;\n".to_string())
+        };
+        let src = "#include \"a.h\"\n#include \"b.h\"\n#include \"c.h\"\n";
+        let config = PreprocessorConfig::for_linux().with_max_total_bytes(100);
+        let mut pp = PreprocessorDriver::with_config(&config).with_include_resolver(resolve);
+        let err = pp.process(src).unwrap_err();
+        assert_eq!(err.line, 3);
+        assert!(
+            err.to_string()
+                .contains("including 'c.h' (21 bytes) brings the input to 108 bytes"),
+            "{err}"
+        );
+
+        // The count starts over for each translation unit
+        assert!(pp.process("#include \"a.h\"\n").is_ok());
+        assert!(pp.process("#include \"a.h\"\n").is_ok());
+
+        let (pp, warnings) =
+            collecting_preprocessor(config.with_include_size_policy(IncludeSizePolicy::Warn));
+        let mut pp = pp.with_include_resolver(resolve);
+        assert!(pp.process(&format!("{src}{src}")).is_ok());
+        assert_eq!(warnings.borrow().len(), 1);
+    }
+
+    #[test]
+    fn include_graph_dot_lists_each_edge_once() {
+        let config = PreprocessorConfig::for_linux().with_record_include_graph(true);
+        let mut pp = PreprocessorDriver::with_config(&config).with_include_resolver(
+            |path, _kind, _context| match path {
+                "a.h" => IncludeResolution::Content("#include <b.h>\n#include \"c.h\"\n".into()),
+                "c.h" => IncludeResolution::Content("#include <b.h>\n".into()),
+                _ => IncludeResolution::Content("#pragma once\nint b;\n".into()),
+            },
+        );
+        pp.set_current_file("main.c".to_string());
+        pp.process("#include \"a.h\"\n#include <b.h>\n").unwrap();
+        assert_eq!(
+            pp.include_graph_dot(),
+            "digraph includes {\n    \"main.c\" -> \"a.h\";\n    \"a.h\" -> \"b.h\";\n    \
+             \"a.h\" -> \"c.h\";\n    \"c.h\" -> \"b.h\";\n    \"main.c\" -> \"b.h\";\n}\n"
+        );
+
+        // Without the setting nothing is recorded
+        let mut pp = Preprocessor::new()
+            .with_include_resolver(|_, _, _| IncludeResolution::Content(String::new()));
+        pp.process("#include \"a.h\"\n").unwrap();
+        assert_eq!(pp.include_graph_dot(), "digraph includes {\n}\n");
+    }
+
+    #[test]
+    fn macro_argument_count_is_limited() {
+        let config = PreprocessorConfig::builder()
+            .max_macro_args(3)
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        let src = "#define F(...) __VA_ARGS__\nF(1, 2, 3)\n";
+        assert_eq!(pp.process(src).unwrap(), "1,2,3\n");
+        let err = pp.process("F(1, 2, 3, 4)\n").unwrap_err();
+        assert!(matches!(err.kind, PreprocessErrorKind::LimitExceeded(_)));
+        assert!(err.to_string().contains("more than 3 arguments"), "{err}");
+    }
+
+    #[test]
+    fn iteration_budget_stops_processing_and_reports_progress() {
+        let mut src = String::from("#define WRAP(x) [x]\n");
+        for i in 0..500 {
+            src.push_str(&format!("int v{i} = WRAP({i});\n"));
+        }
+        let config = PreprocessorConfig::default().with_max_iterations(100);
+        let err = process(&src, &config).unwrap_err();
+        assert!(matches!(err.kind, PreprocessErrorKind::BudgetExceeded(_)));
+        assert!(
+            err.to_string()
+                .contains("budget exceeded: more than 100 lines and macro expansions")
+        );
+        assert_eq!(err.file, "<stdin>");
+        assert!(
+            err.line > 1 && err.line < 100,
+            "stopped at line {}",
+            err.line
+        );
+
+        // The budget is per call, so a reused driver starts afresh
+        let mut pp = Preprocessor::with_config(&config);
+        pp.process("int a;\n").unwrap();
+        pp.process("int b;\n").unwrap();
+    }
+
+    #[test]
+    fn passed_deadline_stops_processing() {
+        let src = "int x;\n".repeat(1000);
+        let config = PreprocessorConfig::default().with_deadline(std::time::Instant::now());
+        let err = process(&src, &config).unwrap_err();
+        assert!(err.to_string().contains("budget exceeded: deadline passed"));
+        assert!(err.line < 1000);
+
+        let config = PreprocessorConfig::default()
+            .with_deadline(std::time::Instant::now() + std::time::Duration::from_secs(60));
+        assert!(process(&src, &config).is_ok());
+    }
+
+    #[test]
+    fn tokenize_then_render_is_identity_without_comments() {
+        let lines = [
+            "int x = a ## b; c#d # e",
+            "## leading and trailing ##",
+            "x = y / z; p->q /= 2; a<<=b>>=c; r = s/ *t;",
+            "s = \"a##b // not a comment\" L'\\'' u8\"q\\\"\";",
+            "\t  mixed \t whitespace  ",
+            "unterminated \"string \\",
+            "caf\u{e9} \u{3bb}(x) $id @ ` ~!%^&*()-+={}[]|:;<>,.?",
+        ];
+        for line in lines {
+            assert_eq!(engine::tokens_to_string(&engine::tokenize_line(line)), line);
+        }
+
+        // Pseudo-random lines over an alphabet heavy in punctuation
+        let alphabet: Vec<char> = "ab_9 \t#/*\"'\\<>=!&|^~%.,;:?()[]{}+-".chars().collect();
+        let mut state: u32 = 0x2545_f491;
+        for _ in 0..2000 {
+            let mut line = String::new();
+            for _ in 0..24 {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                line.push(alphabet[state as usize % alphabet.len()]);
+            }
+            if line.contains("//") || line.contains("/*") {
+                continue;
+            }
+            assert_eq!(
+                engine::tokens_to_string(&engine::tokenize_line(&line)),
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn line_starting_with_paste_operator_is_text() {
+        let mut pp = Preprocessor::new();
+        let result = pp.process("#define N 1\n## N ##\n  ##x\n").unwrap();
+        assert!(result.contains("## 1 ##"));
+        assert!(result.contains("  ##x"));
+    }
+
+    #[test]
+    fn trailing_newline_follows_the_input() {
+        let mut pp = Preprocessor::new();
+        assert_eq!(
+            pp.process("#define A 1\nint a = A;\n").unwrap(),
+            "int a = 1;\n"
+        );
+        assert_eq!(pp.process("#define A 1\nint a = A;").unwrap(), "int a = 1;");
+        assert_eq!(pp.process("").unwrap(), "");
+        assert_eq!(pp.process("\n").unwrap(), "\n");
+
+        let config = PreprocessorConfig::default().with_line_ending(LineEnding::CRLF);
+        let mut pp = Preprocessor::with_config(&config);
+        assert_eq!(pp.process("a\r\nb\r\n").unwrap(), "a\r\nb\r\n");
+        assert_eq!(pp.process("a\r\nb").unwrap(), "a\r\nb");
+
+        for input in ["a\r\nb\r\n", "a\r\nb"] {
+            let mut written = Vec::new();
+            pp.process_to_writer(input, &mut written).unwrap();
+            assert_eq!(String::from_utf8(written).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn assert_directive_answers_predicate_tests() {
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        let src = "#assert machine(x86)\n#assert machine( arm  64 )\n#if #machine(x86)\nyes_x86\n#endif\n#if #machine(mips)\nyes_mips\n#endif\n#if #machine(arm 64) && #machine && !#system\nyes_arm\n#endif\n";
+        let result = pp.process(src).unwrap();
+        assert!(result.contains("yes_x86"));
+        assert!(!result.contains("yes_mips"));
+        assert!(result.contains("yes_arm"));
+        assert!(
+            warnings
+                .borrow()
+                .iter()
+                .any(|w| w.contains("#assert is a deprecated GCC extension"))
+        );
+        assert!(
+            warnings
+                .borrow()
+                .iter()
+                .any(|w| w.contains("assertions are a deprecated extension"))
+        );
+    }
+
+    #[test]
+    fn unassert_removes_one_answer_or_all() {
+        let mut pp = Preprocessor::with_config(&PreprocessorConfig::for_linux());
+        let src = "#assert cpu(a)\n#assert cpu(b)\n#unassert cpu(a)\n#if #cpu(a)\nhas_a\n#endif\n#if #cpu(b)\nhas_b\n#endif\n#unassert cpu\n#if #cpu\nhas_any\n#endif\n";
+        let result = pp.process(src).unwrap();
+        assert!(!result.contains("has_a"));
+        assert!(result.contains("has_b"));
+        assert!(!result.contains("has_any"));
+
+        assert!(pp.process("#assert cpu\n").is_err());
+    }
+
+    #[test]
+    fn assertions_are_gcc_only() {
+        let mut pp = Preprocessor::with_config(&PreprocessorConfig::for_windows());
+        let result = pp.process("#assert machine(x86)\nok\n").unwrap();
+        assert!(result.contains("ok"));
+        assert!(pp.process("#if #machine(x86)\n#endif\n").is_err());
+    }
+
+    // -- Config builder tests --
+
+    #[test]
+    fn builder_happy_path_applies_defines_and_include_dirs() {
+        let dir = std::env::temp_dir();
+        let (config, warnings) = PreprocessorConfig::builder()
+            .target(Target::Windows)
+            .define("FOO=42")
+            .define("BAR")
+            .define("FOO=42")
+            .undefine("_WINDOWS")
+            .include_dir(&dir)
+            .require_existing_include_dirs(true)
+            .build_with_warnings()
+            .unwrap();
+        assert!(warnings.is_empty());
+        assert!(matches!(config.compiler, Compiler::MSVC));
+        assert!(matches!(config.line_ending, LineEnding::CRLF));
+        assert_eq!(
+            config.defines,
+            vec![
+                ("FOO".to_string(), "42".to_string()),
+                ("BAR".to_string(), "1".to_string())
+            ]
+        );
+        assert_eq!(
+            config.include_dirs,
+            vec![dir.to_string_lossy().into_owned()]
+        );
+
+        let seen_dirs = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&seen_dirs);
+        let mut config = config;
+        config.include_resolver = Some(Rc::new(move |_, _, context: &IncludeContext| {
+            seen.borrow_mut().clone_from(&context.include_dirs);
+            IncludeResolution::Content(String::new())
+        }));
+        let result = process("#include <x.h>\nFOO BAR _WINDOWS _WIN32\n", &config).unwrap();
+        assert!(result.contains("42 1 _WINDOWS 1"));
+        assert_eq!(*seen_dirs.borrow(), config.include_dirs);
+    }
+
+    #[test]
+    fn reapplying_config_replaces_builtins() {
+        let mut pp = PreprocessorDriver::with_config(&PreprocessorConfig::for_windows());
+        pp.define("USER", None, "1", false);
+        assert!(pp.is_defined("_WIN32") && pp.is_defined("_MSC_VER"));
+
+        pp.apply_config(&PreprocessorConfig::for_linux());
+        assert!(!pp.is_defined("_WIN32") && !pp.is_defined("_MSC_VER"));
+        assert!(pp.is_defined("__linux__") && pp.is_defined("__GNUC__"));
+        assert!(pp.is_defined("USER"));
+
+        pp.clear_builtins();
+        assert!(!pp.is_defined("__linux__") && !pp.is_defined("__SIZEOF_INT__"));
+        assert!(pp.is_defined("USER"));
+    }
+
+    #[test]
+    fn intrinsics_expand_to_portable_code() {
+        let src = "if (__builtin_expect(x, 0)) __builtin_unreachable();
+#define OFF(t, m) __builtin_offsetof(t, m)
+n = OFF(struct s, field) + __builtin_constant_p(n);
+v = __builtin_bswap32(v);
+#ifdef __builtin_expect
+stubbed
+#endif
+";
+        let mut pp = PreprocessorDriver::with_config(&PreprocessorConfig::for_linux());
+        assert_eq!(
+            pp.process(src).unwrap(),
+            "if ((x)) ;
+n = ((size_t)&((struct s*)0)->field) + 0;
+v = __builtin_bswap32(v);
+stubbed
+"
+        );
+
+        let groups = BuiltinGroups {
+            intrinsics: false,
+            ..BuiltinGroups::default()
+        };
+        pp.apply_config(&PreprocessorConfig::for_linux().with_builtin_macro_groups(groups));
+        assert!(!pp.is_defined("__builtin_expect"));
+        assert_eq!(
+            pp.process("if (__builtin_expect(x, 0)) f();\n").unwrap(),
+            "if (__builtin_expect(x, 0)) f();\n"
+        );
+    }
+
+    #[test]
+    fn msvc_compat_stubs_strip_windows_annotations() {
+        let src = "__declspec(dllimport) int __cdecl copy(_Out_writes_(n) char *dst, _In_opt_ const char *src, size_t n);
+__forceinline void __stdcall f(_Inout_ int *p);
+";
+        let mut pp = PreprocessorDriver::with_config(
+            &PreprocessorConfig::for_windows().with_msvc_compat_stubs(),
+        );
+        assert_eq!(
+            pp.process(src).unwrap(),
+            " int  copy( char *dst,  const char *src, size_t n);\r\ninline void  f( int *p);\r\n"
+        );
+
+        // Only MSVC gets the stubs
+        let pp = PreprocessorDriver::with_config(
+            &PreprocessorConfig::for_linux().with_msvc_compat_stubs(),
+        );
+        assert!(!pp.is_defined("__declspec"));
+    }
+
+    #[test]
+    fn builtin_rewrites_are_validated_and_applied() {
+        let config = PreprocessorConfig::builder()
+            .builtin_rewrites(vec![
+                ("__attribute__(x)".to_string(), None),
+                ("__restrict".to_string(), Some("restrict".to_string())),
+            ])
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        assert_eq!(
+            pp.process("void f(char *__restrict p) __attribute__((noreturn));\n")
+                .unwrap(),
+            "void f(char *restrict p) ;\n"
+        );
+        assert_eq!(pp.macro_kind("__restrict"), Some(MacroKind::Builtin));
+
+        for spec in ["1bad", "F(x", "F(x) body"] {
+            let result = PreprocessorConfig::builder()
+                .builtin_rewrites(vec![(spec.to_string(), None)])
+                .build();
+            assert!(
+                matches!(result, Err(ConfigError::MalformedDefine(ref s)) if s == spec),
+                "{spec}"
+            );
+        }
+    }
+
+    #[test]
+    fn builtin_groups_can_be_disabled() {
+        let groups = BuiltinGroups {
+            target: false,
+            ..BuiltinGroups::default()
+        };
+        let pp = PreprocessorDriver::with_config(
+            &PreprocessorConfig::for_linux().with_builtin_macro_groups(groups),
+        );
+        assert!(!pp.is_defined("__linux__") && !pp.is_defined("__BYTE_ORDER__"));
+        assert!(pp.is_defined("__GNUC__") && pp.is_defined("__SIZEOF_LONG__"));
+
+        let none = BuiltinGroups {
+            target: false,
+            compiler: false,
+            intrinsics: false,
+            sizeof: false,
+        };
+        let config = PreprocessorConfig::builder()
+            .builtin_macro_groups(none)
+            .define("KEEP=1")
+            .build()
+            .unwrap();
+        let pp = PreprocessorDriver::with_config(&config);
+        assert_eq!(pp.get_macros().keys().collect::<Vec<_>>(), ["KEEP"]);
+    }
+
+    #[test]
+    fn sizeof_macros_follow_the_target_data_model() {
+        let src = "__SIZEOF_LONG__ __SIZEOF_WCHAR_T__ __SIZEOF_SHORT__ __SIZEOF_DOUBLE__\n";
+        let mut pp = PreprocessorDriver::with_config(&PreprocessorConfig::for_linux());
+        assert_eq!(pp.process(src).unwrap(), "8 4 2 8\n");
+
+        pp.apply_config(&PreprocessorConfig::for_windows());
+        assert_eq!(pp.process(src).unwrap(), "4 2 2 8\r\n");
+    }
+
+    #[test]
+    fn limit_macros_match_lp64_on_linux() {
+        let src = "#if __CHAR_BIT__ == 8 && __SCHAR_MAX__ == 127 && __SHRT_MAX__ == 32767
+#if __INT_MAX__ == 2147483647 && __LONG_MAX__ == __LONG_LONG_MAX__
+int lp64;
+#endif
+#endif
+";
+        let mut pp = PreprocessorDriver::with_config(&PreprocessorConfig::for_linux());
+        assert!(pp.process(src).unwrap().contains("int lp64;"));
+        assert_eq!(
+            pp.process("__LONG_MAX__ __WCHAR_MAX__\n").unwrap(),
+            "9223372036854775807 2147483647\n"
+        );
+
+        let config = PreprocessorConfig::builder()
+            .define("__INT_MAX__=32767")
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        assert_eq!(pp.process("__INT_MAX__\n").unwrap(), "32767\n");
+    }
+
+    #[test]
+    fn byte_order_macros_default_to_little_endian() {
+        let src = "#if __BYTE_ORDER__ == __ORDER_LITTLE_ENDIAN__\nlittle\n#elif __BYTE_ORDER__ == __ORDER_BIG_ENDIAN__\nbig\n#endif\n";
+        for config in [
+            PreprocessorConfig::for_linux(),
+            PreprocessorConfig::for_windows(),
+            PreprocessorConfig::for_macos(),
+        ] {
+            let mut pp = PreprocessorDriver::with_config(&config);
+            assert_eq!(pp.process(src).unwrap().trim(), "little");
+        }
+
+        let config = PreprocessorConfig::builder()
+            .byte_order(ByteOrder::Big)
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        assert_eq!(pp.process(src).unwrap().trim(), "big");
+    }
+
+    #[test]
+    fn line_breaks_in_api_bodies_stay_on_one_line() {
+        let src = "int a = API; int b = CONFIG;\nint c;\n";
+        let expected = "int a = 1 + 2; int b = 3 + 4;\nint c;\n";
+
+        let config = PreprocessorConfig::builder()
+            .define("CONFIG=3 +\n4")
+            .build()
+            .unwrap();
+        let mut pp = PreprocessorDriver::with_config(&config);
+        pp.define("API", None, "1 +\r\n2", false);
+        assert_eq!(pp.process(src).unwrap(), expected);
+
+        let ctx = unsafe { c_api::includium_new(std::ptr::null()) };
+        let define = |name: &std::ffi::CStr, body: &std::ffi::CStr| unsafe {
+            c_api::includium_define(ctx, name.as_ptr(), body.as_ptr())
+        };
+        assert_eq!(define(c"API", c"1 +\n2"), 0);
+        assert_eq!(define(c"CONFIG", c"3\n+ 4"), 0);
+        assert_eq!(define(c"1BAD", c"x"), -1);
+        let input = std::ffi::CString::new(src).unwrap();
+        unsafe {
+            let result = c_api::includium_process(ctx, input.as_ptr());
+            assert_eq!(std::ffi::CStr::from_ptr(result).to_str().unwrap(), expected);
+            c_api::includium_free_result(result);
+            c_api::includium_free(ctx);
+        }
+    }
+
+    #[test]
+    fn latin1_comment_fails_in_strict_mode_and_is_replaced_when_lossy() {
+        // "caf\xe9" is Latin-1, not UTF-8
+        let src: &[u8] = b"#define N 1\nint x = N; /* caf\xe9 */\nint y = N;\n";
+        let err = process_bytes(src, &PreprocessorConfig::for_linux()).unwrap_err();
+        assert_eq!((err.line, err.column), (2, Some(18)));
+        assert!(err.to_string().contains("invalid UTF-8 in input"), "{err}");
+
+        let config = PreprocessorConfig::for_linux().with_lossy_utf8(true);
+        let (mut pp, warnings) = collecting_preprocessor(config);
+        assert_eq!(pp.process_bytes(src).unwrap(), "int x = 1;  \nint y = 1;\n");
+        assert_eq!(
+            *warnings.borrow(),
+            ["<stdin>:2: invalid UTF-8 replaced with U+FFFD"]
+        );
+
+        // Outside comments the replacement character is kept
+        let out = pp.process_bytes(b"const char *s = \"\xff\"; N\n").unwrap();
+        assert_eq!(out, "const char *s = \"\u{fffd}\"; 1\n");
+
+        let dir = std::env::temp_dir().join(format!("includium-lossy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("old.c"), dir.join("old.i"));
+        fs::write(&input, src).unwrap();
+        assert!(process_file(&input, &output, &PreprocessorConfig::for_linux()).is_err());
+        process_file_lossy(&input, &output, &PreprocessorConfig::for_linux()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "int x = 1;  \nint y = 1;\n"
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn nul_bytes_are_dropped_with_a_warning() {
+        let src = "#define N 1\nint a\0 = N;\nint b = N;\n";
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::for_linux());
+        assert_eq!(pp.process(src).unwrap(), "int a = 1;\nint b = 1;\n");
+        assert_eq!(*warnings.borrow(), ["<stdin>:2: null character(s) ignored"]);
+
+        let strict = PreprocessorConfig::for_linux().with_strict(true);
+        let err = process(src, &strict).unwrap_err();
+        assert_eq!((err.line, err.column), (2, Some(6)));
+
+        // The C API takes a length, so NUL bytes reach the preprocessor
+        let ctx = unsafe { c_api::includium_new(std::ptr::null()) };
+        let lossy_input = b"int\0 c; /* \xe9 */\n";
+        unsafe {
+            let result = c_api::includium_process_bytes(ctx, src.as_ptr().cast(), src.len(), 0);
+            assert_eq!(
+                std::ffi::CStr::from_ptr(result).to_str().unwrap(),
+                "int a = 1;\nint b = 1;\n"
+            );
+            c_api::includium_free_result(result);
+            let result = c_api::includium_process_bytes(
+                ctx,
+                lossy_input.as_ptr().cast(),
+                lossy_input.len(),
+                0,
+            );
+            assert!(result.is_null());
+            let result = c_api::includium_process_bytes(
+                ctx,
+                lossy_input.as_ptr().cast(),
+                lossy_input.len(),
+                1,
+            );
+            assert_eq!(
+                std::ffi::CStr::from_ptr(result).to_str().unwrap(),
+                "int c;  \n"
+            );
+            c_api::includium_free_result(result);
+            // A lossy call doesn't make later calls lossy
+            let invalid = b"/* \xe9 */\n\0";
+            assert!(c_api::includium_process(ctx, invalid.as_ptr().cast()).is_null());
+            c_api::includium_free(ctx);
+        }
+    }
+
+    #[test]
+    fn freebsd_target_defines_its_macros() {
+        let mut pp = PreprocessorDriver::with_config(&PreprocessorConfig::for_freebsd());
+        assert!(pp.is_defined("__FreeBSD__") && pp.is_defined("__unix__"));
+        assert!(pp.is_defined("__clang__") && !pp.is_defined("__linux__"));
+        assert_eq!(pp.process("__SIZEOF_LONG__\n").unwrap(), "8\n");
+
+        let config = PreprocessorConfig::builder()
+            .target(Target::FreeBSD)
+            .build()
+            .unwrap();
+        assert!(PreprocessorDriver::with_config(&config).is_defined("__FreeBSD__"));
+    }
+
+    #[test]
+    fn builtins_never_overwrite_user_definitions() {
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&warnings);
+        let config =
+            PreprocessorConfig::for_linux().with_warning_handler(Rc::new(move |msg: &str| {
+                sink.borrow_mut().push(msg.to_string())
+            }));
+        let mut pp = PreprocessorDriver::new();
+        pp.define("_WIN32", None, "1", false);
+        pp.define("__linux__", None, "2", false);
+        pp.define("__unix__", None, "1", false);
+        pp.apply_config(&config);
+
+        assert_eq!(
+            pp.process("_WIN32 __linux__ __unix__\n").unwrap(),
+            "1 2 1\n"
+        );
+        // Only the conflicting definition is reported
+        assert_eq!(
+            warnings.borrow().as_slice(),
+            ["builtin macro '__linux__' not defined: keeping the user definition"]
+        );
+    }
+
+    #[test]
+    fn builder_rejects_zero_limits() {
+        let err = PreprocessorConfig::builder()
+            .recursion_limit(0)
+            .build()
+            .err();
+        assert_eq!(err, Some(ConfigError::ZeroLimit("recursion_limit")));
+
+        let err = PreprocessorConfig::builder()
+            .max_iterations(0)
+            .build()
+            .err();
+        assert_eq!(err, Some(ConfigError::ZeroLimit("max_iterations")));
+
+        let err = PreprocessorConfig::builder()
+            .max_macro_args(0)
+            .build()
+            .err();
+        assert_eq!(err, Some(ConfigError::ZeroLimit("max_macro_args")));
+
+        let err = PreprocessorConfig::builder()
+            .max_expansion_tokens(0)
+            .build()
+            .err();
+        assert_eq!(err, Some(ConfigError::ZeroLimit("max_expansion_tokens")));
+    }
+
+    #[test]
+    fn builder_rejects_malformed_defines() {
+        for bad in ["", "=1", "1ABC=2", "A-B"] {
+            let err = PreprocessorConfig::builder().define(bad).build().err();
+            assert_eq!(err, Some(ConfigError::MalformedDefine(bad.to_string())));
+        }
+        let err = PreprocessorConfig::builder().undefine("A B").build().err();
+        assert_eq!(err, Some(ConfigError::MalformedDefine("A B".to_string())));
+    }
+
+    #[test]
+    fn builder_rejects_conflicting_defines() {
+        let err = PreprocessorConfig::builder()
+            .define("X=1")
+            .define("X=2")
+            .build()
+            .err();
+        assert_eq!(
+            err,
+            Some(ConfigError::ConflictingDefine {
+                name: "X".to_string(),
+                first: "1".to_string(),
+                second: "2".to_string(),
+            })
+        );
+
+        let err = PreprocessorConfig::builder()
+            .define("X")
+            .undefine("X")
+            .build()
+            .err();
+        assert_eq!(
+            err,
+            Some(ConfigError::DefineUndefineConflict("X".to_string()))
+        );
+    }
+
+    #[test]
+    fn builder_checks_include_dirs_only_when_asked() {
+        let missing = std::env::temp_dir().join("includium-no-such-dir");
+        assert!(
+            PreprocessorConfig::builder()
+                .include_dir(&missing)
+                .build()
+                .is_ok()
+        );
+        let err = PreprocessorConfig::builder()
+            .include_dir(&missing)
+            .require_existing_include_dirs(true)
+            .build()
+            .err();
+        assert_eq!(
+            err,
+            Some(ConfigError::MissingIncludeDir(
+                missing.to_string_lossy().into_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn builder_warns_on_dubious_combinations() {
+        let (_, warnings) = PreprocessorConfig::builder()
+            .target(Target::MacOS)
+            .compiler(Compiler::MSVC)
+            .include_dir("inc")
+            .include_dir("inc")
+            .build_with_warnings()
+            .unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("given more than once"));
+        assert!(warnings[1].contains("MSVC"));
+    }
+
+    // -- File system resolver tests --
+
+    /// Write `files` under a fresh temporary directory and return its path
+    fn temp_tree(name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join(format!("includium-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        for (path, content) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn latin1_header_is_decoded_like_the_main_input() {
+        let root = temp_tree("latin1-header", &[]);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("old.h"), b"int a; /* caf\xe9 */\n").unwrap();
+        let header = root.join("old.h").to_string_lossy().into_owned();
+        let resolver = FileSystemResolver::new().with_include_dir(&root);
+
+        let config = PreprocessorConfig {
+            include_resolver: Some(resolver.clone().into_resolver()),
+            ..PreprocessorConfig::for_linux()
+        };
+        let err = process("#include <old.h>\n", &config).unwrap_err();
+        assert!(err.to_string().contains("invalid UTF-8 in input"), "{err}");
+        assert_eq!((err.file.as_str(), err.line), (header.as_str(), 1));
+
+        let config = PreprocessorConfig {
+            include_resolver: Some(resolver.into_resolver()),
+            ..PreprocessorConfig::for_linux().with_lossy_utf8(true)
+        };
+        let (mut pp, warnings) = collecting_preprocessor(config);
+        assert_eq!(pp.process("#include <old.h>\n").unwrap(), "int a;  \n\n");
+        assert_eq!(
+            *warnings.borrow(),
+            [format!("{header}:1: invalid UTF-8 replaced with U+FFFD")]
+        );
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    /// Preprocess `root/m.c` with `FileSystemResolver` searching `include_dirs`
+    fn process_tree(
+        root: &std::path::Path,
+        include_dirs: &[&str],
+        config: PreprocessorConfig,
+    ) -> Result<String, PreprocessError> {
+        let mut resolver = FileSystemResolver::new();
+        for dir in include_dirs {
+            resolver = resolver.with_include_dir(root.join(dir));
+        }
+        let config = PreprocessorConfig {
+            include_resolver: Some(resolver.into_resolver()),
+            ..config
+        };
+        let mut pp = Preprocessor::with_config(&config);
+        pp.set_current_file(root.join("m.c").to_string_lossy().into_owned());
+        pp.process(&std::fs::read_to_string(root.join("m.c")).unwrap())
+    }
+
+    #[test]
+    fn self_include_in_a_subdirectory_is_found() {
+        let root = temp_tree(
+            "self-include-subdir",
+            &[
+                (
+                    "m.c",
+                    "#include \"dir/self.h\"\n#include \"dir/guarded.h\"\n",
+                ),
+                ("dir/self.h", "int self_h;\n#include \"self.h\"\n"),
+                (
+                    "dir/guarded.h",
+                    "#ifndef GUARDED_H\n#define GUARDED_H\n#include \"guarded.h\"\nint guarded;\n#endif\n",
+                ),
+            ],
+        );
+        let err = process_tree(&root, &[], PreprocessorConfig::for_linux()).unwrap_err();
+        assert!(
+            err.to_string().contains("file includes itself: 'self.h'"),
+            "{err}"
+        );
+        assert_eq!(err.file, root.join("dir/self.h").to_string_lossy());
+
+        std::fs::write(root.join("m.c"), "#include \"dir/guarded.h\"\n").unwrap();
+        let out = process_tree(&root, &[], PreprocessorConfig::for_linux()).unwrap();
+        assert_eq!(out.matches("int guarded;").count(), 1);
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn include_cycle_in_a_subdirectory_is_found() {
+        let root = temp_tree(
+            "include-cycle-subdir",
+            &[
+                ("m.c", "#include \"dir/a.h\"\n"),
+                ("dir/a.h", "#include \"b.h\"\n"),
+                ("dir/b.h", "#include \"a.h\"\n"),
+            ],
+        );
+        let err = process_tree(&root, &[], PreprocessorConfig::for_linux()).unwrap_err();
+        assert!(
+            err.to_string().contains("Include cycle detected for 'a.h'"),
+            "{err}"
+        );
+        assert_eq!(err.file, root.join("dir/b.h").to_string_lossy());
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn headers_found_through_include_dirs_use_their_own_directory() {
+        let root = temp_tree(
+            "include-dir-nested",
+            &[
+                (
+                    "m.c",
+                    "#include <sub/a.h>\n#include <sub/../sub/a.h>\n#include \"inc/sub/a.h\"\n",
+                ),
+                (
+                    "inc/sub/a.h",
+                    "#pragma once\n#include \"b.h\"\nconst char *a = __FILE__;\n",
+                ),
+                ("inc/sub/b.h", "int sub_b;\n"),
+                ("inc/b.h", "int wrong_b;\n"),
+            ],
+        );
+        let out = process_tree(&root, &["inc"], PreprocessorConfig::for_linux()).unwrap();
+        assert_eq!(out.matches("int sub_b;").count(), 1, "{out}");
+        assert!(!out.contains("wrong_b"));
+        let file = root.join("inc").join("sub/a.h");
+        assert!(
+            out.contains(&format!("const char *a = \"{}\";", file.display())),
+            "{out}"
+        );
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn include_depth_is_limited() {
+        let config = PreprocessorConfig::builder()
+            .max_include_depth(5)
+            .build()
+            .unwrap();
+        let mut pp = Preprocessor::with_config(&config).with_include_resolver(|path, _, _| {
+            let n: usize = path.trim_end_matches(".h").parse().unwrap();
+            Some(format!("#include \"{}.h\"\n", n + 1))
+        });
+        let err = pp.process("#include \"0.h\"\n").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("#include nested more than 5 levels deep"),
+            "{err}"
+        );
+        assert_eq!(err.file, "4.h");
+
+        assert!(matches!(
+            PreprocessorConfig::builder().max_include_depth(0).build(),
+            Err(ConfigError::ZeroLimit("max_include_depth"))
+        ));
+    }
+
+    #[test]
+    fn include_context_reports_including_file_and_dir() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = Rc::clone(&seen);
+        let mut pp = Preprocessor::new().with_include_resolver(move |path, _kind, context| {
+            record.borrow_mut().push((
+                context.including_file.clone(),
+                context.including_dir.clone(),
+            ));
+            (path == "a.h")
+                .then(|| "#include \"b.h\"\n".to_string())
+                .or_else(|| (path == "b.h").then(String::new))
+        });
+        pp.set_current_file("src/main.c".to_string());
+        pp.process("#include \"a.h\"\n").unwrap();
+        let seen = seen.borrow();
+        assert_eq!(seen[0].0, "src/main.c");
+        assert_eq!(seen[0].1.as_deref(), Some("src"));
+
+        let mut pp = Preprocessor::new().with_include_resolver(|_, _, context| {
+            assert_eq!(context.including_file, "<stdin>");
+            assert_eq!(context.including_dir, None);
+            Some(String::new())
+        });
+        pp.process("#include <x.h>\n").unwrap();
+    }
+
+    #[test]
+    fn file_system_resolver_search_order() {
+        let root = temp_tree(
+            "search-order",
+            &[
+                ("src/main.c", ""),
+                (
+                    "src/include/a.h",
+                    "#include \"detail/b.h\"\n#include <b.h>\n",
+                ),
+                ("src/include/detail/b.h", "int nested_b;\n"),
+                ("src/include/b.h", "int sibling_b;\n"),
+                ("user/b.h", "int user_b;\n"),
+                ("user/c.h", "int user_c;\n"),
+                ("system/c.h", "int system_c;\n"),
+                ("system/d.h", "int system_d;\n"),
+            ],
+        );
+        let resolver = FileSystemResolver::new()
+            .with_include_dir(root.join("user"))
+            .with_system_dir(root.join("system"));
+        let config = PreprocessorConfig {
+            include_resolver: Some(resolver.into_resolver()),
+            ..PreprocessorConfig::for_linux()
+        };
+        let mut pp = Preprocessor::with_config(&config);
+        pp.set_current_file(root.join("src/main.c").to_string_lossy().into_owned());
+        let out = pp
+            .process("#include \"include/a.h\"\n#include <c.h>\n#include \"d.h\"\n")
+            .unwrap();
+        let lines: Vec<&str> = out.lines().filter(|l| !l.trim().is_empty()).collect();
+        // Quote includes chain relative to each including file; angle includes
+        // skip that directory and go to the user dirs, then the system dirs
+        assert_eq!(
+            lines,
+            vec![
+                "int nested_b;",
+                "int user_b;",
+                "int user_c;",
+                "int system_d;"
+            ]
+        );
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn file_system_resolver_routes_by_include_kind() {
+        let root = temp_tree(
+            "include-kind-dirs",
+            &[
+                ("here/x.h", ""),
+                ("user/y.h", ""),
+                ("system/x.h", ""),
+                ("system/y.h", ""),
+                ("system/z.h", ""),
+            ],
+        );
+        let resolver = FileSystemResolver::with_dirs([root.join("user")], [root.join("system")]);
+        let context = IncludeContext {
+            including_dir: Some(root.join("here").to_string_lossy().into_owned()),
+            ..IncludeContext::default()
+        };
+        let find = |path, kind| resolver.find(path, &kind, &context).unwrap();
+
+        assert_eq!(find("x.h", IncludeKind::Local), root.join("here/x.h"));
+        assert_eq!(find("x.h", IncludeKind::System), root.join("system/x.h"));
+        assert_eq!(find("y.h", IncludeKind::Local), root.join("user/y.h"));
+        assert_eq!(find("y.h", IncludeKind::System), root.join("user/y.h"));
+        // Local includes fall back to the system directories
+        assert_eq!(find("z.h", IncludeKind::Local), root.join("system/z.h"));
+
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn file_system_resolver_reads_gcc_environment_variables() {
+        use std::ffi::OsString;
+        use std::path::PathBuf;
+
+        let joined = |paths: &[&str]| std::env::join_paths(paths).unwrap();
+        let vars = |name: &str| -> Option<OsString> {
+            match name {
+                "CPATH" => Some(joined(&["cp1", "", "cp2"])),
+                "C_INCLUDE_PATH" => Some(joined(&["c_sys"])),
+                "CPLUS_INCLUDE_PATH" => Some(joined(&["cxx_sys"])),
+                _ => None,
+            }
+        };
+        let context = IncludeContext {
+            include_dirs: vec!["explicit".to_string()],
+            including_dir: Some("here".to_string()),
+            ..IncludeContext::default()
+        };
+        let dirs = |language, kind| {
+            resolver::FileSystemResolver::from_env_vars(language, vars).search_dirs(&kind, &context)
+        };
+
+        let expected: Vec<PathBuf> = ["here", "explicit", "cp1", ".", "cp2", "c_sys"]
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(dirs(Language::C, IncludeKind::Local), expected);
+        assert_eq!(dirs(Language::C, IncludeKind::System), expected[1..]);
+        assert_eq!(
+            dirs(Language::Cpp, IncludeKind::System).last(),
+            Some(&PathBuf::from("cxx_sys"))
+        );
+        assert!(
+            resolver::FileSystemResolver::from_env_vars(Language::C, |_| None)
+                .search_dirs(&IncludeKind::System, &IncludeContext::default())
+                .is_empty()
+        );
+    }
+
+    // -- Line ending and BOM normalization tests --
+
+    #[test]
+    fn normalize_input_removes_bom() {
+        let bom = "\u{FEFF}";
+        let input = format!("{bom}#define A 1\nA\n");
+        let result = engine::normalize_input(&input);
+        assert_eq!(result, "#define A 1\nA\n");
+    }
+
+    #[test]
+    fn normalize_input_converts_crlf() {
+        let input = "#define A 1\r\nA\r\n";
+        let result = engine::normalize_input(input);
+        assert_eq!(result, "#define A 1\nA\n");
+    }
+
+    #[test]
+    fn normalize_input_converts_bare_cr() {
+        let input = "#define A 1\rA\r";
+        let result = engine::normalize_input(input);
+        assert_eq!(result, "#define A 1\nA\n");
+    }
+
+    #[test]
+    fn normalize_input_mixed_endings() {
+        let input = "a\nb\r\nc\r";
+        let result = engine::normalize_input(input);
+        assert_eq!(result, "a\nb\nc\n");
+    }
+
+    #[test]
+    fn split_lines_handles_every_ending() {
+        let lines: Vec<&str> = engine::split_lines("a\rb\r\nc\n\rd").collect();
+        assert_eq!(lines, ["a", "b", "c", "", "d"]);
+        assert_eq!(engine::split_lines("a\r").count(), 1);
+        assert_eq!(engine::split_lines("").count(), 0);
+    }
+
+    #[test]
+    fn cr_delimited_define_is_recognized() {
+        let mut pp = Preprocessor::new();
+        let out = pp.process("#define A 1\rA\r").unwrap();
+        assert_eq!(out, "1\n");
+    }
+
+    #[test]
+    fn mixed_line_endings_process_like_lf() {
+        let input = "#define A 1\r#define B 2\r\n#if A + B == 3\nA B\r#endif\r\n";
+        let mut pp = Preprocessor::new();
+        assert_eq!(pp.process(input).unwrap(), "1 2\n");
+    }
+
+    #[test]
+    fn cr_line_splice_joins_directive() {
+        let mut pp = Preprocessor::new();
+        let out = pp
+            .process("#define SUM(a, b) \\\r(a + b)\rSUM(1, 2)\r")
+            .unwrap();
+        assert_eq!(out, "(1 + 2)\n");
+    }
+
+    #[test]
+    fn spliced_lines_keep_line_numbers() {
+        let mut pp = Preprocessor::new();
+        let out = pp
+            .process(
+                "#define A \\\n  1\n__LINE__ A\n#define F(x, y) x y\nF(\\\n1,\n 2)\n__LINE__\n",
+            )
+            .unwrap();
+        assert_eq!(out, "3 1\n1 2\n8\n");
+    }
+
+    #[test]
+    fn line_comment_ending_in_backslash_warns() {
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let out = pp
+            .process(
+                "// note \\\nint swallowed;\n__LINE__\n#if 0\n// skipped \\\nskipped\n#endif\n",
+            )
+            .unwrap();
+        assert_eq!(out, " \n3\n");
+        assert_eq!(*warnings.borrow(), ["<stdin>:1: multi-line comment"]);
+    }
+
+    #[test]
+    fn backslash_before_trailing_space_splices_with_warning() {
+        let (mut pp, warnings) = collecting_preprocessor(PreprocessorConfig::default());
+        let out = pp.process("int a = \\ \t\n1;\n__LINE__\n").unwrap();
+        assert_eq!(out, "int a = 1;\n3\n");
+        assert_eq!(
+            *warnings.borrow(),
+            ["<stdin>:1: backslash and newline separated by space"]
+        );
+    }
+
+    #[test]
+    fn parse_events_splits_cr_lines() {
+        let pp = Preprocessor::new();
+        let events = pp.parse_events("#define A 1\rA\r#undef A\r").unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(
+            &events[2],
+            PpEvent::Undef { name, location } if name == "A" && location.line == 3
+        ));
+    }
+
+    #[test]
+    fn normalize_input_no_change_for_lf() {
+        let input = "#define A 1\nA\n";
+        let result = engine::normalize_input(input);
+        assert_eq!(result, input);
+    }
+
+    #[test]
+    fn denormalize_output_to_crlf() {
+        let input = "a\nb\nc\n";
+        let result = engine::denormalize_output(input, &LineEnding::CRLF);
+        assert_eq!(result, "a\r\nb\r\nc\r\n");
+    }
+
+    #[test]
+    fn denormalize_output_to_cr() {
+        let input = "a\nb\nc\n";
         let result = engine::denormalize_output(input, &LineEnding::CR);
         assert_eq!(result, "a\rb\rc\r");
     }
@@ -1572,4 +5190,169 @@ FRESH_MACRO
         let out = pp.process(input).unwrap();
         assert_eq!(out, "1\n");
     }
+
+    #[test]
+    fn initial_file_names_the_main_input() {
+        let config = PreprocessorConfig::for_linux().with_initial_file("buffer.c");
+        let mut pp = PreprocessorDriver::with_config(&config);
+        assert_eq!(pp.process("__FILE__\n").unwrap(), "\"buffer.c\"\n");
+
+        let err = pp.process("#error stop\n").unwrap_err();
+        assert_eq!(err.file, "buffer.c");
+
+        pp.set_current_file("other.c".to_string());
+        pp.reset();
+        assert_eq!(pp.process("__FILE__\n").unwrap(), "\"buffer.c\"\n");
+    }
+
+    mod properties {
+        use crate::engine;
+        use crate::token::Token;
+        use proptest::prelude::*;
+
+        /// Characters that exercise the tokenizer: identifiers, numbers,
+        /// literals, escapes and punctuation
+        const LINE_CHARS: &str = "[a-z0-9_ \t#\"'\\\\<>=!&|^~%.,;:?()\\[\\]{}+*/-]{0,40}";
+
+        fn comment_free_line() -> impl Strategy<Value = String> {
+            LINE_CHARS.prop_filter("line contains a comment", |s| {
+                !s.contains("//") && !s.contains("/*")
+            })
+        }
+
+        proptest! {
+            #![proptest_config(ProptestConfig::with_cases(512))]
+
+            #[test]
+            fn tokenize_round_trips_without_comments(line in comment_free_line()) {
+                prop_assert_eq!(engine::tokens_to_string(&engine::tokenize_line(&line)), line);
+            }
+
+            #[test]
+            fn text_without_macros_passes_through(
+                line in "[A-Z0-9 \t\"'\\\\<>=!&|^~%.,;:?()\\[\\]{}+*/-]{0,40}",
+            ) {
+                prop_assume!(!line.contains("//") && !line.contains("/*"));
+                prop_assume!(!line.trim_start().starts_with('#') && !line.trim_end_matches([' ', '\t']).ends_with('\\'));
+                let input = format!("{line}\n");
+                let output = crate::Preprocessor::new().process(&input);
+                prop_assert_eq!(output.ok(), Some(input));
+            }
+
+            #[test]
+            fn strip_comments_is_idempotent(line in LINE_CHARS) {
+                let once = engine::strip_comments(&line);
+                prop_assert_eq!(engine::strip_comments(&once), once);
+            }
+
+            #[test]
+            fn strip_comments_keeps_string_literals(
+                before in "[a-z =*]{0,10}",
+                inner in "[a-z0-9 /*#']{0,20}",
+            ) {
+                let line = format!("{before}\"{inner}\";");
+                let stripped = engine::strip_comments(&line);
+                let literal = format!("\"{inner}\"");
+                prop_assert!(stripped.contains(&literal), "{:?} became {:?}", line, stripped);
+            }
+
+            #[test]
+            fn line_splice_removes_only_splices(text in "[a-z\\\\\n ]{0,40}") {
+                let spliced = engine::line_splice(&text);
+                prop_assert_eq!(spliced.len(), text.len() - 2 * text.matches("\\\n").count());
+            }
+
+            #[test]
+            fn token_pasting_consumes_every_paste_operator(
+                first in "[a-z0-9_]{0,4}",
+                rest in prop::collection::vec(("( ?## ?| )", "[a-z0-9_+<>=-]{0,3}"), 0..6),
+            ) {
+                // An empty operand stands for an empty macro argument, which
+                // substitution turns into a placemarker
+                let operand = |text: &str| {
+                    if text.is_empty() {
+                        vec![engine::PLACEMARKER]
+                    } else {
+                        engine::tokenize_line(text)
+                    }
+                };
+                let mut tokens = operand(&first);
+                for (separator, text) in &rest {
+                    tokens.extend(engine::tokenize_line(separator));
+                    tokens.extend(operand(text));
+                }
+                let pasted = engine::apply_token_pasting(&tokens, |_, _| {});
+                prop_assert!(
+                    !pasted.iter().any(|t| matches!(t, Token::Other(s) if s.trim() == "##")),
+                    "{:?} {:?} pasted to {:?}", first, rest, pasted
+                );
+            }
+
+            #[test]
+            fn expression_evaluation_never_panics(
+                parts in prop::collection::vec(
+                    prop_oneof![
+                        any::<i64>().prop_map(|n| n.unsigned_abs().to_string()),
+                        Just("9223372036854775807".to_string()),
+                        prop::sample::select(vec![
+                            "+", "-", "*", "/", "%", "<<", ">>", "<", ">", "<=", ">=", "==", "!=",
+                            "&", "|", "^", "~", "!", "&&", "||", "(", ")", "defined", "X",
+                        ]).prop_map(str::to_string),
+                    ],
+                    0..24,
+                ),
+            ) {
+                let expr = parts.join(" ");
+                if let Ok(tokens) = engine::tokenize_expression(&expr) {
+                    let _ = engine::evaluate_expression_tokens(&tokens, |_: &str| false);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "std"))]
+mod no_std_tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn driver_runs_without_std() {
+        let mut pp = PreprocessorDriver::new().with_include_resolver(|path, _, _| {
+            (path == "a.h").then(|| "#define A 1\n".to_string())
+        });
+        let out = pp
+            .process("#include \"a.h\"\n#if A\nint a = A;\n#endif\n")
+            .unwrap();
+        assert_eq!(out.trim(), "int a = 1;");
+    }
+
+    #[test]
+    fn date_and_time_are_placeholders_without_a_clock() {
+        let out = process("__DATE__ __TIME__\n", &PreprocessorConfig::default()).unwrap();
+        assert_eq!(out.trim(), "\"??? ?? ????\" \"??:??:??\"");
+    }
+
+    #[test]
+    fn including_dir_is_found_lexically() {
+        let mut pp = PreprocessorDriver::new().with_include_resolver(|_, _, context| {
+            assert_eq!(context.including_dir.as_deref(), Some("src/sub"));
+            Some(String::new())
+        });
+        pp.set_current_file("src/sub/main.c".to_string());
+        pp.process("#include \"a.h\"\n").unwrap();
+    }
+
+    #[test]
+    fn pragma_once_normalizes_names_lexically() {
+        let mut pp = PreprocessorDriver::new().with_include_resolver(|path, _, _| {
+            path.ends_with("a.h")
+                .then(|| "#pragma once\nint a;\n".to_string())
+        });
+        let out = pp
+            .process("#include \"a.h\"\n#include \"./a.h\"\n#include \"dir/../a.h\"\n")
+            .unwrap();
+        assert_eq!(out.matches("int a;").count(), 1);
+    }
 }
@@ -1,5 +1,11 @@
-use std::rc::Rc;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ops::Index;
 
+use crate::HashMap;
+use crate::engine;
 use crate::token::Token;
 
 /// A preprocessor macro definition
@@ -8,8 +14,271 @@ pub struct Macro {
     pub(crate) params: Option<Vec<String>>,
     pub(crate) body: Rc<Vec<Token>>,
     pub(crate) is_variadic: bool,
-    #[allow(dead_code)] // For future tooling integration
     pub(crate) definition_location: Option<(String, usize)>,
-    #[allow(dead_code)] // For future tooling integration
     pub(crate) is_builtin: bool,
 }
+
+/// What sort of macro a name refers to, as reported by
+/// [`PreprocessorDriver::macro_kind`](crate::PreprocessorDriver::macro_kind)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacroKind {
+    /// A user-defined macro without a parameter list
+    Object,
+    /// A user-defined macro with a parameter list
+    Function {
+        /// Number of named parameters, not counting `...`
+        arity: usize,
+        /// Whether the parameter list ends in `...`
+        variadic: bool,
+    },
+    /// A predefined macro whose value depends on where it is expanded, such
+    /// as `__LINE__` or `__COUNTER__`
+    Dynamic,
+    /// A macro predefined by the configuration, such as `__STDC__` or `__linux__`
+    Builtin,
+}
+
+/// Two macros are equal when their parameters, variadic flag and replacement
+/// tokens match exactly. Where a macro was defined and whether it is a builtin
+/// are bookkeeping rather than part of the definition, so they are ignored.
+impl PartialEq for Macro {
+    fn eq(&self, other: &Self) -> bool {
+        self.params == other.params
+            && self.is_variadic == other.is_variadic
+            && self.body == other.body
+    }
+}
+
+impl Eq for Macro {}
+
+impl Macro {
+    /// Parameter names of a function-like macro, or `None` for an object-like macro
+    #[must_use]
+    pub fn params(&self) -> Option<&[String]> {
+        self.params.as_deref()
+    }
+
+    /// Whether the macro accepts a variable number of arguments (`...`)
+    #[must_use]
+    pub const fn is_variadic(&self) -> bool {
+        self.is_variadic
+    }
+
+    /// Whether the macro was predefined by the configuration rather than the user
+    #[must_use]
+    pub const fn is_builtin(&self) -> bool {
+        self.is_builtin
+    }
+
+    /// Whether this is a builtin, object-like or function-like macro
+    #[must_use]
+    pub fn kind(&self) -> MacroKind {
+        match &self.params {
+            _ if self.is_builtin => MacroKind::Builtin,
+            None => MacroKind::Object,
+            Some(params) => MacroKind::Function {
+                arity: params.len(),
+                variadic: self.is_variadic,
+            },
+        }
+    }
+
+    /// The replacement list rendered back to source text
+    #[must_use]
+    pub fn body_text(&self) -> String {
+        engine::tokens_to_string(&self.body)
+    }
+
+    /// Render the macro as a `#define` line for `name`, without a line terminator
+    ///
+    /// Function-like macros list their parameters, ending in `...` if variadic.
+    #[must_use]
+    pub fn to_define_string(&self, name: &str) -> String {
+        let mut out = format!("#define {name}");
+        if let Some(params) = &self.params {
+            let mut list = params.clone();
+            if self.is_variadic {
+                list.push("...".to_string());
+            }
+            out.push('(');
+            out.push_str(&list.join(", "));
+            out.push(')');
+        }
+        let body = self.body_text();
+        if !body.is_empty() {
+            out.push(' ');
+            out.push_str(&body);
+        }
+        out
+    }
+
+    /// Whether two definitions are identical in the sense of C11 6.10.3p2:
+    /// same parameters and the same replacement list, where any run of
+    /// whitespace counts as a single separator
+    pub(crate) fn same_definition(&self, other: &Self) -> bool {
+        fn normalized(body: &[Token]) -> Vec<&str> {
+            let mut out: Vec<&str> = Vec::with_capacity(body.len());
+            for token in body {
+                let text = engine::token_to_string(token);
+                if text.chars().all(char::is_whitespace) {
+                    if out.last().is_some_and(|last| *last != " ") {
+                        out.push(" ");
+                    }
+                } else {
+                    out.push(text);
+                }
+            }
+            if out.last() == Some(&" ") {
+                out.pop();
+            }
+            out
+        }
+
+        self.params == other.params
+            && self.is_variadic == other.is_variadic
+            && normalized(&self.body) == normalized(&other.body)
+    }
+}
+
+/// A macro table in a portable form, as produced by
+/// [`PreprocessorDriver::export_macros`](crate::PreprocessorDriver::export_macros)
+/// or converted from [`PreprocessorDriver::get_macros`](crate::PreprocessorDriver::get_macros)
+///
+/// With the `serde` feature it can be serialized, e.g. to JSON, so a later run
+/// can start from the same macros without replaying the headers that made them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MacroSet {
+    /// The macros, sorted by name
+    pub macros: Vec<MacroEntry>,
+}
+
+/// One macro of a [`MacroSet`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MacroEntry {
+    /// Macro name
+    pub name: String,
+    /// Parameter names of a function-like macro, or `None` for an object-like macro
+    pub params: Option<Vec<String>>,
+    /// Whether the parameter list ends in `...`
+    pub variadic: bool,
+    /// Replacement list as source text
+    pub body: String,
+    /// Whether the macro was predefined by the configuration
+    pub builtin: bool,
+}
+
+impl MacroSet {
+    /// Collect every macro of `table`
+    pub(crate) fn from_table(table: &MacroTable) -> Self {
+        Self::from(table.as_map())
+    }
+}
+
+impl From<&HashMap<String, Macro>> for MacroSet {
+    /// Snapshot a macro table such as the one returned by
+    /// [`PreprocessorDriver::get_macros`](crate::PreprocessorDriver::get_macros)
+    fn from(table: &HashMap<String, Macro>) -> Self {
+        let mut macros: Vec<MacroEntry> = table
+            .iter()
+            .map(|(name, mac)| MacroEntry {
+                name: name.clone(),
+                params: mac.params.clone(),
+                variadic: mac.is_variadic,
+                body: mac.body_text(),
+                builtin: mac.is_builtin,
+            })
+            .collect();
+        macros.sort_by(|a, b| a.name.cmp(&b.name));
+        Self { macros }
+    }
+}
+
+/// The set of defined macros
+///
+/// Changes can be grouped into nested scopes: each open scope keeps the
+/// previous value of every entry changed while it is open, so rolling it back
+/// only touches those entries instead of copying the whole table.
+#[derive(Clone, Debug, Default)]
+pub struct MacroTable {
+    macros: HashMap<String, Macro>,
+    scopes: Vec<Vec<(String, Option<Macro>)>>,
+}
+
+impl MacroTable {
+    /// Create an empty table
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look up a macro by name
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&Macro> {
+        self.macros.get(name)
+    }
+
+    /// Check if a macro is defined
+    #[must_use]
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+    }
+
+    /// Names of all defined macros, in no particular order
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.macros.keys()
+    }
+
+    /// The defined macros as a plain map
+    #[must_use]
+    pub const fn as_map(&self) -> &HashMap<String, Macro> {
+        &self.macros
+    }
+
+    /// Define or replace a macro, returning the previous definition
+    pub fn insert(&mut self, name: String, mac: Macro) -> Option<Macro> {
+        let previous = self.macros.insert(name.clone(), mac);
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((name, previous.clone()));
+        }
+        previous
+    }
+
+    /// Remove a macro, returning its definition if it was defined
+    pub fn remove(&mut self, name: &str) -> Option<Macro> {
+        let previous = self.macros.remove(name);
+        if let Some(scope) = self.scopes.last_mut()
+            && previous.is_some()
+        {
+            scope.push((name.to_string(), previous.clone()));
+        }
+        previous
+    }
+
+    /// Start recording changes so they can be undone by [`Self::pop_scope`]
+    pub(crate) fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Undo every change made since the matching [`Self::push_scope`]
+    pub(crate) fn pop_scope(&mut self) {
+        let Some(changes) = self.scopes.pop() else {
+            return;
+        };
+        for (name, previous) in changes.into_iter().rev() {
+            match previous {
+                Some(mac) => self.macros.insert(name, mac),
+                None => self.macros.remove(&name),
+            };
+        }
+    }
+}
+
+impl Index<&str> for MacroTable {
+    type Output = Macro;
+
+    fn index(&self, name: &str) -> &Macro {
+        &self.macros[name]
+    }
+}
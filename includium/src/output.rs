@@ -0,0 +1,48 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::config::IncludeKind;
+use crate::context::PreprocessStats;
+use crate::event::{PpEvent, SourceLocation};
+
+/// A warning reported while preprocessing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Warning text, without the location prefix
+    pub message: String,
+    /// Where the warning was reported
+    pub location: SourceLocation,
+}
+
+/// A header read while preprocessing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dependency {
+    /// Path of the header, as reported by `__FILE__` inside it
+    pub path: String,
+    /// Whether the first include of the header used quotes or angle brackets
+    pub kind: IncludeKind,
+    /// Location of the first `#include` of the header
+    pub included_from: SourceLocation,
+}
+
+/// Everything produced by [`PreprocessorDriver::process_full`](crate::PreprocessorDriver::process_full)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProcessOutput {
+    /// Preprocessed text, as returned by `process`
+    pub text: String,
+    /// Warnings in the order they were reported
+    pub warnings: Vec<Diagnostic>,
+    /// Headers read, each listed once in the order first included
+    pub dependencies: Vec<Dependency>,
+    /// Work counters after processing, accumulated across calls
+    pub stats: PreprocessStats,
+}
+
+/// Warnings and dependencies gathered during one `process_full` call, and
+/// events during one `events` call
+#[derive(Debug, Default)]
+pub(crate) struct Recording {
+    pub(crate) warnings: Vec<Diagnostic>,
+    pub(crate) dependencies: Vec<Dependency>,
+    pub(crate) events: Option<Vec<PpEvent>>,
+}
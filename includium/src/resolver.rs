@@ -0,0 +1,172 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::config::{IncludeContext, IncludeKind, IncludeResolution, IncludeResolver, Language};
+
+/// Include resolver that reads headers from the file system
+///
+/// Search order follows GCC and Clang:
+///
+/// 1. for `#include "..."` only, the directory of the including file
+/// 2. the configured [`IncludeContext::include_dirs`] (`-I`), then the
+///    resolver's own user include directories
+/// 3. the system include directories
+///
+/// Absolute header names are opened directly.
+#[derive(Clone, Debug, Default)]
+pub struct FileSystemResolver {
+    include_dirs: Vec<PathBuf>,
+    system_dirs: Vec<PathBuf>,
+}
+
+impl FileSystemResolver {
+    /// Create a resolver with no search directories
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a resolver with user include directories (`-I`), searched by
+    /// both include forms, and system directories (`-isystem`), searched
+    /// after them
+    #[must_use]
+    pub fn with_dirs<U, S>(include_dirs: U, system_dirs: S) -> Self
+    where
+        U: IntoIterator,
+        U::Item: Into<PathBuf>,
+        S: IntoIterator,
+        S::Item: Into<PathBuf>,
+    {
+        Self {
+            include_dirs: include_dirs.into_iter().map(Into::into).collect(),
+            system_dirs: system_dirs.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Add a user include directory, searched by both include forms
+    #[must_use]
+    pub fn with_include_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.include_dirs.push(dir.into());
+        self
+    }
+
+    /// Add a system include directory, searched after the user directories
+    #[must_use]
+    pub fn with_system_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.system_dirs.push(dir.into());
+        self
+    }
+
+    /// Create a resolver seeded from the GCC environment variables
+    ///
+    /// `CPATH` entries are searched by both include forms, after any `-I`
+    /// directories. `C_INCLUDE_PATH` (or `CPLUS_INCLUDE_PATH` for C++) entries
+    /// become system directories. Empty entries mean the current directory.
+    #[must_use]
+    pub fn from_env(language: Language) -> Self {
+        Self::from_env_vars(language, |name| env::var_os(name))
+    }
+
+    /// Like [`FileSystemResolver::from_env`], reading variables through `var`
+    pub(crate) fn from_env_vars(
+        language: Language,
+        var: impl Fn(&str) -> Option<OsString>,
+    ) -> Self {
+        let paths = |name: &str| -> Vec<PathBuf> {
+            var(name)
+                .map(|value| {
+                    env::split_paths(&value)
+                        .map(|path| {
+                            if path.as_os_str().is_empty() {
+                                PathBuf::from(".")
+                            } else {
+                                path
+                            }
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let system_var = match language {
+            Language::C => "C_INCLUDE_PATH",
+            Language::Cpp => "CPLUS_INCLUDE_PATH",
+        };
+        Self {
+            include_dirs: paths("CPATH"),
+            system_dirs: paths(system_var),
+        }
+    }
+
+    /// Directories searched for an include of `kind`, in order
+    #[must_use]
+    pub fn search_dirs(&self, kind: &IncludeKind, context: &IncludeContext) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        if *kind == IncludeKind::Local
+            && let Some(dir) = &context.including_dir
+        {
+            dirs.push(PathBuf::from(dir));
+        }
+        dirs.extend(context.include_dirs.iter().map(PathBuf::from));
+        dirs.extend(self.include_dirs.iter().cloned());
+        dirs.extend(self.system_dirs.iter().cloned());
+        dirs
+    }
+
+    /// Find the file an include refers to without reading it
+    #[must_use]
+    pub fn find(
+        &self,
+        path: &str,
+        kind: &IncludeKind,
+        context: &IncludeContext,
+    ) -> Option<PathBuf> {
+        let header = Path::new(path);
+        if header.is_absolute() {
+            return header.is_file().then(|| header.to_path_buf());
+        }
+        // `dir/a.h` rather than `./dir/a.h` for the current directory, as
+        // GCC reports it
+        self.search_dirs(kind, context)
+            .into_iter()
+            .map(|dir| {
+                if dir == Path::new(".") {
+                    header.to_path_buf()
+                } else {
+                    dir.join(header)
+                }
+            })
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Find and read the file an include refers to
+    ///
+    /// The content is returned as bytes, so the driver decodes a header in a
+    /// legacy encoding the same way as the main input.
+    #[must_use]
+    pub fn resolve(
+        &self,
+        path: &str,
+        kind: &IncludeKind,
+        context: &IncludeContext,
+    ) -> IncludeResolution {
+        let Some(found) = self.find(path, kind, context) else {
+            return IncludeResolution::NotFound;
+        };
+        match fs::read(&found) {
+            Ok(content) => IncludeResolution::File {
+                path: found.to_string_lossy().into_owned(),
+                content,
+            },
+            Err(error) => IncludeResolution::Unreadable(format!("{}: {error}", found.display())),
+        }
+    }
+
+    /// Convert into an [`IncludeResolver`] for use in a configuration
+    #[must_use]
+    pub fn into_resolver(self) -> IncludeResolver {
+        Rc::new(move |path, kind, context| self.resolve(path, &kind, context))
+    }
+}
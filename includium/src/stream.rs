@@ -0,0 +1,243 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Deref;
+
+use crate::engine;
+use crate::token::{Token, is_identifier_continue};
+
+/// What kind of preprocessing token a [`PpToken`] is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PpTokenKind {
+    /// Identifier or keyword
+    Identifier,
+    /// Preprocessing number such as `42`, `0x1F` or `1.5e-3`
+    Number,
+    /// String literal, including its quotes
+    StringLiteral,
+    /// Character constant, including its quotes
+    CharLiteral,
+    /// Punctuator such as `+`, `->` or `<<=`
+    Punctuator,
+    /// Spaces and tabs between tokens on a line
+    Whitespace,
+    /// Line terminator, in the configured line ending
+    Newline,
+    /// Any other character, such as a stray `$` or `@`
+    Other,
+}
+
+/// Where a token sits in the preprocessed output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset of the token's first byte in the output text
+    pub start: usize,
+    /// Byte offset just past the token in the output text
+    pub end: usize,
+    /// Output line (1-based)
+    pub line: usize,
+    /// Character column in the output line (1-based)
+    pub column: usize,
+}
+
+/// A token of preprocessed output
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PpToken {
+    /// Kind of token
+    pub kind: PpTokenKind,
+    /// Token text as it appears in the output
+    pub text: String,
+    /// Position of the token in the output
+    pub span: Span,
+}
+
+/// Preprocessed output as typed tokens, as returned by
+/// [`PreprocessorDriver::process_tokens`](crate::PreprocessorDriver::process_tokens)
+///
+/// Displaying the stream gives exactly the text `process` returns for the
+/// same input. Tokens produced by `##` stay single tokens.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenStream {
+    tokens: Vec<PpToken>,
+    len: usize,
+    line: usize,
+    column: usize,
+}
+
+impl TokenStream {
+    /// Tokens other than whitespace and line terminators
+    pub fn significant(&self) -> impl Iterator<Item = &PpToken> {
+        self.tokens
+            .iter()
+            .filter(|token| !matches!(token.kind, PpTokenKind::Whitespace | PpTokenKind::Newline))
+    }
+
+    /// The tokens as a vector
+    #[must_use]
+    pub fn into_vec(self) -> Vec<PpToken> {
+        self.tokens
+    }
+
+    /// Append one output line, given the tokens it was built from if known
+    pub(crate) fn push_line(&mut self, text: &str, tokens: Option<&[Token]>, ending: &str) {
+        let lexed;
+        let tokens = if let Some(tokens) = tokens {
+            tokens
+        } else {
+            lexed = engine::tokenize_line(text);
+            &lexed
+        };
+        self.line += 1;
+        self.column = 1;
+        for (kind, text) in classify(tokens) {
+            self.push(kind, text);
+        }
+        self.push(PpTokenKind::Newline, ending.to_string());
+    }
+
+    /// Drop the terminator of the last line, for input without a final newline
+    pub(crate) fn pop_newline(&mut self) {
+        if let Some(token) = self
+            .tokens
+            .pop_if(|token| token.kind == PpTokenKind::Newline)
+        {
+            self.len = token.span.start;
+        }
+    }
+
+    fn push(&mut self, kind: PpTokenKind, text: String) {
+        let start = self.len;
+        self.len += text.len();
+        let span = Span {
+            start,
+            end: self.len,
+            line: self.line,
+            column: self.column,
+        };
+        self.column += text.chars().count();
+        self.tokens.push(PpToken { kind, text, span });
+    }
+}
+
+impl Deref for TokenStream {
+    type Target = [PpToken];
+
+    fn deref(&self) -> &[PpToken] {
+        &self.tokens
+    }
+}
+
+impl<'a> IntoIterator for &'a TokenStream {
+    type Item = &'a PpToken;
+    type IntoIter = core::slice::Iter<'a, PpToken>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tokens.iter()
+    }
+}
+
+impl IntoIterator for TokenStream {
+    type Item = PpToken;
+    type IntoIter = alloc::vec::IntoIter<PpToken>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.tokens.into_iter()
+    }
+}
+
+impl fmt::Display for TokenStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in &self.tokens {
+            f.write_str(&token.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Group the internal tokens of a line into typed tokens
+///
+/// The tokenizer splits numbers and punctuators into single characters, so
+/// runs of them are joined again here. Multi-character tokens, such as the
+/// result of a `##` paste, are never split or joined with their neighbors.
+fn classify(tokens: &[Token]) -> Vec<(PpTokenKind, String)> {
+    let other = |i: usize| match tokens.get(i) {
+        Some(Token::Other(s)) if s.chars().count() == 1 => s.chars().next(),
+        _ => None,
+    };
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let (kind, text) = match &tokens[i] {
+            Token::Identifier(s) => (PpTokenKind::Identifier, s.clone()),
+            Token::StringLiteral(s) => (PpTokenKind::StringLiteral, s.clone()),
+            Token::CharLiteral(s) => (PpTokenKind::CharLiteral, s.clone()),
+            Token::Other(s) if s.trim().is_empty() => (PpTokenKind::Whitespace, s.clone()),
+            // A macro name met during its own expansion is kept as `Other` so
+            // it isn't expanded again, but it is still an identifier
+            Token::Other(s) if engine::is_valid_identifier(s) => {
+                (PpTokenKind::Identifier, s.clone())
+            }
+            Token::Other(s)
+                if s.starts_with(|c: char| c.is_ascii_digit())
+                    || (s == "." && other(i + 1).is_some_and(|c| c.is_ascii_digit())) =>
+            {
+                let mut number = s.clone();
+                while let Some(next) = tokens.get(i + 1) {
+                    let continues = match next {
+                        Token::Identifier(id) => {
+                            number.push_str(id);
+                            true
+                        }
+                        Token::Other(t)
+                            if t.chars().all(|c| is_identifier_continue(c) || c == '.') =>
+                        {
+                            number.push_str(t);
+                            true
+                        }
+                        Token::Other(t)
+                            if (t == "+" || t == "-") && number.ends_with(['e', 'E', 'p', 'P']) =>
+                        {
+                            number.push_str(t);
+                            true
+                        }
+                        _ => false,
+                    };
+                    if !continues {
+                        break;
+                    }
+                    i += 1;
+                }
+                (PpTokenKind::Number, number)
+            }
+            Token::Other(s) if s.chars().count() > 1 => {
+                let kind = if engine::PUNCTUATORS.contains(&s.as_str()) {
+                    PpTokenKind::Punctuator
+                } else {
+                    PpTokenKind::Other
+                };
+                (kind, s.clone())
+            }
+            Token::Other(s) => {
+                // Longest punctuator spelled by this and the following characters
+                let mut candidate = String::new();
+                let mut longest = None;
+                for (offset, c) in (i..).map_while(|j| other(j).map(|c| (j - i, c))).take(4) {
+                    candidate.push(c);
+                    if engine::PUNCTUATORS.contains(&candidate.as_str()) {
+                        longest = Some((offset, candidate.clone()));
+                    }
+                }
+                match longest {
+                    Some((offset, punctuator)) => {
+                        i += offset;
+                        (PpTokenKind::Punctuator, punctuator)
+                    }
+                    None => (PpTokenKind::Other, s.clone()),
+                }
+            }
+        };
+        out.push((kind, text));
+        i += 1;
+    }
+    out
+}